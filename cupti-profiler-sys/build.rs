@@ -14,14 +14,151 @@
 
 use std::env;
 
+/// Splits `s` on commas that are not nested inside brackets.
+fn split_top_level(s: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0i32;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '(' | '<' | '[' => depth += 1,
+            ')' | '>' | ']' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+        .into_iter()
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .collect()
+}
+
+/// Generates trampolines that resolve every `cupti*` function in the bindings
+/// through `dlsym` on first use instead of linking against libcupti.
+fn generate_dynamic_cupti(bindings: &str) -> String {
+    let mut out = String::new();
+    for decl in bindings.split("pub fn ").skip(1) {
+        if !decl.starts_with("cupti") {
+            continue;
+        }
+        let name_end = decl.find('(').expect("function without arguments");
+        let name = &decl[..name_end];
+        let mut depth = 0;
+        let mut args_end = name_end;
+        for (i, c) in decl[name_end..].char_indices() {
+            match c {
+                '(' => depth += 1,
+                ')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        args_end = name_end + i;
+                        break;
+                    }
+                }
+                _ => {}
+            }
+        }
+        let args = split_top_level(&decl[name_end + 1..args_end]);
+        let ret = decl[args_end + 1..]
+            .split(';')
+            .next()
+            .unwrap()
+            .trim()
+            .trim_start_matches("->")
+            .trim();
+        let arg_names: Vec<&str> = args
+            .iter()
+            .map(|a| a.split(':').next().unwrap().trim())
+            .collect();
+        let arg_types: Vec<&str> = args
+            .iter()
+            .map(|a| a.split_once(':').unwrap().1.trim())
+            .collect();
+        out.push_str(&format!(
+            "pub unsafe fn {name}({args}) -> {ret} {{\n\
+             \x20   type Func = unsafe extern \"C\" fn({types}) -> {ret};\n\
+             \x20   static SYMBOL: std::sync::OnceLock<usize> = std::sync::OnceLock::new();\n\
+             \x20   let ptr = *SYMBOL.get_or_init(|| crate::dynamic::symbol(c\"{name}\") as usize);\n\
+             \x20   if ptr == 0 {{\n\
+             \x20       return CUptiResult_CUPTI_ERROR_NOT_INITIALIZED;\n\
+             \x20   }}\n\
+             \x20   std::mem::transmute::<usize, Func>(ptr)({names})\n\
+             }}\n",
+            args = args.join(", "),
+            types = arg_types.join(", "),
+            names = arg_names.join(", "),
+        ));
+    }
+    out
+}
+
+/// Pregenerated bindings, as (cargo feature, file stem under `src/bindings/`).
+const CUDA_BINDINGS: &[(&str, &str)] = &[
+    ("CUDA_11_8", "cuda_11_8"),
+    ("CUDA_12_X", "cuda_12"),
+    ("CUDA_13", "cuda_13"),
+];
+
+/// Returns the bindings file stem for the single enabled `cuda-*` feature.
+fn selected_bindings() -> &'static str {
+    let selected: Vec<&str> = CUDA_BINDINGS
+        .iter()
+        .filter(|(feature, _)| env::var(format!("CARGO_FEATURE_{}", feature)).is_ok())
+        .map(|(_, stem)| *stem)
+        .collect();
+    match selected[..] {
+        [stem] => stem,
+        [] => panic!("Enable one of the cuda-11-8, cuda-12-x or cuda-13 features"),
+        _ => panic!(
+            "Only one of the cuda-11-8, cuda-12-x or cuda-13 features can be enabled; \
+             use default-features = false to select an older CUDA release"
+        ),
+    }
+}
+
+/// Returns the CUDA installation root.
+///
+/// `CUDA_HOME` takes precedence, followed by `CUDA_PATH` as set by the Windows
+/// CUDA installer, and finally `/usr/local/cuda` on other platforms.
+fn cuda_home(target_os: &str) -> String {
+    env::var("CUDA_HOME")
+        .or_else(|_| env::var("CUDA_PATH"))
+        .unwrap_or_else(|_| {
+            if target_os == "windows" {
+                panic!("Set CUDA_PATH or CUDA_HOME to the CUDA installation");
+            }
+            "/usr/local/cuda".to_string()
+        })
+}
+
 fn main() {
+    let target_os = env::var("CARGO_CFG_TARGET_OS").unwrap_or_default();
+    let cuda_bindings = selected_bindings();
+    let bindings_path = format!("src/bindings/{}.rs", cuda_bindings);
+    println!(
+        "cargo::rustc-check-cfg=cfg(cupti_bindings, values({}))",
+        CUDA_BINDINGS
+            .iter()
+            .map(|(_, stem)| format!("\"{}\"", stem))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    println!("cargo:rustc-cfg=cupti_bindings=\"{}\"", cuda_bindings);
     let use_stubs = env::var("CARGO_FEATURE_STUBS").is_ok();
+    let use_dynamic = env::var("CARGO_FEATURE_DYNAMIC").is_ok() && !use_stubs;
+    println!("cargo::rustc-check-cfg=cfg(cupti_dynamic)");
     #[cfg(feature = "gen")]
     {
-        let cuda_path = env::var("CUDA_HOME").unwrap_or_else(|_| "/usr/local/cuda".to_string());
+        let cuda_path = cuda_home(&target_os);
         let cuda_include = format!("{}/include", cuda_path);
         println!("cargo:rerun-if-changed=wrapper.h");
         println!("cargo:rerun-if-env-changed=CUDA_HOME");
+        println!("cargo:rerun-if-env-changed=CUDA_PATH");
         let bindings = bindgen::Builder::default()
             .header("wrapper.h")
             .clang_arg(format!("-I{}", cuda_include))
@@ -38,24 +175,59 @@ fn main() {
             .parse_callbacks(Box::new(bindgen::CargoCallbacks::new()))
             .generate()
             .expect("Unable to generate bindings");
-        // Write to src/bindings.rs directly
-        let out_path = std::path::PathBuf::from("src/bindings.rs");
+        // Write to the bindings file of the selected CUDA release directly
+        let out_path = std::path::PathBuf::from(&bindings_path);
         bindings
             .write_to_file(out_path)
             .expect("Couldn't write bindings!");
     }
+    if !std::path::Path::new(&bindings_path).exists() {
+        panic!(
+            "{} is missing; generate it with the gen feature against a matching CUDA toolkit",
+            bindings_path
+        );
+    }
+    if use_dynamic {
+        println!("cargo:rerun-if-changed={}", bindings_path);
+        println!("cargo:rustc-cfg=cupti_dynamic");
+        let bindings = std::fs::read_to_string(&bindings_path).expect("Couldn't read bindings!");
+        let out_path = std::path::PathBuf::from(env::var("OUT_DIR").unwrap());
+        std::fs::write(
+            out_path.join("dynamic_cupti.rs"),
+            generate_dynamic_cupti(&bindings),
+        )
+        .expect("Couldn't write dynamic CUPTI trampolines!");
+    }
     if use_stubs {
+        println!("cargo:rerun-if-changed=stubs.cpp");
         cc::Build::new()
             .file("stubs.cpp")
             // stubs.cpp is self-contained and does not need CUDA headers
             .cpp(true)
             .compile("cupti_stubs");
     } else {
-        println!(
-            "cargo:rustc-link-search=native={}/lib64",
-            env::var("CUDA_HOME").unwrap_or_else(|_| "/usr/local/cuda".to_string())
-        );
-        println!("cargo:rustc-link-lib=cupti");
+        println!("cargo:rerun-if-env-changed=CUDA_HOME");
+        println!("cargo:rerun-if-env-changed=CUDA_PATH");
+        let cuda_path = cuda_home(&target_os);
+        if target_os == "windows" {
+            // cuda.lib lives with the toolkit, cupti.lib in the CUPTI extras.
+            println!("cargo:rustc-link-search=native={}/lib/x64", cuda_path);
+            println!(
+                "cargo:rustc-link-search=native={}/extras/CUPTI/lib64",
+                cuda_path
+            );
+        } else {
+            println!("cargo:rustc-link-search=native={}/lib64", cuda_path);
+            // Jetson boards ship libcuda with the Tegra drivers rather than
+            // in a directory the linker searches.
+            let tegra_lib = "/usr/lib/aarch64-linux-gnu/tegra";
+            if std::path::Path::new(tegra_lib).exists() {
+                println!("cargo:rustc-link-search=native={}", tegra_lib);
+            }
+        }
+        if !use_dynamic {
+            println!("cargo:rustc-link-lib=cupti");
+        }
         println!("cargo:rustc-link-lib=cuda");
     }
 }
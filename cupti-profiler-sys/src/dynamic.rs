@@ -0,0 +1,133 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Runtime loading of libcupti for the `dynamic` feature.
+
+use std::env;
+use std::ffi::{c_void, CStr, CString};
+use std::sync::OnceLock;
+
+static LIBRARY: OnceLock<usize> = OnceLock::new();
+
+#[cfg(unix)]
+mod os {
+    use std::ffi::{c_char, c_void};
+
+    pub unsafe fn open(path: *const c_char) -> *mut c_void {
+        libc::dlopen(path, libc::RTLD_NOW | libc::RTLD_LOCAL)
+    }
+
+    pub unsafe fn lookup(library: *mut c_void, name: *const c_char) -> *mut c_void {
+        libc::dlsym(library, name)
+    }
+}
+
+#[cfg(windows)]
+mod os {
+    use std::ffi::{c_char, c_void};
+
+    extern "system" {
+        fn LoadLibraryA(name: *const c_char) -> *mut c_void;
+        fn GetProcAddress(module: *mut c_void, name: *const c_char) -> *mut c_void;
+    }
+
+    pub unsafe fn open(path: *const c_char) -> *mut c_void {
+        LoadLibraryA(path)
+    }
+
+    pub unsafe fn lookup(library: *mut c_void, name: *const c_char) -> *mut c_void {
+        GetProcAddress(library, name)
+    }
+}
+
+/// Library paths tried in order until one loads.
+///
+/// `CUPTI_LIBRARY_PATH` takes precedence, followed by the CUPTI shipped with the
+/// toolkit in `CUDA_HOME` and finally the default dynamic linker search path.
+#[cfg(unix)]
+fn candidates() -> Vec<String> {
+    let mut paths = Vec::new();
+    if let Ok(path) = env::var("CUPTI_LIBRARY_PATH") {
+        paths.push(path);
+    }
+    if let Ok(cuda_home) = env::var("CUDA_HOME") {
+        paths.push(format!("{}/extras/CUPTI/lib64/libcupti.so", cuda_home));
+        paths.push(format!("{}/lib64/libcupti.so", cuda_home));
+    }
+    paths.push("libcupti.so".to_string());
+    paths.push("libcupti.so.13".to_string());
+    paths
+}
+
+/// Library paths tried in order until one loads.
+///
+/// The Windows DLL name carries the CUPTI release (e.g. `cupti64_2025.1.0.dll`),
+/// so the CUPTI directory of `CUDA_PATH` is scanned for it after
+/// `CUPTI_LIBRARY_PATH`.
+#[cfg(windows)]
+fn candidates() -> Vec<String> {
+    let mut paths = Vec::new();
+    if let Ok(path) = env::var("CUPTI_LIBRARY_PATH") {
+        paths.push(path);
+    }
+    if let Ok(cuda_path) = env::var("CUDA_PATH").or_else(|_| env::var("CUDA_HOME")) {
+        let dir = std::path::Path::new(&cuda_path).join("extras\\CUPTI\\lib64");
+        if let Ok(entries) = std::fs::read_dir(&dir) {
+            let mut dlls: Vec<String> = entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| {
+                    path.file_name()
+                        .and_then(|name| name.to_str())
+                        .is_some_and(|name| name.starts_with("cupti64_") && name.ends_with(".dll"))
+                })
+                .filter_map(|path| path.to_str().map(str::to_string))
+                .collect();
+            // Prefer the newest release if several are installed side by side.
+            dlls.sort();
+            paths.extend(dlls.into_iter().rev());
+        }
+    }
+    paths
+}
+
+fn library() -> *mut c_void {
+    *LIBRARY.get_or_init(|| {
+        for path in candidates() {
+            let Ok(c_path) = CString::new(path) else {
+                continue;
+            };
+            let handle = unsafe { os::open(c_path.as_ptr()) };
+            if !handle.is_null() {
+                return handle as usize;
+            }
+        }
+        eprintln!("Failed to load libcupti; set CUPTI_LIBRARY_PATH to its location");
+        0
+    }) as *mut c_void
+}
+
+/// Returns true if a CUPTI library was found and loaded.
+pub fn is_loaded() -> bool {
+    !library().is_null()
+}
+
+/// Resolves `name` from the loaded CUPTI library, or null if unavailable.
+pub fn symbol(name: &CStr) -> *mut c_void {
+    let library = library();
+    if library.is_null() {
+        return std::ptr::null_mut();
+    }
+    unsafe { os::lookup(library, name.as_ptr()) }
+}
@@ -12,10 +12,43 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[cfg(not(cupti_dynamic))]
 #[allow(non_upper_case_globals)]
 #[allow(non_camel_case_types)]
 #[allow(non_snake_case)]
 #[allow(dead_code)]
 #[allow(clippy::all)]
+#[cfg_attr(cupti_bindings = "cuda_11_8", path = "bindings/cuda_11_8.rs")]
+#[cfg_attr(cupti_bindings = "cuda_12", path = "bindings/cuda_12.rs")]
+#[cfg_attr(cupti_bindings = "cuda_13", path = "bindings/cuda_13.rs")]
 pub mod bindings;
+
+#[cfg(cupti_dynamic)]
+#[allow(non_upper_case_globals)]
+#[allow(non_camel_case_types)]
+#[allow(non_snake_case)]
+#[allow(dead_code)]
+#[allow(clippy::all)]
+#[cfg_attr(cupti_bindings = "cuda_11_8", path = "bindings/cuda_11_8.rs")]
+#[cfg_attr(cupti_bindings = "cuda_12", path = "bindings/cuda_12.rs")]
+#[cfg_attr(cupti_bindings = "cuda_13", path = "bindings/cuda_13.rs")]
+mod raw;
+
+#[cfg(cupti_dynamic)]
+pub mod dynamic;
+
+#[cfg(feature = "stubs")]
+#[allow(non_snake_case)]
+pub mod stubs;
+
+/// With the `dynamic` feature, every `cupti*` function is replaced by a
+/// trampoline that resolves the symbol from a runtime-loaded libcupti.
+#[cfg(cupti_dynamic)]
+#[allow(non_snake_case)]
+#[allow(clippy::all)]
+pub mod bindings {
+    pub use super::raw::*;
+    include!(concat!(env!("OUT_DIR"), "/dynamic_cupti.rs"));
+}
+
 pub use bindings::*;
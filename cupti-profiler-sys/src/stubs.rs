@@ -0,0 +1,68 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Controls of the simulated CUPTI compiled from `stubs.cpp`.
+
+use crate::bindings::*;
+use std::os::raw::{c_char, c_int, c_void};
+
+extern "C" {
+    /// Restores the simulation to its initial state.
+    pub fn cuptiStubReset();
+    /// Sets the versions reported by `cuptiGetVersion` and `cuDriverGetVersion`.
+    pub fn cuptiStubSetVersions(cuptiVersion: u32, driverVersion: c_int);
+    /// Sets the value reported by `cuDeviceGetAttribute` for `attrib`.
+    pub fn cuptiStubSetDeviceAttribute(attrib: CUdevice_attribute, value: c_int);
+    /// Sets the number of hardware counters `metricName` needs. Metrics need
+    /// 1 counter unless set.
+    pub fn cuptiStubSetMetricCounters(metricName: *const c_char, numCounters: usize);
+    /// Sets how many counters can be collected in one pass, 0 for no limit.
+    /// Configurations needing more counters report more passes.
+    pub fn cuptiStubSetCountersPerPass(numCounters: usize);
+    /// Sets what `cuptiRangeProfilerEnable` returns, `CUPTI_SUCCESS` unless set.
+    pub fn cuptiStubSetRangeProfilerResult(result: CUptiResult);
+    /// Makes the next decodes drop the last `count` ranges collected, as if
+    /// they did not fit in the counter data image.
+    pub fn cuptiStubDropRanges(count: usize);
+    /// Makes sizing a counter data image larger than `size` bytes fail with
+    /// `CUPTI_ERROR_OUT_OF_MEMORY`, or lifts the limit if `size` is 0.
+    pub fn cuptiStubSetMaxCounterDataSize(size: usize);
+    /// Returns how many range profiler sessions were enabled since the last
+    /// reset.
+    pub fn cuptiStubRangeProfilerEnables() -> usize;
+    /// Makes `ConfigAddMetrics` reject any list with `metricName` in it.
+    pub fn cuptiStubSetInvalidMetric(metricName: *const c_char);
+    /// Makes the device a Tegra iGPU if `tegra` is nonzero, with a chip name
+    /// ending in B and no counter availability image.
+    pub fn cuptiStubSetTegra(tegra: c_int);
+    /// Invokes the subscriber callback if `cbid` is enabled. Returns 1 if delivered.
+    pub fn cuptiStubDeliverCallback(
+        domain: CUpti_CallbackDomain,
+        cbid: CUpti_CallbackId,
+        cbdata: *const c_void,
+    ) -> u32;
+    /// Queues a copy of `record` for the next activity flush. Returns 1 if its
+    /// kind is enabled.
+    pub fn cuptiStubPushActivityRecord(record: *const CUpti_Activity, size: usize) -> u32;
+    /// Queues a range that the next decode of `ctx`'s range profiler writes
+    /// into its counter data image. Ignored unless the range profiler has
+    /// been started, and once the current pass holds `maxRangesPerPass`
+    /// ranges.
+    pub fn cuptiStubPushRange(
+        ctx: CUcontext,
+        name: *const c_char,
+        values: *const f64,
+        numValues: usize,
+    );
+}
@@ -12,77 +12,528 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+// Simulated CUDA/CUPTI for the `stubs` feature.
+//
+// Besides satisfying the linker, the stubs keep enough state to drive the
+// Rust pipeline without a GPU: the registered subscriber and activity buffer
+// callbacks, the enabled callback domains and activity kinds, queued activity
+// records and the ranges decoded into counter data images. Tests script it
+// through the `cuptiStub*` functions at the end of this file.
+
+#include <stddef.h>
 #include <stdint.h>
+#include <stdio.h>
 #include <string.h>
 
+#include <algorithm>
+#include <chrono>
+#include <map>
+#include <mutex>
+#include <set>
+#include <string>
+#include <utility>
+#include <vector>
+
 // Dummy types for stubs to avoid CUDA header dependency
 typedef int CUresult;
 typedef int CUptiResult;
 typedef int CUdevice;
-typedef int CUcontext;
-typedef int CUfunction;
+typedef struct CUctx_st *CUcontext;
+typedef struct CUfunc_st *CUfunction;
+typedef struct CUstream_st *CUstream;
 typedef int CUdevice_attribute;
 typedef int CUfunction_attribute;
 typedef int CUpti_ActivityKind;
 typedef int CUpti_CallbackDomain;
 typedef int CUpti_CallbackId;
+typedef int CUpti_ProfilerType;
+typedef int CUpti_ProfilerRange;
+typedef int CUpti_ProfilerReplayMode;
+typedef int CUpti_MetricType;
+typedef int CUpti_MetricCollectionScope;
+typedef void CUpti_Profiler_Host_Object;
+typedef void CUpti_RangeProfiler_Object;
+typedef size_t (*CUoccupancyB2DSize)(int blockSize);
 
 #define CUDA_SUCCESS 0
+#define CUDA_ERROR_INVALID_DEVICE 101
+#define CUDA_ERROR_INVALID_CONTEXT 201
+#define CU_DEVICE_ATTRIBUTE_MULTIPROCESSOR_COUNT 16
+#define CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MAJOR 75
+#define CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MINOR 76
 #define CUPTI_SUCCESS 0
+#define CUPTI_ERROR_INVALID_PARAMETER 1
+#define CUPTI_ERROR_OUT_OF_MEMORY 8
+#define CUPTI_ERROR_INVALID_METRIC_NAME 17
+#define CUPTI_ERROR_MAX_LIMIT_REACHED 12
+#define CUPTI_ERROR_NOT_SUPPORTED 27
+#define CUPTI_ERROR_MULTIPLE_SUBSCRIBERS_NOT_SUPPORTED 39
 
+// Parameter structs mirror the layouts in the CUPTI headers, since the Rust
+// side fills them in through the generated bindings.
 typedef struct {
-  void *pCounterAvailabilityImage;
+  size_t structSize;
+  void *pPriv;
+  CUcontext ctx;
   size_t counterAvailabilityImageSize;
+  uint8_t *pCounterAvailabilityImage;
+  bool bAllowDeviceLevelCounters;
 } CUpti_Profiler_GetCounterAvailability_Params;
 
 typedef struct {
+  size_t structSize;
+  void *pPriv;
+  CUpti_ProfilerType profilerType;
+  const char *pChipName;
+  const uint8_t *pCounterAvailabilityImage;
+  CUpti_Profiler_Host_Object *pHostObject;
+} CUpti_Profiler_Host_Initialize_Params;
+
+typedef struct {
+  size_t structSize;
+  void *pPriv;
+  CUpti_Profiler_Host_Object *pHostObject;
+} CUpti_Profiler_Host_Deinitialize_Params;
+
+typedef struct {
+  size_t structSize;
+  void *pPriv;
+  CUpti_Profiler_Host_Object *pHostObject;
+  const char **ppMetricNames;
+  size_t numMetrics;
+} CUpti_Profiler_Host_ConfigAddMetrics_Params;
+
+typedef struct {
+  size_t structSize;
+  void *pPriv;
+  CUpti_Profiler_Host_Object *pHostObject;
   size_t configImageSize;
 } CUpti_Profiler_Host_GetConfigImageSize_Params;
 
 typedef struct {
+  size_t structSize;
+  void *pPriv;
+  CUpti_Profiler_Host_Object *pHostObject;
+  size_t configImageSize;
+  uint8_t *pConfigImage;
+} CUpti_Profiler_Host_GetConfigImage_Params;
+
+typedef struct {
+  size_t structSize;
+  void *pPriv;
+  size_t configImageSize;
+  uint8_t *pConfigImage;
+  size_t numOfPasses;
+} CUpti_Profiler_Host_GetNumOfPasses_Params;
+
+typedef struct {
+  size_t structSize;
+  void *pPriv;
+  CUpti_Profiler_Host_Object *pHostObject;
+  CUpti_MetricType metricType;
+  const char **ppMetricNames;
+  size_t numMetrics;
+} CUpti_Profiler_Host_GetBaseMetrics_Params;
+
+typedef struct {
+  size_t structSize;
+  void *pPriv;
+  CUpti_Profiler_Host_Object *pHostObject;
+  CUpti_MetricType metricType;
+  const char *pMetricName;
+  size_t numOfSubmetrics;
+  const char **ppSubMetrics;
+} CUpti_Profiler_Host_GetSubMetrics_Params;
+
+typedef struct {
+  size_t structSize;
+  void *pPriv;
+  CUpti_Profiler_Host_Object *pHostObject;
+  const char *pMetricName;
+  const char *pDescription;
+  const char *pHwUnit;
+  const char *pDimUnit;
+  CUpti_MetricType metricType;
+  CUpti_MetricCollectionScope metricCollectionScope;
+} CUpti_Profiler_Host_GetMetricProperties_Params;
+
+typedef struct {
+  size_t structSize;
+  void *pPriv;
+  CUpti_Profiler_Host_Object *pHostObject;
+  const uint8_t *pCounterDataImage;
+  size_t counterDataImageSize;
+  size_t rangeIndex;
+  const char **ppMetricNames;
+  size_t numMetrics;
+  double *pMetricValues;
+} CUpti_Profiler_Host_EvaluateToGpuValues_Params;
+
+typedef struct {
+  size_t structSize;
+  void *pPriv;
+  size_t deviceIndex;
+  const char *pChipName;
+} CUpti_Device_GetChipName_Params;
+
+typedef struct {
+  size_t structSize;
+  void *pPriv;
+  CUcontext ctx;
+  CUpti_RangeProfiler_Object *pRangeProfilerObject;
+} CUpti_RangeProfiler_Enable_Params;
+
+typedef struct {
+  size_t structSize;
+  void *pPriv;
+  CUpti_RangeProfiler_Object *pRangeProfilerObject;
+} CUpti_RangeProfiler_Disable_Params;
+
+typedef struct {
+  size_t structSize;
+  void *pPriv;
+  CUpti_RangeProfiler_Object *pRangeProfilerObject;
+} CUpti_RangeProfiler_Start_Params;
+
+typedef struct {
+  size_t structSize;
+  void *pPriv;
+  CUpti_RangeProfiler_Object *pRangeProfilerObject;
+  size_t passIndex;
+  size_t targetNestingLevel;
+  uint8_t isAllPassSubmitted;
+} CUpti_RangeProfiler_Stop_Params;
+
+typedef struct {
+  size_t structSize;
+  void *pPriv;
+  CUpti_RangeProfiler_Object *pRangeProfilerObject;
+  size_t configSize;
+  const uint8_t *pConfig;
+  size_t counterDataImageSize;
+  uint8_t *pCounterDataImage;
+  CUpti_ProfilerRange range;
+  CUpti_ProfilerReplayMode replayMode;
+  size_t maxRangesPerPass;
+  uint16_t numNestingLevels;
+  uint16_t minNestingLevel;
+  size_t passIndex;
+  uint16_t targetNestingLevel;
+} CUpti_RangeProfiler_SetConfig_Params;
+
+typedef struct {
+  size_t structSize;
+  void *pPriv;
+  CUpti_RangeProfiler_Object *pRangeProfilerObject;
+  const char **pMetricNames;
+  size_t numMetrics;
+  size_t maxNumOfRanges;
+  uint32_t maxNumRangeTreeNodes;
   size_t counterDataSize;
 } CUpti_RangeProfiler_GetCounterDataSize_Params;
 
+typedef struct {
+  size_t structSize;
+  void *pPriv;
+  CUpti_RangeProfiler_Object *pRangeProfilerObject;
+  size_t counterDataSize;
+  uint8_t *pCounterData;
+} CUpti_RangeProfiler_CounterDataImage_Initialize_Params;
+
+typedef struct {
+  size_t structSize;
+  void *pPriv;
+  CUpti_RangeProfiler_Object *pRangeProfilerObject;
+  size_t numOfRangeDropped;
+} CUpti_RangeProfiler_DecodeData_Params;
+
+typedef struct {
+  size_t structSize;
+  void *pPriv;
+  const uint8_t *pCounterDataImage;
+  size_t counterDataImageSize;
+  size_t numTotalRanges;
+} CUpti_RangeProfiler_GetCounterDataInfo_Params;
+
+typedef struct {
+  size_t structSize;
+  void *pPriv;
+  const uint8_t *pCounterDataImage;
+  size_t counterDataImageSize;
+  size_t rangeIndex;
+  const char *rangeDelimiter;
+  const char *rangeName;
+} CUpti_RangeProfiler_CounterData_GetRangeInfo_Params;
+
 // Opaque pointers for other structs
 typedef void CUpti_Profiler_Initialize_Params;
 typedef void CUpti_Profiler_DeInitialize_Params;
-typedef void CUpti_Profiler_Host_Initialize_Params;
-typedef void CUpti_Profiler_Host_Deinitialize_Params;
-typedef void CUpti_Profiler_Host_ConfigAddMetrics_Params;
-typedef void CUpti_Profiler_Host_GetConfigImage_Params;
-typedef void CUpti_Profiler_Host_EvaluateToGpuValues_Params;
-typedef void CUpti_Device_GetChipName_Params;
-typedef void CUpti_RangeProfiler_Enable_Params;
-typedef void CUpti_RangeProfiler_Disable_Params;
-typedef void CUpti_RangeProfiler_Start_Params;
-typedef void CUpti_RangeProfiler_Stop_Params;
-typedef void CUpti_RangeProfiler_SetConfig_Params;
-typedef void CUpti_RangeProfiler_CounterDataImage_Initialize_Params;
-typedef void CUpti_RangeProfiler_DecodeData_Params;
-typedef void CUpti_RangeProfiler_GetCounterDataInfo_Params;
-typedef void CUpti_RangeProfiler_CounterData_GetRangeInfo_Params;
 typedef void *CUpti_SubscriberHandle;
 typedef void (*CUpti_CallbackFunc)(void *userdata, CUpti_CallbackDomain domain,
                                    CUpti_CallbackId cbid, const void *cbdata);
 typedef void (*CUpti_BuffersCallbackRequestFunc)(uint8_t **buffer, size_t *size,
                                                  size_t *maxNumRecords);
-typedef void (*CUpti_BuffersCallbackCompleteFunc)(uint8_t *buffer, size_t size,
+typedef void (*CUpti_BuffersCallbackCompleteFunc)(CUcontext context,
+                                                  uint32_t streamId,
+                                                  uint8_t *buffer, size_t size,
                                                   size_t validSize);
-typedef void CUpti_Activity;
+typedef struct {
+  CUpti_ActivityKind kind;
+} CUpti_Activity;
+
+namespace {
+
+// Counter data images written by the simulated range profiler start with this
+// header, followed by `max_ranges` entries of a NUL-terminated range name and
+// `num_metrics` doubles.
+struct CounterDataHeader {
+  uint64_t magic;
+  uint64_t max_ranges;
+  uint64_t num_metrics;
+  uint64_t num_ranges;
+};
+
+const uint64_t kCounterDataMagic = 0x53524e4f43544e43;  // "CNTCONRS"
+const size_t kRangeNameSize = 128;
+
+size_t RangeEntrySize(uint64_t num_metrics) {
+  return kRangeNameSize + num_metrics * sizeof(double);
+}
+
+const CounterDataHeader *ReadHeader(const uint8_t *image, size_t size) {
+  if (image == nullptr || size < sizeof(CounterDataHeader)) {
+    return nullptr;
+  }
+  const CounterDataHeader *header =
+      reinterpret_cast<const CounterDataHeader *>(image);
+  return header->magic == kCounterDataMagic ? header : nullptr;
+}
+
+const uint8_t *RangeEntry(const uint8_t *image, const CounterDataHeader *header,
+                          size_t index) {
+  return image + sizeof(CounterDataHeader) +
+         index * RangeEntrySize(header->num_metrics);
+}
+
+struct Range {
+  std::string name;
+  std::vector<double> values;
+};
+
+struct RangeProfilerObject {
+  CUcontext ctx;
+  uint8_t *counter_data;
+  size_t counter_data_size;
+  size_t num_metrics;
+  size_t max_ranges;
+  size_t max_ranges_per_pass;
+};
+
+// Activity records are stored in buffers with their size in front, so
+// cuptiActivityGetNextRecord can step over records of any kind.
+const size_t kRecordHeaderSize = sizeof(uint64_t);
+
+size_t AlignRecord(size_t size) { return (size + 7) & ~size_t(7); }
+
+// A compute capability 9.0 device, which the range profiler supports.
+std::map<CUdevice_attribute, int> DefaultDeviceAttributes() {
+  return {{CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MAJOR, 9},
+          {CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MINOR, 0}};
+}
+
+struct SimState {
+  std::mutex mutex;
+  int driver_version = 13010;
+  uint32_t cupti_version = 130100;
+  std::map<CUdevice_attribute, int> device_attributes =
+      DefaultDeviceAttributes();
+  bool subscribed = false;
+  CUpti_CallbackFunc callback = nullptr;
+  void *userdata = nullptr;
+  std::map<CUpti_CallbackDomain, bool> enabled_domains;
+  std::map<std::pair<CUpti_CallbackDomain, CUpti_CallbackId>, bool>
+      enabled_callbacks;
+  std::set<CUpti_ActivityKind> enabled_activity_kinds;
+  CUpti_BuffersCallbackRequestFunc buffer_requested = nullptr;
+  CUpti_BuffersCallbackCompleteFunc buffer_completed = nullptr;
+  std::vector<std::vector<uint8_t>> activity_records;
+  std::map<CUcontext, std::vector<Range>> pending_ranges;
+  // Contexts with a started range profiler, the only ones kernels are
+  // profiled on, and how many more ranges fit before the next stop or
+  // decode.
+  std::map<CUcontext, size_t> profiled_contexts;
+  // Hardware counters each metric needs, 1 unless set, and how many
+  // counters fit in one pass, 0 for no limit.
+  std::map<std::string, size_t> metric_counters;
+  size_t counters_per_pass = 0;
+  // What cuptiRangeProfilerEnable returns, e.g. when another profiler owns
+  // the GPU counters.
+  CUptiResult range_profiler_result = CUPTI_SUCCESS;
+  // Ranges the next decodes drop, as if they did not fit in the image.
+  size_t ranges_to_drop = 0;
+  // Largest counter data image that fits in memory, 0 for any size.
+  size_t max_counter_data_size = 0;
+  // Range profiler sessions enabled so far, over all contexts.
+  size_t range_profiler_enables = 0;
+  // Metrics the chip does not have, which ConfigAddMetrics rejects.
+  std::set<std::string> invalid_metrics;
+  // Whether the device is a Tegra iGPU, whose chip name ends in B and which
+  // has no counter availability image.
+  bool tegra = false;
+};
+
+SimState &State() {
+  static SimState state;
+  return state;
+}
+
+struct HostObject {
+  size_t num_counters = 0;
+};
+
+// Config images start with the number of passes they need.
+const size_t kConfigImageSize = 100;
+const char kChipName[] = "SIM100";
+const char kTegraChipName[] = "SIM10B";
+
+// The simulated chip's base metrics, as listed by the profiler host.
+struct BaseMetric {
+  const char *name;
+  CUpti_MetricType type;
+  const char *description;
+  const char *hw_unit;
+  const char *dim_unit;
+};
+
+const CUpti_MetricType kMetricTypeCounter = 0;
+const CUpti_MetricType kMetricTypeRatio = 1;
+const CUpti_MetricType kMetricTypeThroughput = 2;
+const CUpti_MetricType kNumMetricTypes = 3;
+const CUpti_MetricCollectionScope kMetricCollectionScopeContext = 0;
+
+const BaseMetric kBaseMetrics[] = {
+    {"gpu__time_duration", kMetricTypeCounter, "total duration in nanoseconds",
+     "gpu", "nsecond"},
+    {"sm__cycles_elapsed", kMetricTypeCounter, "# of cycles elapsed on SM",
+     "sm", "cycle"},
+    {"smsp__inst_executed", kMetricTypeCounter,
+     "# of warp instructions executed", "smsp", "inst"},
+    {"l1tex__t_sector_hit_rate", kMetricTypeRatio,
+     "# of sector hits per sector", "l1tex", "sector"},
+    {"sm__throughput", kMetricTypeThroughput,
+     "SM throughput assuming ideal load balancing", "sm", "percent"},
+};
+const size_t kNumBaseMetrics = sizeof(kBaseMetrics) / sizeof(kBaseMetrics[0]);
+
+// Sub-metrics of each metric type, indexed by type.
+const char *const kSubMetrics[][4] = {
+    {"sum", "avg", "max", "min"},
+    {"ratio", "pct", nullptr, nullptr},
+    {"avg.pct_of_peak_sustained_elapsed", "pct_of_peak_sustained_elapsed",
+     nullptr, nullptr},
+};
+
+const BaseMetric *FindBaseMetric(const char *name) {
+  for (const BaseMetric &metric : kBaseMetrics) {
+    if (name != nullptr && strcmp(metric.name, name) == 0) {
+      return &metric;
+    }
+  }
+  return nullptr;
+}
+
+// Per-type lists of base metric names, in the layout
+// cuptiProfilerHostGetBaseMetrics returns them in.
+struct BaseMetricNames {
+  std::vector<const char *> names[kNumMetricTypes];
+  BaseMetricNames() {
+    for (const BaseMetric &metric : kBaseMetrics) {
+      names[metric.type].push_back(metric.name);
+    }
+  }
+};
+
+const BaseMetricNames &MetricNames() {
+  static BaseMetricNames names;
+  return names;
+}
+
+}  // namespace
 
 // Define stubs for CUDA/CUPTI functions used in Rust
 
 extern "C" {
 
+CUresult cuInit(unsigned int flags) {
+  (void)flags;
+  return CUDA_SUCCESS;
+}
+// A single simulated device, whose primary context is the one with ID 1.
+CUresult cuDeviceGetCount(int *count) {
+  *count = 1;
+  return CUDA_SUCCESS;
+}
+CUresult cuDeviceGet(CUdevice *device, int ordinal) {
+  if (ordinal != 0) {
+    return CUDA_ERROR_INVALID_DEVICE;
+  }
+  *device = ordinal;
+  return CUDA_SUCCESS;
+}
+CUresult cuDevicePrimaryCtxRetain(CUcontext *pctx, CUdevice dev) {
+  if (dev != 0) {
+    return CUDA_ERROR_INVALID_DEVICE;
+  }
+  *pctx = reinterpret_cast<CUcontext>(uintptr_t(1));
+  return CUDA_SUCCESS;
+}
+CUresult cuDevicePrimaryCtxRelease_v2(CUdevice dev) {
+  return dev == 0 ? CUDA_SUCCESS : CUDA_ERROR_INVALID_DEVICE;
+}
+CUresult cuDriverGetVersion(int *driverVersion) {
+  std::lock_guard<std::mutex> lock(State().mutex);
+  *driverVersion = State().driver_version;
+  return CUDA_SUCCESS;
+}
 CUresult cuCtxGetDevice(CUdevice *device) {
-  (void)device;
+  *device = 0;
+  return CUDA_SUCCESS;
+}
+// Each thread has its own stack of current contexts, as in the driver.
+static std::vector<CUcontext> &CurrentContexts() {
+  static thread_local std::vector<CUcontext> contexts;
+  return contexts;
+}
+CUresult cuCtxPushCurrent_v2(CUcontext ctx) {
+  if (ctx == nullptr) {
+    return CUDA_ERROR_INVALID_CONTEXT;
+  }
+  CurrentContexts().push_back(ctx);
+  return CUDA_SUCCESS;
+}
+CUresult cuCtxPopCurrent_v2(CUcontext *pctx) {
+  if (CurrentContexts().empty()) {
+    return CUDA_ERROR_INVALID_CONTEXT;
+  }
+  if (pctx != nullptr) {
+    *pctx = CurrentContexts().back();
+  }
+  CurrentContexts().pop_back();
+  return CUDA_SUCCESS;
+}
+CUresult cuDeviceGetName(char *name, int len, CUdevice dev) {
+  if (dev != 0) {
+    return CUDA_ERROR_INVALID_DEVICE;
+  }
+  snprintf(name, size_t(len), "%s", "Simulated GPU");
   return CUDA_SUCCESS;
 }
 CUresult cuDeviceGetAttribute(int *pi, CUdevice_attribute attrib,
                               CUdevice dev) {
-  (void)attrib;
   (void)dev;
-  *pi = 0;
+  std::lock_guard<std::mutex> lock(State().mutex);
+  auto it = State().device_attributes.find(attrib);
+  *pi = it != State().device_attributes.end() ? it->second : 0;
   return CUDA_SUCCESS;
 }
 CUresult cuFuncGetAttribute(int *pi, CUfunction_attribute attrib,
@@ -102,6 +553,31 @@ CUresult cuOccupancyMaxActiveBlocksPerMultiprocessor(int *numBlocks,
   *numBlocks = 1;
   return CUDA_SUCCESS;
 }
+CUresult cuOccupancyMaxPotentialBlockSizeWithFlags(
+    int *minGridSize, int *blockSize, CUfunction func,
+    CUoccupancyB2DSize blockSizeToDynamicSMemSize, size_t dynamicSMemSize,
+    int blockSizeLimit, unsigned int flags) {
+  (void)func;
+  (void)blockSizeToDynamicSMemSize;
+  (void)dynamicSMemSize;
+  (void)flags;
+  // One 256-thread block per SM, matching the occupancy reported above.
+  *blockSize =
+      blockSizeLimit > 0 && blockSizeLimit < 256 ? blockSizeLimit : 256;
+  std::lock_guard<std::mutex> lock(State().mutex);
+  auto it = State().device_attributes.find(
+      CU_DEVICE_ATTRIBUTE_MULTIPROCESSOR_COUNT);
+  *minGridSize = it != State().device_attributes.end() ? it->second : 0;
+  return CUDA_SUCCESS;
+}
+CUresult cuOccupancyMaxPotentialBlockSize(
+    int *minGridSize, int *blockSize, CUfunction func,
+    CUoccupancyB2DSize blockSizeToDynamicSMemSize, size_t dynamicSMemSize,
+    int blockSizeLimit) {
+  return cuOccupancyMaxPotentialBlockSizeWithFlags(
+      minGridSize, blockSize, func, blockSizeToDynamicSMemSize,
+      dynamicSMemSize, blockSizeLimit, 0);
+}
 
 CUptiResult cuptiProfilerInitialize(CUpti_Profiler_Initialize_Params *pParams) {
   (void)pParams;
@@ -115,101 +591,314 @@ CUptiResult cuptiProfilerDeInitialize(
 
 CUptiResult cuptiProfilerHostInitialize(
     CUpti_Profiler_Host_Initialize_Params *pParams) {
-  (void)pParams;
+  pParams->pHostObject = new HostObject();
   return CUPTI_SUCCESS;
 }
 CUptiResult cuptiProfilerHostDeinitialize(
     CUpti_Profiler_Host_Deinitialize_Params *pParams) {
-  (void)pParams;
+  delete static_cast<HostObject *>(pParams->pHostObject);
   return CUPTI_SUCCESS;
 }
 CUptiResult cuptiProfilerHostConfigAddMetrics(
     CUpti_Profiler_Host_ConfigAddMetrics_Params *pParams) {
-  (void)pParams;
+  HostObject *host = static_cast<HostObject *>(pParams->pHostObject);
+  std::lock_guard<std::mutex> lock(State().mutex);
+  for (size_t i = 0; i < pParams->numMetrics; ++i) {
+    if (State().invalid_metrics.count(pParams->ppMetricNames[i]) != 0) {
+      return CUPTI_ERROR_INVALID_METRIC_NAME;
+    }
+  }
+  for (size_t i = 0; i < pParams->numMetrics; ++i) {
+    auto it = State().metric_counters.find(pParams->ppMetricNames[i]);
+    host->num_counters +=
+        it != State().metric_counters.end() ? it->second : 1;
+  }
   return CUPTI_SUCCESS;
 }
 CUptiResult cuptiProfilerHostGetConfigImageSize(
     CUpti_Profiler_Host_GetConfigImageSize_Params *pParams) {
-  pParams->configImageSize = 100;  // Mock size
+  pParams->configImageSize = kConfigImageSize;
   return CUPTI_SUCCESS;
 }
 CUptiResult cuptiProfilerHostGetConfigImage(
     CUpti_Profiler_Host_GetConfigImage_Params *pParams) {
-  (void)pParams;
+  if (pParams->configImageSize < sizeof(size_t)) {
+    return CUPTI_ERROR_INVALID_PARAMETER;
+  }
+  HostObject *host = static_cast<HostObject *>(pParams->pHostObject);
+  size_t per_pass;
+  {
+    std::lock_guard<std::mutex> lock(State().mutex);
+    per_pass = State().counters_per_pass;
+  }
+  size_t passes = 1;
+  if (per_pass != 0 && host->num_counters > per_pass) {
+    passes = (host->num_counters + per_pass - 1) / per_pass;
+  }
+  memset(pParams->pConfigImage, 0, pParams->configImageSize);
+  memcpy(pParams->pConfigImage, &passes, sizeof(passes));
+  return CUPTI_SUCCESS;
+}
+CUptiResult cuptiProfilerHostGetNumOfPasses(
+    CUpti_Profiler_Host_GetNumOfPasses_Params *pParams) {
+  if (pParams->pConfigImage == nullptr ||
+      pParams->configImageSize < sizeof(size_t)) {
+    return CUPTI_ERROR_INVALID_PARAMETER;
+  }
+  memcpy(&pParams->numOfPasses, pParams->pConfigImage, sizeof(size_t));
+  return CUPTI_SUCCESS;
+}
+CUptiResult cuptiProfilerHostGetBaseMetrics(
+    CUpti_Profiler_Host_GetBaseMetrics_Params *pParams) {
+  if (pParams->metricType < 0 || pParams->metricType >= kNumMetricTypes) {
+    return CUPTI_ERROR_INVALID_PARAMETER;
+  }
+  const std::vector<const char *> &names =
+      MetricNames().names[pParams->metricType];
+  pParams->ppMetricNames = const_cast<const char **>(names.data());
+  pParams->numMetrics = names.size();
+  return CUPTI_SUCCESS;
+}
+CUptiResult cuptiProfilerHostGetSubMetrics(
+    CUpti_Profiler_Host_GetSubMetrics_Params *pParams) {
+  const BaseMetric *metric = FindBaseMetric(pParams->pMetricName);
+  if (metric == nullptr || metric->type != pParams->metricType) {
+    return CUPTI_ERROR_INVALID_METRIC_NAME;
+  }
+  const char *const *sub_metrics = kSubMetrics[metric->type];
+  size_t count = 0;
+  while (count < 4 && sub_metrics[count] != nullptr) {
+    count++;
+  }
+  pParams->ppSubMetrics = const_cast<const char **>(sub_metrics);
+  pParams->numOfSubmetrics = count;
+  return CUPTI_SUCCESS;
+}
+CUptiResult cuptiProfilerHostGetMetricProperties(
+    CUpti_Profiler_Host_GetMetricProperties_Params *pParams) {
+  const BaseMetric *metric = FindBaseMetric(pParams->pMetricName);
+  if (metric == nullptr) {
+    return CUPTI_ERROR_INVALID_METRIC_NAME;
+  }
+  pParams->pDescription = metric->description;
+  pParams->pHwUnit = metric->hw_unit;
+  pParams->pDimUnit = metric->dim_unit;
+  pParams->metricType = metric->type;
+  pParams->metricCollectionScope = kMetricCollectionScopeContext;
   return CUPTI_SUCCESS;
 }
 CUptiResult cuptiProfilerHostEvaluateToGpuValues(
     CUpti_Profiler_Host_EvaluateToGpuValues_Params *pParams) {
-  (void)pParams;
+  const CounterDataHeader *header =
+      ReadHeader(pParams->pCounterDataImage, pParams->counterDataImageSize);
+  if (header == nullptr || pParams->rangeIndex >= header->num_ranges) {
+    return CUPTI_ERROR_INVALID_PARAMETER;
+  }
+  const double *values = reinterpret_cast<const double *>(
+      RangeEntry(pParams->pCounterDataImage, header, pParams->rangeIndex) +
+      kRangeNameSize);
+  for (size_t i = 0; i < pParams->numMetrics; ++i) {
+    pParams->pMetricValues[i] = i < header->num_metrics ? values[i] : 0.0;
+  }
   return CUPTI_SUCCESS;
 }
 
 CUptiResult cuptiDeviceGetChipName(CUpti_Device_GetChipName_Params *pParams) {
-  // Mock chip name
-  (void)pParams;
+  std::lock_guard<std::mutex> lock(State().mutex);
+  pParams->pChipName = State().tegra ? kTegraChipName : kChipName;
   return CUPTI_SUCCESS;
 }
 
 CUptiResult cuptiProfilerGetCounterAvailability(
     CUpti_Profiler_GetCounterAvailability_Params *pParams) {
-  if (pParams->pCounterAvailabilityImage == 0) {
+  {
+    std::lock_guard<std::mutex> lock(State().mutex);
+    if (State().tegra) {
+      return CUPTI_ERROR_NOT_SUPPORTED;
+    }
+  }
+  if (pParams->pCounterAvailabilityImage == nullptr) {
     pParams->counterAvailabilityImageSize = 100;
+  } else {
+    memset(pParams->pCounterAvailabilityImage, 0,
+           pParams->counterAvailabilityImageSize);
   }
   return CUPTI_SUCCESS;
 }
 
 CUptiResult cuptiRangeProfilerEnable(
     CUpti_RangeProfiler_Enable_Params *pParams) {
-  (void)pParams;
+  {
+    std::lock_guard<std::mutex> lock(State().mutex);
+    if (State().range_profiler_result != CUPTI_SUCCESS) {
+      return State().range_profiler_result;
+    }
+    State().range_profiler_enables++;
+  }
+  RangeProfilerObject *object = new RangeProfilerObject();
+  object->ctx = pParams->ctx;
+  pParams->pRangeProfilerObject = object;
   return CUPTI_SUCCESS;
 }
 CUptiResult cuptiRangeProfilerDisable(
     CUpti_RangeProfiler_Disable_Params *pParams) {
-  (void)pParams;
+  RangeProfilerObject *object =
+      static_cast<RangeProfilerObject *>(pParams->pRangeProfilerObject);
+  {
+    std::lock_guard<std::mutex> lock(State().mutex);
+    State().profiled_contexts.erase(object->ctx);
+  }
+  delete object;
   return CUPTI_SUCCESS;
 }
 CUptiResult cuptiRangeProfilerStart(CUpti_RangeProfiler_Start_Params *pParams) {
-  (void)pParams;
+  RangeProfilerObject *object =
+      static_cast<RangeProfilerObject *>(pParams->pRangeProfilerObject);
+  std::lock_guard<std::mutex> lock(State().mutex);
+  State().profiled_contexts[object->ctx] = object->max_ranges_per_pass;
   return CUPTI_SUCCESS;
 }
 CUptiResult cuptiRangeProfilerStop(CUpti_RangeProfiler_Stop_Params *pParams) {
-  (void)pParams;
+  RangeProfilerObject *object =
+      static_cast<RangeProfilerObject *>(pParams->pRangeProfilerObject);
+  {
+    std::lock_guard<std::mutex> lock(State().mutex);
+    State().profiled_contexts.erase(object->ctx);
+  }
+  pParams->passIndex = 0;
+  pParams->targetNestingLevel = 1;
+  pParams->isAllPassSubmitted = 1;
   return CUPTI_SUCCESS;
 }
 CUptiResult cuptiRangeProfilerSetConfig(
     CUpti_RangeProfiler_SetConfig_Params *pParams) {
-  (void)pParams;
+  RangeProfilerObject *object =
+      static_cast<RangeProfilerObject *>(pParams->pRangeProfilerObject);
+  if (object == nullptr) {
+    return CUPTI_ERROR_INVALID_PARAMETER;
+  }
+  object->counter_data = pParams->pCounterDataImage;
+  object->counter_data_size = pParams->counterDataImageSize;
+  object->max_ranges_per_pass = pParams->maxRangesPerPass;
   return CUPTI_SUCCESS;
 }
 CUptiResult cuptiRangeProfilerGetCounterDataSize(
     CUpti_RangeProfiler_GetCounterDataSize_Params *pParams) {
-  pParams->counterDataSize = 100;
+  RangeProfilerObject *object =
+      static_cast<RangeProfilerObject *>(pParams->pRangeProfilerObject);
+  if (object != nullptr) {
+    object->num_metrics = pParams->numMetrics;
+    object->max_ranges = pParams->maxNumOfRanges;
+  }
+  pParams->counterDataSize =
+      sizeof(CounterDataHeader) +
+      pParams->maxNumOfRanges * RangeEntrySize(pParams->numMetrics);
+  std::lock_guard<std::mutex> lock(State().mutex);
+  if (State().max_counter_data_size != 0 &&
+      pParams->counterDataSize > State().max_counter_data_size) {
+    return CUPTI_ERROR_OUT_OF_MEMORY;
+  }
   return CUPTI_SUCCESS;
 }
 CUptiResult cuptiRangeProfilerCounterDataImageInitialize(
     CUpti_RangeProfiler_CounterDataImage_Initialize_Params *pParams) {
-  (void)pParams;
+  if (pParams->pCounterData == nullptr ||
+      pParams->counterDataSize < sizeof(CounterDataHeader)) {
+    return CUPTI_ERROR_INVALID_PARAMETER;
+  }
+  CounterDataHeader *header =
+      reinterpret_cast<CounterDataHeader *>(pParams->pCounterData);
+  if (header->magic != kCounterDataMagic) {
+    // A fresh image takes its shape from the last counter data size query.
+    RangeProfilerObject *object =
+        static_cast<RangeProfilerObject *>(pParams->pRangeProfilerObject);
+    header->magic = kCounterDataMagic;
+    header->num_metrics = object != nullptr ? object->num_metrics : 0;
+    header->max_ranges = (pParams->counterDataSize - sizeof(CounterDataHeader)) /
+                         RangeEntrySize(header->num_metrics);
+  }
+  header->num_ranges = 0;
   return CUPTI_SUCCESS;
 }
 CUptiResult cuptiRangeProfilerDecodeData(
     CUpti_RangeProfiler_DecodeData_Params *pParams) {
-  (void)pParams;
+  RangeProfilerObject *object =
+      static_cast<RangeProfilerObject *>(pParams->pRangeProfilerObject);
+  if (object == nullptr) {
+    return CUPTI_ERROR_INVALID_PARAMETER;
+  }
+  std::vector<Range> ranges;
+  size_t kept = 0;
+  {
+    std::lock_guard<std::mutex> lock(State().mutex);
+    ranges.swap(State().pending_ranges[object->ctx]);
+    size_t dropped = std::min(State().ranges_to_drop, ranges.size());
+    State().ranges_to_drop -= dropped;
+    kept = ranges.size() - dropped;
+    // Decoding makes room for another pass worth of ranges.
+    auto it = State().profiled_contexts.find(object->ctx);
+    if (it != State().profiled_contexts.end()) {
+      it->second = object->max_ranges_per_pass;
+    }
+  }
+  CounterDataHeader *header = const_cast<CounterDataHeader *>(
+      ReadHeader(object->counter_data, object->counter_data_size));
+  pParams->numOfRangeDropped = 0;
+  for (size_t i = 0; i < ranges.size(); ++i) {
+    const Range &range = ranges[i];
+    if (header == nullptr || header->num_ranges >= header->max_ranges ||
+        i >= kept) {
+      pParams->numOfRangeDropped++;
+      continue;
+    }
+    uint8_t *entry = const_cast<uint8_t *>(
+        RangeEntry(object->counter_data, header, header->num_ranges++));
+    memset(entry, 0, RangeEntrySize(header->num_metrics));
+    strncpy(reinterpret_cast<char *>(entry), range.name.c_str(),
+            kRangeNameSize - 1);
+    double *values = reinterpret_cast<double *>(entry + kRangeNameSize);
+    for (size_t i = 0; i < header->num_metrics && i < range.values.size();
+         ++i) {
+      values[i] = range.values[i];
+    }
+  }
   return CUPTI_SUCCESS;
 }
 CUptiResult cuptiRangeProfilerGetCounterDataInfo(
     CUpti_RangeProfiler_GetCounterDataInfo_Params *pParams) {
-  (void)pParams;
+  const CounterDataHeader *header =
+      ReadHeader(pParams->pCounterDataImage, pParams->counterDataImageSize);
+  pParams->numTotalRanges = header != nullptr ? header->num_ranges : 0;
   return CUPTI_SUCCESS;
 }
 CUptiResult cuptiRangeProfilerCounterDataGetRangeInfo(
     CUpti_RangeProfiler_CounterData_GetRangeInfo_Params *pParams) {
-  (void)pParams;
+  const CounterDataHeader *header =
+      ReadHeader(pParams->pCounterDataImage, pParams->counterDataImageSize);
+  if (header == nullptr || pParams->rangeIndex >= header->num_ranges) {
+    return CUPTI_ERROR_INVALID_PARAMETER;
+  }
+  pParams->rangeName = reinterpret_cast<const char *>(
+      RangeEntry(pParams->pCounterDataImage, header, pParams->rangeIndex));
   return CUPTI_SUCCESS;
 }
 
 CUptiResult cuptiGetContextId(CUcontext context, uint32_t *contextId) {
+  // Simulated contexts are small integers cast to pointers.
+  *contextId = context != nullptr ? uint32_t(uintptr_t(context)) : 1;
+  return CUPTI_SUCCESS;
+}
+CUptiResult cuptiGetStreamIdEx(CUcontext context, CUstream stream,
+                               uint8_t perThreadStream, uint32_t *streamId) {
   (void)context;
-  *contextId = 1;
+  (void)perThreadStream;
+  // Simulated streams are their IDs cast to pointers.
+  *streamId = uint32_t(uintptr_t(stream));
+  return CUPTI_SUCCESS;
+}
+CUptiResult cuptiGetVersion(uint32_t *version) {
+  std::lock_guard<std::mutex> lock(State().mutex);
+  *version = State().cupti_version;
   return CUPTI_SUCCESS;
 }
 CUptiResult cuptiGetLastError() { return CUPTI_SUCCESS; }
@@ -221,51 +910,284 @@ CUptiResult cuptiGetResultString(CUptiResult result, const char **str) {
 
 CUptiResult cuptiSubscribe(CUpti_SubscriberHandle *subscriber,
                            CUpti_CallbackFunc callback, void *userdata) {
+  std::lock_guard<std::mutex> lock(State().mutex);
+  if (State().subscribed) {
+    return CUPTI_ERROR_MULTIPLE_SUBSCRIBERS_NOT_SUPPORTED;
+  }
+  State().subscribed = true;
+  State().callback = callback;
+  State().userdata = userdata;
+  *subscriber = &State();
+  return CUPTI_SUCCESS;
+}
+CUptiResult cuptiUnsubscribe(CUpti_SubscriberHandle subscriber) {
   (void)subscriber;
-  (void)callback;
-  (void)userdata;
+  std::lock_guard<std::mutex> lock(State().mutex);
+  State().subscribed = false;
+  State().callback = nullptr;
+  State().userdata = nullptr;
+  State().enabled_domains.clear();
+  State().enabled_callbacks.clear();
   return CUPTI_SUCCESS;
 }
 CUptiResult cuptiEnableCallback(uint32_t enable,
                                 CUpti_SubscriberHandle subscriber,
                                 CUpti_CallbackDomain domain,
                                 CUpti_CallbackId cbid) {
-  (void)enable;
   (void)subscriber;
-  (void)domain;
-  (void)cbid;
+  std::lock_guard<std::mutex> lock(State().mutex);
+  State().enabled_callbacks[std::make_pair(domain, cbid)] = enable != 0;
   return CUPTI_SUCCESS;
 }
 CUptiResult cuptiEnableDomain(uint32_t enable,
                               CUpti_SubscriberHandle subscriber,
                               CUpti_CallbackDomain domain) {
-  (void)enable;
   (void)subscriber;
-  (void)domain;
+  std::lock_guard<std::mutex> lock(State().mutex);
+  State().enabled_domains[domain] = enable != 0;
+  // Enabling a domain overrides earlier per-callback settings within it.
+  for (auto it = State().enabled_callbacks.begin();
+       it != State().enabled_callbacks.end();) {
+    it = it->first.first == domain ? State().enabled_callbacks.erase(it)
+                                   : std::next(it);
+  }
+  return CUPTI_SUCCESS;
+}
+
+CUptiResult cuptiGetTimestamp(uint64_t *timestamp) {
+  *timestamp = uint64_t(std::chrono::duration_cast<std::chrono::nanoseconds>(
+                            std::chrono::steady_clock::now().time_since_epoch())
+                            .count());
   return CUPTI_SUCCESS;
 }
 
 CUptiResult cuptiActivityEnable(CUpti_ActivityKind kind) {
+  std::lock_guard<std::mutex> lock(State().mutex);
+  State().enabled_activity_kinds.insert(kind);
+  return CUPTI_SUCCESS;
+}
+CUptiResult cuptiActivityEnableContext(CUcontext context,
+                                       CUpti_ActivityKind kind) {
+  (void)context;
+  (void)kind;
+  return CUPTI_SUCCESS;
+}
+CUptiResult cuptiActivityDisableContext(CUcontext context,
+                                        CUpti_ActivityKind kind) {
+  (void)context;
   (void)kind;
   return CUPTI_SUCCESS;
 }
 CUptiResult cuptiActivityRegisterCallbacks(
     CUpti_BuffersCallbackRequestFunc funcBufferRequested,
     CUpti_BuffersCallbackCompleteFunc funcBufferCompleted) {
-  (void)funcBufferRequested;
-  (void)funcBufferCompleted;
+  std::lock_guard<std::mutex> lock(State().mutex);
+  State().buffer_requested = funcBufferRequested;
+  State().buffer_completed = funcBufferCompleted;
   return CUPTI_SUCCESS;
 }
 CUptiResult cuptiActivityFlushAll(uint32_t flag) {
   (void)flag;
+  CUpti_BuffersCallbackRequestFunc buffer_requested;
+  CUpti_BuffersCallbackCompleteFunc buffer_completed;
+  std::vector<std::vector<uint8_t>> records;
+  {
+    std::lock_guard<std::mutex> lock(State().mutex);
+    buffer_requested = State().buffer_requested;
+    buffer_completed = State().buffer_completed;
+    if (buffer_requested == nullptr || buffer_completed == nullptr) {
+      return CUPTI_SUCCESS;
+    }
+    records.swap(State().activity_records);
+  }
+  // Callbacks run without the lock held, since they call back into CUPTI.
+  size_t next = 0;
+  while (next < records.size()) {
+    uint8_t *buffer = nullptr;
+    size_t size = 0;
+    size_t max_num_records = 0;
+    buffer_requested(&buffer, &size, &max_num_records);
+    if (buffer == nullptr) {
+      return CUPTI_SUCCESS;
+    }
+    size_t valid_size = 0;
+    size_t first = next;
+    while (next < records.size()) {
+      size_t record_size = records[next].size();
+      size_t needed = kRecordHeaderSize + AlignRecord(record_size);
+      if (valid_size + needed > size) {
+        break;
+      }
+      uint64_t header = record_size;
+      memcpy(buffer + valid_size, &header, kRecordHeaderSize);
+      memcpy(buffer + valid_size + kRecordHeaderSize, records[next].data(),
+             record_size);
+      valid_size += needed;
+      ++next;
+    }
+    if (next == first) {
+      // The record doesn't fit in an empty buffer; drop it.
+      ++next;
+    }
+    buffer_completed(nullptr, 0, buffer, size, valid_size);
+  }
+  return CUPTI_SUCCESS;
+}
+CUptiResult cuptiFinalize() {
+  std::lock_guard<std::mutex> lock(State().mutex);
+  State().subscribed = false;
+  State().callback = nullptr;
+  State().userdata = nullptr;
+  State().enabled_domains.clear();
+  State().enabled_callbacks.clear();
+  State().enabled_activity_kinds.clear();
+  State().buffer_requested = nullptr;
+  State().buffer_completed = nullptr;
+  State().activity_records.clear();
   return CUPTI_SUCCESS;
 }
 CUptiResult cuptiActivityGetNextRecord(uint8_t *buffer,
                                        size_t validBufferSizeBytes,
                                        CUpti_Activity **record) {
-  (void)buffer;
-  (void)validBufferSizeBytes;
-  (void)record;
+  size_t offset = 0;
+  if (*record != nullptr) {
+    uint8_t *current = reinterpret_cast<uint8_t *>(*record);
+    uint64_t current_size;
+    memcpy(&current_size, current - kRecordHeaderSize, kRecordHeaderSize);
+    offset = size_t(current - buffer) + AlignRecord(current_size);
+  }
+  if (offset + kRecordHeaderSize > validBufferSizeBytes) {
+    return CUPTI_ERROR_MAX_LIMIT_REACHED;
+  }
+  *record = reinterpret_cast<CUpti_Activity *>(buffer + offset +
+                                               kRecordHeaderSize);
   return CUPTI_SUCCESS;
 }
+
+// Simulation controls, not part of the CUPTI API.
+
+void cuptiStubReset() {
+  std::lock_guard<std::mutex> lock(State().mutex);
+  SimState &state = State();
+  state.driver_version = 13010;
+  state.cupti_version = 130100;
+  state.device_attributes = DefaultDeviceAttributes();
+  state.subscribed = false;
+  state.callback = nullptr;
+  state.userdata = nullptr;
+  state.enabled_domains.clear();
+  state.enabled_callbacks.clear();
+  state.enabled_activity_kinds.clear();
+  state.buffer_requested = nullptr;
+  state.buffer_completed = nullptr;
+  state.activity_records.clear();
+  state.pending_ranges.clear();
+  state.profiled_contexts.clear();
+  state.metric_counters.clear();
+  state.counters_per_pass = 0;
+  state.range_profiler_result = CUPTI_SUCCESS;
+  state.ranges_to_drop = 0;
+  state.max_counter_data_size = 0;
+  state.range_profiler_enables = 0;
+  state.invalid_metrics.clear();
+  state.tegra = false;
+}
+
+void cuptiStubSetVersions(uint32_t cuptiVersion, int driverVersion) {
+  std::lock_guard<std::mutex> lock(State().mutex);
+  State().cupti_version = cuptiVersion;
+  State().driver_version = driverVersion;
+}
+
+void cuptiStubSetDeviceAttribute(CUdevice_attribute attrib, int value) {
+  std::lock_guard<std::mutex> lock(State().mutex);
+  State().device_attributes[attrib] = value;
+}
+
+void cuptiStubSetMetricCounters(const char *metricName, size_t numCounters) {
+  std::lock_guard<std::mutex> lock(State().mutex);
+  State().metric_counters[metricName] = numCounters;
+}
+
+void cuptiStubSetCountersPerPass(size_t numCounters) {
+  std::lock_guard<std::mutex> lock(State().mutex);
+  State().counters_per_pass = numCounters;
+}
+
+void cuptiStubSetRangeProfilerResult(CUptiResult result) {
+  std::lock_guard<std::mutex> lock(State().mutex);
+  State().range_profiler_result = result;
+}
+
+void cuptiStubDropRanges(size_t count) {
+  std::lock_guard<std::mutex> lock(State().mutex);
+  State().ranges_to_drop = count;
+}
+
+void cuptiStubSetMaxCounterDataSize(size_t size) {
+  std::lock_guard<std::mutex> lock(State().mutex);
+  State().max_counter_data_size = size;
+}
+
+size_t cuptiStubRangeProfilerEnables() {
+  std::lock_guard<std::mutex> lock(State().mutex);
+  return State().range_profiler_enables;
+}
+
+void cuptiStubSetInvalidMetric(const char *metricName) {
+  std::lock_guard<std::mutex> lock(State().mutex);
+  State().invalid_metrics.insert(metricName);
+}
+
+void cuptiStubSetTegra(int tegra) {
+  std::lock_guard<std::mutex> lock(State().mutex);
+  State().tegra = tegra != 0;
+}
+
+uint32_t cuptiStubDeliverCallback(CUpti_CallbackDomain domain,
+                                  CUpti_CallbackId cbid, const void *cbdata) {
+  CUpti_CallbackFunc callback;
+  void *userdata;
+  {
+    std::lock_guard<std::mutex> lock(State().mutex);
+    SimState &state = State();
+    if (!state.subscribed || state.callback == nullptr) {
+      return 0;
+    }
+    auto it = state.enabled_callbacks.find(std::make_pair(domain, cbid));
+    bool enabled = it != state.enabled_callbacks.end()
+                       ? it->second
+                       : state.enabled_domains[domain];
+    if (!enabled) {
+      return 0;
+    }
+    callback = state.callback;
+    userdata = state.userdata;
+  }
+  callback(userdata, domain, cbid, cbdata);
+  return 1;
+}
+
+uint32_t cuptiStubPushActivityRecord(const CUpti_Activity *record,
+                                     size_t size) {
+  std::lock_guard<std::mutex> lock(State().mutex);
+  if (State().enabled_activity_kinds.count(record->kind) == 0) {
+    return 0;
+  }
+  const uint8_t *bytes = reinterpret_cast<const uint8_t *>(record);
+  State().activity_records.emplace_back(bytes, bytes + size);
+  return 1;
+}
+
+void cuptiStubPushRange(CUcontext ctx, const char *name, const double *values,
+                        size_t numValues) {
+  std::lock_guard<std::mutex> lock(State().mutex);
+  auto it = State().profiled_contexts.find(ctx);
+  if (it == State().profiled_contexts.end() || it->second == 0) {
+    return;
+  }
+  it->second--;
+  State().pending_ranges[ctx].push_back(
+      Range{name, std::vector<double>(values, values + numValues)});
+}
 }
@@ -15,7 +15,11 @@
 #include <cuda.h>
 #include <cupti.h>
 #include <cupti_activity.h>
+#include <cupti_profiler_target.h>
+#include <cupti_target.h>
+// The ProfilerHost and RangeProfiler object APIs were added in CUDA 12.6.
+#if CUDA_VERSION >= 12060
 #include <cupti_profiler_host.h>
 #include <cupti_range_profiler.h>
-#include <cupti_target.h>
+#endif
 #include <stdbool.h>
@@ -20,6 +20,30 @@ pub fn activity_enable(kind: CUpti_ActivityKind) -> Result<(), CUptiResult> {
     Ok(())
 }
 
+/// Enables a CUPTI activity kind for a single context.
+/// # Safety
+///
+/// The `ctx` pointer must be a valid CUDA context.
+pub unsafe fn activity_enable_context(
+    ctx: CUcontext,
+    kind: CUpti_ActivityKind,
+) -> Result<(), CUptiResult> {
+    check_cupti!(unsafe { cuptiActivityEnableContext(ctx, kind) });
+    Ok(())
+}
+
+/// Disables a CUPTI activity kind for a single context.
+/// # Safety
+///
+/// The `ctx` pointer must be a valid CUDA context.
+pub unsafe fn activity_disable_context(
+    ctx: CUcontext,
+    kind: CUpti_ActivityKind,
+) -> Result<(), CUptiResult> {
+    check_cupti!(unsafe { cuptiActivityDisableContext(ctx, kind) });
+    Ok(())
+}
+
 /// Registers callbacks for CUPTI activity buffering.
 /// # Safety
 ///
@@ -38,6 +62,13 @@ pub fn activity_flush_all(flag: u32) -> Result<(), CUptiResult> {
     Ok(())
 }
 
+/// Current time on the clock of CUPTI's activity record timestamps, in ns.
+pub fn get_timestamp() -> Result<u64, CUptiResult> {
+    let mut timestamp = 0;
+    check_cupti!(unsafe { cuptiGetTimestamp(&mut timestamp) });
+    Ok(timestamp)
+}
+
 /// Retrieves the next activity record from a buffer.
 /// # Safety
 ///
@@ -53,3 +84,12 @@ pub unsafe fn activity_get_next_record(
     }
     Ok(())
 }
+
+/// Detaches CUPTI from the process.
+///
+/// All subscribers must have been unsubscribed and activity buffers flushed
+/// before calling this.
+pub fn finalize() -> Result<(), CUptiResult> {
+    check_cupti!(unsafe { cuptiFinalize() });
+    Ok(())
+}
@@ -0,0 +1,278 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Lists the metrics the installed GPUs can collect.
+//!
+//! Every distinct chip is listed once, with its base metrics, their
+//! descriptions and the passes each needs on its own, so metric lists for
+//! `INJECTION_METRICS` can be put together without other tools.
+
+use cupti_profiler::{
+    get_device_count, get_result_string, init, metric_type_name, MetricInfo, ProfilerHost,
+    METRIC_TYPES,
+};
+use std::{env, process};
+
+const USAGE: &str = "\
+Usage: cupti-metrics-list [OPTIONS] [FILTER...]
+
+Lists the base metrics of the installed GPUs. With FILTERs, only metrics whose
+name contains one of them are listed.
+
+Options:
+  -d, --device N     Only list the GPU with CUDA ordinal N
+  -t, --type TYPE    Only list `counter`, `ratio` or `throughput` metrics
+  -s, --sub-metrics  Print the collectable metric names instead, one per line
+  -h, --help         Print this help
+";
+
+#[derive(Debug, Default, PartialEq)]
+struct Options {
+    device: Option<i32>,
+    metric_type: Option<String>,
+    sub_metrics: bool,
+    filters: Vec<String>,
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Option<Options>, String> {
+    let mut options = Options::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-d" | "--device" => {
+                let device = value()?;
+                options.device = Some(
+                    device
+                        .parse()
+                        .map_err(|_| format!("Invalid device {}", device))?,
+                );
+            }
+            "-t" | "--type" => {
+                let metric_type = value()?.to_ascii_lowercase();
+                if !METRIC_TYPES
+                    .iter()
+                    .any(|&t| metric_type_name(t) == metric_type)
+                {
+                    return Err(format!("Unknown metric type {}", metric_type));
+                }
+                options.metric_type = Some(metric_type);
+            }
+            "-s" | "--sub-metrics" => options.sub_metrics = true,
+            _ if arg.starts_with('-') => return Err(format!("Unknown option {}", arg)),
+            _ => options.filters.push(arg.to_ascii_lowercase()),
+        }
+    }
+    Ok(Some(options))
+}
+
+impl Options {
+    fn matches(&self, name: &str) -> bool {
+        let name = name.to_ascii_lowercase();
+        self.filters.is_empty() || self.filters.iter().any(|filter| name.contains(filter))
+    }
+}
+
+fn format_passes(num_passes: Option<usize>) -> String {
+    match num_passes {
+        Some(1) => "1 pass".to_string(),
+        Some(n) => format!("{} passes", n),
+        None => "? passes".to_string(),
+    }
+}
+
+/// Formats the metrics of a chip as aligned rows.
+fn format_metrics(metrics: &[MetricInfo]) -> Vec<String> {
+    let name_width = metrics.iter().map(|m| m.name.len()).max().unwrap_or(0);
+    metrics
+        .iter()
+        .map(|m| {
+            let unit = if m.dim_unit.is_empty() {
+                String::new()
+            } else {
+                format!(" [{}]", m.dim_unit)
+            };
+            format!(
+                "  {:name_width$}  {:10}  {:9}  {}{}",
+                m.name,
+                metric_type_name(m.metric_type),
+                format_passes(m.num_passes),
+                m.description,
+                unit,
+            )
+        })
+        .collect()
+}
+
+/// Lists the matching metrics of a chip.
+fn list_metrics(host: &ProfilerHost, options: &Options) -> Vec<MetricInfo> {
+    let mut metrics = Vec::new();
+    for metric_type in METRIC_TYPES {
+        if options
+            .metric_type
+            .as_ref()
+            .is_some_and(|t| t != metric_type_name(metric_type))
+        {
+            continue;
+        }
+        let names = match host.base_metrics(metric_type) {
+            Ok(names) => names,
+            Err(e) => {
+                eprintln!(
+                    "Failed to list {} metrics: {}",
+                    metric_type_name(metric_type),
+                    get_result_string(e)
+                );
+                continue;
+            }
+        };
+        for name in names.iter().filter(|name| options.matches(name)) {
+            match host.metric_info(name) {
+                Ok(info) => metrics.push(info),
+                Err(e) => eprintln!("Failed to describe {}: {}", name, get_result_string(e)),
+            }
+        }
+    }
+    metrics.sort_by(|a, b| a.name.cmp(&b.name));
+    metrics
+}
+
+fn run(options: &Options) -> Result<(), String> {
+    init().map_err(|e| format!("Failed to initialize CUDA: error {}", e))?;
+    let num_devices =
+        get_device_count().map_err(|e| format!("Failed to count GPUs: error {}", e))?;
+    let devices: Vec<i32> = match options.device {
+        Some(device) if device < 0 || device >= num_devices => {
+            return Err(format!("No device {}, found {}", device, num_devices));
+        }
+        Some(device) => vec![device],
+        None => (0..num_devices).collect(),
+    };
+    if devices.is_empty() {
+        return Err("No GPUs found".to_string());
+    }
+    // GPUs of the same chip have the same metrics, so each chip is listed
+    // once.
+    let mut chips: Vec<(ProfilerHost, Vec<i32>)> = Vec::new();
+    for device in devices {
+        let host = ProfilerHost::for_device(device).map_err(|e| {
+            format!(
+                "Failed to set up the profiler for device {}: {}",
+                device,
+                get_result_string(e)
+            )
+        })?;
+        match chips
+            .iter_mut()
+            .find(|(chip, _)| chip.chip_name() == host.chip_name())
+        {
+            Some((_, chip_devices)) => chip_devices.push(device),
+            None => chips.push((host, vec![device])),
+        }
+    }
+    for (i, (host, chip_devices)) in chips.iter().enumerate() {
+        let metrics = list_metrics(host, options);
+        if options.sub_metrics {
+            for metric in &metrics {
+                for sub_metric in &metric.sub_metrics {
+                    println!("{}", sub_metric);
+                }
+            }
+            continue;
+        }
+        if i > 0 {
+            println!();
+        }
+        let chip_devices: Vec<String> = chip_devices.iter().map(|d| d.to_string()).collect();
+        println!(
+            "{} (device{} {})",
+            host.chip_name(),
+            if chip_devices.len() > 1 { "s" } else { "" },
+            chip_devices.join(", ")
+        );
+        for row in format_metrics(&metrics) {
+            println!("{}", row);
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            print!("{}", USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(e) = run(&options) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cupti_profiler::bindings::*;
+
+    fn parse(args: &[&str]) -> Result<Option<Options>, String> {
+        parse_args(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let options = parse(&["-d", "1", "--type", "Ratio", "L1tex", "-s"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(options.device, Some(1));
+        assert_eq!(options.metric_type.as_deref(), Some("ratio"));
+        assert!(options.sub_metrics);
+        assert!(options.matches("l1tex__t_sector_hit_rate"));
+        assert!(!options.matches("sm__throughput"));
+        assert!(Options::default().matches("sm__throughput"));
+        assert_eq!(parse(&["-h"]), Ok(None));
+        assert!(parse(&["-d", "x"]).is_err());
+        assert!(parse(&["-t", "gauge"]).is_err());
+        assert!(parse(&["--bogus"]).is_err());
+    }
+
+    #[test]
+    fn test_format_metrics() {
+        let metric = |name: &str, num_passes| MetricInfo {
+            name: name.to_string(),
+            metric_type: CUpti_MetricType_CUPTI_METRIC_TYPE_COUNTER,
+            description: "cycles".to_string(),
+            hw_unit: "sm".to_string(),
+            dim_unit: "cycle".to_string(),
+            sub_metrics: Vec::new(),
+            num_passes,
+        };
+        let rows = format_metrics(&[metric("sm__cycles", Some(1)), metric("a", Some(3))]);
+        assert_eq!(
+            rows[0],
+            "  sm__cycles  counter     1 pass     cycles [cycle]"
+        );
+        assert_eq!(
+            rows[1],
+            "  a           counter     3 passes   cycles [cycle]"
+        );
+        assert_eq!(format_passes(None), "? passes");
+    }
+}
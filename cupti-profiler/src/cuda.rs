@@ -13,6 +13,10 @@
 // limitations under the License.
 
 use crate::bindings::*;
+use std::ffi::{c_char, CStr};
+
+/// Longest device name `get_device_name` reads, with the terminating NUL.
+const MAX_DEVICE_NAME_LEN: usize = 256;
 
 /// Safe wrapper for `cuCtxGetDevice`.
 /// # Safety
@@ -27,6 +31,88 @@ pub unsafe fn get_device(_ctx: CUcontext) -> Result<CUdevice, u32> {
     Ok(device)
 }
 
+/// Safe wrapper for `cuCtxPushCurrent`. The context must be popped with
+/// `pop_current_context` on the same thread.
+/// # Safety
+///
+/// The `ctx` pointer must be a valid CUDA context.
+pub unsafe fn push_current_context(ctx: CUcontext) -> Result<(), u32> {
+    let res = unsafe { cuCtxPushCurrent_v2(ctx) };
+    if res != 0 {
+        return Err(res);
+    }
+    Ok(())
+}
+
+/// Safe wrapper for `cuCtxPopCurrent`.
+pub fn pop_current_context() -> Result<CUcontext, u32> {
+    let mut ctx: CUcontext = std::ptr::null_mut();
+    let res = unsafe { cuCtxPopCurrent_v2(&mut ctx) };
+    if res != 0 {
+        return Err(res);
+    }
+    Ok(ctx)
+}
+
+/// Safe wrapper for `cuInit`.
+pub fn init() -> Result<(), u32> {
+    let res = unsafe { cuInit(0) };
+    if res != 0 {
+        return Err(res);
+    }
+    Ok(())
+}
+
+/// Safe wrapper for `cuDeviceGetCount`.
+pub fn get_device_count() -> Result<i32, u32> {
+    let mut count = 0;
+    let res = unsafe { cuDeviceGetCount(&mut count) };
+    if res != 0 {
+        return Err(res);
+    }
+    Ok(count)
+}
+
+/// Safe wrapper for `cuDeviceGet`.
+pub fn get_device_by_ordinal(ordinal: i32) -> Result<CUdevice, u32> {
+    let mut device: CUdevice = 0;
+    let res = unsafe { cuDeviceGet(&mut device, ordinal) };
+    if res != 0 {
+        return Err(res);
+    }
+    Ok(device)
+}
+
+/// Safe wrapper for `cuDevicePrimaryCtxRetain`. The context must be released
+/// with `release_primary_context`.
+pub fn retain_primary_context(dev: CUdevice) -> Result<CUcontext, u32> {
+    let mut ctx: CUcontext = std::ptr::null_mut();
+    let res = unsafe { cuDevicePrimaryCtxRetain(&mut ctx, dev) };
+    if res != 0 {
+        return Err(res);
+    }
+    Ok(ctx)
+}
+
+/// Safe wrapper for `cuDevicePrimaryCtxRelease`.
+pub fn release_primary_context(dev: CUdevice) -> Result<(), u32> {
+    let res = unsafe { cuDevicePrimaryCtxRelease_v2(dev) };
+    if res != 0 {
+        return Err(res);
+    }
+    Ok(())
+}
+
+/// Safe wrapper for `cuDriverGetVersion`.
+pub fn get_driver_version() -> Result<i32, u32> {
+    let mut version = 0;
+    let res = unsafe { cuDriverGetVersion(&mut version) };
+    if res != 0 {
+        return Err(res);
+    }
+    Ok(version)
+}
+
 /// Safe wrapper for `cuDeviceGetAttribute`.
 pub fn get_device_attribute(dev: CUdevice, attr: CUdevice_attribute) -> Result<i32, u32> {
     let mut val = 0;
@@ -37,6 +123,18 @@ pub fn get_device_attribute(dev: CUdevice, attr: CUdevice_attribute) -> Result<i
     Ok(val)
 }
 
+/// Safe wrapper for `cuDeviceGetName`.
+pub fn get_device_name(dev: CUdevice) -> Result<String, u32> {
+    let mut name = [0 as c_char; MAX_DEVICE_NAME_LEN];
+    let res = unsafe { cuDeviceGetName(name.as_mut_ptr(), name.len() as i32, dev) };
+    if res != 0 {
+        return Err(res);
+    }
+    Ok(unsafe { CStr::from_ptr(name.as_ptr()) }
+        .to_string_lossy()
+        .into_owned())
+}
+
 /// Safe wrapper for `cuFuncGetAttribute`.
 /// # Safety
 ///
@@ -74,6 +172,65 @@ pub unsafe fn occupancy_max_active_blocks_per_multiprocessor(
     Ok(num_blocks)
 }
 
+/// Safe wrapper for `cuOccupancyMaxPotentialBlockSize`.
+///
+/// Returns the minimum grid size needed for full occupancy and the block size
+/// that achieves it. A `block_size_limit` of 0 means no limit.
+/// # Safety
+///
+/// The `func` pointer must be a valid CUDA function handle.
+pub unsafe fn occupancy_max_potential_block_size(
+    func: CUfunction,
+    dynamic_smem_size: usize,
+    block_size_limit: i32,
+) -> Result<(i32, i32), u32> {
+    let mut min_grid_size = 0;
+    let mut block_size = 0;
+    let res = unsafe {
+        cuOccupancyMaxPotentialBlockSize(
+            &mut min_grid_size,
+            &mut block_size,
+            func,
+            None,
+            dynamic_smem_size,
+            block_size_limit,
+        )
+    };
+    if res != 0 {
+        return Err(res);
+    }
+    Ok((min_grid_size, block_size))
+}
+
+/// Safe wrapper for `cuOccupancyMaxPotentialBlockSizeWithFlags`.
+/// # Safety
+///
+/// The `func` pointer must be a valid CUDA function handle.
+pub unsafe fn occupancy_max_potential_block_size_with_flags(
+    func: CUfunction,
+    dynamic_smem_size: usize,
+    block_size_limit: i32,
+    flags: u32,
+) -> Result<(i32, i32), u32> {
+    let mut min_grid_size = 0;
+    let mut block_size = 0;
+    let res = unsafe {
+        cuOccupancyMaxPotentialBlockSizeWithFlags(
+            &mut min_grid_size,
+            &mut block_size,
+            func,
+            None,
+            dynamic_smem_size,
+            block_size_limit,
+            flags,
+        )
+    };
+    if res != 0 {
+        return Err(res);
+    }
+    Ok((min_grid_size, block_size))
+}
+
 /// Gets the CUPTI context ID for a CUDA context.
 /// # Safety
 ///
@@ -83,3 +240,17 @@ pub unsafe fn get_context_id(ctx: CUcontext) -> u32 {
     let _ = unsafe { cuptiGetContextId(ctx, &mut ctx_id) };
     ctx_id
 }
+
+/// Gets the CUPTI stream ID of `stream`, the `streamId` of the activity
+/// records of kernels launched on it.
+/// # Safety
+///
+/// The `ctx` pointer must be a valid CUDA context containing `stream`.
+pub unsafe fn get_stream_id(ctx: CUcontext, stream: CUstream) -> Result<u32, CUptiResult> {
+    let mut stream_id = 0;
+    let res = unsafe { cuptiGetStreamIdEx(ctx, stream, 0, &mut stream_id) };
+    if res != CUptiResult_CUPTI_SUCCESS {
+        return Err(res);
+    }
+    Ok(stream_id)
+}
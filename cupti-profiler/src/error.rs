@@ -33,3 +33,55 @@ pub fn get_result_string(result: CUptiResult) -> String {
         CStr::from_ptr(err_str).to_string_lossy().into_owned()
     }
 }
+
+/// Explains why the profiler cannot collect counters, for the results that
+/// mean it is unavailable in this process rather than failing for a while.
+pub fn profiler_unavailable_reason(result: CUptiResult) -> Option<&'static str> {
+    if result == CUptiResult_CUPTI_ERROR_INSUFFICIENT_PRIVILEGES
+        || result == CUptiResult_CUPTI_ERROR_VIRTUALIZED_DEVICE_INSUFFICIENT_PRIVILEGES
+    {
+        Some("the driver restricts GPU performance counters to admin users (NVreg_RestrictProfilingToAdminUsers)")
+    } else if result == CUptiResult_CUPTI_ERROR_HARDWARE_BUSY
+        || result == CUptiResult_CUPTI_ERROR_MULTIPLE_SUBSCRIBERS_NOT_SUPPORTED
+        || result == CUptiResult_CUPTI_ERROR_OLD_PROFILER_API_INITIALIZED
+    {
+        Some("another profiler, such as Nsight Compute or Nsight Systems, is using the GPU performance counters")
+    } else if result == CUptiResult_CUPTI_ERROR_NOT_SUPPORTED
+        || result == CUptiResult_CUPTI_ERROR_VIRTUALIZED_DEVICE_NOT_SUPPORTED
+        || result == CUptiResult_CUPTI_ERROR_CONFIDENTIAL_COMPUTING_NOT_SUPPORTED
+        || result == CUptiResult_CUPTI_ERROR_CMP_DEVICE_NOT_SUPPORTED
+        || result == CUptiResult_CUPTI_ERROR_MIG_DEVICE_NOT_SUPPORTED
+        || result == CUptiResult_CUPTI_ERROR_SLI_DEVICE_NOT_SUPPORTED
+        || result == CUptiResult_CUPTI_ERROR_WSL_DEVICE_NOT_SUPPORTED
+    {
+        Some("the device or its configuration does not support the range profiler")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profiler_unavailable_reason() {
+        assert!(
+            profiler_unavailable_reason(CUptiResult_CUPTI_ERROR_INSUFFICIENT_PRIVILEGES)
+                .unwrap()
+                .contains("admin")
+        );
+        assert!(
+            profiler_unavailable_reason(CUptiResult_CUPTI_ERROR_HARDWARE_BUSY)
+                .unwrap()
+                .contains("another profiler")
+        );
+        assert!(
+            profiler_unavailable_reason(CUptiResult_CUPTI_ERROR_MIG_DEVICE_NOT_SUPPORTED).is_some()
+        );
+        assert_eq!(
+            profiler_unavailable_reason(CUptiResult_CUPTI_ERROR_OUT_OF_MEMORY),
+            None
+        );
+    }
+}
@@ -0,0 +1,212 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::bindings::*;
+
+/// Driver API calls that launch kernels, whose parameters
+/// `LaunchInfo::from_callback_data` decodes.
+pub const LAUNCH_CALLBACKS: [CUpti_CallbackId; 4] = [
+    CUpti_driver_api_trace_cbid_enum_CUPTI_DRIVER_TRACE_CBID_cuLaunchKernel,
+    CUpti_driver_api_trace_cbid_enum_CUPTI_DRIVER_TRACE_CBID_cuLaunchKernel_ptsz,
+    CUpti_driver_api_trace_cbid_enum_CUPTI_DRIVER_TRACE_CBID_cuLaunchKernelEx,
+    CUpti_driver_api_trace_cbid_enum_CUPTI_DRIVER_TRACE_CBID_cuLaunchKernelEx_ptsz,
+];
+
+/// Launch configuration of a kernel, as passed to one of the `cuLaunchKernel`
+/// driver API variants.
+#[derive(Debug, Clone, Copy)]
+pub struct LaunchInfo {
+    pub function: CUfunction,
+    pub stream: CUstream,
+    pub grid_dim: (u32, u32, u32),
+    pub block_dim: (u32, u32, u32),
+    /// Dynamic shared memory per block, in bytes.
+    pub shared_mem_bytes: u32,
+}
+
+macro_rules! impl_from_launch_params {
+    ($params:ty) => {
+        impl From<&$params> for LaunchInfo {
+            fn from(params: &$params) -> Self {
+                Self {
+                    function: params.f,
+                    stream: params.hStream,
+                    grid_dim: (params.gridDimX, params.gridDimY, params.gridDimZ),
+                    block_dim: (params.blockDimX, params.blockDimY, params.blockDimZ),
+                    shared_mem_bytes: params.sharedMemBytes,
+                }
+            }
+        }
+    };
+}
+
+impl_from_launch_params!(cuLaunchKernel_params);
+impl_from_launch_params!(cuLaunchKernel_ptsz_params);
+
+impl LaunchInfo {
+    /// Extracts the launch configuration from `cuLaunchKernelEx` parameters.
+    /// Returns `None` if the launch config pointer is null.
+    /// # Safety
+    ///
+    /// `config` must be null or point to a valid `CUlaunchConfig`.
+    pub unsafe fn from_launch_ex(
+        function: CUfunction,
+        config: *const CUlaunchConfig,
+    ) -> Option<Self> {
+        let config = unsafe { config.as_ref() }?;
+        Some(Self {
+            function,
+            stream: config.hStream,
+            grid_dim: (config.gridDimX, config.gridDimY, config.gridDimZ),
+            block_dim: (config.blockDimX, config.blockDimY, config.blockDimZ),
+            shared_mem_bytes: config.sharedMemBytes,
+        })
+    }
+
+    /// Extracts the launch configuration from the data of a driver API
+    /// callback. Returns `None` if `cbid` is not a kernel launch.
+    /// # Safety
+    ///
+    /// `cb_data` must be the callback data CUPTI passed along with `cbid` for
+    /// the driver API domain.
+    pub unsafe fn from_callback_data(
+        cbid: CUpti_CallbackId,
+        cb_data: &CUpti_CallbackData,
+    ) -> Option<Self> {
+        let params = cb_data.functionParams;
+        if params.is_null() {
+            return None;
+        }
+        if cbid == CUpti_driver_api_trace_cbid_enum_CUPTI_DRIVER_TRACE_CBID_cuLaunchKernel {
+            Some(Self::from(unsafe {
+                &*(params as *const cuLaunchKernel_params)
+            }))
+        } else if cbid
+            == CUpti_driver_api_trace_cbid_enum_CUPTI_DRIVER_TRACE_CBID_cuLaunchKernel_ptsz
+        {
+            Some(Self::from(unsafe {
+                &*(params as *const cuLaunchKernel_ptsz_params)
+            }))
+        } else if cbid == CUpti_driver_api_trace_cbid_enum_CUPTI_DRIVER_TRACE_CBID_cuLaunchKernelEx
+        {
+            let params = unsafe { &*(params as *const cuLaunchKernelEx_params) };
+            unsafe { Self::from_launch_ex(params.f, params.config) }
+        } else if cbid
+            == CUpti_driver_api_trace_cbid_enum_CUPTI_DRIVER_TRACE_CBID_cuLaunchKernelEx_ptsz
+        {
+            let params = unsafe { &*(params as *const cuLaunchKernelEx_ptsz_params) };
+            unsafe { Self::from_launch_ex(params.f, params.config) }
+        } else {
+            None
+        }
+    }
+
+    /// Total number of blocks in the grid.
+    pub fn grid_size(&self) -> u64 {
+        self.grid_dim.0 as u64 * self.grid_dim.1 as u64 * self.grid_dim.2 as u64
+    }
+
+    /// Number of threads per block.
+    pub fn block_size(&self) -> u64 {
+        self.block_dim.0 as u64 * self.block_dim.1 as u64 * self.block_dim.2 as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::c_void;
+    use std::ptr;
+
+    fn callback_data(params: *const c_void) -> CUpti_CallbackData {
+        let mut data: CUpti_CallbackData = unsafe { std::mem::zeroed() };
+        data.functionParams = params;
+        data
+    }
+
+    #[test]
+    fn test_launch_kernel() {
+        let mut params: cuLaunchKernel_params = unsafe { std::mem::zeroed() };
+        params.f = 0x10 as CUfunction;
+        params.hStream = 0x20 as CUstream;
+        params.gridDimX = 4;
+        params.gridDimY = 2;
+        params.gridDimZ = 1;
+        params.blockDimX = 128;
+        params.blockDimY = 1;
+        params.blockDimZ = 1;
+        params.sharedMemBytes = 512;
+        let data = callback_data(&params as *const cuLaunchKernel_params as *const c_void);
+        let info = unsafe {
+            LaunchInfo::from_callback_data(
+                CUpti_driver_api_trace_cbid_enum_CUPTI_DRIVER_TRACE_CBID_cuLaunchKernel,
+                &data,
+            )
+        }
+        .unwrap();
+        assert_eq!(info.function, params.f);
+        assert_eq!(info.stream, params.hStream);
+        assert_eq!(info.grid_dim, (4, 2, 1));
+        assert_eq!(info.block_dim, (128, 1, 1));
+        assert_eq!(info.shared_mem_bytes, 512);
+        assert_eq!(info.grid_size(), 8);
+        assert_eq!(info.block_size(), 128);
+    }
+
+    #[test]
+    fn test_launch_kernel_ex() {
+        let mut config: CUlaunchConfig = unsafe { std::mem::zeroed() };
+        config.gridDimX = 16;
+        config.gridDimY = 1;
+        config.gridDimZ = 1;
+        config.blockDimX = 32;
+        config.blockDimY = 8;
+        config.blockDimZ = 1;
+        config.sharedMemBytes = 1024;
+        let mut params: cuLaunchKernelEx_params = unsafe { std::mem::zeroed() };
+        params.f = 0x30 as CUfunction;
+        params.config = &config;
+        let data = callback_data(&params as *const cuLaunchKernelEx_params as *const c_void);
+        let cbid = CUpti_driver_api_trace_cbid_enum_CUPTI_DRIVER_TRACE_CBID_cuLaunchKernelEx;
+        let info = unsafe { LaunchInfo::from_callback_data(cbid, &data) }.unwrap();
+        assert_eq!(info.function, params.f);
+        assert_eq!(info.grid_dim, (16, 1, 1));
+        assert_eq!(info.block_dim, (32, 8, 1));
+        assert_eq!(info.shared_mem_bytes, 1024);
+        assert_eq!(info.block_size(), 256);
+
+        assert!(unsafe { LaunchInfo::from_launch_ex(params.f, ptr::null()) }.is_none());
+    }
+
+    #[test]
+    fn test_not_a_launch() {
+        let data = callback_data(ptr::null());
+        assert!(unsafe {
+            LaunchInfo::from_callback_data(
+                CUpti_driver_api_trace_cbid_enum_CUPTI_DRIVER_TRACE_CBID_cuLaunchKernel,
+                &data,
+            )
+        }
+        .is_none());
+        let params = [0u8; 64];
+        let data = callback_data(params.as_ptr() as *const c_void);
+        assert!(unsafe {
+            LaunchInfo::from_callback_data(
+                CUpti_driver_api_trace_cbid_enum_CUPTI_DRIVER_TRACE_CBID_cuMemAlloc,
+                &data,
+            )
+        }
+        .is_none());
+    }
+}
@@ -23,17 +23,34 @@ pub use error::*;
 pub mod cuda;
 pub use cuda::*;
 
+pub mod version;
+pub use version::*;
+
 pub mod activity;
 pub use activity::*;
 
 pub mod subscriber;
 pub use subscriber::*;
 
+pub mod launch;
+pub use launch::*;
+
+// The ProfilerHost and RangeProfiler object APIs only exist in the CUDA 13
+// bindings; older toolkits get the callback and activity wrappers only.
+#[cfg(feature = "cuda-13")]
 pub mod profiler;
+#[cfg(feature = "cuda-13")]
 pub use profiler::*;
 
+#[cfg(feature = "cuda-13")]
 pub mod range_profiler;
+#[cfg(feature = "cuda-13")]
 pub use range_profiler::*;
 
+#[cfg(feature = "cuda-13")]
 pub mod metric_evaluator;
+#[cfg(feature = "cuda-13")]
 pub use metric_evaluator::*;
+
+#[cfg(feature = "stubs")]
+pub mod simulation;
@@ -13,7 +13,10 @@
 // limitations under the License.
 
 use crate::bindings::*;
-use crate::profiler::{get_chip_name, get_counter_availability_image, Profiler, ProfilerHost};
+use crate::profiler::{
+    get_chip_name, get_counter_availability_image_for_chip, metric_c_strings, Profiler,
+    ProfilerHost,
+};
 use std::ffi::{CStr, CString};
 use std::os::raw::c_char;
 
@@ -23,12 +26,43 @@ pub struct MetricValuePair {
     pub value: f64,
 }
 
+/// Fewest ranges worth evaluating on a thread of their own.
+pub const MIN_RANGES_PER_THREAD: usize = 16;
+
+/// Delimiter CUPTI places between the names of nested ranges.
+pub const RANGE_DELIMITER: &str = "/";
+
 /// Contains profiling results for a specific range.
 pub struct RangeInfo {
+    /// Full range name, including the names of enclosing ranges.
     pub range_name: String,
+    /// Names of the enclosing ranges, outermost first.
+    pub parent_ranges: Vec<String>,
+    /// Nesting depth, 0 for a top-level range.
+    pub depth: usize,
     pub metric_and_values: Vec<MetricValuePair>,
 }
 
+impl RangeInfo {
+    /// Returns the name of the range itself, without its parents.
+    pub fn leaf_name(&self) -> &str {
+        self.range_name
+            .rsplit(RANGE_DELIMITER)
+            .next()
+            .unwrap_or(&self.range_name)
+    }
+}
+
+/// Splits a delimited range name into its enclosing ranges, outermost first.
+fn parse_parent_ranges(range_name: &str) -> Vec<String> {
+    let mut parts: Vec<String> = range_name
+        .split(RANGE_DELIMITER)
+        .map(str::to_string)
+        .collect();
+    parts.pop();
+    parts
+}
+
 /// High-level evaluator to extract metrics from counter data.
 pub struct MetricEvaluator {
     pub host: ProfilerHost,
@@ -52,7 +86,7 @@ impl MetricEvaluator {
             }
         }
         let chip_name = get_chip_name(device as usize)?;
-        let counter_avail = get_counter_availability_image(ctx)?;
+        let counter_avail = get_counter_availability_image_for_chip(ctx, &chip_name)?;
         host.setup(
             &chip_name,
             counter_avail,
@@ -61,6 +95,22 @@ impl MetricEvaluator {
         Ok(Self { host })
     }
 
+    /// Creates an evaluator for counter data collected on `chip_name`,
+    /// without a GPU. The counter availability image is optional for
+    /// evaluation, so it may be empty.
+    pub fn for_chip(
+        chip_name: &str,
+        counter_availability_image: Vec<u8>,
+    ) -> Result<Self, CUptiResult> {
+        let mut host = ProfilerHost::new();
+        host.setup(
+            chip_name,
+            counter_availability_image,
+            CUpti_ProfilerType_CUPTI_PROFILER_TYPE_RANGE_PROFILER,
+        )?;
+        Ok(Self { host })
+    }
+
     pub fn get_num_of_ranges(&self, counter_data_image: &[u8]) -> Result<usize, CUptiResult> {
         let mut params: CUpti_RangeProfiler_GetCounterDataInfo_Params =
             unsafe { std::mem::zeroed() };
@@ -82,23 +132,37 @@ impl MetricEvaluator {
         params.pCounterDataImage = counter_data_image.as_ptr();
         params.counterDataImageSize = counter_data_image.len();
         params.rangeIndex = range_index;
-        let delim = CString::new("/").unwrap();
+        let delim = CString::new(RANGE_DELIMITER).unwrap();
         params.rangeDelimiter = delim.as_ptr();
         check_cupti!(unsafe { cuptiRangeProfilerCounterDataGetRangeInfo(&mut params) });
         let c_str = unsafe { CStr::from_ptr(params.rangeName) };
         Ok(c_str.to_string_lossy().into_owned())
     }
 
+    /// Returns the range at `range_index` with its position in the range
+    /// hierarchy. Metric values are left empty.
+    pub fn get_range_info(
+        &self,
+        range_index: usize,
+        counter_data_image: &[u8],
+    ) -> Result<RangeInfo, CUptiResult> {
+        let range_name = self.get_range_name(range_index, counter_data_image)?;
+        let parent_ranges = parse_parent_ranges(&range_name);
+        Ok(RangeInfo {
+            range_name,
+            depth: parent_ranges.len(),
+            parent_ranges,
+            metric_and_values: Vec::new(),
+        })
+    }
+
     pub fn evaluate_metrics_for_range(
         &self,
         counter_data_image: &[u8],
         metric_names: &[String],
         range_index: usize,
     ) -> Result<Vec<f64>, CUptiResult> {
-        let c_metric_names: Vec<CString> = metric_names
-            .iter()
-            .map(|s| CString::new(s.as_str()).unwrap())
-            .collect();
+        let c_metric_names = metric_c_strings(metric_names)?;
         let mut c_metric_ptrs: Vec<*const c_char> =
             c_metric_names.iter().map(|s| s.as_ptr()).collect();
         let mut metric_values = vec![0.0f64; metric_names.len()];
@@ -116,28 +180,106 @@ impl MetricEvaluator {
         Ok(metric_values)
     }
 
+    /// Returns the range at `range_index` with its metric values.
+    pub fn evaluate_range(
+        &self,
+        counter_data_image: &[u8],
+        metric_names: &[String],
+        range_index: usize,
+    ) -> Result<RangeInfo, CUptiResult> {
+        let mut range_info = self.get_range_info(range_index, counter_data_image)?;
+        let values =
+            self.evaluate_metrics_for_range(counter_data_image, metric_names, range_index)?;
+        range_info.metric_and_values = metric_names
+            .iter()
+            .zip(values)
+            .map(|(metric_name, value)| MetricValuePair {
+                metric_name: metric_name.clone(),
+                value,
+            })
+            .collect();
+        Ok(range_info)
+    }
+
     pub fn evaluate_all_ranges(
         &self,
         counter_data_image: &[u8],
         metric_names: &[String],
     ) -> Result<Vec<RangeInfo>, CUptiResult> {
         let num_ranges = self.get_num_of_ranges(counter_data_image)?;
-        let mut range_infos = Vec::new();
-        for i in 0..num_ranges {
-            let range_name = self.get_range_name(i, counter_data_image)?;
-            let values = self.evaluate_metrics_for_range(counter_data_image, metric_names, i)?;
-            let mut metric_pairs = Vec::new();
-            for (j, val) in values.iter().enumerate() {
-                metric_pairs.push(MetricValuePair {
-                    metric_name: metric_names[j].clone(),
-                    value: *val,
-                });
+        (0..num_ranges)
+            .map(|i| self.evaluate_range(counter_data_image, metric_names, i))
+            .collect()
+    }
+
+    /// Evaluates all ranges like `evaluate_all_ranges`, spread over up to
+    /// `num_threads` threads since host evaluation is CPU-bound.
+    pub fn evaluate_all_ranges_parallel(
+        &self,
+        counter_data_image: &[u8],
+        metric_names: &[String],
+        num_threads: usize,
+    ) -> Result<Vec<RangeInfo>, CUptiResult> {
+        self.evaluate_ranges_parallel(counter_data_image, metric_names, 0, num_threads)
+    }
+
+    /// Evaluates the ranges from `first_range` on, spread over up to
+    /// `num_threads` threads. Ranges before `first_range` were evaluated
+    /// already and are skipped.
+    ///
+    /// Every extra thread gets its own host object, and each thread takes at
+    /// least `MIN_RANGES_PER_THREAD` ranges. Ranges are returned in order.
+    pub fn evaluate_ranges_parallel(
+        &self,
+        counter_data_image: &[u8],
+        metric_names: &[String],
+        first_range: usize,
+        num_threads: usize,
+    ) -> Result<Vec<RangeInfo>, CUptiResult> {
+        let end = self.get_num_of_ranges(counter_data_image)?;
+        let num_ranges = end.saturating_sub(first_range);
+        let num_threads = num_threads.min(num_ranges / MIN_RANGES_PER_THREAD).max(1);
+        let chunk_size = num_ranges.div_ceil(num_threads).max(1);
+        let evaluate_chunk = |evaluator: &MetricEvaluator, start: usize| {
+            (start..(start + chunk_size).min(end))
+                .map(|i| evaluator.evaluate_range(counter_data_image, metric_names, i))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = (first_range + chunk_size..end)
+                .step_by(chunk_size)
+                .map(|start| {
+                    scope.spawn(move || {
+                        let evaluator = MetricEvaluator {
+                            host: self.host.try_clone()?,
+                        };
+                        evaluate_chunk(&evaluator, start)
+                    })
+                })
+                .collect();
+            let mut range_infos = evaluate_chunk(self, first_range)?;
+            for handle in handles {
+                let chunk = handle
+                    .join()
+                    .map_err(|_| CUptiResult_CUPTI_ERROR_UNKNOWN)??;
+                range_infos.extend(chunk);
             }
-            range_infos.push(RangeInfo {
-                range_name,
-                metric_and_values: metric_pairs,
-            });
-        }
-        Ok(range_infos)
+            Ok(range_infos)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_parent_ranges() {
+        assert!(parse_parent_ranges("kernel").is_empty());
+        assert_eq!(
+            parse_parent_ranges("outer/inner/kernel"),
+            vec!["outer", "inner"]
+        );
+        assert_eq!(parse_parent_ranges("/kernel"), vec![""]);
     }
 }
@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use crate::bindings::*;
+use crate::cuda::{get_device_by_ordinal, release_primary_context, retain_primary_context};
 use std::ffi::{c_void, CStr, CString};
 use std::os::raw::c_char;
 use std::ptr;
@@ -33,6 +34,74 @@ impl Profiler {
     }
 }
 
+/// A base metric of a chip, as listed by the profiler host.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricInfo {
+    pub name: String,
+    pub metric_type: CUpti_MetricType,
+    pub description: String,
+    pub hw_unit: String,
+    pub dim_unit: String,
+    /// Names of the metrics that can be collected, such as `<name>.sum`.
+    pub sub_metrics: Vec<String>,
+    /// Passes needed to collect the metric on its own, if known.
+    pub num_passes: Option<usize>,
+}
+
+/// Metric types in the order CUPTI numbers them.
+pub const METRIC_TYPES: [CUpti_MetricType; 3] = [
+    CUpti_MetricType_CUPTI_METRIC_TYPE_COUNTER,
+    CUpti_MetricType_CUPTI_METRIC_TYPE_RATIO,
+    CUpti_MetricType_CUPTI_METRIC_TYPE_THROUGHPUT,
+];
+
+/// Returns the lowercase name of a metric type.
+#[allow(nonstandard_style)]
+pub fn metric_type_name(metric_type: CUpti_MetricType) -> &'static str {
+    match metric_type {
+        CUpti_MetricType_CUPTI_METRIC_TYPE_COUNTER => "counter",
+        CUpti_MetricType_CUPTI_METRIC_TYPE_RATIO => "ratio",
+        CUpti_MetricType_CUPTI_METRIC_TYPE_THROUGHPUT => "throughput",
+        _ => "unknown",
+    }
+}
+
+/// Converts metric names to the C strings CUPTI takes, failing with
+/// `CUPTI_ERROR_INVALID_METRIC_NAME` for a name with a NUL in it.
+pub fn metric_c_strings(metric_names: &[String]) -> Result<Vec<CString>, CUptiResult> {
+    metric_names
+        .iter()
+        .map(|s| CString::new(s.as_str()).map_err(|_| CUptiResult_CUPTI_ERROR_INVALID_METRIC_NAME))
+        .collect()
+}
+
+/// Copies `len` C strings owned by CUPTI.
+///
+/// # Safety
+///
+/// `names` must point to `len` valid C strings, unless `len` is 0.
+unsafe fn c_strings(names: *const *const c_char, len: usize) -> Vec<String> {
+    if names.is_null() {
+        return Vec::new();
+    }
+    unsafe { std::slice::from_raw_parts(names, len) }
+        .iter()
+        .map(|&name| unsafe { c_string(name) })
+        .collect()
+}
+
+/// Copies a C string owned by CUPTI, which may be null.
+///
+/// # Safety
+///
+/// `s` must be null or a valid C string.
+unsafe fn c_string(s: *const c_char) -> String {
+    if s.is_null() {
+        return String::new();
+    }
+    unsafe { CStr::from_ptr(s) }.to_string_lossy().into_owned()
+}
+
 /// Manages the host-side CUPTI profiler object.
 pub struct ProfilerHost {
     chip_name: String,
@@ -77,12 +146,50 @@ impl ProfilerHost {
         params.structSize = struct_size_up_to!(CUpti_Profiler_Host_Initialize_Params, pHostObject: *mut CUpti_Profiler_Host_Object);
         params.profilerType = profiler_type;
         params.pChipName = c_chip_name.as_ptr();
-        params.pCounterAvailabilityImage = self.counter_availability_image.as_ptr();
+        if !self.counter_availability_image.is_empty() {
+            params.pCounterAvailabilityImage = self.counter_availability_image.as_ptr();
+        }
         check_cupti!(unsafe { cuptiProfilerHostInitialize(&mut params) });
         self.host_object = params.pHostObject;
         Ok(())
     }
 
+    /// Sets up a host for the chip of the device with CUDA ordinal `ordinal`,
+    /// with the counters available in its primary context.
+    pub fn for_device(ordinal: i32) -> Result<Self, CUptiResult> {
+        let device =
+            get_device_by_ordinal(ordinal).map_err(|_| CUptiResult_CUPTI_ERROR_INVALID_DEVICE)?;
+        let chip_name = get_chip_name(ordinal as usize)?;
+        let ctx = retain_primary_context(device).map_err(|_| CUptiResult_CUPTI_ERROR_UNKNOWN)?;
+        let counter_availability_image =
+            unsafe { get_counter_availability_image_for_chip(ctx, &chip_name) };
+        let _ = release_primary_context(device);
+        let mut host = ProfilerHost::new();
+        host.setup(
+            &chip_name,
+            counter_availability_image?,
+            CUpti_ProfilerType_CUPTI_PROFILER_TYPE_RANGE_PROFILER,
+        )?;
+        Ok(host)
+    }
+
+    /// Name of the chip the host was set up for.
+    pub fn chip_name(&self) -> &str {
+        &self.chip_name
+    }
+
+    /// Creates another host object for the same chip and counters, so that
+    /// metrics can be evaluated on several threads at once.
+    pub fn try_clone(&self) -> Result<Self, CUptiResult> {
+        let mut host = ProfilerHost::new();
+        host.setup(
+            &self.chip_name,
+            self.counter_availability_image.clone(),
+            self.profiler_type,
+        )?;
+        Ok(host)
+    }
+
     pub fn teardown(&mut self) -> Result<(), CUptiResult> {
         if self.host_object.is_null() {
             return Ok(());
@@ -97,10 +204,7 @@ impl ProfilerHost {
 
     /// Creates a configuration image for the specified metrics.
     pub fn create_config_image(&mut self, metric_names: &[String]) -> Result<Vec<u8>, CUptiResult> {
-        let c_metric_names: Vec<CString> = metric_names
-            .iter()
-            .map(|s| CString::new(s.as_str()).unwrap())
-            .collect();
+        let c_metric_names = metric_c_strings(metric_names)?;
         let mut c_metric_ptrs: Vec<*const c_char> =
             c_metric_names.iter().map(|s| s.as_ptr()).collect();
         check_cupti!(unsafe {
@@ -128,6 +232,116 @@ impl ProfilerHost {
         check_cupti!(unsafe { cuptiProfilerHostGetConfigImage(&mut params_img) });
         Ok(config_image)
     }
+
+    /// Returns the number of passes needed to collect `metric_names`.
+    ///
+    /// Metrics added to a host object stay in its configuration, so the
+    /// query is made on a fresh host object.
+    pub fn num_passes(&self, metric_names: &[String]) -> Result<usize, CUptiResult> {
+        let mut host = self.try_clone()?;
+        let config_image = host.create_config_image(metric_names)?;
+        get_num_of_passes(&config_image)
+    }
+
+    /// Returns the names of the chip's base metrics of `metric_type`.
+    pub fn base_metrics(&self, metric_type: CUpti_MetricType) -> Result<Vec<String>, CUptiResult> {
+        let mut params: CUpti_Profiler_Host_GetBaseMetrics_Params = unsafe { std::mem::zeroed() };
+        params.structSize =
+            struct_size_up_to!(CUpti_Profiler_Host_GetBaseMetrics_Params, numMetrics: usize);
+        params.pHostObject = self.host_object;
+        params.metricType = metric_type;
+        check_cupti!(unsafe { cuptiProfilerHostGetBaseMetrics(&mut params) });
+        Ok(unsafe { c_strings(params.ppMetricNames, params.numMetrics) })
+    }
+
+    /// Returns the metrics that can be collected for a base metric of
+    /// `metric_type`, such as `sm__cycles_elapsed.sum` for
+    /// `sm__cycles_elapsed`.
+    pub fn sub_metrics(
+        &self,
+        metric_type: CUpti_MetricType,
+        base_metric: &str,
+    ) -> Result<Vec<String>, CUptiResult> {
+        let c_base_metric =
+            CString::new(base_metric).map_err(|_| CUptiResult_CUPTI_ERROR_INVALID_METRIC_NAME)?;
+        let mut params: CUpti_Profiler_Host_GetSubMetrics_Params = unsafe { std::mem::zeroed() };
+        params.structSize = struct_size_up_to!(CUpti_Profiler_Host_GetSubMetrics_Params, ppSubMetrics: *mut *const c_char);
+        params.pHostObject = self.host_object;
+        params.metricType = metric_type;
+        params.pMetricName = c_base_metric.as_ptr();
+        check_cupti!(unsafe { cuptiProfilerHostGetSubMetrics(&mut params) });
+        let sub_metrics = unsafe { c_strings(params.ppSubMetrics, params.numOfSubmetrics) };
+        Ok(sub_metrics
+            .iter()
+            .map(|sub_metric| format!("{}.{}", base_metric, sub_metric.trim_start_matches('.')))
+            .collect())
+    }
+
+    /// Describes a base metric, along with its sub-metrics and the passes it
+    /// takes to collect on its own.
+    pub fn metric_info(&self, base_metric: &str) -> Result<MetricInfo, CUptiResult> {
+        let c_base_metric =
+            CString::new(base_metric).map_err(|_| CUptiResult_CUPTI_ERROR_INVALID_METRIC_NAME)?;
+        let mut params: CUpti_Profiler_Host_GetMetricProperties_Params =
+            unsafe { std::mem::zeroed() };
+        params.structSize = struct_size_up_to!(
+            CUpti_Profiler_Host_GetMetricProperties_Params,
+            metricCollectionScope: CUpti_MetricCollectionScope
+        );
+        params.pHostObject = self.host_object;
+        params.pMetricName = c_base_metric.as_ptr();
+        check_cupti!(unsafe { cuptiProfilerHostGetMetricProperties(&mut params) });
+        let sub_metrics = self.sub_metrics(params.metricType, base_metric)?;
+        // Sub-metrics only roll up the same counters differently, so any of
+        // them takes as many passes as the others.
+        let num_passes = sub_metrics
+            .first()
+            .and_then(|sub_metric| self.num_passes(std::slice::from_ref(sub_metric)).ok());
+        Ok(MetricInfo {
+            name: base_metric.to_string(),
+            metric_type: params.metricType,
+            description: unsafe { c_string(params.pDescription) },
+            hw_unit: unsafe { c_string(params.pHwUnit) },
+            dim_unit: unsafe { c_string(params.pDimUnit) },
+            sub_metrics,
+            num_passes,
+        })
+    }
+
+    /// Returns the metrics of `metric_names` the chip does not have, which
+    /// make configuring the whole list fail. Each metric is only checked on
+    /// its own if the list as a whole fails.
+    pub fn invalid_metrics(&self, metric_names: &[String]) -> Vec<String> {
+        if self.num_passes(metric_names).is_ok() {
+            return Vec::new();
+        }
+        metric_names
+            .iter()
+            .filter(|metric_name| {
+                self.num_passes(std::slice::from_ref(metric_name))
+                    .is_err_and(|e| e == CUptiResult_CUPTI_ERROR_INVALID_METRIC_NAME)
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Splits `metric_names` into the metrics that can be collected together
+    /// in a single pass, and the ones that would need kernel replay.
+    ///
+    /// Metrics are taken in order, each one kept if it still fits in one pass
+    /// with the metrics kept before it. Metrics that can't be configured at
+    /// all are dropped too.
+    pub fn single_pass_metrics(&self, metric_names: &[String]) -> (Vec<String>, Vec<String>) {
+        let mut kept = Vec::new();
+        let mut dropped = Vec::new();
+        for metric_name in metric_names {
+            kept.push(metric_name.clone());
+            if self.num_passes(&kept) != Ok(1) {
+                dropped.push(kept.pop().unwrap());
+            }
+        }
+        (kept, dropped)
+    }
 }
 
 impl Drop for ProfilerHost {
@@ -142,6 +356,19 @@ impl Default for ProfilerHost {
     }
 }
 
+/// Returns the number of passes a configuration image needs. Anything more
+/// than 1 means kernels are replayed.
+pub fn get_num_of_passes(config_image: &[u8]) -> Result<usize, CUptiResult> {
+    let mut params: CUpti_Profiler_Host_GetNumOfPasses_Params = unsafe { std::mem::zeroed() };
+    params.structSize =
+        struct_size_up_to!(CUpti_Profiler_Host_GetNumOfPasses_Params, numOfPasses: usize);
+    params.configImageSize = config_image.len();
+    // CUPTI only reads the image.
+    params.pConfigImage = config_image.as_ptr() as *mut u8;
+    check_cupti!(unsafe { cuptiProfilerHostGetNumOfPasses(&mut params) });
+    Ok(params.numOfPasses)
+}
+
 /// Retrieves the chip name for a given device index.
 pub fn get_chip_name(device_index: usize) -> Result<String, CUptiResult> {
     let mut params: CUpti_Device_GetChipName_Params = unsafe { std::mem::zeroed() };
@@ -168,6 +395,30 @@ pub unsafe fn get_counter_availability_image(ctx: CUcontext) -> Result<Vec<u8>,
     Ok(image)
 }
 
+/// Whether `chip_name` is that of a Tegra iGPU, such as `GV11B` (Xavier) or
+/// `GA10B` (Orin), which CUPTI names with a trailing B where discrete chips
+/// end in a digit.
+pub fn is_tegra_chip(chip_name: &str) -> bool {
+    let mut chars = chip_name.trim().chars().rev();
+    matches!(chars.next(), Some('B' | 'b')) && chars.next().is_some_and(|c| c.is_ascii_digit())
+}
+
+/// Gets the counter availability image of `ctx` on `chip_name`. Tegra iGPUs
+/// do not report one, so they get an empty image, which CUPTI takes as all
+/// counters being available.
+/// # Safety
+///
+/// The `ctx` pointer must be a valid CUDA context.
+pub unsafe fn get_counter_availability_image_for_chip(
+    ctx: CUcontext,
+    chip_name: &str,
+) -> Result<Vec<u8>, CUptiResult> {
+    match unsafe { get_counter_availability_image(ctx) } {
+        Err(_) if is_tegra_chip(chip_name) => Ok(Vec::new()),
+        result => result,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -180,4 +431,28 @@ mod tests {
             "Host object should be null initially"
         );
     }
+
+    #[test]
+    fn test_is_tegra_chip() {
+        for chip in ["GA10B", "GV11B", "gp10b", "SIM10B"] {
+            assert!(is_tegra_chip(chip), "{}", chip);
+        }
+        for chip in ["GA100", "AD102", "GH100", "GB202", "B", ""] {
+            assert!(!is_tegra_chip(chip), "{}", chip);
+        }
+    }
+
+    #[test]
+    fn test_metric_c_strings() {
+        let names = ["sm__cycles_elapsed.avg".to_string()];
+        assert_eq!(
+            metric_c_strings(&names).unwrap()[0].to_bytes(),
+            names[0].as_bytes()
+        );
+        let names = ["sm__cycles\0elapsed.avg".to_string()];
+        assert_eq!(
+            metric_c_strings(&names).unwrap_err(),
+            CUptiResult_CUPTI_ERROR_INVALID_METRIC_NAME
+        );
+    }
 }
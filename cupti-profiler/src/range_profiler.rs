@@ -13,10 +13,68 @@
 // limitations under the License.
 
 use crate::bindings::*;
-use crate::profiler::{get_chip_name, get_counter_availability_image, ProfilerHost};
-use std::ffi::CString;
+use crate::profiler::{
+    get_chip_name, get_counter_availability_image, metric_c_strings, ProfilerHost,
+};
 use std::os::raw::c_char;
 use std::ptr;
+use std::sync::Mutex;
+
+/// Oldest compute capability the range profiler supports, that of Volta.
+pub const MIN_COMPUTE_CAPABILITY: (i32, i32) = (7, 0);
+
+/// Explains why the range profiler cannot be used on a device of
+/// `compute_capability`, or returns `None` if it can.
+pub fn compute_capability_unsupported(compute_capability: (i32, i32)) -> Option<String> {
+    (compute_capability < MIN_COMPUTE_CAPABILITY).then(|| {
+        format!(
+            "compute capability {}.{} is below the {}.{} the range profiler needs",
+            compute_capability.0,
+            compute_capability.1,
+            MIN_COMPUTE_CAPABILITY.0,
+            MIN_COMPUTE_CAPABILITY.1
+        )
+    })
+}
+
+/// Most config images kept in `CONFIG_IMAGES`, the oldest being dropped
+/// first.
+pub const MAX_CACHED_CONFIG_IMAGES: usize = 16;
+
+/// A config image built for a chip and a metric list.
+struct CachedConfigImage {
+    chip_name: String,
+    /// The metrics, in order.
+    metric_names: Vec<String>,
+    config_image: Vec<u8>,
+}
+
+/// Config images built so far, so that reconfiguring a context or
+/// configuring another on the same chip skips building the host object and
+/// the config image again.
+static CONFIG_IMAGES: Mutex<Vec<CachedConfigImage>> = Mutex::new(Vec::new());
+
+/// The config image cached for `metric_names` on `chip_name`, if any.
+fn cached_config_image(chip_name: &str, metric_names: &[String]) -> Option<Vec<u8>> {
+    let images = CONFIG_IMAGES.lock().unwrap_or_else(|e| e.into_inner());
+    images
+        .iter()
+        .find(|image| image.chip_name == chip_name && image.metric_names == metric_names)
+        .map(|image| image.config_image.clone())
+}
+
+/// Caches the config image of `metric_names` on `chip_name`.
+fn cache_config_image(chip_name: &str, metric_names: &[String], config_image: &[u8]) {
+    let mut images = CONFIG_IMAGES.lock().unwrap_or_else(|e| e.into_inner());
+    if images.len() >= MAX_CACHED_CONFIG_IMAGES {
+        images.remove(0);
+    }
+    images.push(CachedConfigImage {
+        chip_name: chip_name.to_string(),
+        metric_names: metric_names.to_vec(),
+        config_image: config_image.to_vec(),
+    });
+}
 
 /// Manages on-device range profiling sessions.
 pub struct RangeProfiler {
@@ -26,6 +84,9 @@ pub struct RangeProfiler {
     pub pass_index: usize,
     pub target_nesting_level: usize,
     pub is_all_pass_submitted: bool,
+    /// Ranges recorded between a start and a stop, 0 for as many as the
+    /// counter data image holds.
+    pub max_ranges_per_pass: usize,
 }
 
 unsafe impl Send for RangeProfiler {}
@@ -41,6 +102,7 @@ impl RangeProfiler {
             pass_index: 0,
             target_nesting_level: 0,
             is_all_pass_submitted: false,
+            max_ranges_per_pass: 0,
         }
     }
 
@@ -90,6 +152,9 @@ impl RangeProfiler {
     }
 
     /// Sets the configuration for the range profiler, including metrics to collect.
+    ///
+    /// The config image is built once per chip and metric list, as contexts
+    /// on the same chip have the same counters available.
     pub fn set_config(
         &mut self,
         metric_names: &[String],
@@ -97,22 +162,43 @@ impl RangeProfiler {
         max_num_ranges: usize,
         replay_mode: CUpti_ProfilerReplayMode,
     ) -> Result<(), CUptiResult> {
-        let mut host = ProfilerHost::new();
         let mut device: CUdevice = 0;
         unsafe {
             cuCtxGetDevice(&mut device);
         }
         let chip_name = get_chip_name(device as usize)?;
-        let counter_avail = unsafe { get_counter_availability_image(self.context)? };
-        host.setup(
-            &chip_name,
-            counter_avail,
-            CUpti_ProfilerType_CUPTI_PROFILER_TYPE_RANGE_PROFILER,
-        )?;
-        self.config_image = host.create_config_image(metric_names)?;
+        self.config_image = match cached_config_image(&chip_name, metric_names) {
+            Some(config_image) => config_image,
+            None => {
+                let mut host = ProfilerHost::new();
+                let counter_avail = unsafe { get_counter_availability_image(self.context)? };
+                host.setup(
+                    &chip_name,
+                    counter_avail,
+                    CUpti_ProfilerType_CUPTI_PROFILER_TYPE_RANGE_PROFILER,
+                )?;
+                let config_image = host.create_config_image(metric_names)?;
+                cache_config_image(&chip_name, metric_names, &config_image);
+                config_image
+            }
+        };
         if counter_data_image.is_empty() {
             self.create_counter_data_image(max_num_ranges, metric_names, counter_data_image)?;
         }
+        self.set_counter_data_image(counter_data_image, max_num_ranges, replay_mode)
+    }
+
+    /// Switches the session to another counter data image, reusing the
+    /// configuration image from `set_config`.
+    ///
+    /// The image must have been created for the same metrics and number of
+    /// ranges, and the profiler must be stopped.
+    pub fn set_counter_data_image(
+        &mut self,
+        counter_data_image: &mut [u8],
+        max_num_ranges: usize,
+        replay_mode: CUpti_ProfilerReplayMode,
+    ) -> Result<(), CUptiResult> {
         let mut params: CUpti_RangeProfiler_SetConfig_Params = unsafe { std::mem::zeroed() };
         params.structSize =
             struct_size_up_to!(CUpti_RangeProfiler_SetConfig_Params, targetNestingLevel: u16);
@@ -123,7 +209,10 @@ impl RangeProfiler {
         params.counterDataImageSize = counter_data_image.len();
         params.range = CUpti_ProfilerRange_CUPTI_AutoRange;
         params.replayMode = replay_mode;
-        params.maxRangesPerPass = max_num_ranges;
+        params.maxRangesPerPass = match self.max_ranges_per_pass {
+            0 => max_num_ranges,
+            n => n.min(max_num_ranges),
+        };
         params.numNestingLevels = 1;
         params.minNestingLevel = 1;
         params.passIndex = self.pass_index;
@@ -138,10 +227,7 @@ impl RangeProfiler {
         metric_names: &[String],
         counter_data_image: &mut Vec<u8>,
     ) -> Result<(), CUptiResult> {
-        let c_metric_names: Vec<CString> = metric_names
-            .iter()
-            .map(|s| CString::new(s.as_str()).unwrap())
-            .collect();
+        let c_metric_names = metric_c_strings(metric_names)?;
         let mut c_metric_ptrs: Vec<*const c_char> =
             c_metric_names.iter().map(|s| s.as_ptr()).collect();
         let mut params: CUpti_RangeProfiler_GetCounterDataSize_Params =
@@ -164,13 +250,16 @@ impl RangeProfiler {
         Ok(())
     }
 
-    pub fn decode_counter_data(&self) -> Result<(), CUptiResult> {
+    /// Decodes the collected ranges into the counter data image. Returns how
+    /// many ranges were dropped for not fitting in the image, which are the
+    /// last ones collected.
+    pub fn decode_counter_data(&self) -> Result<usize, CUptiResult> {
         let mut params: CUpti_RangeProfiler_DecodeData_Params = unsafe { std::mem::zeroed() };
         params.structSize =
             struct_size_up_to!(CUpti_RangeProfiler_DecodeData_Params, numOfRangeDropped: usize);
         params.pRangeProfilerObject = self.range_profiler_object;
         check_cupti!(unsafe { cuptiRangeProfilerDecodeData(&mut params) });
-        Ok(())
+        Ok(params.numOfRangeDropped)
     }
 
     pub fn initialize_counter_data_image(
@@ -226,4 +315,37 @@ mod tests {
         // Prevent Drop from running, as it calls FFI functions that are missing
         std::mem::forget(profiler);
     }
+
+    #[test]
+    fn test_compute_capability_unsupported() {
+        assert_eq!(compute_capability_unsupported((9, 0)), None);
+        assert_eq!(compute_capability_unsupported((7, 0)), None);
+        assert_eq!(
+            compute_capability_unsupported((6, 1)).as_deref(),
+            Some("compute capability 6.1 is below the 7.0 the range profiler needs")
+        );
+    }
+
+    #[test]
+    fn test_config_image_cache() {
+        let metrics = vec!["sm__cycles_elapsed.avg".to_string()];
+        let reordered = vec![
+            "gpu__time_duration.sum".to_string(),
+            "sm__cycles_elapsed.avg".to_string(),
+        ];
+        assert_eq!(cached_config_image("test-chip", &metrics), None);
+        cache_config_image("test-chip", &metrics, &[1, 2, 3]);
+        assert_eq!(
+            cached_config_image("test-chip", &metrics),
+            Some(vec![1, 2, 3])
+        );
+        assert_eq!(cached_config_image("other-chip", &metrics), None);
+        assert_eq!(cached_config_image("test-chip", &reordered), None);
+        // The oldest image makes room for new ones.
+        for i in 0..MAX_CACHED_CONFIG_IMAGES {
+            cache_config_image("test-chip", &[i.to_string()], &[]);
+        }
+        assert_eq!(cached_config_image("test-chip", &metrics), None);
+        assert!(CONFIG_IMAGES.lock().unwrap().len() <= MAX_CACHED_CONFIG_IMAGES);
+    }
 }
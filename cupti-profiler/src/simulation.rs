@@ -0,0 +1,639 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Scripted GPU for the `stubs` feature.
+//!
+//! Synthesizes the CUPTI callbacks, activity records and counter data a real
+//! device would produce, so the profiling pipeline can be tested without a GPU.
+//! The simulation is process-wide; tests that use it must not run concurrently.
+
+use crate::bindings::*;
+use cupti_profiler_sys::stubs::*;
+use std::ffi::{c_char, c_int, c_void, CString};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+static NEXT_CORRELATION_ID: AtomicU32 = AtomicU32::new(1);
+
+/// A kernel launch to simulate.
+#[derive(Debug, Clone)]
+pub struct SimulatedKernel {
+    pub name: String,
+    pub function: CUfunction,
+    pub grid_size: (u32, u32, u32),
+    pub block_size: (u32, u32, u32),
+    pub dynamic_shared_memory: u32,
+    pub static_shared_memory: i32,
+    pub registers_per_thread: u16,
+    /// Values the range profiler reports, in the order metrics were configured.
+    pub metric_values: Vec<f64>,
+    pub start: u64,
+    pub end: u64,
+    /// External correlation IDs pushed for the launch, as with
+    /// `cuptiActivityPushExternalCorrelationId`.
+    pub external_ids: Vec<(CUpti_ExternalCorrelationKind, u64)>,
+    /// Stream the kernel is launched on, see `create_stream`.
+    pub stream_id: u32,
+}
+
+impl Default for SimulatedKernel {
+    fn default() -> Self {
+        Self {
+            name: "kernel".to_string(),
+            function: ptr::null_mut(),
+            grid_size: (1, 1, 1),
+            block_size: (32, 1, 1),
+            dynamic_shared_memory: 0,
+            static_shared_memory: 0,
+            registers_per_thread: 32,
+            metric_values: Vec::new(),
+            start: 0,
+            end: 0,
+            external_ids: Vec::new(),
+            stream_id: 0,
+        }
+    }
+}
+
+/// Restores the simulation to its initial state.
+pub fn reset() {
+    unsafe { cuptiStubReset() };
+}
+
+/// Sets the versions reported for the runtime CUPTI and CUDA driver.
+pub fn set_versions(cupti_version: u32, driver_version: i32) {
+    unsafe { cuptiStubSetVersions(cupti_version, driver_version) };
+}
+
+/// Sets the value reported for a device attribute.
+pub fn set_device_attribute(attr: CUdevice_attribute, value: i32) {
+    unsafe { cuptiStubSetDeviceAttribute(attr, value) };
+}
+
+/// Sets the number of hardware counters a metric needs. Metrics need 1
+/// counter unless set.
+pub fn set_metric_counters(metric_name: &str, num_counters: usize) {
+    let metric_name = CString::new(metric_name).unwrap();
+    unsafe { cuptiStubSetMetricCounters(metric_name.as_ptr(), num_counters) };
+}
+
+/// Sets how many counters fit in one pass, 0 for no limit. Configurations
+/// needing more counters report more passes.
+pub fn set_counters_per_pass(num_counters: usize) {
+    unsafe { cuptiStubSetCountersPerPass(num_counters) };
+}
+
+/// Sets what enabling the range profiler returns, e.g.
+/// `CUPTI_ERROR_INSUFFICIENT_PRIVILEGES` to simulate counters restricted to
+/// admin users.
+pub fn set_range_profiler_result(result: CUptiResult) {
+    unsafe { cuptiStubSetRangeProfilerResult(result) };
+}
+
+/// Makes the next decodes drop the last `count` ranges collected, and report
+/// them in `numOfRangeDropped`.
+pub fn drop_ranges(count: usize) {
+    unsafe { cuptiStubDropRanges(count) };
+}
+
+/// Makes counter data images of more than `size` bytes run out of memory,
+/// or lets them have any size again if `size` is 0.
+pub fn set_max_counter_data_size(size: usize) {
+    unsafe { cuptiStubSetMaxCounterDataSize(size) };
+}
+
+/// Returns how many range profiler sessions were enabled since the last
+/// reset, over all contexts.
+pub fn range_profiler_enables() -> usize {
+    unsafe { cuptiStubRangeProfilerEnables() }
+}
+
+/// Makes the chip not have `metric_name`, so that configuring any list of
+/// metrics with it fails with `CUPTI_ERROR_INVALID_METRIC_NAME`.
+pub fn set_invalid_metric(metric_name: &str) {
+    let metric_name = CString::new(metric_name).unwrap();
+    unsafe { cuptiStubSetInvalidMetric(metric_name.as_ptr()) };
+}
+
+/// Makes the device a Tegra iGPU, like those of Jetson boards, whose chip
+/// name ends in B and which has no counter availability image.
+pub fn set_tegra(tegra: bool) {
+    unsafe { cuptiStubSetTegra(tegra as c_int) };
+}
+
+/// Returns the simulated context whose CUPTI context ID is `id`.
+pub fn context(id: u32) -> CUcontext {
+    id as usize as CUcontext
+}
+
+fn resource_callback(cbid: CUpti_CallbackIdResource, ctx_id: u32) -> bool {
+    let mut data: CUpti_ResourceData = unsafe { std::mem::zeroed() };
+    data.context = context(ctx_id);
+    unsafe {
+        cuptiStubDeliverCallback(
+            CUpti_CallbackDomain_CUPTI_CB_DOMAIN_RESOURCE,
+            cbid,
+            &data as *const CUpti_ResourceData as *const c_void,
+        ) != 0
+    }
+}
+
+/// Delivers a context-created resource callback for the context with ID
+/// `ctx_id`. Returns true if the subscriber had it enabled.
+pub fn create_context(ctx_id: u32) -> bool {
+    resource_callback(
+        CUpti_CallbackIdResource_CUPTI_CBID_RESOURCE_CONTEXT_CREATED,
+        ctx_id,
+    )
+}
+
+/// Delivers a context-destroy-starting resource callback for the context with
+/// ID `ctx_id`. Returns true if the subscriber had it enabled.
+pub fn destroy_context(ctx_id: u32) -> bool {
+    resource_callback(
+        CUpti_CallbackIdResource_CUPTI_CBID_RESOURCE_CONTEXT_DESTROY_STARTING,
+        ctx_id,
+    )
+}
+
+/// Returns the simulated stream whose CUPTI stream ID is `id`.
+pub fn stream(id: u32) -> CUstream {
+    id as usize as CUstream
+}
+
+/// Simulates `cuStreamCreate` of the stream with ID `stream_id` on the
+/// context with ID `ctx_id`, delivering the driver API enter and exit
+/// callbacks. Returns true if the exit callback was delivered.
+pub fn create_stream(ctx_id: u32, stream_id: u32) -> bool {
+    let mut handle: CUstream = ptr::null_mut();
+    let mut params: cuStreamCreate_params = unsafe { std::mem::zeroed() };
+    params.phStream = &mut handle;
+    let mut result: CUresult = 0;
+    let function_name = c"cuStreamCreate";
+    let mut data: CUpti_CallbackData = unsafe { std::mem::zeroed() };
+    data.functionName = function_name.as_ptr();
+    data.functionParams = &params as *const cuStreamCreate_params as *const c_void;
+    data.functionReturnValue = &mut result as *mut CUresult as *mut c_void;
+    data.context = context(ctx_id);
+    data.contextUid = ctx_id;
+    data.correlationId = NEXT_CORRELATION_ID.fetch_add(1, Ordering::SeqCst);
+    let deliver = |data: &CUpti_CallbackData| unsafe {
+        cuptiStubDeliverCallback(
+            CUpti_CallbackDomain_CUPTI_CB_DOMAIN_DRIVER_API,
+            CUpti_driver_api_trace_cbid_enum_CUPTI_DRIVER_TRACE_CBID_cuStreamCreate,
+            data as *const CUpti_CallbackData as *const c_void,
+        ) != 0
+    };
+    data.callbackSite = CUpti_ApiCallbackSite_CUPTI_API_ENTER;
+    deliver(&data);
+    unsafe { *params.phStream = stream(stream_id) };
+    data.callbackSite = CUpti_ApiCallbackSite_CUPTI_API_EXIT;
+    deliver(&data)
+}
+
+/// `nvtxNameCuStreamA_params` of the CUPTI NVTX callback data.
+#[repr(C)]
+struct NvtxNameCuStreamAParams {
+    stream: CUstream,
+    name: *const c_char,
+}
+
+/// Simulates `nvtxNameCuStreamA` naming the stream with ID `stream_id`.
+/// Returns true if the subscriber had the callback enabled.
+pub fn name_stream(stream_id: u32, name: &str) -> bool {
+    let name = CString::new(name).unwrap();
+    let params = NvtxNameCuStreamAParams {
+        stream: stream(stream_id),
+        name: name.as_ptr(),
+    };
+    let function_name = c"nvtxNameCuStreamA";
+    let data = CUpti_NvtxData {
+        functionName: function_name.as_ptr(),
+        functionParams: &params as *const NvtxNameCuStreamAParams as *const c_void,
+        functionReturnValue: ptr::null(),
+    };
+    unsafe {
+        cuptiStubDeliverCallback(
+            CUpti_CallbackDomain_CUPTI_CB_DOMAIN_NVTX,
+            CUpti_nvtx_api_trace_cbid_CUPTI_CBID_NVTX_nvtxNameCuStreamA,
+            &data as *const CUpti_NvtxData as *const c_void,
+        ) != 0
+    }
+}
+
+/// Simulates `cuLaunchKernel` on the context with ID `ctx_id`.
+///
+/// Delivers the driver API enter and exit callbacks, and in between queues the
+/// kernel's range for the context's range profiler and its kernel activity
+/// record for the next flush. Returns true if the enter callback was delivered.
+pub fn launch_kernel(ctx_id: u32, kernel: &SimulatedKernel) -> bool {
+    let mut params: cuLaunchKernel_params = unsafe { std::mem::zeroed() };
+    params.f = kernel.function;
+    params.gridDimX = kernel.grid_size.0;
+    params.gridDimY = kernel.grid_size.1;
+    params.gridDimZ = kernel.grid_size.2;
+    params.blockDimX = kernel.block_size.0;
+    params.blockDimY = kernel.block_size.1;
+    params.blockDimZ = kernel.block_size.2;
+    params.sharedMemBytes = kernel.dynamic_shared_memory;
+    params.hStream = stream(kernel.stream_id);
+    deliver_launch(
+        ctx_id,
+        kernel,
+        CUpti_driver_api_trace_cbid_enum_CUPTI_DRIVER_TRACE_CBID_cuLaunchKernel,
+        c"cuLaunchKernel".as_ptr(),
+        &params as *const cuLaunchKernel_params as *const c_void,
+    )
+}
+
+/// Simulates `cuLaunchKernelEx` on the context with ID `ctx_id`, like
+/// `launch_kernel`.
+pub fn launch_kernel_ex(ctx_id: u32, kernel: &SimulatedKernel) -> bool {
+    let mut config: CUlaunchConfig = unsafe { std::mem::zeroed() };
+    config.gridDimX = kernel.grid_size.0;
+    config.gridDimY = kernel.grid_size.1;
+    config.gridDimZ = kernel.grid_size.2;
+    config.blockDimX = kernel.block_size.0;
+    config.blockDimY = kernel.block_size.1;
+    config.blockDimZ = kernel.block_size.2;
+    config.sharedMemBytes = kernel.dynamic_shared_memory;
+    config.hStream = stream(kernel.stream_id);
+    let mut params: cuLaunchKernelEx_params = unsafe { std::mem::zeroed() };
+    params.f = kernel.function;
+    params.config = &config;
+    deliver_launch(
+        ctx_id,
+        kernel,
+        CUpti_driver_api_trace_cbid_enum_CUPTI_DRIVER_TRACE_CBID_cuLaunchKernelEx,
+        c"cuLaunchKernelEx".as_ptr(),
+        &params as *const cuLaunchKernelEx_params as *const c_void,
+    )
+}
+
+/// Delivers the callbacks of a kernel launch with the parameters `params` of
+/// the driver API call `cbid`, see `launch_kernel`.
+fn deliver_launch(
+    ctx_id: u32,
+    kernel: &SimulatedKernel,
+    cbid: CUpti_CallbackId,
+    function_name: *const c_char,
+    params: *const c_void,
+) -> bool {
+    let mut data: CUpti_CallbackData = unsafe { std::mem::zeroed() };
+    data.functionName = function_name;
+    data.functionParams = params;
+    data.context = context(ctx_id);
+    data.contextUid = ctx_id;
+    data.correlationId = NEXT_CORRELATION_ID.fetch_add(1, Ordering::SeqCst);
+    let deliver = |data: &CUpti_CallbackData| unsafe {
+        cuptiStubDeliverCallback(
+            CUpti_CallbackDomain_CUPTI_CB_DOMAIN_DRIVER_API,
+            cbid,
+            data as *const CUpti_CallbackData as *const c_void,
+        ) != 0
+    };
+    data.callbackSite = CUpti_ApiCallbackSite_CUPTI_API_ENTER;
+    let delivered = deliver(&data);
+    push_range(ctx_id, &kernel.name, &kernel.metric_values);
+    for &(kind, external_id) in &kernel.external_ids {
+        push_external_correlation(kind, external_id, data.correlationId);
+    }
+    push_kernel_activity(ctx_id, kernel, data.correlationId);
+    data.callbackSite = CUpti_ApiCallbackSite_CUPTI_API_EXIT;
+    deliver(&data);
+    delivered
+}
+
+/// Queues a range that the next decode of the range profiler on the context
+/// with ID `ctx_id` picks up. Like real kernels, ranges are only recorded while
+/// a range profiler is started on the context, up to its maximum number of
+/// ranges per pass.
+pub fn push_range(ctx_id: u32, name: &str, metric_values: &[f64]) {
+    let name = CString::new(name).unwrap();
+    unsafe {
+        cuptiStubPushRange(
+            context(ctx_id),
+            name.as_ptr(),
+            metric_values.as_ptr(),
+            metric_values.len(),
+        )
+    };
+}
+
+/// Queues the external correlation record CUPTI writes for a call made with
+/// `external_id` pushed. Returns true if external correlation activity is
+/// enabled.
+pub fn push_external_correlation(
+    kind: CUpti_ExternalCorrelationKind,
+    external_id: u64,
+    correlation_id: u32,
+) -> bool {
+    let mut record: CUpti_ActivityExternalCorrelation = unsafe { std::mem::zeroed() };
+    record.kind = CUpti_ActivityKind_CUPTI_ACTIVITY_KIND_EXTERNAL_CORRELATION;
+    record.externalKind = kind;
+    record.externalId = external_id;
+    record.correlationId = correlation_id;
+    unsafe {
+        cuptiStubPushActivityRecord(
+            &record as *const CUpti_ActivityExternalCorrelation as *const CUpti_Activity,
+            std::mem::size_of::<CUpti_ActivityExternalCorrelation>(),
+        ) != 0
+    }
+}
+
+/// Queues the record CUPTI writes when the stream-ordered allocator pool at
+/// `address` on `device_id` is created, trimmed or destroyed (`operation`),
+/// with `size` bytes reserved and `used` of them in use, stamped with the
+/// current time. Returns true if memory pool activity is enabled.
+pub fn push_memory_pool_activity(
+    device_id: u32,
+    operation: CUpti_ActivityMemoryPoolOperationType,
+    address: u64,
+    size: u64,
+    used: u64,
+) -> bool {
+    let mut record: CUpti_ActivityMemoryPool2 = unsafe { std::mem::zeroed() };
+    record.kind = CUpti_ActivityKind_CUPTI_ACTIVITY_KIND_MEMORY_POOL;
+    record.memoryPoolOperationType = operation;
+    record.memoryPoolType = CUpti_ActivityMemoryPoolType_CUPTI_ACTIVITY_MEMORY_POOL_TYPE_LOCAL;
+    record.deviceId = device_id;
+    record.address = address;
+    record.size = size;
+    record.utilizedSize = used;
+    record.timestamp = crate::get_timestamp().unwrap();
+    unsafe {
+        cuptiStubPushActivityRecord(
+            &record as *const CUpti_ActivityMemoryPool2 as *const CUpti_Activity,
+            std::mem::size_of::<CUpti_ActivityMemoryPool2>(),
+        ) != 0
+    }
+}
+
+/// Queues the record CUPTI writes for a copy of `bytes` bytes from
+/// `src_device_id` to `dst_device_id`, run by `device_id` on the stream with
+/// ID `stream_id` and taking `duration` ns up to now. Returns true if
+/// peer-to-peer memcpy activity is enabled.
+pub fn push_peer_copy_activity(
+    device_id: u32,
+    stream_id: u32,
+    src_device_id: u32,
+    dst_device_id: u32,
+    bytes: u64,
+    duration: u64,
+) -> bool {
+    let mut record: CUpti_ActivityMemcpyPtoP2 = unsafe { std::mem::zeroed() };
+    record.kind = CUpti_ActivityKind_CUPTI_ACTIVITY_KIND_MEMCPY2;
+    record.copyKind = CUpti_ActivityMemcpyKind_CUPTI_ACTIVITY_MEMCPY_KIND_PTOP as u8;
+    record.bytes = bytes;
+    record.end = crate::get_timestamp().unwrap();
+    record.start = record.end.saturating_sub(duration);
+    record.deviceId = device_id;
+    record.streamId = stream_id;
+    record.srcDeviceId = src_device_id;
+    record.dstDeviceId = dst_device_id;
+    unsafe {
+        cuptiStubPushActivityRecord(
+            &record as *const CUpti_ActivityMemcpyPtoP2 as *const CUpti_Activity,
+            std::mem::size_of::<CUpti_ActivityMemcpyPtoP2>(),
+        ) != 0
+    }
+}
+
+/// Queues a kernel activity record for the next activity flush. Returns true
+/// if kernel activity is enabled.
+pub fn push_kernel_activity(ctx_id: u32, kernel: &SimulatedKernel, correlation_id: u32) -> bool {
+    let mut record: CUpti_ActivityKernel4 = unsafe { std::mem::zeroed() };
+    record.kind = CUpti_ActivityKind_CUPTI_ACTIVITY_KIND_KERNEL;
+    record.registersPerThread = kernel.registers_per_thread;
+    record.start = kernel.start;
+    record.end = kernel.end;
+    record.contextId = ctx_id;
+    record.gridX = kernel.grid_size.0 as i32;
+    record.gridY = kernel.grid_size.1 as i32;
+    record.gridZ = kernel.grid_size.2 as i32;
+    record.blockX = kernel.block_size.0 as i32;
+    record.blockY = kernel.block_size.1 as i32;
+    record.blockZ = kernel.block_size.2 as i32;
+    record.staticSharedMemory = kernel.static_shared_memory;
+    record.dynamicSharedMemory = kernel.dynamic_shared_memory as i32;
+    record.streamId = kernel.stream_id;
+    record.correlationId = correlation_id;
+    // Records are copied into activity buffers later, so the name has to
+    // outlive this call, just like CUPTI's own string table.
+    record.name = CString::new(kernel.name.as_str()).unwrap().into_raw();
+    unsafe {
+        cuptiStubPushActivityRecord(
+            &record as *const CUpti_ActivityKernel4 as *const CUpti_Activity,
+            std::mem::size_of::<CUpti_ActivityKernel4>(),
+        ) != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        activity_enable, activity_flush_all, activity_get_next_record, activity_register_callbacks,
+    };
+    use std::sync::Mutex;
+
+    static SIMULATION: Mutex<()> = Mutex::new(());
+    static COMPLETED: Mutex<Vec<(u32, String)>> = Mutex::new(Vec::new());
+
+    unsafe extern "C" fn buffer_requested(
+        buffer: *mut *mut u8,
+        size: *mut usize,
+        _max_num_records: *mut usize,
+    ) {
+        *size = 1024;
+        *buffer = Box::into_raw(vec![0u8; 1024].into_boxed_slice()) as *mut u8;
+    }
+
+    unsafe extern "C" fn buffer_completed(
+        _ctx: CUcontext,
+        _stream_id: u32,
+        buffer: *mut u8,
+        size: usize,
+        valid_size: usize,
+    ) {
+        let mut record: *mut CUpti_Activity = ptr::null_mut();
+        while activity_get_next_record(buffer, valid_size, &mut record).is_ok() {
+            let k = &*(record as *const CUpti_ActivityKernel4);
+            let name = std::ffi::CStr::from_ptr(k.name)
+                .to_string_lossy()
+                .into_owned();
+            COMPLETED.lock().unwrap().push((k.contextId, name));
+        }
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(buffer, size)));
+    }
+
+    #[cfg(feature = "cuda-13")]
+    #[test]
+    fn test_counter_data_round_trip() {
+        use crate::{MetricEvaluator, RangeProfiler};
+
+        let _guard = SIMULATION.lock().unwrap();
+        reset();
+        let ctx = context(7);
+        let metrics = vec![
+            "gpu__time_duration.sum".to_string(),
+            "sm__cycles".to_string(),
+        ];
+        let mut image = Vec::new();
+        let mut rp = RangeProfiler::new(ctx);
+        rp.enable().unwrap();
+        rp.set_config(
+            &metrics,
+            &mut image,
+            4,
+            CUpti_ProfilerReplayMode_CUPTI_KernelReplay,
+        )
+        .unwrap();
+        rp.start().unwrap();
+        push_range(7, "first", &[100.0, 5.0]);
+        push_range(7, "outer/second", &[200.0]);
+        rp.stop().unwrap();
+        rp.decode_counter_data().unwrap();
+        let me = unsafe { MetricEvaluator::new(ctx) }.unwrap();
+        let ranges = me.evaluate_all_ranges(&image, &metrics).unwrap();
+        assert_eq!(ranges.len(), 2);
+        assert_eq!(ranges[0].range_name, "first");
+        assert_eq!(ranges[0].depth, 0);
+        assert_eq!(ranges[0].metric_and_values[0].value, 100.0);
+        assert_eq!(ranges[0].metric_and_values[1].value, 5.0);
+        assert_eq!(ranges[1].range_name, "outer/second");
+        assert_eq!(ranges[1].leaf_name(), "second");
+        assert_eq!(ranges[1].parent_ranges, vec!["outer"]);
+        assert_eq!(ranges[1].depth, 1);
+        assert_eq!(ranges[1].metric_and_values[1].value, 0.0);
+        rp.initialize_counter_data_image(&mut image).unwrap();
+        assert_eq!(me.get_num_of_ranges(&image).unwrap(), 0);
+        rp.disable().unwrap();
+    }
+
+    #[cfg(feature = "cuda-13")]
+    #[test]
+    fn test_single_pass_metrics() {
+        use crate::MetricEvaluator;
+
+        let _guard = SIMULATION.lock().unwrap();
+        reset();
+        let metrics: Vec<String> = ["a", "b", "c", "d"].map(String::from).to_vec();
+        let me = unsafe { MetricEvaluator::new(context(1)) }.unwrap();
+        assert_eq!(me.host.num_passes(&metrics), Ok(1));
+
+        set_counters_per_pass(4);
+        set_metric_counters("b", 3);
+        set_metric_counters("c", 2);
+        assert_eq!(me.host.num_passes(&metrics), Ok(2));
+        let (kept, dropped) = me.host.single_pass_metrics(&metrics);
+        assert_eq!(kept, vec!["a", "b"]);
+        assert_eq!(dropped, vec!["c", "d"]);
+        assert_eq!(me.host.num_passes(&kept), Ok(1));
+    }
+
+    #[cfg(feature = "cuda-13")]
+    #[test]
+    fn test_invalid_metrics() {
+        use crate::MetricEvaluator;
+
+        let _guard = SIMULATION.lock().unwrap();
+        reset();
+        let metrics: Vec<String> = ["a", "typo", "b", "bogus"].map(String::from).to_vec();
+        let me = unsafe { MetricEvaluator::new(context(1)) }.unwrap();
+        assert!(me.host.invalid_metrics(&metrics).is_empty());
+        set_invalid_metric("typo");
+        set_invalid_metric("bogus");
+        assert!(me.host.num_passes(&metrics).is_err());
+        assert_eq!(me.host.invalid_metrics(&metrics), vec!["typo", "bogus"]);
+    }
+
+    #[cfg(feature = "cuda-13")]
+    #[test]
+    fn test_list_metrics() {
+        use crate::{ProfilerHost, METRIC_TYPES};
+
+        let _guard = SIMULATION.lock().unwrap();
+        reset();
+        let host = ProfilerHost::for_device(0).unwrap();
+        assert_eq!(host.chip_name(), "SIM100");
+        assert!(ProfilerHost::for_device(1).is_err());
+        let names: Vec<String> = METRIC_TYPES
+            .iter()
+            .flat_map(|&metric_type| host.base_metrics(metric_type).unwrap())
+            .collect();
+        assert!(names.contains(&"sm__cycles_elapsed".to_string()));
+        assert!(names.contains(&"sm__throughput".to_string()));
+
+        set_counters_per_pass(2);
+        set_metric_counters("sm__throughput.avg.pct_of_peak_sustained_elapsed", 3);
+        let info = host.metric_info("sm__throughput").unwrap();
+        assert_eq!(
+            info.metric_type,
+            CUpti_MetricType_CUPTI_METRIC_TYPE_THROUGHPUT
+        );
+        assert_eq!(info.dim_unit, "percent");
+        assert_eq!(
+            info.sub_metrics[0],
+            "sm__throughput.avg.pct_of_peak_sustained_elapsed"
+        );
+        assert_eq!(info.num_passes, Some(2));
+        let info = host.metric_info("sm__cycles_elapsed").unwrap();
+        assert_eq!(info.description, "# of cycles elapsed on SM");
+        assert_eq!(info.sub_metrics.len(), 4);
+        assert_eq!(info.num_passes, Some(1));
+        assert!(host.metric_info("bogus").is_err());
+    }
+
+    #[cfg(feature = "cuda-13")]
+    #[test]
+    fn test_tegra_counter_availability() {
+        use crate::{MetricEvaluator, ProfilerHost};
+
+        let _guard = SIMULATION.lock().unwrap();
+        reset();
+        set_tegra(true);
+        assert!(unsafe { crate::get_counter_availability_image(context(1)) }.is_err());
+        let me = unsafe { MetricEvaluator::new(context(1)) }.unwrap();
+        assert_eq!(me.host.chip_name(), "SIM10B");
+        assert_eq!(ProfilerHost::for_device(0).unwrap().chip_name(), "SIM10B");
+        reset();
+        assert_eq!(ProfilerHost::for_device(0).unwrap().chip_name(), "SIM100");
+    }
+
+    #[test]
+    fn test_activity_flush() {
+        let _guard = SIMULATION.lock().unwrap();
+        reset();
+        COMPLETED.lock().unwrap().clear();
+        let kernel = SimulatedKernel {
+            name: "vector_add".to_string(),
+            ..Default::default()
+        };
+        assert!(!push_kernel_activity(3, &kernel, 1));
+        activity_enable(CUpti_ActivityKind_CUPTI_ACTIVITY_KIND_KERNEL).unwrap();
+        unsafe { activity_register_callbacks(Some(buffer_requested), Some(buffer_completed)) }
+            .unwrap();
+        // More records than fit in one buffer.
+        for _ in 0..10 {
+            assert!(push_kernel_activity(3, &kernel, 1));
+        }
+        activity_flush_all(0).unwrap();
+        let completed = COMPLETED.lock().unwrap();
+        assert_eq!(completed.len(), 10);
+        assert!(completed
+            .iter()
+            .all(|(id, name)| *id == 3 && name == "vector_add"));
+    }
+}
@@ -28,6 +28,15 @@ pub unsafe fn subscribe(
     Ok(subscriber)
 }
 
+/// Unsubscribes from CUPTI callbacks.
+/// # Safety
+///
+/// The subscriber handle must be valid.
+pub unsafe fn unsubscribe(subscriber: CUpti_SubscriberHandle) -> Result<(), CUptiResult> {
+    check_cupti!(unsafe { cuptiUnsubscribe(subscriber) });
+    Ok(())
+}
+
 /// # Safety
 ///
 /// The subscriber handle must be valid.
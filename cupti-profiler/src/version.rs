@@ -0,0 +1,115 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::bindings::*;
+use std::fmt;
+
+/// CUPTI API version the bindings were generated against.
+///
+/// Parameter structs are sized with `struct_size_up_to!` from these bindings, so
+/// a runtime CUPTI older than this may interpret them with a different layout.
+pub const BINDINGS_CUPTI_API_VERSION: u32 = CUPTI_API_VERSION;
+
+/// Gets the API version of the CUPTI library loaded at runtime.
+pub fn get_cupti_version() -> Result<u32, CUptiResult> {
+    let mut version = 0;
+    check_cupti!(unsafe { cuptiGetVersion(&mut version) });
+    Ok(version)
+}
+
+/// Splits a CUPTI API version (e.g. `130100`) into its CUDA (major, minor).
+pub fn cupti_version_parts(version: u32) -> (u32, u32) {
+    (version / 10000, (version / 100) % 100)
+}
+
+/// Splits a CUDA driver version (e.g. `13010`) into (major, minor).
+pub fn driver_version_parts(version: i32) -> (i32, i32) {
+    (version / 1000, (version % 1000) / 10)
+}
+
+/// Describes why the runtime CUPTI/driver can't be used with these bindings.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VersionMismatch {
+    /// The runtime CUPTI is older than the bindings.
+    CuptiTooOld { runtime: u32, required: u32 },
+    /// The driver is older than the CUDA release the bindings target.
+    DriverTooOld { runtime: i32, required_major: i32 },
+}
+
+impl fmt::Display for VersionMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VersionMismatch::CuptiTooOld { runtime, required } => write!(
+                f,
+                "CUPTI API version {} is older than the {} this library was built against",
+                runtime, required
+            ),
+            VersionMismatch::DriverTooOld {
+                runtime,
+                required_major,
+            } => {
+                let (major, minor) = driver_version_parts(*runtime);
+                write!(
+                    f,
+                    "CUDA driver {}.{} is older than the required CUDA {}",
+                    major, minor, required_major
+                )
+            }
+        }
+    }
+}
+
+/// Checks runtime CUPTI and driver versions against the bindings.
+pub fn check_versions(cupti_version: u32, driver_version: i32) -> Result<(), VersionMismatch> {
+    if cupti_version < BINDINGS_CUPTI_API_VERSION {
+        return Err(VersionMismatch::CuptiTooOld {
+            runtime: cupti_version,
+            required: BINDINGS_CUPTI_API_VERSION,
+        });
+    }
+    let required_major = cupti_version_parts(BINDINGS_CUPTI_API_VERSION).0 as i32;
+    if driver_version_parts(driver_version).0 < required_major {
+        return Err(VersionMismatch::DriverTooOld {
+            runtime: driver_version,
+            required_major,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_parts() {
+        assert_eq!(cupti_version_parts(130100), (13, 1));
+        assert_eq!(driver_version_parts(12040), (12, 4));
+    }
+
+    #[test]
+    fn test_check_versions() {
+        let (major, _) = cupti_version_parts(BINDINGS_CUPTI_API_VERSION);
+        let driver = major as i32 * 1000;
+        assert!(check_versions(BINDINGS_CUPTI_API_VERSION, driver).is_ok());
+        assert!(matches!(
+            check_versions(BINDINGS_CUPTI_API_VERSION - 1, driver),
+            Err(VersionMismatch::CuptiTooOld { .. })
+        ));
+        assert!(matches!(
+            check_versions(BINDINGS_CUPTI_API_VERSION, driver - 1000),
+            Err(VersionMismatch::DriverTooOld { .. })
+        ));
+    }
+}
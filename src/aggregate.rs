@@ -0,0 +1,288 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Aggregation of the launches of a kernel into one event per window.
+//!
+//! With `INJECTION_AGGREGATE_WINDOW_MS` set, the launches of a kernel with
+//! the same launch configuration are not written one by one. The first
+//! launch of each within a window stands for all of them, with the mean
+//! duration and metric values, their minimum and maximum as extra data, and
+//! how many launches it stands for as `aggregated_count`.
+
+use cupti_profiler::MetricValuePair;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Launches of a kernel with the same signature are aggregated together.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct KernelSignature {
+    pub name: String,
+    pub grid_size: (i32, i32, i32),
+    pub block_size: (i32, i32, i32),
+    /// CUPTI ID of the stream, whose queue the aggregate goes on.
+    pub stream_id: u32,
+}
+
+/// Sum, minimum and maximum of a value over the launches that have it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stats {
+    pub sum: f64,
+    pub min: f64,
+    pub max: f64,
+    pub count: u64,
+}
+
+impl Stats {
+    fn new(value: f64) -> Self {
+        Self {
+            sum: value,
+            min: value,
+            max: value,
+            count: 1,
+        }
+    }
+
+    fn add(&mut self, value: f64) {
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.count += 1;
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.sum / self.count as f64
+    }
+}
+
+/// Adds `value` to the stats of `name`, keeping names in the order first
+/// seen so that counter IDs stay the same.
+fn add_named<K: PartialEq>(stats: &mut Vec<(K, Stats)>, name: K, value: f64) {
+    match stats.iter_mut().find(|(n, _)| *n == name) {
+        Some((_, stats)) => stats.add(value),
+        None => stats.push((name, Stats::new(value))),
+    }
+}
+
+/// The launches of one kernel signature in a window.
+#[derive(Debug, Clone)]
+pub struct Aggregate {
+    /// Start of the first launch.
+    pub timestamp: u64,
+    /// Stream of the launches.
+    pub stream_id: u32,
+    pub duration: Stats,
+    /// Extra data of the first launch.
+    pub extra_data: Vec<(&'static str, String)>,
+    /// Metric values of the profiled launches, in the order of their ranges.
+    pub metrics: Vec<(String, Stats)>,
+    /// Derived counter values of the profiled launches.
+    pub derived: Vec<(&'static str, Stats)>,
+}
+
+impl Aggregate {
+    /// Number of launches aggregated.
+    pub fn count(&self) -> u64 {
+        self.duration.count
+    }
+
+    /// Mean duration of the launches, the duration of the event.
+    pub fn mean_duration(&self) -> u64 {
+        self.duration.mean() as u64
+    }
+
+    /// Extra data of the event: that of the first launch, followed by the
+    /// launch count and the minimum and maximum of the duration and of each
+    /// metric.
+    pub fn extra_data(&self) -> Vec<(String, String)> {
+        let mut data: Vec<(String, String)> = self
+            .extra_data
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.clone()))
+            .collect();
+        data.push(("aggregated_count".to_string(), self.count().to_string()));
+        data.push(("duration_min".to_string(), self.duration.min.to_string()));
+        data.push(("duration_max".to_string(), self.duration.max.to_string()));
+        for (name, stats) in &self.metrics {
+            data.push((format!("{}.min", name), stats.min.to_string()));
+            data.push((format!("{}.max", name), stats.max.to_string()));
+        }
+        data
+    }
+
+    /// Mean value of each metric, what the event's counters carry.
+    pub fn mean_metrics(&self) -> Vec<MetricValuePair> {
+        self.metrics
+            .iter()
+            .map(|(name, stats)| MetricValuePair {
+                metric_name: name.clone(),
+                value: stats.mean(),
+            })
+            .collect()
+    }
+
+    /// Mean value of each derived counter.
+    pub fn mean_derived(&self) -> Vec<(&'static str, f64)> {
+        self.derived
+            .iter()
+            .map(|&(name, stats)| (name, stats.mean()))
+            .collect()
+    }
+}
+
+/// Aggregates launches by signature over consecutive windows of kernel time.
+pub struct Aggregator {
+    window: u64,
+    window_start: u64,
+    index: HashMap<KernelSignature, usize>,
+    /// Aggregates of the current window, in the order of their first launch.
+    aggregates: Vec<Aggregate>,
+}
+
+impl Aggregator {
+    /// Aggregates over windows of `window`, or returns nothing if it is zero.
+    pub fn new(window: Duration) -> Option<Self> {
+        (!window.is_zero()).then(|| Self {
+            window: window.as_nanos() as u64,
+            window_start: 0,
+            index: HashMap::new(),
+            aggregates: Vec::new(),
+        })
+    }
+
+    /// Adds a launch that started at `timestamp`. If it is past the current
+    /// window, that window is closed first and its aggregates returned.
+    pub fn add(
+        &mut self,
+        signature: KernelSignature,
+        timestamp: u64,
+        duration: u64,
+        extra_data: &[(&'static str, String)],
+        metrics: &[MetricValuePair],
+        derived: &[(&'static str, f64)],
+    ) -> Vec<Aggregate> {
+        let closed = if timestamp >= self.window_start.saturating_add(self.window) {
+            self.window_start = timestamp;
+            self.take()
+        } else {
+            Vec::new()
+        };
+        let aggregate = match self.index.get(&signature) {
+            Some(&index) => {
+                let aggregate = &mut self.aggregates[index];
+                aggregate.duration.add(duration as f64);
+                aggregate
+            }
+            None => {
+                let stream_id = signature.stream_id;
+                self.index.insert(signature, self.aggregates.len());
+                self.aggregates.push(Aggregate {
+                    timestamp,
+                    stream_id,
+                    duration: Stats::new(duration as f64),
+                    extra_data: extra_data.to_vec(),
+                    metrics: Vec::new(),
+                    derived: Vec::new(),
+                });
+                self.aggregates.last_mut().unwrap()
+            }
+        };
+        for metric in metrics {
+            add_named(
+                &mut aggregate.metrics,
+                metric.metric_name.clone(),
+                metric.value,
+            );
+        }
+        for &(name, value) in derived {
+            add_named(&mut aggregate.derived, name, value);
+        }
+        closed
+    }
+
+    /// Closes the current window, returning its aggregates.
+    pub fn take(&mut self) -> Vec<Aggregate> {
+        self.index.clear();
+        std::mem::take(&mut self.aggregates)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signature(name: &str) -> KernelSignature {
+        KernelSignature {
+            name: name.to_string(),
+            grid_size: (1, 1, 1),
+            block_size: (32, 1, 1),
+            stream_id: 0,
+        }
+    }
+
+    fn metric(value: f64) -> Vec<MetricValuePair> {
+        vec![MetricValuePair {
+            metric_name: "sm__cycles_elapsed.avg".to_string(),
+            value,
+        }]
+    }
+
+    #[test]
+    fn test_aggregator() {
+        assert!(Aggregator::new(Duration::ZERO).is_none());
+        let mut aggregator = Aggregator::new(Duration::from_micros(1)).unwrap();
+        let extra_data = [("kernel_name", "a".to_string())];
+        assert!(aggregator
+            .add(signature("a"), 0, 100, &extra_data, &metric(10.0), &[])
+            .is_empty());
+        assert!(aggregator
+            .add(signature("b"), 200, 50, &[], &[], &[])
+            .is_empty());
+        assert!(aggregator
+            .add(signature("a"), 400, 300, &[], &metric(30.0), &[("x", 1.0)])
+            .is_empty());
+        // A launch past the window closes it.
+        let closed = aggregator.add(signature("a"), 1000, 10, &[], &[], &[]);
+        assert_eq!(closed.len(), 2);
+        let a = &closed[0];
+        assert_eq!(a.timestamp, 0);
+        assert_eq!(a.count(), 2);
+        assert_eq!(a.mean_duration(), 200);
+        assert_eq!(a.extra_data, extra_data);
+        let means = a.mean_metrics();
+        assert_eq!(means.len(), 1);
+        assert_eq!(means[0].metric_name, "sm__cycles_elapsed.avg");
+        assert_eq!(means[0].value, 20.0);
+        assert_eq!(a.mean_derived(), vec![("x", 1.0)]);
+        let extra = a.extra_data();
+        let value = |name: &str| {
+            extra
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.as_str())
+                .unwrap()
+        };
+        assert_eq!(value("aggregated_count"), "2");
+        assert_eq!(value("duration_min"), "100");
+        assert_eq!(value("duration_max"), "300");
+        assert_eq!(value("sm__cycles_elapsed.avg.min"), "10");
+        assert_eq!(value("sm__cycles_elapsed.avg.max"), "30");
+        assert_eq!(closed[1].count(), 1);
+        assert!(closed[1].metrics.is_empty());
+        // The launch that closed the window starts the next one.
+        let rest = aggregator.take();
+        assert_eq!(rest.len(), 1);
+        assert_eq!(rest[0].timestamp, 1000);
+        assert!(aggregator.take().is_empty());
+    }
+}
@@ -0,0 +1,313 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Rules that flag anomalous kernels in the trace.
+//!
+//! A rule compares a field of a kernel, its `duration`, an extra data value
+//! or a metric, against a threshold, such as `achieved_occupancy<20`. Only
+//! `duration` can be compared against its spread instead, as in
+//! `duration>3sigma`, for which the durations of each kernel are recorded as
+//! activity records come in. Kernels that break a rule get the rules they
+//! break as `anomaly` extra data.
+
+use crate::prometheus::MAX_KERNEL_NAMES;
+use cupti_profiler::MetricValuePair;
+use once_cell::sync::Lazy;
+use std::{collections::HashMap, fmt, sync::Mutex};
+
+/// Rules used when `INJECTION_ANOMALY_RULES` is `1`.
+pub const DEFAULT_RULES: &str = "achieved_occupancy<20,dram_bw_pct_of_peak>90,duration>3sigma";
+
+/// Field that stands for the kernel's duration, in nanoseconds.
+pub const DURATION_FIELD: &str = "duration";
+
+/// Launches of a kernel recorded before its duration is compared against
+/// their spread.
+pub const MIN_SIGMA_SAMPLES: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Below,
+    Above,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Threshold {
+    Value(f64),
+    /// Standard deviations from the mean of the kernel.
+    Sigma(f64),
+}
+
+/// A rule that kernels whose `field` is below or above `threshold` break.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub field: String,
+    pub comparison: Comparison,
+    pub threshold: Threshold,
+}
+
+impl Rule {
+    /// Parses a rule like `achieved_occupancy<20` or `duration>3sigma`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (field, comparison, threshold) = match s.split_once('<') {
+            Some((field, threshold)) => (field, Comparison::Below, threshold),
+            None => match s.split_once('>') {
+                Some((field, threshold)) => (field, Comparison::Above, threshold),
+                None => return Err(format!("Anomaly rule {:?} has no < or >", s)),
+            },
+        };
+        let field = field.trim();
+        if field.is_empty() {
+            return Err(format!("Anomaly rule {:?} has no field", s));
+        }
+        let threshold = threshold.trim();
+        let threshold = match threshold
+            .strip_suffix("sigma")
+            .or_else(|| threshold.strip_suffix('σ'))
+        {
+            Some(_) if field != DURATION_FIELD => {
+                return Err(format!(
+                    "Anomaly rule {:?} compares against sigma, which only {} can",
+                    s, DURATION_FIELD
+                ));
+            }
+            Some(sigmas) => sigmas.trim().parse().ok().map(Threshold::Sigma),
+            None => threshold.parse().ok().map(Threshold::Value),
+        };
+        match threshold {
+            Some(threshold) => Ok(Self {
+                field: field.to_string(),
+                comparison,
+                threshold,
+            }),
+            None => Err(format!("Anomaly rule {:?} has an invalid threshold", s)),
+        }
+    }
+
+    /// Whether `value` breaks the rule, given the mean and standard deviation
+    /// of the kernel's durations for sigma thresholds.
+    fn is_broken_by(&self, value: f64, spread: Option<(f64, f64)>) -> bool {
+        let threshold = match self.threshold {
+            Threshold::Value(threshold) => threshold,
+            Threshold::Sigma(sigmas) => {
+                let Some((mean, std_dev)) = spread else {
+                    return false;
+                };
+                match self.comparison {
+                    Comparison::Below => mean - sigmas * std_dev,
+                    Comparison::Above => mean + sigmas * std_dev,
+                }
+            }
+        };
+        match self.comparison {
+            Comparison::Below => value < threshold,
+            Comparison::Above => value > threshold,
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let comparison = match self.comparison {
+            Comparison::Below => '<',
+            Comparison::Above => '>',
+        };
+        match self.threshold {
+            Threshold::Value(value) => write!(f, "{}{}{}", self.field, comparison, value),
+            Threshold::Sigma(sigmas) => write!(f, "{}{}{}sigma", self.field, comparison, sigmas),
+        }
+    }
+}
+
+/// Parses the comma or semicolon separated rules of
+/// `INJECTION_ANOMALY_RULES`, where `1` stands for `DEFAULT_RULES`. Invalid
+/// rules are skipped with a warning.
+pub fn parse_rules(s: &str) -> Vec<Rule> {
+    let s = if s.trim() == "1" { DEFAULT_RULES } else { s };
+    s.split([',', ';'])
+        .map(str::trim)
+        .filter(|rule| !rule.is_empty())
+        .filter_map(|rule| match Rule::parse(rule) {
+            Ok(rule) => Some(rule),
+            Err(e) => {
+                eprintln!("{}", e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Whether any of `rules` needs the durations of each kernel recorded.
+pub fn needs_durations(rules: &[Rule]) -> bool {
+    rules
+        .iter()
+        .any(|rule| matches!(rule.threshold, Threshold::Sigma(_)))
+}
+
+/// Running mean and variance of a kernel's durations, by Welford's method.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct Spread {
+    count: u64,
+    mean: f64,
+    m2: f64,
+}
+
+impl Spread {
+    fn add(&mut self, value: f64) {
+        self.count += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    /// Mean and standard deviation, once there are enough values.
+    fn mean_and_std_dev(&self) -> Option<(f64, f64)> {
+        (self.count >= MIN_SIGMA_SAMPLES)
+            .then(|| (self.mean, (self.m2 / (self.count - 1) as f64).sqrt()))
+    }
+}
+
+/// Spread of the durations of each kernel, `None` unless collection was
+/// started.
+static DURATIONS: Lazy<Mutex<Option<HashMap<String, Spread>>>> = Lazy::new(|| Mutex::new(None));
+
+/// Adds a completed kernel, if durations are collected.
+pub fn record_kernel(name: &str, duration: u64) {
+    if let Ok(mut durations) = DURATIONS.lock() {
+        let Some(durations) = durations.as_mut() else {
+            return;
+        };
+        // Kernels past the limit are not compared against their spread.
+        if durations.len() < MAX_KERNEL_NAMES || durations.contains_key(name) {
+            durations
+                .entry(name.to_string())
+                .or_default()
+                .add(duration as f64);
+        }
+    }
+}
+
+/// Starts collecting the durations sigma rules compare against.
+pub fn collect() {
+    if let Ok(mut durations) = DURATIONS.lock() {
+        durations.get_or_insert_with(HashMap::new);
+    }
+}
+
+fn duration_spread(name: &str) -> Option<(f64, f64)> {
+    let durations = DURATIONS.lock().ok()?;
+    durations.as_ref()?.get(name)?.mean_and_std_dev()
+}
+
+/// The rules a kernel named `kernel_name` breaks, comma separated, or `None`
+/// if it breaks none. Fields are looked up in `extra_data`, then `metrics`,
+/// and rules on fields the kernel does not have are not broken.
+pub fn check<'a>(
+    rules: &[Rule],
+    kernel_name: &str,
+    duration: u64,
+    extra_data: impl Iterator<Item = &'a (&'static str, String)> + Clone,
+    metrics: &[MetricValuePair],
+) -> Option<String> {
+    let mut spread = None;
+    let broken: Vec<String> = rules
+        .iter()
+        .filter(|rule| {
+            let value = if rule.field == DURATION_FIELD {
+                Some(duration as f64)
+            } else {
+                extra_data
+                    .clone()
+                    .find(|(name, _)| *name == rule.field)
+                    .and_then(|(_, value)| value.parse().ok())
+                    .or_else(|| {
+                        metrics
+                            .iter()
+                            .find(|metric| metric.metric_name == rule.field)
+                            .map(|metric| metric.value)
+                    })
+            };
+            let Some(value) = value.filter(|value: &f64| value.is_finite()) else {
+                return false;
+            };
+            if matches!(rule.threshold, Threshold::Sigma(_)) && spread.is_none() {
+                spread = Some(duration_spread(kernel_name));
+            }
+            rule.is_broken_by(value, spread.flatten())
+        })
+        .map(Rule::to_string)
+        .collect();
+    (!broken.is_empty()).then(|| broken.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rules() {
+        assert_eq!(
+            parse_rules("1"),
+            [
+                Rule {
+                    field: "achieved_occupancy".to_string(),
+                    comparison: Comparison::Below,
+                    threshold: Threshold::Value(20.0),
+                },
+                Rule {
+                    field: "dram_bw_pct_of_peak".to_string(),
+                    comparison: Comparison::Above,
+                    threshold: Threshold::Value(90.0),
+                },
+                Rule {
+                    field: "duration".to_string(),
+                    comparison: Comparison::Above,
+                    threshold: Threshold::Sigma(3.0),
+                },
+            ]
+        );
+        let rules =
+            parse_rules(" sm__cycles_elapsed.avg > 1e6 ; duration<2σ,x=1,y<z,ipc_active>2sigma,");
+        let rules: Vec<String> = rules.iter().map(Rule::to_string).collect();
+        assert_eq!(rules, ["sm__cycles_elapsed.avg>1000000", "duration<2sigma"]);
+    }
+
+    #[test]
+    fn test_check() {
+        let rules = parse_rules("achieved_occupancy<20,sm__cycles>100,duration>3sigma");
+        let extra_data = [("achieved_occupancy", "12.5".to_string())];
+        let metrics = [MetricValuePair {
+            metric_name: "sm__cycles".to_string(),
+            value: 50.0,
+        }];
+        assert_eq!(
+            check(&rules, "k", 1000, extra_data.iter(), &metrics),
+            Some("achieved_occupancy<20".to_string())
+        );
+        assert_eq!(check(&rules, "k", 1000, [].iter(), &[]), None);
+
+        // Durations are compared against their spread once there are enough.
+        collect();
+        for _ in 0..MIN_SIGMA_SAMPLES / 2 {
+            record_kernel("anomaly_test", 90);
+            record_kernel("anomaly_test", 110);
+        }
+        assert_eq!(check(&rules, "anomaly_test", 120, [].iter(), &[]), None);
+        assert_eq!(
+            check(&rules, "anomaly_test", 200, [].iter(), &metrics),
+            Some("duration>3sigma".to_string())
+        );
+        assert_eq!(check(&rules, "anomaly_test_2", 200, [].iter(), &[]), None);
+    }
+}
@@ -0,0 +1,553 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Per-kernel baseline that runs are compared against.
+//!
+//! A baseline holds the mean duration and metric values of each kernel, by
+//! demangled name. It is written by `trace-summarize --write-baseline` as
+//!
+//! ```json
+//! {"kernels":{
+//! "scale(float*)":{"count":2,"duration":1500,"metrics":{"sm__cycles_elapsed.avg":40}}
+//! }}
+//! ```
+//!
+//! and a JSON Lines export of an earlier run can be used as one directly.
+//! Kernels that run slower than their baseline by more than the threshold
+//! are annotated in the trace, see [`set_baseline`].
+
+use crate::json_export::append_string;
+use crate::summary::KernelSummary;
+use crate::trace_emitter::demangle;
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    fs,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+/// Default slowdown, in percent, before a kernel counts as regressed.
+pub const DEFAULT_THRESHOLD_PCT: f64 = 10.0;
+
+/// A parsed JSON value, for the little JSON the baseline is read from.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn members(&self) -> &[(String, Value)] {
+        match self {
+            Value::Object(members) => members,
+            _ => &[],
+        }
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    /// Parses `text` as a single JSON value.
+    fn parse(text: &'a str) -> Result<Value, String> {
+        let mut parser = Parser { text, pos: 0 };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.pos < text.len() {
+            return Err(parser.error("trailing characters"));
+        }
+        Ok(value)
+    }
+
+    fn error(&self, message: &str) -> String {
+        format!("{} at offset {}", message, self.pos)
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.as_bytes().get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(|b| b.is_ascii_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.peek() != Some(byte) {
+            return Err(self.error(&format!("expected `{}`", byte as char)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn literal(&mut self, word: &str, value: Value) -> Result<Value, String> {
+        if !self.text[self.pos..].starts_with(word) {
+            return Err(self.error("invalid literal"));
+        }
+        self.pos += word.len();
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'{') => {
+                self.pos += 1;
+                let mut members = Vec::new();
+                self.skip_whitespace();
+                if self.peek() == Some(b'}') {
+                    self.pos += 1;
+                    return Ok(Value::Object(members));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let key = self.string()?;
+                    self.expect(b':')?;
+                    members.push((key, self.value()?));
+                    self.skip_whitespace();
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        Some(b'}') => {
+                            self.pos += 1;
+                            return Ok(Value::Object(members));
+                        }
+                        _ => return Err(self.error("expected `,` or `}`")),
+                    }
+                }
+            }
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                self.skip_whitespace();
+                if self.peek() == Some(b']') {
+                    self.pos += 1;
+                    return Ok(Value::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    self.skip_whitespace();
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        Some(b']') => {
+                            self.pos += 1;
+                            return Ok(Value::Array(items));
+                        }
+                        _ => return Err(self.error("expected `,` or `]`")),
+                    }
+                }
+            }
+            Some(b'"') => self.string().map(Value::String),
+            Some(b't') => self.literal("true", Value::Bool(true)),
+            Some(b'f') => self.literal("false", Value::Bool(false)),
+            Some(b'n') => self.literal("null", Value::Null),
+            Some(_) => {
+                let start = self.pos;
+                while self
+                    .peek()
+                    .is_some_and(|b| b.is_ascii_digit() || b"+-.eE".contains(&b))
+                {
+                    self.pos += 1;
+                }
+                self.text[start..self.pos]
+                    .parse()
+                    .map(Value::Number)
+                    .map_err(|_| self.error("invalid number"))
+            }
+            None => Err(self.error("unexpected end")),
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = self
+            .text
+            .get(self.pos..self.pos + 4)
+            .ok_or_else(|| self.error("truncated escape"))?;
+        let code = u32::from_str_radix(digits, 16).map_err(|_| self.error("invalid escape"))?;
+        self.pos += 4;
+        Ok(code)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        if self.peek() != Some(b'"') {
+            return Err(self.error("expected string"));
+        }
+        self.pos += 1;
+        let mut out = String::new();
+        loop {
+            // Multi-byte characters never contain these bytes, so the
+            // slices below fall on character boundaries.
+            let start = self.pos;
+            while self.peek().is_some_and(|b| b != b'"' && b != b'\\') {
+                self.pos += 1;
+            }
+            out.push_str(&self.text[start..self.pos]);
+            match self.peek() {
+                Some(b'"') => {
+                    self.pos += 1;
+                    return Ok(out);
+                }
+                Some(b'\\') => {
+                    self.pos += 1;
+                    let escape = self.peek().ok_or_else(|| self.error("truncated escape"))?;
+                    self.pos += 1;
+                    match escape {
+                        b'"' => out.push('"'),
+                        b'\\' => out.push('\\'),
+                        b'/' => out.push('/'),
+                        b'b' => out.push('\u{8}'),
+                        b'f' => out.push('\u{c}'),
+                        b'n' => out.push('\n'),
+                        b'r' => out.push('\r'),
+                        b't' => out.push('\t'),
+                        b'u' => {
+                            let mut code = self.hex4()?;
+                            if (0xd800..0xdc00).contains(&code)
+                                && self.text[self.pos..].starts_with("\\u")
+                            {
+                                self.pos += 2;
+                                let low = self.hex4()?;
+                                code = match low {
+                                    0xdc00..=0xdfff => {
+                                        0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00)
+                                    }
+                                    _ => 0xfffd,
+                                };
+                            }
+                            out.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+                        }
+                        _ => return Err(self.error("invalid escape")),
+                    }
+                }
+                _ => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+}
+
+/// Baseline of one kernel.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KernelBaseline {
+    /// Launches the means were taken over.
+    pub count: u64,
+    /// Mean duration, in nanoseconds.
+    pub duration: f64,
+    /// Mean of each metric, in the order first seen.
+    pub metrics: Vec<(String, f64)>,
+}
+
+/// Sums that a kernel's baseline is averaged from.
+#[derive(Default)]
+struct Sums {
+    count: u64,
+    duration: f64,
+    metrics: Vec<(String, f64, u64)>,
+}
+
+impl Sums {
+    fn means(self) -> KernelBaseline {
+        KernelBaseline {
+            count: self.count,
+            duration: self.duration / self.count.max(1) as f64,
+            metrics: self
+                .metrics
+                .into_iter()
+                .map(|(name, sum, n)| (name, sum / n as f64))
+                .collect(),
+        }
+    }
+}
+
+/// Mean duration and metrics of each kernel, by demangled name.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Baseline {
+    pub kernels: HashMap<String, KernelBaseline>,
+    /// Slowdown, in percent, before a kernel counts as regressed.
+    pub threshold_pct: f64,
+}
+
+impl Baseline {
+    /// Parses a baseline, or a JSON Lines export whose kernels are averaged
+    /// by name.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut kernels = HashMap::new();
+        match Parser::parse(text) {
+            Ok(value) if value.get("kernels").is_some() => {
+                for (name, kernel) in value.get("kernels").map_or(&[][..], Value::members) {
+                    let duration = kernel
+                        .get("duration")
+                        .and_then(Value::as_f64)
+                        .ok_or_else(|| format!("Kernel {} has no duration", name))?;
+                    let metrics = kernel
+                        .get("metrics")
+                        .map_or(&[][..], Value::members)
+                        .iter()
+                        .filter_map(|(metric, v)| Some((metric.clone(), v.as_f64()?)))
+                        .collect();
+                    let count = kernel.get("count").and_then(Value::as_f64).unwrap_or(1.0);
+                    kernels.insert(
+                        name.clone(),
+                        KernelBaseline {
+                            count: count as u64,
+                            duration,
+                            metrics,
+                        },
+                    );
+                }
+            }
+            _ => {
+                let mut sums: HashMap<String, Sums> = HashMap::new();
+                for (i, line) in text.lines().enumerate() {
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let kernel =
+                        Parser::parse(line).map_err(|e| format!("Line {}: {}", i + 1, e))?;
+                    let extra_data = kernel.get("extra_data");
+                    let value = |key| extra_data.and_then(|e| e.get(key)).and_then(Value::as_str);
+                    let name = match value("kernel_demangled_name") {
+                        Some(name) => name.to_string(),
+                        None => demangle(value("kernel_name").unwrap_or_default()),
+                    };
+                    let duration = kernel
+                        .get("duration")
+                        .and_then(Value::as_f64)
+                        .ok_or_else(|| format!("Line {}: no duration", i + 1))?;
+                    let sums = sums.entry(name).or_default();
+                    sums.count += 1;
+                    sums.duration += duration;
+                    let metrics = kernel.get("metrics").map_or(&[][..], Value::members);
+                    for (metric, value) in metrics {
+                        let Some(value) = value.as_f64() else {
+                            continue;
+                        };
+                        match sums.metrics.iter_mut().find(|(m, _, _)| m == metric) {
+                            Some((_, sum, n)) => {
+                                *sum += value;
+                                *n += 1;
+                            }
+                            None => sums.metrics.push((metric.clone(), value, 1)),
+                        }
+                    }
+                }
+                kernels = sums
+                    .into_iter()
+                    .map(|(name, sums)| (name, sums.means()))
+                    .collect();
+            }
+        }
+        Ok(Self {
+            kernels,
+            threshold_pct: DEFAULT_THRESHOLD_PCT,
+        })
+    }
+
+    /// Reads a baseline file, see `parse`.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        Self::parse(&text).map_err(|e| format!("Failed to parse {}: {}", path.display(), e))
+    }
+
+    /// Takes the baseline from the kernels of a trace.
+    pub fn from_summaries(summaries: &[KernelSummary]) -> Self {
+        let kernels = summaries
+            .iter()
+            .map(|s| {
+                let baseline = KernelBaseline {
+                    count: s.count,
+                    duration: s.mean_duration(),
+                    metrics: s
+                        .counter_means()
+                        .map(|(name, mean)| (name.to_string(), mean))
+                        .collect(),
+                };
+                (s.name.clone(), baseline)
+            })
+            .collect();
+        Self {
+            kernels,
+            threshold_pct: DEFAULT_THRESHOLD_PCT,
+        }
+    }
+
+    /// Formats the baseline with one kernel per line, sorted by name so that
+    /// baselines diff well.
+    pub fn to_json(&self) -> String {
+        let mut names: Vec<&String> = self.kernels.keys().collect();
+        names.sort();
+        let mut out = String::from("{\"kernels\":{\n");
+        for (i, name) in names.into_iter().enumerate() {
+            let kernel = &self.kernels[name];
+            if i > 0 {
+                out.push_str(",\n");
+            }
+            append_string(&mut out, name);
+            let _ = write!(
+                out,
+                ":{{\"count\":{},\"duration\":{},\"metrics\":{{",
+                kernel.count, kernel.duration
+            );
+            let metrics = kernel.metrics.iter().filter(|(_, v)| v.is_finite());
+            for (j, (metric, value)) in metrics.enumerate() {
+                if j > 0 {
+                    out.push(',');
+                }
+                append_string(&mut out, metric);
+                let _ = write!(out, ":{}", value);
+            }
+            out.push_str("}}");
+        }
+        out.push_str("\n}}\n");
+        out
+    }
+
+    /// Change of `duration` from the kernel's baseline, in percent, if it
+    /// has one.
+    pub fn duration_change_pct(&self, name: &str, duration: f64) -> Option<f64> {
+        let baseline = self.kernels.get(name)?;
+        change_pct(baseline.duration, duration)
+    }
+
+    /// Slowdown of the kernel, in percent, if it exceeds the threshold.
+    pub fn regression_pct(&self, name: &str, duration: f64) -> Option<f64> {
+        self.duration_change_pct(name, duration)
+            .filter(|&pct| pct > self.threshold_pct)
+    }
+}
+
+/// Change from `baseline` to `value`, in percent. There is none from 0.
+pub fn change_pct(baseline: f64, value: f64) -> Option<f64> {
+    (baseline > 0.0).then(|| 100.0 * (value - baseline) / baseline)
+}
+
+/// Baseline the emitted kernels are compared against, if any.
+static BASELINE: Mutex<Option<Arc<Baseline>>> = Mutex::new(None);
+
+/// Sets the baseline that emitted kernels are compared against. Kernels
+/// that regressed beyond its threshold get `duration_vs_baseline_pct` and
+/// `baseline_duration` extra data.
+pub fn set_baseline(baseline: Option<Baseline>) {
+    if let Ok(mut current) = BASELINE.lock() {
+        *current = baseline.map(Arc::new);
+    }
+}
+
+/// Returns the baseline set with `set_baseline`.
+pub fn baseline() -> Option<Arc<Baseline>> {
+    BASELINE.lock().ok().and_then(|baseline| baseline.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_json() {
+        let value =
+            Parser::parse(r#" {"a": [1, -2.5e1, true, null], "b\u00e9\n": "x\"\\y\ud83d\ude00"} "#)
+                .unwrap();
+        assert_eq!(
+            value,
+            Value::Object(vec![
+                (
+                    "a".to_string(),
+                    Value::Array(vec![
+                        Value::Number(1.0),
+                        Value::Number(-25.0),
+                        Value::Bool(true),
+                        Value::Null
+                    ])
+                ),
+                (
+                    "b\u{e9}\n".to_string(),
+                    Value::String("x\"\\y\u{1f600}".to_string())
+                ),
+            ])
+        );
+        assert!(Parser::parse("{\"a\":1,}").is_err());
+        assert!(Parser::parse("[1] 2").is_err());
+        assert!(Parser::parse("\"open").is_err());
+    }
+
+    #[test]
+    fn test_baseline() {
+        let mut baseline = Baseline::parse(concat!(
+            r#"{"ctx_id":1,"timestamp":0,"duration":1000,"extra_data":{"kernel_name":"_Z5scalePf","kernel_demangled_name":"scale(float*)"},"metrics":{"sm__cycles":10,"x":null}}"#,
+            "\n",
+            r#"{"ctx_id":1,"timestamp":5,"duration":3000,"extra_data":{"kernel_name":"_Z5scalePf"},"metrics":{"sm__cycles":30}}"#,
+            "\n\n",
+        ))
+        .unwrap();
+        assert_eq!(
+            baseline.kernels["scale(float*)"],
+            KernelBaseline {
+                count: 2,
+                duration: 2000.0,
+                metrics: vec![("sm__cycles".to_string(), 20.0)],
+            }
+        );
+        assert_eq!(
+            baseline.duration_change_pct("scale(float*)", 2500.0),
+            Some(25.0)
+        );
+        assert_eq!(baseline.regression_pct("scale(float*)", 2100.0), None);
+        assert_eq!(baseline.regression_pct("scale(float*)", 2500.0), Some(25.0));
+        assert_eq!(baseline.regression_pct("other", 2500.0), None);
+        baseline.threshold_pct = 30.0;
+        assert_eq!(baseline.regression_pct("scale(float*)", 2500.0), None);
+
+        // The baseline format reads back what it wrote.
+        let json = baseline.to_json();
+        assert_eq!(
+            json,
+            "{\"kernels\":{\n\"scale(float*)\":{\"count\":2,\"duration\":2000,\"metrics\":{\"sm__cycles\":20}}\n}}\n"
+        );
+        let parsed = Baseline::parse(&json).unwrap();
+        assert_eq!(parsed.kernels, baseline.kernels);
+        assert!(Baseline::parse("{\"kernels\":{\"k\":{}}}").is_err());
+        assert!(Baseline::parse("not json").is_err());
+        assert_eq!(change_pct(0.0, 1.0), None);
+    }
+}
@@ -0,0 +1,253 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Evaluates saved counter data images.
+//!
+//! Images saved with `INJECTION_DUMP_DIR` hold the raw counters of each
+//! range, so metrics can be evaluated from them later, on a machine without
+//! the GPU they were collected on.
+
+use cupti_profiler::{get_result_string, MetricEvaluator, RangeInfo};
+use perfetto_cupti_gpu_compute::metrics::parse_metrics;
+use std::{env, fs, path::Path, path::PathBuf, process};
+
+const USAGE: &str = "\
+Usage: counter-data-eval [OPTIONS] FILE...
+
+Evaluates counter data images saved with INJECTION_DUMP_DIR and prints the
+metrics of every range.
+
+Options:
+  -c, --chip NAME     Chip the images were collected on (default: the start of
+                      each file name, as INJECTION_DUMP_DIR names them)
+  -m, --metrics LIST  Comma separated metrics to evaluate (default: the
+                      metrics the library collects by default)
+      --csv           Print `file,range,metric,value` rows instead
+  -h, --help          Print this help
+";
+
+#[derive(Debug, Default, PartialEq)]
+struct Options {
+    chip: Option<String>,
+    metrics: Vec<String>,
+    csv: bool,
+    files: Vec<PathBuf>,
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Option<Options>, String> {
+    let mut options = Options::default();
+    let mut metrics = String::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-c" | "--chip" => options.chip = Some(value()?),
+            "-m" | "--metrics" => metrics = value()?,
+            "--csv" => options.csv = true,
+            _ if arg.starts_with('-') => return Err(format!("Unknown option {}", arg)),
+            _ => options.files.push(PathBuf::from(arg)),
+        }
+    }
+    if options.files.is_empty() {
+        return Err("No counter data files given".to_string());
+    }
+    options.metrics = parse_metrics(&metrics);
+    Ok(Some(options))
+}
+
+/// Chip name of a file named by `INJECTION_DUMP_DIR`, e.g. `GA100` for
+/// `GA100-ctx1-000003.counterdata`.
+fn chip_from_file_name(path: &Path) -> Option<&str> {
+    let name = path.file_name()?.to_str()?.strip_suffix(".counterdata")?;
+    name.split_once("-ctx")
+        .map(|(chip, _)| chip)
+        .filter(|chip| !chip.is_empty())
+}
+
+/// Quotes a CSV field if it needs to be.
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// Formats the ranges of an image as aligned rows, one per metric.
+fn format_ranges(ranges: &[RangeInfo]) -> Vec<String> {
+    let range_width = ranges.iter().map(|r| r.range_name.len()).max().unwrap_or(0);
+    let metric_width = ranges
+        .iter()
+        .flat_map(|r| &r.metric_and_values)
+        .map(|m| m.metric_name.len())
+        .max()
+        .unwrap_or(0);
+    ranges
+        .iter()
+        .flat_map(|r| {
+            r.metric_and_values.iter().map(move |m| {
+                format!(
+                    "  {:range_width$}  {:metric_width$}  {}",
+                    r.range_name, m.metric_name, m.value
+                )
+            })
+        })
+        .collect()
+}
+
+fn format_csv(file: &Path, ranges: &[RangeInfo]) -> Vec<String> {
+    let file = csv_field(&file.to_string_lossy());
+    ranges
+        .iter()
+        .flat_map(|r| {
+            let file = &file;
+            r.metric_and_values.iter().map(move |m| {
+                format!(
+                    "{},{},{},{}",
+                    file,
+                    csv_field(&r.range_name),
+                    csv_field(&m.metric_name),
+                    m.value
+                )
+            })
+        })
+        .collect()
+}
+
+fn run(options: &Options) -> Result<(), String> {
+    // Evaluators are created once per chip.
+    let mut evaluators: Vec<MetricEvaluator> = Vec::new();
+    if options.csv {
+        println!("file,range,metric,value");
+    }
+    for (i, file) in options.files.iter().enumerate() {
+        let chip = options
+            .chip
+            .as_deref()
+            .or_else(|| chip_from_file_name(file))
+            .ok_or_else(|| format!("No chip name for {}, use --chip", file.display()))?;
+        let image =
+            fs::read(file).map_err(|e| format!("Failed to read {}: {}", file.display(), e))?;
+        let index = match evaluators.iter().position(|me| me.host.chip_name() == chip) {
+            Some(index) => index,
+            None => {
+                let me = MetricEvaluator::for_chip(chip, Vec::new())
+                    .map_err(|e| format!("Failed to set up {}: {}", chip, get_result_string(e)))?;
+                evaluators.push(me);
+                evaluators.len() - 1
+            }
+        };
+        let ranges = evaluators[index]
+            .evaluate_all_ranges(&image, &options.metrics)
+            .map_err(|e| {
+                format!(
+                    "Failed to evaluate {}: {}",
+                    file.display(),
+                    get_result_string(e)
+                )
+            })?;
+        let rows = if options.csv {
+            format_csv(file, &ranges)
+        } else {
+            if i > 0 {
+                println!();
+            }
+            println!("{} ({}, {} ranges)", file.display(), chip, ranges.len());
+            format_ranges(&ranges)
+        };
+        for row in rows {
+            println!("{}", row);
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            print!("{}", USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(e) = run(&options) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cupti_profiler::MetricValuePair;
+
+    fn parse(args: &[&str]) -> Result<Option<Options>, String> {
+        parse_args(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let options = parse(&["-c", "GA100", "-m", "a.sum,b.max", "--csv", "x.counterdata"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(options.chip.as_deref(), Some("GA100"));
+        assert_eq!(options.metrics, vec!["a.sum", "b.max"]);
+        assert!(options.csv);
+        assert_eq!(options.files, vec![PathBuf::from("x.counterdata")]);
+        assert!(!parse(&["x.counterdata"])
+            .unwrap()
+            .unwrap()
+            .metrics
+            .is_empty());
+        assert_eq!(parse(&["-h"]), Ok(None));
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["-c"]).is_err());
+        assert!(parse(&["--bogus", "x.counterdata"]).is_err());
+
+        let chip = |name: &str| chip_from_file_name(Path::new(name)).map(str::to_string);
+        assert_eq!(
+            chip("/tmp/dump/GA100-ctx1-000003.counterdata"),
+            Some("GA100".to_string())
+        );
+        assert_eq!(chip("image.bin"), None);
+        assert_eq!(chip("-ctx1-000003.counterdata"), None);
+    }
+
+    #[test]
+    fn test_format_ranges() {
+        let range = |name: &str, value| RangeInfo {
+            range_name: name.to_string(),
+            parent_ranges: Vec::new(),
+            depth: 0,
+            metric_and_values: vec![MetricValuePair {
+                metric_name: "gpu__time_duration.sum".to_string(),
+                value,
+            }],
+        };
+        let ranges = [range("scale", 1.5), range("add<float, 2>", 20.0)];
+        let rows = format_ranges(&ranges);
+        assert_eq!(rows[0], "  scale          gpu__time_duration.sum  1.5");
+        assert_eq!(rows[1], "  add<float, 2>  gpu__time_duration.sum  20");
+        let rows = format_csv(Path::new("a.counterdata"), &ranges);
+        assert_eq!(
+            rows[1],
+            "a.counterdata,\"add<float, 2>\",gpu__time_duration.sum,20"
+        );
+    }
+}
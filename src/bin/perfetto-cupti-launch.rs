@@ -0,0 +1,295 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Runs a command with the injection library loaded.
+//!
+//! The options are turned into the `INJECTION_*` environment variables the
+//! library reads, and the process is replaced by the command, so its exit
+//! status and signals are the command's own.
+
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::{self, Command},
+};
+
+/// File name of the injection library, looked up next to the launcher.
+const LIBRARY_NAME: &str = "libperfetto_cupti_gpu_compute.so";
+
+const USAGE: &str = "\
+Usage: perfetto-cupti-launch [OPTIONS] [--] COMMAND [ARGS...]
+
+Runs COMMAND with the Perfetto CUPTI injection library loaded.
+
+Options:
+  -o, --output FILE     Record a trace in the process and write it to FILE,
+                        also when a system session records it too
+  -j, --json FILE       Also write every kernel to FILE as a line of JSON
+      --json-format FMT `lines` (default), `chrome` for chrome://tracing, or
+                        `ncu-csv` for `ncu --csv --page raw` columns
+  -m, --metrics LIST    Comma separated metrics to collect
+  -v, --verbose         Log profiling events to stdout
+      --single-pass     Only collect the metrics that fit in a single pass
+      --replay MODE     Kernel replay, `kernel` or `off`
+  -e, --env NAME=VALUE  Set INJECTION_NAME, e.g. `-e max_ranges=64`
+  -l, --library PATH    Injection library to load (default: next to this
+                        executable)
+      --preload         Also add the library to LD_PRELOAD
+  -h, --help            Print this help
+";
+
+/// What to run and how.
+#[derive(Debug, Default, PartialEq)]
+struct Launch {
+    library: Option<PathBuf>,
+    preload: bool,
+    /// Environment variables for the library, in the order given.
+    env: Vec<(String, OsString)>,
+    command: Vec<OsString>,
+}
+
+/// Turns `max-ranges` or `MAX_RANGES` into `INJECTION_MAX_RANGES`.
+fn injection_var(name: &str) -> String {
+    let name = name.to_ascii_uppercase().replace('-', "_");
+    if name.starts_with("INJECTION_") {
+        name
+    } else {
+        format!("INJECTION_{}", name)
+    }
+}
+
+/// Parses the launcher options, which end at the first argument that is not
+/// one, or after `--`.
+fn parse_args(args: impl IntoIterator<Item = OsString>) -> Result<Option<Launch>, String> {
+    let mut launch = Launch::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let Some(option) = arg.to_str().filter(|s| s.starts_with('-')) else {
+            launch.command.push(arg);
+            break;
+        };
+        // Accept `--option=value` as well as `--option value`.
+        let (option, inline_value) = match option.split_once('=') {
+            Some((option, value)) if option.starts_with("--") => {
+                (option, Some(OsString::from(value)))
+            }
+            _ => (option, None),
+        };
+        let mut value = || {
+            inline_value
+                .clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("{} needs a value", option))
+        };
+        match option {
+            "--" => break,
+            "-h" | "--help" => return Ok(None),
+            "-o" | "--output" => {
+                // The command may change directories before the trace is
+                // written.
+                let path = std::path::absolute(value()?)
+                    .map_err(|e| format!("Invalid output path: {}", e))?;
+                launch
+                    .env
+                    .push(("INJECTION_TRACE_FILE".to_string(), path.into()));
+            }
+            "-j" | "--json" => {
+                let path = std::path::absolute(value()?)
+                    .map_err(|e| format!("Invalid JSON path: {}", e))?;
+                launch
+                    .env
+                    .push(("INJECTION_JSON_FILE".to_string(), path.into()));
+            }
+            "--json-format" => launch
+                .env
+                .push(("INJECTION_JSON_FORMAT".to_string(), value()?)),
+            "-m" | "--metrics" => launch.env.push(("INJECTION_METRICS".to_string(), value()?)),
+            "-v" | "--verbose" => launch
+                .env
+                .push(("INJECTION_VERBOSE".to_string(), "1".into())),
+            "--single-pass" => launch
+                .env
+                .push(("INJECTION_SINGLE_PASS".to_string(), "1".into())),
+            "--replay" => launch.env.push(("INJECTION_REPLAY".to_string(), value()?)),
+            "-e" | "--env" => {
+                let assignment = value()?;
+                let (name, value) = assignment
+                    .to_str()
+                    .and_then(|s| s.split_once('='))
+                    .ok_or_else(|| format!("Expected NAME=VALUE, got {:?}", assignment))?;
+                launch.env.push((injection_var(name), value.into()));
+            }
+            "-l" | "--library" => launch.library = Some(value()?.into()),
+            "--preload" => launch.preload = true,
+            _ => return Err(format!("Unknown option {}", option)),
+        }
+    }
+    launch.command.extend(args);
+    if launch.command.is_empty() {
+        return Err("No command given".to_string());
+    }
+    Ok(Some(launch))
+}
+
+/// Finds the injection library next to the launcher, which is where cargo
+/// puts both.
+fn default_library() -> Result<PathBuf, String> {
+    let exe = env::current_exe().map_err(|e| format!("Cannot locate launcher: {}", e))?;
+    let library = exe.with_file_name(LIBRARY_NAME);
+    if !library.exists() {
+        return Err(format!(
+            "{} not found next to the launcher, use --library",
+            LIBRARY_NAME
+        ));
+    }
+    Ok(library)
+}
+
+/// Prepends `library` to an `LD_PRELOAD` value.
+fn preload_value(library: &Path, existing: Option<&OsStr>) -> OsString {
+    let mut value = OsString::from(library);
+    if let Some(existing) = existing.filter(|s| !s.is_empty()) {
+        value.push(":");
+        value.push(existing);
+    }
+    value
+}
+
+fn run(launch: Launch) -> Result<(), String> {
+    let library = match launch.library {
+        Some(library) => library,
+        None => default_library()?,
+    };
+    // The driver dlopens the path, which only looks in the library search
+    // path unless it has a slash.
+    let library = std::path::absolute(&library)
+        .map_err(|e| format!("Invalid library path {}: {}", library.display(), e))?;
+    let mut command = Command::new(&launch.command[0]);
+    command
+        .args(&launch.command[1..])
+        .env("CUDA_INJECTION64_PATH", &library)
+        .envs(launch.env);
+    if launch.preload {
+        let existing = env::var_os("LD_PRELOAD");
+        command.env("LD_PRELOAD", preload_value(&library, existing.as_deref()));
+    }
+    // Only returns on failure.
+    let e = command.exec();
+    Err(format!(
+        "Failed to run {}: {}",
+        launch.command[0].to_string_lossy(),
+        e
+    ))
+}
+
+fn main() {
+    let launch = match parse_args(env::args_os().skip(1)) {
+        Ok(Some(launch)) => launch,
+        Ok(None) => {
+            print!("{}", USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(e) = run(launch) {
+        eprintln!("{}", e);
+        process::exit(127);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Option<Launch>, String> {
+        parse_args(args.iter().map(OsString::from))
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let launch = parse(&[
+            "-m",
+            "sm__cycles_elapsed.avg",
+            "--replay=off",
+            "-e",
+            "max-ranges=64",
+            "--preload",
+            "./app",
+            "-v",
+            "--",
+        ])
+        .unwrap()
+        .unwrap();
+        assert!(launch.preload);
+        assert_eq!(
+            launch.env,
+            vec![
+                (
+                    "INJECTION_METRICS".to_string(),
+                    "sm__cycles_elapsed.avg".into()
+                ),
+                ("INJECTION_REPLAY".to_string(), "off".into()),
+                ("INJECTION_MAX_RANGES".to_string(), "64".into()),
+            ]
+        );
+        // Options after the command are the command's.
+        assert_eq!(launch.command, vec!["./app", "-v", "--"]);
+
+        let launch = parse(&[
+            "-o",
+            "trace.pftrace",
+            "--json=k.json",
+            "--json-format",
+            "chrome",
+            "--",
+            "-app",
+        ])
+        .unwrap()
+        .unwrap();
+        let (name, path) = &launch.env[0];
+        assert_eq!(name, "INJECTION_TRACE_FILE");
+        assert!(Path::new(path).is_absolute());
+        let (name, path) = &launch.env[1];
+        assert_eq!(name, "INJECTION_JSON_FILE");
+        assert!(Path::new(path).is_absolute());
+        assert_eq!(
+            launch.env[2],
+            ("INJECTION_JSON_FORMAT".to_string(), "chrome".into())
+        );
+        assert_eq!(launch.command, vec!["-app"]);
+
+        assert_eq!(parse(&["--help", "./app"]), Ok(None));
+        assert!(parse(&["-v"]).is_err());
+        assert!(parse(&["--metrics"]).is_err());
+        assert!(parse(&["-e", "verbose", "./app"]).is_err());
+        assert!(parse(&["--bogus", "./app"]).is_err());
+    }
+
+    #[test]
+    fn test_preload_value() {
+        let library = Path::new("/opt/lib.so");
+        assert_eq!(preload_value(library, None), "/opt/lib.so");
+        assert_eq!(preload_value(library, Some(OsStr::new(""))), "/opt/lib.so");
+        assert_eq!(
+            preload_value(library, Some(OsStr::new("/a.so"))),
+            "/opt/lib.so:/a.so"
+        );
+        assert_eq!(injection_var("INJECTION_VERBOSE"), "INJECTION_VERBOSE");
+    }
+}
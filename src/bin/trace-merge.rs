@@ -0,0 +1,138 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Merges the traces that the processes of a job wrote on their own.
+//!
+//! MPI jobs often run without a central tracing service, so every rank
+//! records itself with `INJECTION_TRACE_FILE`. This puts those files into
+//! one trace, with the kernels of every rank on tracks of their own.
+
+use perfetto_cupti_gpu_compute::merge::merge;
+use std::{env, fs, path::PathBuf, process};
+
+const USAGE: &str = "\
+Usage: trace-merge -o OUTPUT [LABEL=]FILE...
+
+Merges traces written by the injection library into one, with the kernels of
+each trace on GPU tracks of their own. The queues of a trace are named after
+its LABEL, which defaults to the file name without its extension.
+
+Options:
+  -o, --output FILE  Write the merged trace to FILE
+  -h, --help         Print this help
+";
+
+#[derive(Debug, Default, PartialEq)]
+struct Options {
+    output: PathBuf,
+    /// Label and path of each trace, in the order given.
+    inputs: Vec<(String, PathBuf)>,
+}
+
+/// Splits `LABEL=FILE`, or labels `FILE` with its name without extension.
+fn parse_input(arg: &str) -> (String, PathBuf) {
+    match arg.split_once('=') {
+        Some((label, path)) if !label.is_empty() => (label.to_string(), PathBuf::from(path)),
+        _ => {
+            let path = PathBuf::from(arg);
+            let label = path
+                .file_stem()
+                .map_or_else(|| arg.to_string(), |s| s.to_string_lossy().into_owned());
+            (label, path)
+        }
+    }
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Option<Options>, String> {
+    let mut options = Options::default();
+    let mut output = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-o" | "--output" => output = Some(PathBuf::from(value()?)),
+            _ if arg.starts_with('-') => return Err(format!("Unknown option {}", arg)),
+            _ => options.inputs.push(parse_input(&arg)),
+        }
+    }
+    options.output = output.ok_or_else(|| "No output file given".to_string())?;
+    if options.inputs.is_empty() {
+        return Err("No trace files given".to_string());
+    }
+    Ok(Some(options))
+}
+
+fn run(options: &Options) -> Result<(), String> {
+    let mut traces = Vec::with_capacity(options.inputs.len());
+    for (label, path) in &options.inputs {
+        let trace =
+            fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        traces.push((label.as_str(), trace));
+    }
+    let traces: Vec<(&str, &[u8])> = traces
+        .iter()
+        .map(|(label, trace)| (*label, trace.as_slice()))
+        .collect();
+    let merged = merge(&traces).map_err(|e| format!("Failed to parse traces: {:?}", e))?;
+    fs::write(&options.output, merged)
+        .map_err(|e| format!("Failed to write {}: {}", options.output.display(), e))
+}
+
+fn main() {
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            print!("{}", USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(e) = run(&options) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Option<Options>, String> {
+        parse_args(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let options = parse(&["-o", "job.pftrace", "out/rank0.pftrace", "r1=rank1.pftrace"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(options.output, PathBuf::from("job.pftrace"));
+        assert_eq!(
+            options.inputs,
+            [
+                ("rank0".to_string(), PathBuf::from("out/rank0.pftrace")),
+                ("r1".to_string(), PathBuf::from("rank1.pftrace")),
+            ]
+        );
+        assert_eq!(parse(&["--help"]), Ok(None));
+        assert!(parse(&["rank0.pftrace"]).is_err());
+        assert!(parse(&["-o", "job.pftrace"]).is_err());
+        assert!(parse(&["-o"]).is_err());
+        assert!(parse(&["--bogus", "-o", "job.pftrace", "rank0.pftrace"]).is_err());
+    }
+}
@@ -0,0 +1,225 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Prints per-kernel aggregates of a trace.
+//!
+//! Kernels are grouped by name with their launch count, total and mean
+//! duration and the mean of each metric, for a quick look at a trace without
+//! opening the UI.
+//!
+//! Compared against a baseline, the mean durations are shown as changes and
+//! kernels that slowed down beyond the threshold fail the run, which makes a
+//! CI performance gate.
+
+use perfetto_cupti_gpu_compute::baseline::{change_pct, Baseline, DEFAULT_THRESHOLD_PCT};
+use perfetto_cupti_gpu_compute::summary::{summarize, KernelSummary};
+use std::{env, fs, path::PathBuf, process};
+
+const USAGE: &str = "\
+Usage: trace-summarize [OPTIONS] FILE
+
+Prints the kernels of a trace written by the injection library, longest total
+duration first.
+
+Options:
+  -n, --top N                Only print the N kernels with the longest total
+                             duration
+  -b, --baseline FILE        Compare mean durations with a baseline, or with
+                             a JSON Lines export, and fail if a kernel
+                             regressed
+  -t, --threshold PCT        Slowdown that counts as a regression (default 10)
+  -w, --write-baseline FILE  Write the kernels of the trace as a baseline
+  -h, --help                 Print this help
+";
+
+#[derive(Debug, Default, PartialEq)]
+struct Options {
+    top: Option<usize>,
+    baseline: Option<PathBuf>,
+    threshold: Option<f64>,
+    write_baseline: Option<PathBuf>,
+    path: PathBuf,
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Option<Options>, String> {
+    let mut options = Options::default();
+    let mut path = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-n" | "--top" => {
+                let n = value()?;
+                options.top = Some(n.parse().map_err(|_| format!("Invalid count {}", n))?);
+            }
+            "-b" | "--baseline" => options.baseline = Some(PathBuf::from(value()?)),
+            "-t" | "--threshold" => {
+                let pct = value()?;
+                options.threshold = Some(
+                    pct.trim_end_matches('%')
+                        .parse()
+                        .ok()
+                        .filter(|pct: &f64| pct.is_finite() && *pct >= 0.0)
+                        .ok_or_else(|| format!("Invalid threshold {}", pct))?,
+                );
+            }
+            "-w" | "--write-baseline" => options.write_baseline = Some(PathBuf::from(value()?)),
+            _ if arg.starts_with('-') => return Err(format!("Unknown option {}", arg)),
+            _ if path.is_some() => return Err(format!("Unexpected argument {}", arg)),
+            _ => path = Some(PathBuf::from(arg)),
+        }
+    }
+    options.path = path.ok_or_else(|| "No trace file given".to_string())?;
+    Ok(Some(options))
+}
+
+/// Formats the kernels as aligned rows under a header, each followed by its
+/// indented counter means. With a baseline, the change of each mean from it
+/// is shown too, and kernels slower than its threshold are marked.
+fn format_summaries(summaries: &[KernelSummary], baseline: Option<&Baseline>) -> Vec<String> {
+    let name_width = summaries
+        .iter()
+        .map(|s| s.name.len())
+        .chain(["kernel".len()])
+        .max()
+        .unwrap_or(0);
+    let mut header = format!(
+        "{:name_width$}  {:>8}  {:>14}  {:>12}",
+        "kernel", "count", "total (ns)", "mean (ns)"
+    );
+    if baseline.is_some() {
+        header.push_str("  vs baseline");
+    }
+    let mut rows = vec![header];
+    for summary in summaries {
+        let mean = summary.mean_duration();
+        let mut row = format!(
+            "{:name_width$}  {:>8}  {:>14}  {:>12.1}",
+            summary.name, summary.count, summary.total_duration, mean
+        );
+        let kernel = baseline.and_then(|b| b.kernels.get(&summary.name));
+        if let Some(baseline) = baseline {
+            match kernel.and_then(|k| change_pct(k.duration, mean)) {
+                Some(pct) if pct > baseline.threshold_pct => {
+                    row.push_str(&format!("  {:+.1}% REGRESSED", pct))
+                }
+                Some(pct) => row.push_str(&format!("  {:+.1}%", pct)),
+                None => row.push_str("  new"),
+            }
+        }
+        rows.push(row);
+        for (name, mean) in summary.counter_means() {
+            let value = kernel.and_then(|k| k.metrics.iter().find(|(m, _)| m == name));
+            match value.and_then(|(_, value)| change_pct(*value, mean)) {
+                Some(pct) => rows.push(format!("    {}  {}  {:+.1}%", name, mean, pct)),
+                None => rows.push(format!("    {}  {}", name, mean)),
+            }
+        }
+    }
+    rows
+}
+
+fn run(options: &Options) -> Result<(), String> {
+    let trace = fs::read(&options.path)
+        .map_err(|e| format!("Failed to read {}: {}", options.path.display(), e))?;
+    let mut summaries = summarize(&trace)
+        .map_err(|e| format!("Failed to parse {}: {:?}", options.path.display(), e))?;
+    if summaries.is_empty() {
+        return Err(format!("No kernels in {}", options.path.display()));
+    }
+    if let Some(path) = &options.write_baseline {
+        fs::write(path, Baseline::from_summaries(&summaries).to_json())
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    }
+    let baseline = match &options.baseline {
+        Some(path) => {
+            let mut baseline = Baseline::load(path)?;
+            baseline.threshold_pct = options.threshold.unwrap_or(DEFAULT_THRESHOLD_PCT);
+            Some(baseline)
+        }
+        None => None,
+    };
+    // Every kernel is checked, also those cut by `--top`.
+    let regressed = baseline.as_ref().map_or(0, |baseline| {
+        summaries
+            .iter()
+            .filter(|s| {
+                baseline
+                    .regression_pct(&s.name, s.mean_duration())
+                    .is_some()
+            })
+            .count()
+    });
+    if let Some(top) = options.top {
+        summaries.truncate(top);
+    }
+    for row in format_summaries(&summaries, baseline.as_ref()) {
+        println!("{}", row);
+    }
+    match baseline {
+        Some(baseline) if regressed > 0 => Err(format!(
+            "{} kernels regressed by more than {}%",
+            regressed, baseline.threshold_pct
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn main() {
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            print!("{}", USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(e) = run(&options) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Option<Options>, String> {
+        parse_args(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let options = parse(&["--top", "5", "trace.pftrace"]).unwrap().unwrap();
+        assert_eq!(options.top, Some(5));
+        assert_eq!(options.path, PathBuf::from("trace.pftrace"));
+        assert_eq!(parse(&["-h"]), Ok(None));
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["-n", "x", "trace.pftrace"]).is_err());
+        assert!(parse(&["a.pftrace", "b.pftrace"]).is_err());
+        assert!(parse(&["--bogus", "trace.pftrace"]).is_err());
+        let options = parse(&["-b", "base.json", "-t", "5%", "-w", "new.json", "t.pftrace"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(options.baseline, Some(PathBuf::from("base.json")));
+        assert_eq!(options.threshold, Some(5.0));
+        assert_eq!(options.write_baseline, Some(PathBuf::from("new.json")));
+        assert!(parse(&["-t", "-1", "trace.pftrace"]).is_err());
+        assert!(parse(&["trace.pftrace", "-b"]).is_err());
+    }
+}
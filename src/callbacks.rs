@@ -12,12 +12,128 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::state::{KernelActivity, KernelLaunch, GLOBAL_STATE};
-use crate::tracing::trace_time_ns;
+use crate::anomaly;
+use crate::clocks;
+use crate::config::{Config, FatalErrorPolicy, Replay};
+use crate::contexts;
+use crate::copies;
+use crate::energy;
+use crate::environment;
+use crate::histograms;
+use crate::library_calls;
+use crate::memory_pools;
+use crate::metrics;
+use crate::overhead::{self, Sampling, Stat};
+use crate::prometheus;
+use crate::state::{
+    lock_context, lock_global_state, CtxProfilerData, GlobalState, KernelActivity, KernelLaunch,
+    CONTEXT_DATA,
+};
+use crate::streams;
+use crate::top_kernels;
+use crate::tracing::{is_tracing, is_tracing_counters, trace_time_ns};
+use crate::worker;
+use crate::{emit_before_crash, emit_completed, emit_destroyed_context};
 use cupti_profiler::bindings::*;
 use cupti_profiler::{self as profiler, *};
 use libc::c_void;
-use std::{ffi::CStr, panic, ptr};
+use once_cell::sync::Lazy;
+use std::{
+    cell::Cell,
+    collections::{HashMap, VecDeque},
+    ffi::CStr,
+    panic, ptr,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+thread_local! {
+    /// When the enter callback of a profiled launch on this thread started, so
+    /// that the exit callback can account for the kernel replay in between.
+    static PROFILED_LAUNCH_ENTERED: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// Drops the metrics of `metric_names` that the chip does not have, which
+/// would otherwise keep the whole list from being configured. The duration
+/// metric is always kept.
+fn validate_metrics(host: &ProfilerHost, metric_names: &[String]) -> Arc<[String]> {
+    let invalid = host.invalid_metrics(metric_names);
+    if invalid.is_empty() {
+        return metric_names.into();
+    }
+    eprintln!(
+        "Dropped {} metrics the {} chip does not have: {}",
+        invalid.len(),
+        host.chip_name(),
+        invalid.join(", ")
+    );
+    metrics::remove_metrics(metric_names, &invalid).into()
+}
+
+/// Trims `metric_names` to the metrics that can be collected in one pass, so
+/// that kernels are never replayed. The dropped metrics are reported if
+/// `report` is set, and in verbose output.
+fn schedule_single_pass(
+    host: &ProfilerHost,
+    metric_names: &[String],
+    report: bool,
+    verbose: bool,
+) -> Arc<[String]> {
+    let (kept, dropped) = host.single_pass_metrics(metric_names);
+    if !dropped.is_empty() && (report || verbose) {
+        eprintln!(
+            "Dropped {} metrics that need more than one pass: {}",
+            dropped.len(),
+            dropped.join(", ")
+        );
+    }
+    if verbose {
+        println!("Collecting {} metrics in a single pass", kept.len());
+    }
+    kept.into()
+}
+
+/// What the launch callback needs of `Config`, copied out under the global
+/// lock at every launch instead of the whole configuration.
+struct LaunchConfig {
+    metrics: Arc<[String]>,
+    decode_interval: Duration,
+    fixed_ranges: bool,
+    max_kernels: usize,
+    spill_threshold: usize,
+    verbose: bool,
+}
+
+impl LaunchConfig {
+    fn new(config: &Config) -> Self {
+        Self {
+            metrics: Arc::clone(&config.metrics),
+            decode_interval: config.decode_interval,
+            fixed_ranges: config.fixed_ranges,
+            max_kernels: config.max_kernels,
+            spill_threshold: config.spill_threshold,
+            verbose: config.verbose,
+        }
+    }
+}
+
+/// Locks the data of a context like `lock_context`, adding the time spent
+/// waiting to the lock wait counter.
+fn lock_context_timed(data: &Mutex<CtxProfilerData>) -> MutexGuard<'_, CtxProfilerData> {
+    let started = Instant::now();
+    let data = lock_context(data);
+    overhead::record_stat(Stat::LockWait, started.elapsed());
+    data
+}
+
+/// Locks `GLOBAL_STATE` like `lock_global_state`, recording the wait like
+/// `lock_context_timed`.
+fn lock_state_timed() -> MutexGuard<'static, GlobalState> {
+    let started = Instant::now();
+    let state = lock_global_state();
+    overhead::record_stat(Stat::LockWait, started.elapsed());
+    state
+}
 
 /// Callback for CUPTI to request a buffer for storing activity records.
 /// # Safety
@@ -34,9 +150,106 @@ pub unsafe extern "C" fn buffer_requested(
     });
 }
 
+/// Correlation IDs of API calls kept waiting for their kernel record. Calls
+/// that launch no kernel, such as copies, get external IDs too, so the
+/// oldest are dropped past this.
+const MAX_PENDING_EXTERNAL_IDS: usize = 65536;
+
+/// External correlation IDs by the correlation ID of the API call they were
+/// pushed for. Their records come as the call is made, so they are usually
+/// in an earlier buffer than the kernel record.
+#[derive(Default)]
+struct ExternalIds {
+    ids: HashMap<u32, Vec<(u32, u64)>>,
+    /// Correlation IDs in the order they were added.
+    order: VecDeque<u32>,
+}
+
+impl ExternalIds {
+    fn add(&mut self, correlation_id: u32, kind: u32, external_id: u64) {
+        let ids = self.ids.entry(correlation_id).or_default();
+        if ids.is_empty() {
+            self.order.push_back(correlation_id);
+        }
+        ids.push((kind, external_id));
+        while self.order.len() > MAX_PENDING_EXTERNAL_IDS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+    }
+
+    /// Removes and returns the external IDs of a call.
+    fn take(&mut self, correlation_id: u32) -> Vec<(u32, u64)> {
+        // `order` keeps the ID until it is the oldest; removing it from the
+        // map is enough for it to be skipped.
+        self.ids.remove(&correlation_id).unwrap_or_default()
+    }
+}
+
+static EXTERNAL_IDS: Lazy<Mutex<ExternalIds>> = Lazy::new(Mutex::default);
+
+/// Extracts the kernel records of an activity buffer, along with the ID of
+/// the context each kernel ran on. External correlation records are matched
+/// to the kernels of the calls they were pushed for, and memory pool and
+/// memory records go to `memory_pools`.
+/// # Safety
+///
+/// `buffer` must hold `valid_size` bytes of activity records.
+unsafe fn parse_kernel_activities(
+    buffer: *mut u8,
+    valid_size: usize,
+) -> Vec<(u32, KernelActivity)> {
+    let mut activities = Vec::new();
+    let mut record: *mut CUpti_Activity = ptr::null_mut();
+    let mut external_ids = EXTERNAL_IDS.lock().unwrap_or_else(|e| e.into_inner());
+    while unsafe { profiler::activity_get_next_record(buffer, valid_size, &mut record) }.is_ok() {
+        let r = unsafe { &*record };
+        if r.kind == CUpti_ActivityKind_CUPTI_ACTIVITY_KIND_EXTERNAL_CORRELATION {
+            let c = unsafe { &*(record as *const CUpti_ActivityExternalCorrelation) };
+            external_ids.add(c.correlationId, c.externalKind, c.externalId);
+        } else if r.kind == CUpti_ActivityKind_CUPTI_ACTIVITY_KIND_MEMORY_POOL {
+            memory_pools::add_pool_record(unsafe {
+                &*(record as *const CUpti_ActivityMemoryPool2)
+            });
+        } else if r.kind == CUpti_ActivityKind_CUPTI_ACTIVITY_KIND_MEMORY2 {
+            memory_pools::add_memory_record(unsafe { &*(record as *const CUpti_ActivityMemory3) });
+        } else if r.kind == CUpti_ActivityKind_CUPTI_ACTIVITY_KIND_MEMCPY {
+            copies::add_memcpy_record(unsafe { &*(record as *const CUpti_ActivityMemcpy4) });
+        } else if r.kind == CUpti_ActivityKind_CUPTI_ACTIVITY_KIND_MEMCPY2 {
+            copies::add_peer_record(unsafe { &*(record as *const CUpti_ActivityMemcpyPtoP2) });
+        } else if r.kind == CUpti_ActivityKind_CUPTI_ACTIVITY_KIND_KERNEL {
+            let k = unsafe { &*(record as *const CUpti_ActivityKernel4) };
+            activities.push((
+                k.contextId,
+                KernelActivity {
+                    kernel_name: unsafe { CStr::from_ptr(k.name) }
+                        .to_string_lossy()
+                        .to_string(),
+                    grid_size: (k.gridX, k.gridY, k.gridZ),
+                    block_size: (k.blockX, k.blockY, k.blockZ),
+                    registers_per_thread: k.registersPerThread,
+                    dynamic_shared_memory: k.dynamicSharedMemory,
+                    static_shared_memory: k.staticSharedMemory,
+                    // Kernels are placed at their launch on the trace clock,
+                    // so only the difference of CUPTI's timestamps is used;
+                    // their clock differs between Tegra and discrete GPUs.
+                    duration: k.end.saturating_sub(k.start),
+                    stream_id: k.streamId,
+                    external_ids: external_ids.take(k.correlationId),
+                    correlation_id: k.correlationId,
+                },
+            ));
+        }
+    }
+    activities
+}
+
 /// Callback for CUPTI to notify that a buffer is full or completed.
 ///
 /// Processes the activity records in the buffer, extracting kernel launch details.
+/// Records are parsed before the state lock is taken, so launch callbacks on
+/// other threads only wait for the activities to be appended.
 /// # Safety
 ///
 /// This function is intended to be called by CUPTI. Pointers must be valid.
@@ -48,34 +261,143 @@ pub unsafe extern "C" fn buffer_completed(
     valid_size: usize,
 ) {
     let _ = panic::catch_unwind(|| {
-        if let Ok(mut state) = GLOBAL_STATE.lock() {
-            let mut record: *mut CUpti_Activity = ptr::null_mut();
-            while unsafe { profiler::activity_get_next_record(buffer, valid_size, &mut record) }
-                .is_ok()
-            {
-                let r = &*record;
-                if r.kind == CUpti_ActivityKind_CUPTI_ACTIVITY_KIND_KERNEL {
-                    let k = &*(record as *const CUpti_ActivityKernel4);
-                    if let Some(data) = state.context_data.get_mut(&k.contextId) {
-                        data.kernel_activities.push(KernelActivity {
-                            kernel_name: CStr::from_ptr(k.name).to_string_lossy().to_string(),
-                            grid_size: (k.gridX, k.gridY, k.gridZ),
-                            block_size: (k.blockX, k.blockY, k.blockZ),
-                            registers_per_thread: k.registersPerThread,
-                            dynamic_shared_memory: k.dynamicSharedMemory,
-                            static_shared_memory: k.staticSharedMemory,
-                        });
-                    }
-                }
+        let entered = Instant::now();
+        let activities = parse_kernel_activities(buffer, valid_size);
+        libc::free(buffer as *mut c_void);
+        if activities.is_empty() {
+            return;
+        }
+        let (spill_threshold, emit_interval) = {
+            let state = lock_state_timed();
+            (state.config.spill_threshold, state.config.emit_interval)
+        };
+        for (ctx_id, activity) in activities {
+            prometheus::record_kernel(&activity.kernel_name, activity.duration);
+            top_kernels::record_kernel(&activity.kernel_name, activity.duration);
+            histograms::record_kernel(&activity.kernel_name, activity.duration);
+            anomaly::record_kernel(&activity.kernel_name, activity.duration);
+            if let Some(data) = CONTEXT_DATA.get(ctx_id) {
+                lock_context_timed(&data).add_activity(activity);
             }
         }
-        libc::free(buffer as *mut c_void);
+        for data in CONTEXT_DATA.all() {
+            let mut data = lock_context_timed(&data);
+            // Ranges evaluated since the last launch may complete kernels
+            // that can now be spilled.
+            let ranges = worker::take_results(data.ctx_id);
+            data.add_ranges(ranges);
+            data.spill_completed(spill_threshold);
+        }
+        // Writing completed kernels as they come in leaves less for the exit
+        // handler to do.
+        if !emit_interval.is_zero() && is_tracing() {
+            let mut state = lock_state_timed();
+            if state.last_emit.elapsed() >= emit_interval {
+                emit_completed(&mut state);
+                state.last_emit = Instant::now();
+            }
+        }
+        overhead::record(entered.elapsed());
     });
 }
 
+/// Leaves the context to activity records if `result` means the range
+/// profiler cannot be used in this process, or the environment commonly
+/// blocks the counters, saying why in one line.
+fn set_counters_unavailable(data: &mut CtxProfilerData, result: CUptiResult) {
+    let reason = match (
+        profiler::profiler_unavailable_reason(result),
+        environment::blocker(),
+    ) {
+        (Some(reason), Some(blocker)) => format!("{}; {}", reason, blocker.diagnostic()),
+        (Some(reason), None) => reason.to_string(),
+        (None, Some(blocker)) => blocker.diagnostic().to_string(),
+        (None, None) => return,
+    };
+    let reason = format!("{} ({})", reason, profiler::get_result_string(result));
+    set_counters_unavailable_because(data, reason);
+}
+
+/// Leaves the context to activity records for `reason`, saying so once.
+fn set_counters_unavailable_because(data: &mut CtxProfilerData, reason: String) {
+    eprintln!(
+        "Context {}: no hardware counters, {}; tracing kernels without them",
+        data.ctx_id, reason
+    );
+    data.counters_unavailable = Some(reason);
+}
+
+/// Compute capability of `device`, if the driver reports it.
+fn compute_capability(device: CUdevice) -> Option<(i32, i32)> {
+    let major = profiler::get_device_attribute(
+        device,
+        CUdevice_attribute_enum_CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MAJOR,
+    );
+    let minor = profiler::get_device_attribute(
+        device,
+        CUdevice_attribute_enum_CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MINOR,
+    );
+    Some((major.ok()?, minor.ok()?))
+}
+
+/// Starts the range profiler of the context unless counters are unavailable
+/// on it. Returns true if kernels are profiled from now on.
+fn start_range_profiler(data: &mut CtxProfilerData, metric_names: &Arc<[String]>) -> bool {
+    if data.counters_unavailable.is_some() {
+        return false;
+    }
+    match data.restart(metric_names) {
+        Ok(()) => true,
+        Err(e) => {
+            set_counters_unavailable(data, e);
+            false
+        }
+    }
+}
+
+/// Stops profiling kernels on `ctx` when the application moves on to another
+/// context. Its session stays enabled, with the ranges collected so far, so
+/// switching back only has to start it again.
+fn pause_context(ctx: CUcontext) {
+    let ctx_id = unsafe { profiler::get_context_id(ctx) };
+    if let Some(data) = CONTEXT_DATA.get(ctx_id) {
+        lock_context_timed(&data).pause();
+    }
+}
+
+/// Handles a fatal CUPTI error as `INJECTION_FATAL_ERROR` says. By default,
+/// kernels are no longer profiled but are still traced from the activity
+/// records, and the application is left running.
+pub(crate) fn handle_fatal_error(message: String) {
+    let policy = {
+        let mut state = lock_global_state();
+        if state.config.fatal_error == FatalErrorPolicy::Disable {
+            state.profiling_enabled = false;
+            state.fatal_error = Some(message.clone());
+        }
+        state.config.fatal_error
+    };
+    match policy {
+        FatalErrorPolicy::Disable => {
+            eprintln!("CUPTI Fatal Error: {}; profiling is disabled", message)
+        }
+        FatalErrorPolicy::Exit => {
+            eprintln!("CUPTI Fatal Error: {}", message);
+            std::process::exit(1);
+        }
+        FatalErrorPolicy::Panic => {
+            eprintln!("CUPTI Fatal Error: {}", message);
+            // A panic cannot unwind out of the callback, so it would abort
+            // without writing the trace file.
+            emit_before_crash();
+            std::process::abort();
+        }
+    }
+}
+
 /// Main CUPTI callback handler.
 ///
-/// Intercepts CUDA driver API calls (specifically `cuLaunchKernel`) to manage profiling sessions,
+/// Intercepts CUDA driver API calls (the `cuLaunchKernel` variants) to manage profiling sessions,
 /// and handles resource events for context creation/destruction.
 /// # Safety
 ///
@@ -87,216 +409,287 @@ pub unsafe extern "C" fn profiler_callback_handler(
     cbdata: *const c_void,
 ) {
     let _ = panic::catch_unwind(|| {
+        if domain == CUpti_CallbackDomain_CUPTI_CB_DOMAIN_STATE
+            && cbid == CUpti_CallbackIdState_CUPTI_CBID_STATE_FATAL_ERROR
+        {
+            let notification = &(*(cbdata as *const CUpti_StateData))
+                .__bindgen_anon_1
+                .notification;
+            let result = profiler::get_result_string(notification.result);
+            let message = if notification.message.is_null() {
+                result
+            } else {
+                format!(
+                    "{}: {}",
+                    result,
+                    CStr::from_ptr(notification.message).to_string_lossy()
+                )
+            };
+            handle_fatal_error(message);
+            return;
+        }
         let res = profiler::get_last_error();
         if res != CUptiResult_CUPTI_SUCCESS {
             return;
         }
         if domain == CUpti_CallbackDomain_CUPTI_CB_DOMAIN_DRIVER_API
-            && cbid == CUpti_driver_api_trace_cbid_enum_CUPTI_DRIVER_TRACE_CBID_cuLaunchKernel
+            && profiler::LAUNCH_CALLBACKS.contains(&cbid)
         {
             let cb_data = &*(cbdata as *const CUpti_CallbackData);
             let ctx = cb_data.context;
-            let params = &*(cb_data.functionParams as *const cuLaunchKernel_params);
+            let Some(launch) = LaunchInfo::from_callback_data(cbid, cb_data) else {
+                return;
+            };
             if cb_data.callbackSite == CUpti_ApiCallbackSite_CUPTI_API_ENTER {
-                if let Ok(mut state) = GLOBAL_STATE.lock() {
-                    let metric_names = state.config.metrics.clone();
-                    let active_ctx = state.active_ctx;
-                    match active_ctx {
-                        Some(active_ctx) if active_ctx != ctx => {
-                            let active_ctx_id = unsafe { profiler::get_context_id(active_ctx) };
-                            if let Some(old_data) = state.context_data.get_mut(&active_ctx_id) {
-                                if let Some(rp) = &mut old_data.range_profiler {
-                                    let _ = rp.stop();
-                                    let _ = rp.decode_counter_data();
-                                    if let Some(me) = &old_data.metric_evaluator {
-                                        if let Ok(infos) = me.evaluate_all_ranges(
-                                            &old_data.counter_data_image,
-                                            &metric_names,
-                                        ) {
-                                            old_data.range_info.extend(infos);
-                                        }
-                                    }
-                                    let _ = rp.disable();
-                                }
-                                old_data.range_profiler = None;
-                                old_data.is_active = false;
-                            }
-                            state.active_ctx = None;
-                        }
-                        _ => {}
+                let entered = Instant::now();
+                let tracing = is_tracing_counters();
+                let mut profiled = false;
+                let ctx_id = unsafe { profiler::get_context_id(ctx) };
+                // Only what is shared between contexts is decided under the
+                // global lock; the rest happens under the context's own lock.
+                let (config, previous_ctx, sampling) = {
+                    let mut state = lock_state_timed();
+                    let previous_ctx = state.active_ctx.filter(|&active_ctx| active_ctx != ctx);
+                    if previous_ctx.is_some() {
+                        state.active_ctx = None;
                     }
-                    match active_ctx {
-                        Some(active_ctx) if active_ctx != ctx => {
-                            let active_ctx_id = unsafe { profiler::get_context_id(active_ctx) };
-                            if let Some(old_data) = state.context_data.get_mut(&active_ctx_id) {
-                                if let Some(rp) = &mut old_data.range_profiler {
-                                    let _ = rp.stop();
-                                    let _ = rp.decode_counter_data();
-                                    if let Some(me) = &old_data.metric_evaluator {
-                                        if let Ok(infos) = me.evaluate_all_ranges(
-                                            &old_data.counter_data_image,
-                                            &metric_names,
-                                        ) {
-                                            old_data.range_info.extend(infos);
-                                        }
-                                    }
-                                    let _ = rp.disable();
-                                }
-                                old_data.range_profiler = None;
-                                old_data.is_active = false;
-                            }
-                            state.active_ctx = None;
+                    state.overhead.sample_stats(entered, trace_time_ns);
+                    let sampling = if !CONTEXT_DATA.contains(ctx_id) {
+                        None
+                    } else if tracing && state.profiling_enabled {
+                        // Without a tracing session of the counter data
+                        // source nobody would see the counters, so skip the
+                        // replay overhead and only trace kernels through their
+                        // activity records. The same
+                        // goes for profiling disabled on the control socket.
+                        state.active_ctx = Some(ctx);
+                        Some(state.overhead.next_launch(
+                            entered,
+                            overhead::spent_ns(),
+                            trace_time_ns(),
+                        ))
+                    } else {
+                        state.active_ctx = Some(ctx);
+                        Some(Sampling::Disabled)
+                    };
+                    (LaunchConfig::new(&state.config), previous_ctx, sampling)
+                };
+                let metric_names = &config.metrics;
+                if let Some(previous_ctx) = previous_ctx {
+                    pause_context(previous_ctx);
+                }
+                let data = sampling.zip(CONTEXT_DATA.get(ctx_id));
+                if let Some((sampling, data)) = data {
+                    let mut data = lock_context_timed(&data);
+                    // Metrics set on the control socket need a new range
+                    // profiler session, which profiling kernels starts.
+                    if data.metrics_changed(metric_names) {
+                        data.flush_ranges();
+                    }
+                    match sampling {
+                        Sampling::Disabled => data.flush_ranges(),
+                        Sampling::Skip => data.pause(),
+                        Sampling::Profile if data.range_profiler.is_none() => {
+                            start_range_profiler(&mut data, metric_names);
                         }
-                        _ => {}
+                        Sampling::Profile => data.resume(),
                     }
-                    let ctx_id = unsafe { profiler::get_context_id(ctx) };
-                    if state.context_data.contains_key(&ctx_id) {
-                        state.active_ctx = Some(ctx);
-                        if let Some(data) = state.context_data.get_mut(&ctx_id) {
-                            if data.range_profiler.is_none() {
-                                let mut rp = RangeProfiler::new(ctx);
-                                let _ = rp.enable();
-                                let _ = rp.set_config(
-                                    &metric_names,
-                                    &mut data.counter_data_image,
-                                    data.max_num_ranges,
-                                    CUpti_ProfilerReplayMode_CUPTI_KernelReplay,
+                    profiled = data.is_profiling();
+                    // Each kernel is a range in kernel replay mode, so
+                    // decode only once the image is full or has been
+                    // pending for long enough.
+                    if profiled && data.should_decode(config.decode_interval) {
+                        let max_num_ranges = if config.fixed_ranges {
+                            data.max_num_ranges
+                        } else {
+                            data.adapted_max_num_ranges(
+                                data.last_decode.elapsed(),
+                                config.decode_interval,
+                            )
+                        };
+                        // Launch rates change over time, so size the image
+                        // for what was observed since the last decode.
+                        if max_num_ranges != data.max_num_ranges {
+                            if config.verbose {
+                                println!(
+                                    "Context {}: counter data image resized from {} to {} ranges",
+                                    ctx_id, data.max_num_ranges, max_num_ranges
                                 );
-                                let _ = rp.start();
-                                data.range_profiler = Some(rp);
-                                data.is_active = true;
-                            }
-                            if let Some(rp) = &mut data.range_profiler {
-                                let _ = rp.decode_counter_data();
-                                if let Some(me) = &data.metric_evaluator {
-                                    if let Ok(infos) = me.evaluate_all_ranges(
-                                        &data.counter_data_image,
-                                        &metric_names,
-                                    ) {
-                                        data.range_info.extend(infos);
-                                    }
-                                }
-                                let _ =
-                                    rp.initialize_counter_data_image(&mut data.counter_data_image);
                             }
-                            data.kernel_launches.push(KernelLaunch {
-                                function: params.f,
-                                timestamp: trace_time_ns(),
-                            });
+                            let _ = data.resize(max_num_ranges);
+                        } else {
+                            data.decode_ranges();
                         }
+                        data.last_decode = Instant::now();
+                        // Collect what the worker evaluated so far, so that
+                        // the kernel limit covers it too.
+                        data.add_ranges(worker::take_results(ctx_id));
+                        data.spill_completed(config.spill_threshold);
+                    } else if profiled && data.should_end_pass() {
+                        // The image has room for more, so only start a
+                        // new pass and leave decoding for later.
+                        data.end_pass();
+                    }
+                    if profiled {
+                        data.pending_ranges += 1;
+                        data.pass_ranges += 1;
                     }
+                    data.add_launch(
+                        KernelLaunch {
+                            function: launch.function,
+                            timestamp: trace_time_ns(),
+                            profiled,
+                            correlation_id: cb_data.correlationId,
+                        },
+                        config.max_kernels,
+                    );
+                }
+                overhead::record_stat(Stat::LaunchCallback, entered.elapsed());
+                if profiled {
+                    PROFILED_LAUNCH_ENTERED.with(|cell| cell.set(Some(entered)));
+                } else {
+                    overhead::record(entered.elapsed());
+                }
+            } else if cb_data.callbackSite == CUpti_ApiCallbackSite_CUPTI_API_EXIT {
+                if let Some(entered) = PROFILED_LAUNCH_ENTERED.with(Cell::take) {
+                    overhead::record(entered.elapsed());
                 }
             }
+        } else if domain == CUpti_CallbackDomain_CUPTI_CB_DOMAIN_DRIVER_API
+            && streams::CREATE_CALLBACKS.contains(&cbid)
+        {
+            streams::handle_create_callback(&*(cbdata as *const CUpti_CallbackData));
         } else if domain == CUpti_CallbackDomain_CUPTI_CB_DOMAIN_RESOURCE {
             if cbid == CUpti_CallbackIdResource_CUPTI_CBID_RESOURCE_CONTEXT_CREATED {
                 let res_data = &*(cbdata as *const CUpti_ResourceData);
                 let ctx = res_data.context;
-                if let Ok(mut state) = GLOBAL_STATE.lock() {
-                    let metric_names = state.config.metrics.clone();
-                    if let Some(active_ctx) = state.active_ctx {
-                        let active_ctx_id = unsafe { profiler::get_context_id(active_ctx) };
-                        if let Some(data) = state.context_data.get_mut(&active_ctx_id) {
-                            if data.is_active {
-                                if let Some(rp) = &mut data.range_profiler {
-                                    let _ = rp.stop();
-                                    let _ = rp.decode_counter_data();
-                                    if let Some(me) = &data.metric_evaluator {
-                                        if let Ok(infos) = me.evaluate_all_ranges(
-                                            &data.counter_data_image,
-                                            &metric_names,
-                                        ) {
-                                            data.range_info.extend(infos);
-                                        }
-                                    }
-                                    let _ = rp.disable();
-                                }
-                                data.range_profiler = None;
-                                data.is_active = false;
-                            }
-                        }
-                        state.active_ctx = None;
-                    }
-                    let device_id = unsafe { profiler::get_device(ctx) }.unwrap_or(0);
-                    let num_sms = profiler::get_device_attribute(
-                        device_id,
-                        CUdevice_attribute_enum_CU_DEVICE_ATTRIBUTE_MULTIPROCESSOR_COUNT,
+                let (config, previous_ctx, single_pass_scheduled, metrics_validated) = {
+                    let mut state = lock_global_state();
+                    (
+                        state.config.clone(),
+                        state.active_ctx.take(),
+                        state.single_pass_scheduled,
+                        state.metrics_validated,
                     )
-                    .unwrap_or(0);
-                    let mut data = Box::new(crate::state::CtxProfilerData {
-                        device_id,
-                        num_sms,
-                        max_num_ranges: 10,
-                        is_active: false,
-                        counter_data_image: Vec::new(),
-                        metric_evaluator: None,
-                        range_profiler: None,
-                        range_info: Vec::new(),
-                        kernel_launches: Vec::new(),
-                        kernel_activities: Vec::new(),
+                };
+                let mut metric_names = Arc::clone(&config.metrics);
+                if let Some(previous_ctx) = previous_ctx {
+                    pause_context(previous_ctx);
+                }
+                // Setting up the metric evaluator and range profiler takes a
+                // while, so it is done without holding any lock.
+                let device_id = unsafe { profiler::get_device(ctx) }.unwrap_or(0);
+                let num_sms = profiler::get_device_attribute(
+                    device_id,
+                    CUdevice_attribute_enum_CU_DEVICE_ATTRIBUTE_MULTIPROCESSOR_COUNT,
+                )
+                .unwrap_or(0);
+                energy::add_device(device_id);
+                clocks::add_device(device_id);
+                let ctx_id = unsafe { profiler::get_context_id(ctx) };
+                contexts::add(ctx_id, device_id);
+                let mut data =
+                    CtxProfilerData::new(ctx, ctx_id, device_id, num_sms, config.max_ranges);
+                data.max_ranges_per_pass = config.max_ranges_per_pass;
+                data.replay_mode = config.replay.mode();
+                data.verbose = config.verbose;
+                // Older devices, and processes the driver keeps from the
+                // counters, would fail every range profiler call, so the
+                // profiler is not set up for them at all.
+                let unavailable = compute_capability(device_id)
+                    .and_then(profiler::compute_capability_unsupported)
+                    .or_else(|| {
+                        environment::blocker()
+                            .filter(|blocker| blocker.is_certain())
+                            .map(|blocker| blocker.diagnostic().to_string())
                     });
-                    if Profiler::initialize().is_ok() {
-                        if let Ok(me) = unsafe { MetricEvaluator::new(ctx) } {
-                            data.metric_evaluator = Some(me);
+                if let Some(reason) = unavailable {
+                    set_counters_unavailable_because(&mut data, reason);
+                } else if let Err(e) = Profiler::initialize() {
+                    set_counters_unavailable(&mut data, e);
+                    if data.counters_unavailable.is_none() {
+                        eprintln!(
+                            "Failed to initialize profiler: {}",
+                            profiler::get_result_string(e)
+                        );
+                        data.counters_unavailable =
+                            Some("the profiler failed to initialize".to_string());
+                    }
+                } else if let Ok(me) = unsafe { MetricEvaluator::new(ctx) } {
+                    // One metric the chip does not have fails the whole
+                    // configuration, so those are dropped up front.
+                    if !metrics_validated {
+                        metric_names = validate_metrics(&me.host, &metric_names);
+                        let mut state = lock_global_state();
+                        state.config.metrics = Arc::clone(&metric_names);
+                        state.metrics_validated = true;
+                        if let Some(json) = &mut state.json_export {
+                            json.set_metric_names(&metric_names);
                         }
-                        let mut rp = RangeProfiler::new(ctx);
-                        if rp.enable().is_ok()
-                            && rp
-                                .set_config(
-                                    &metric_names,
-                                    &mut data.counter_data_image,
-                                    data.max_num_ranges,
-                                    CUpti_ProfilerReplayMode_CUPTI_KernelReplay,
-                                )
-                                .is_ok()
-                        {
-                            let _ = rp.start();
-                            data.range_profiler = Some(rp);
-                            data.is_active = true;
-                            state.active_ctx = Some(ctx);
+                    }
+                    let no_replay = config.replay == Replay::Off;
+                    if (config.single_pass || no_replay) && !single_pass_scheduled {
+                        metric_names = schedule_single_pass(
+                            &me.host,
+                            &metric_names,
+                            !no_replay,
+                            config.verbose,
+                        );
+                        let mut state = lock_global_state();
+                        state.config.metrics = Arc::clone(&metric_names);
+                        state.single_pass_scheduled = true;
+                        if let Some(json) = &mut state.json_export {
+                            json.set_metric_names(&metric_names);
                         }
-                        let ctx_id = unsafe { profiler::get_context_id(ctx) };
-                        state.context_data.insert(ctx_id, data);
-                    } else {
-                        eprintln!("Failed to initialize profiler");
                     }
+                    data.metric_evaluator = Some(Arc::new(me));
+                }
+                // Kernels are traced from activity records also when the
+                // range profiler cannot be used.
+                let profiling =
+                    is_tracing_counters() && start_range_profiler(&mut data, &metric_names);
+                CONTEXT_DATA.insert(data);
+                if profiling {
+                    lock_global_state().active_ctx = Some(ctx);
                 }
             } else if cbid == CUpti_CallbackIdResource_CUPTI_CBID_RESOURCE_CONTEXT_DESTROY_STARTING
             {
                 let res_data = &*(cbdata as *const CUpti_ResourceData);
                 let ctx = res_data.context;
                 let ctx_id = unsafe { profiler::get_context_id(ctx) };
-                if let Ok(mut state) = GLOBAL_STATE.lock() {
-                    let metric_names = state.config.metrics.clone();
-                    if let Some(data) = state.context_data.get_mut(&ctx_id) {
-                        if data.is_active {
-                            if let Some(rp) = &mut data.range_profiler {
-                                let _ = rp.stop();
-                                let _ = rp.decode_counter_data();
-                                if let Some(me) = &data.metric_evaluator {
-                                    if let Ok(infos) = me.evaluate_all_ranges(
-                                        &data.counter_data_image,
-                                        &metric_names,
-                                    ) {
-                                        data.range_info.extend(infos);
-                                    }
-                                }
-                                let _ = rp.disable();
-                            }
-                            data.range_profiler = None;
-                            data.is_active = false;
-                        }
-                    }
-                }
+                emit_destroyed_context(ctx, ctx_id);
+            }
+        } else if domain == CUpti_CallbackDomain_CUPTI_CB_DOMAIN_NVTX {
+            let data = &*(cbdata as *const CUpti_NvtxData);
+            if streams::NAME_CALLBACKS.contains(&cbid) {
+                streams::handle_name_callback(data);
+            } else if contexts::NAME_CALLBACKS.contains(&cbid) {
+                contexts::handle_name_callback(data);
+            } else {
+                library_calls::handle_callback(cbid, data);
             }
-        } else if domain == CUpti_CallbackDomain_CUPTI_CB_DOMAIN_STATE
-            && cbid == CUpti_CallbackIdState_CUPTI_CBID_STATE_FATAL_ERROR
-        {
-            let state_data = &*(cbdata as *const CUpti_StateData);
-            let err_str =
-                profiler::get_result_string(state_data.__bindgen_anon_1.notification.result);
-            let msg = CStr::from_ptr(state_data.__bindgen_anon_1.notification.message);
-            eprintln!("CUPTI Fatal Error: {}: {}", err_str, msg.to_string_lossy());
-            std::process::exit(1);
         }
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_external_ids() {
+        let mut ids = ExternalIds::default();
+        ids.add(1, 3, 100);
+        ids.add(1, 4, 200);
+        ids.add(2, 3, 101);
+        assert_eq!(ids.take(1), [(3, 100), (4, 200)]);
+        assert!(ids.take(1).is_empty());
+        // The oldest calls are dropped once too many wait for a kernel.
+        for correlation_id in 3..MAX_PENDING_EXTERNAL_IDS as u32 + 3 {
+            ids.add(correlation_id, 3, correlation_id as u64);
+        }
+        assert!(ids.take(2).is_empty());
+        assert_eq!(ids.take(3), [(3, 3)]);
+        assert!(ids.order.len() <= MAX_PENDING_EXTERNAL_IDS);
+    }
+}
@@ -0,0 +1,199 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The SM and memory clocks kernels actually ran at, from NVML.
+//!
+//! NVML is loaded when `INJECTION_CLOCK_INTERVAL_MS` is set. A thread then
+//! reads the current clocks of every device a context is created on at that
+//! interval, and a kernel gets the lowest clocks read while it ran, or the
+//! last read before it if it ran between two readings. Clocks that drop
+//! below their target, from power or thermal throttling, are a common reason
+//! the same kernel measures differently from run to run.
+
+use crate::nvml::{self, Nvml, NvmlDevice, NVML_CLOCK_MEM, NVML_CLOCK_SM};
+use crate::tracing::trace_time_ns;
+use cupti_profiler::bindings::*;
+use once_cell::sync::Lazy;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Mutex, OnceLock},
+    thread,
+    time::Duration,
+};
+
+/// Samples kept per device, about three hours at a 10 ms interval. Kernels
+/// older than the oldest sample get no clocks.
+pub const MAX_CLOCK_SAMPLES: usize = 1 << 20;
+
+/// SM and memory clocks, in MHz.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Clocks {
+    pub sm: u32,
+    pub memory: u32,
+}
+
+/// Timestamped readings of a device's clocks, oldest first.
+#[derive(Debug, Default)]
+pub struct ClockSamples {
+    /// Trace clock timestamps and clocks.
+    samples: VecDeque<(u64, Clocks)>,
+}
+
+impl ClockSamples {
+    /// Adds a reading, dropping the oldest past `MAX_CLOCK_SAMPLES`.
+    /// Readings must come in timestamp order.
+    pub fn push(&mut self, timestamp: u64, clocks: Clocks) {
+        if self.samples.len() == MAX_CLOCK_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((timestamp, clocks));
+    }
+
+    /// The lowest clocks read from `start` to `end`, or the last read before
+    /// `start` if none were, as long as there is a reading after `end`.
+    pub fn clocks_between(&self, start: u64, end: u64) -> Option<Clocks> {
+        let first = self.samples.partition_point(|&(t, _)| t < start);
+        let last = self.samples.partition_point(|&(t, _)| t <= end);
+        if last == self.samples.len() && self.samples.back()?.0 < end {
+            return None;
+        }
+        if first == last {
+            return Some(self.samples.get(first.checked_sub(1)?)?.1);
+        }
+        self.samples
+            .range(first..last)
+            .map(|&(_, clocks)| clocks)
+            .reduce(|a, b| Clocks {
+                sm: a.sm.min(b.sm),
+                memory: a.memory.min(b.memory),
+            })
+    }
+}
+
+struct Device {
+    handle: NvmlDevice,
+    samples: ClockSamples,
+}
+
+/// NVML, once clocks are sampled.
+static NVML: OnceLock<&'static Nvml> = OnceLock::new();
+
+/// Sampled devices by CUDA device ordinal.
+static DEVICES: Lazy<Mutex<HashMap<CUdevice, Device>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Reads the SM and memory clocks of `device`.
+fn read(nvml: &Nvml, device: NvmlDevice) -> Result<Clocks, i32> {
+    Ok(Clocks {
+        sm: nvml.clock(device, NVML_CLOCK_SM)?,
+        memory: nvml.clock(device, NVML_CLOCK_MEM)?,
+    })
+}
+
+/// Loads NVML and samples the clocks of the devices added with `add_device`
+/// every `interval` from now on.
+pub fn start(interval: Duration) -> Result<(), String> {
+    let nvml = nvml::load()?;
+    if NVML.set(nvml).is_err() {
+        return Ok(());
+    }
+    thread::Builder::new()
+        .name("cupti-clocks".to_string())
+        .spawn(move || loop {
+            thread::sleep(interval);
+            sample();
+        })
+        .map_err(|e| format!("Failed to start clock sampling: {}", e))?;
+    Ok(())
+}
+
+/// Samples `device` from now on, if clocks are sampled. Devices whose clocks
+/// NVML cannot read are skipped with a warning.
+pub fn add_device(device: CUdevice) {
+    let Some(nvml) = NVML.get() else {
+        return;
+    };
+    let Ok(mut devices) = DEVICES.lock() else {
+        return;
+    };
+    if devices.contains_key(&device) {
+        return;
+    }
+    let handle = match nvml.device(device) {
+        Ok(handle) => handle,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    let clocks = match read(nvml, handle) {
+        Ok(clocks) => clocks,
+        Err(result) => {
+            eprintln!(
+                "Failed to read the clocks of device {}: NVML error {}",
+                device, result
+            );
+            return;
+        }
+    };
+    let mut samples = ClockSamples::default();
+    samples.push(trace_time_ns(), clocks);
+    devices.insert(device, Device { handle, samples });
+}
+
+/// Reads the clocks of every sampled device, which the exit handler also
+/// does so that the last kernels have a reading after them.
+pub fn sample() {
+    let Some(nvml) = NVML.get() else {
+        return;
+    };
+    let Ok(mut devices) = DEVICES.lock() else {
+        return;
+    };
+    for device in devices.values_mut() {
+        if let Ok(clocks) = read(nvml, device.handle) {
+            device.samples.push(trace_time_ns(), clocks);
+        }
+    }
+}
+
+/// Clocks of a kernel that ran on `device` from `start` to `end` on the
+/// trace clock, if the device is sampled around it.
+pub fn kernel_clocks(device: CUdevice, start: u64, end: u64) -> Option<Clocks> {
+    NVML.get()?;
+    let devices = DEVICES.lock().ok()?;
+    devices.get(&device)?.samples.clocks_between(start, end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clocks_between() {
+        let clocks = |sm, memory| Clocks { sm, memory };
+        let mut samples = ClockSamples::default();
+        assert_eq!(samples.clocks_between(0, 10), None);
+        samples.push(1000, clocks(1980, 2619));
+        samples.push(2000, clocks(1410, 2619));
+        samples.push(3000, clocks(1755, 1593));
+        samples.push(4000, clocks(1980, 2619));
+        // The lowest of each clock while the kernel ran.
+        assert_eq!(samples.clocks_between(1500, 3500), Some(clocks(1410, 1593)));
+        assert_eq!(samples.clocks_between(1000, 1000), Some(clocks(1980, 2619)));
+        // A kernel between readings ran at the clocks read before it.
+        assert_eq!(samples.clocks_between(2100, 2900), Some(clocks(1410, 2619)));
+        assert_eq!(samples.clocks_between(500, 900), None);
+        assert_eq!(samples.clocks_between(3500, 4500), None);
+    }
+}
@@ -12,8 +12,105 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::metrics::{parse_metrics, DEFAULT_METRICS};
-use std::env;
+use crate::anomaly::{parse_rules, Rule};
+use crate::baseline::DEFAULT_THRESHOLD_PCT;
+use crate::expressions::{self, parse_expressions, Expression};
+use crate::json_export::JsonFormat;
+use crate::library_calls::LIBRARY_DOMAINS;
+use crate::metrics::{
+    append_metrics, parse_counter_aliases, parse_metrics, raw_counter_metrics, DEFAULT_METRICS,
+    ROOFLINE_METRICS,
+};
+use crate::signals::{parse_signal, parse_signals};
+use cupti_profiler::bindings::*;
+use std::{env, path::PathBuf, sync::Arc, time::Duration};
+
+/// Default number of ranges collected before counter data is decoded.
+pub const DEFAULT_MAX_RANGES: usize = 10;
+
+/// Default longest time collected ranges wait before being decoded.
+pub const DEFAULT_DECODE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Default number of kernels kept per context before the oldest are dropped.
+pub const DEFAULT_MAX_KERNELS: usize = 100_000;
+
+/// Default number of kernels kept in memory before completed ones are moved to
+/// disk, 0 to never spill.
+pub const DEFAULT_SPILL_THRESHOLD: usize = 0;
+
+/// Default share of wall time profiling may take, in percent, 0 for no limit.
+pub const DEFAULT_OVERHEAD_BUDGET: f64 = 0.0;
+
+/// Default time between writing completed kernels to the trace while the
+/// application runs, 0 to write everything at exit.
+pub const DEFAULT_EMIT_INTERVAL: Duration = Duration::ZERO;
+
+/// Default time the exit handler may take, 0 for no limit.
+pub const DEFAULT_EXIT_DEADLINE: Duration = Duration::ZERO;
+
+/// Default number of kernels in the table printed at exit.
+pub const DEFAULT_TOP_KERNELS: usize = 10;
+
+/// Default signals that write the trace before they end the process, as
+/// schedulers send them to jobs they stop.
+pub const DEFAULT_FLUSH_SIGNALS: [i32; 2] = [libc::SIGINT, libc::SIGTERM];
+
+/// How kernels are profiled when the metrics need more than one pass.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Replay {
+    /// CUPTI replays each kernel as many times as the metrics need.
+    Kernel,
+    /// Kernels run exactly once. Metrics that don't fit in one pass are
+    /// skipped.
+    Off,
+}
+
+impl Replay {
+    /// Parses an `INJECTION_REPLAY` value.
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "kernel" => Some(Replay::Kernel),
+            "off" | "none" => Some(Replay::Off),
+            _ => None,
+        }
+    }
+
+    /// The range profiler replay mode. Without replay, the profiler is left
+    /// to user replay, where a single-pass configuration is collected in one
+    /// go and nothing is ever run again.
+    pub fn mode(self) -> CUpti_ProfilerReplayMode {
+        match self {
+            Replay::Kernel => CUpti_ProfilerReplayMode_CUPTI_KernelReplay,
+            Replay::Off => CUpti_ProfilerReplayMode_CUPTI_UserReplay,
+        }
+    }
+}
+
+/// What happens when CUPTI reports a fatal error, after which it finalizes
+/// itself.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FatalErrorPolicy {
+    /// Kernels are no longer profiled, but the application keeps running and
+    /// what was collected is still written.
+    #[default]
+    Disable,
+    /// The process exits with status 1.
+    Exit,
+    /// What was collected is written, as at a crash, and the process aborts.
+    Panic,
+}
+
+impl FatalErrorPolicy {
+    /// Parses an `INJECTION_FATAL_ERROR` value.
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "disable" | "continue" => Some(FatalErrorPolicy::Disable),
+            "exit" => Some(FatalErrorPolicy::Exit),
+            "panic" | "abort" => Some(FatalErrorPolicy::Panic),
+            _ => None,
+        }
+    }
+}
 
 /// Configuration for the injection library.
 #[derive(Debug, Clone)]
@@ -21,7 +118,105 @@ pub struct Config {
     /// Whether verbose logging is enabled.
     pub verbose: bool,
     /// List of metrics to be collected.
-    pub metrics: Vec<String>,
+    ///
+    /// Shared so that callbacks can hold on to it without copying the names.
+    pub metrics: Arc<[String]>,
+    /// Signal that detaches the profiler from the process, if any.
+    pub detach_signal: Option<i32>,
+    /// Signals that write everything collected so far, as the exit handler
+    /// does, before the application's handler or the default action runs.
+    pub flush_signals: Vec<i32>,
+    /// Number of ranges the counter data image initially holds between decodes.
+    pub max_ranges: usize,
+    /// Ranges the range profiler records before a pass ends, 0 for as many
+    /// as the counter data image holds.
+    pub max_ranges_per_pass: usize,
+    /// Whether the counter data image keeps `max_ranges` instead of adapting
+    /// to the launch rate.
+    pub fixed_ranges: bool,
+    /// Longest time collected ranges wait before being decoded.
+    pub decode_interval: Duration,
+    /// Kernels stored per context before the oldest are dropped, 0 for no limit.
+    pub max_kernels: usize,
+    /// Kernels kept in memory per context before completed ones are written
+    /// to a temporary file, 0 to keep everything in memory.
+    pub spill_threshold: usize,
+    /// Share of wall time profiling may take, in percent, before fewer kernels
+    /// are profiled. 0 for no limit.
+    pub overhead_budget: f64,
+    /// Time between writing completed kernels to active tracing sessions, 0
+    /// to write everything at exit.
+    pub emit_interval: Duration,
+    /// Time the exit handler may take before the remaining kernels are
+    /// abandoned, 0 for no limit.
+    pub exit_deadline: Duration,
+    /// Whether metrics that would need kernel replay are dropped, so that
+    /// every kernel is profiled in a single pass.
+    pub single_pass: bool,
+    /// Whether kernels may be replayed to collect the metrics.
+    pub replay: Replay,
+    /// File that an in-process tracing session started with the library is
+    /// written to at exit, if any.
+    pub trace_file: Option<PathBuf>,
+    /// Directory every decoded counter data image is saved to before it is
+    /// evaluated, if any.
+    pub dump_dir: Option<PathBuf>,
+    /// File every kernel written to the trace is also written to as a line
+    /// of JSON, if any.
+    pub json_file: Option<PathBuf>,
+    /// Layout of `json_file`.
+    pub json_format: JsonFormat,
+    /// Per-kernel baseline that emitted kernels are compared against, if any.
+    pub baseline_file: Option<PathBuf>,
+    /// Slowdown from the baseline, in percent, that is flagged.
+    pub baseline_threshold: f64,
+    /// Address the Prometheus endpoint listens on, if any.
+    pub prometheus_addr: Option<String>,
+    /// Address the status page is served on, if any.
+    pub status_addr: Option<String>,
+    /// Unix socket the control commands are read from, if any.
+    pub control_socket: Option<PathBuf>,
+    /// Kernels listed in the table printed at exit, 0 for no table.
+    pub top_kernels: usize,
+    /// File the table of top kernels is also written to, if any.
+    pub top_kernels_file: Option<PathBuf>,
+    /// NVTX domains whose ranges are traced as library calls, none if empty.
+    pub library_domains: Vec<String>,
+    /// What a fatal CUPTI error does to the process.
+    pub fatal_error: FatalErrorPolicy,
+    /// Window the launches of a kernel are aggregated over before they are
+    /// written as one, 0 to write every launch.
+    pub aggregate_window: Duration,
+    /// Whether per-kernel histograms are written at exit.
+    pub kernel_histograms: bool,
+    /// Rules that kernels are flagged as anomalies by, none if empty.
+    pub anomaly_rules: Vec<Rule>,
+    /// Interval the energy of the devices is sampled at with NVML, 0 for no
+    /// energy estimates.
+    pub energy_interval: Duration,
+    /// Interval the clocks of the devices are sampled at with NVML, 0 for no
+    /// per-kernel clocks.
+    pub clock_interval: Duration,
+    /// Whether the reserved and used memory of the stream-ordered
+    /// allocator's pools is traced.
+    pub memory_pools: bool,
+    /// Whether copies within and between GPUs are traced.
+    pub device_copies: bool,
+    /// Whether queues and contexts are named after their NVTX names.
+    pub nvtx_names: bool,
+    /// Whether the metrics kernels are placed on the roofline with are
+    /// collected on top of those asked for.
+    pub roofline: bool,
+    /// Whether the raw counters behind `metrics` are written as they are,
+    /// without deriving anything from them.
+    pub raw_counters: bool,
+    /// Values computed from the metrics of each profiled kernel.
+    pub metric_expressions: Vec<Expression>,
+    /// Names counters are shown by in the UI instead of their own, by
+    /// counter name.
+    pub counter_aliases: Vec<(String, String)>,
+    /// Time between periodic flushes of everything collected, zero for none.
+    pub flush_interval: Duration,
 }
 
 impl Default for Config {
@@ -29,6 +224,45 @@ impl Default for Config {
         Self {
             verbose: false,
             metrics: DEFAULT_METRICS.iter().map(|s| s.to_string()).collect(),
+            detach_signal: None,
+            flush_signals: DEFAULT_FLUSH_SIGNALS.to_vec(),
+            max_ranges: DEFAULT_MAX_RANGES,
+            max_ranges_per_pass: 0,
+            fixed_ranges: false,
+            decode_interval: DEFAULT_DECODE_INTERVAL,
+            max_kernels: DEFAULT_MAX_KERNELS,
+            spill_threshold: DEFAULT_SPILL_THRESHOLD,
+            overhead_budget: DEFAULT_OVERHEAD_BUDGET,
+            emit_interval: DEFAULT_EMIT_INTERVAL,
+            exit_deadline: DEFAULT_EXIT_DEADLINE,
+            single_pass: false,
+            replay: Replay::Kernel,
+            trace_file: None,
+            dump_dir: None,
+            json_file: None,
+            json_format: JsonFormat::Lines,
+            baseline_file: None,
+            baseline_threshold: DEFAULT_THRESHOLD_PCT,
+            prometheus_addr: None,
+            status_addr: None,
+            control_socket: None,
+            top_kernels: DEFAULT_TOP_KERNELS,
+            top_kernels_file: None,
+            library_domains: Vec::new(),
+            fatal_error: FatalErrorPolicy::Disable,
+            aggregate_window: Duration::ZERO,
+            kernel_histograms: false,
+            anomaly_rules: Vec::new(),
+            energy_interval: Duration::ZERO,
+            clock_interval: Duration::ZERO,
+            memory_pools: false,
+            device_copies: false,
+            nvtx_names: false,
+            roofline: false,
+            raw_counters: false,
+            metric_expressions: Vec::new(),
+            counter_aliases: Vec::new(),
+            flush_interval: Duration::ZERO,
         }
     }
 }
@@ -37,12 +271,342 @@ impl Config {
     /// Loads configuration from environment variables.
     ///
     /// - `INJECTION_VERBOSE`: specifices if verbose logging is enabled.
-    /// - `INJECTION_METRICS`: semicolon or comma separated list of metrics.
+    /// - `INJECTION_METRICS`: semicolon or comma separated list of metrics and presets (`sol`, `roofline`, `flops`, `dram`, `smem`, `cache`, `stalls`).
+    /// - `INJECTION_ROOFLINE`: adds the metrics kernels are placed on the roofline with.
+    /// - `INJECTION_METRIC_EXPRESSIONS`: semicolon separated values like `my_bw = dram__bytes_read.sum / gpu__time_duration.sum` computed for each profiled kernel, adding the metrics they use.
+    /// - `INJECTION_RAW_COUNTERS`: writes the sums of the raw counters behind the metrics instead of evaluated metrics.
+    /// - `INJECTION_COUNTER_ALIASES`: semicolon separated `counter=alias` pairs, like `sm__throughput.avg.pct_of_peak_sustained_elapsed=SM busy %`, naming counters in the UI.
+    /// - `INJECTION_DETACH_SIGNAL`: signal (name or number) that detaches the profiler.
+    /// - `INJECTION_FLUSH_SIGNALS`: comma separated signals that write the trace before ending the process (default `SIGINT,SIGTERM`, `none` for none).
+    /// - `INJECTION_MAX_RANGES`: ranges collected before counter data is decoded, initially.
+    /// - `INJECTION_MAX_RANGES_PER_PASS`: ranges recorded per pass, at most `INJECTION_MAX_RANGES`.
+    /// - `INJECTION_FIXED_RANGES`: keeps `INJECTION_MAX_RANGES` instead of adapting it.
+    /// - `INJECTION_DECODE_INTERVAL_MS`: longest time between decodes while kernels run.
+    /// - `INJECTION_MAX_KERNELS`: kernels kept per context before the oldest are dropped.
+    /// - `INJECTION_SPILL_THRESHOLD`: kernels kept in memory before completed ones go to disk.
+    /// - `INJECTION_OVERHEAD_BUDGET`: percentage of wall time profiling may take.
+    /// - `INJECTION_EMIT_INTERVAL_MS`: time between writing completed kernels while running.
+    /// - `INJECTION_FLUSH_INTERVAL_MS`: time between background flushes that decode, evaluate and write what was collected (default 0, off).
+    /// - `INJECTION_EXIT_DEADLINE_MS`: time the exit handler may take.
+    /// - `INJECTION_SINGLE_PASS`: drops metrics that would need kernel replay.
+    /// - `INJECTION_REPLAY`: `off` never replays kernels and skips the metrics that would need it.
+    /// - `INJECTION_TRACE_FILE`: records the process in-process and writes the trace there at exit.
+    /// - `INJECTION_DUMP_DIR`: saves decoded counter data images there for `counter-data-eval`.
+    /// - `INJECTION_JSON_FILE`: writes every emitted kernel there as a line of JSON.
+    /// - `INJECTION_JSON_FORMAT`: `chrome` writes `INJECTION_JSON_FILE` as a Chrome trace instead.
+    /// - `INJECTION_BASELINE`: flags kernels slower than their mean in this baseline file.
+    /// - `INJECTION_BASELINE_THRESHOLD`: slowdown in percent that is flagged (default 10).
+    /// - `INJECTION_PROMETHEUS_ADDR`: serves per-kernel aggregates on `host:port` at `/metrics`.
+    /// - `INJECTION_STATUS_ADDR`: serves the status report on `host:port` at `/status`.
+    /// - `INJECTION_CONTROL_SOCKET`: reads control commands from a unix socket at that path.
+    /// - `INJECTION_TOP_KERNELS`: kernels in the table printed to stderr at exit (default 10, 0 for none).
+    /// - `INJECTION_TOP_KERNELS_FILE`: also writes that table to this file.
+    /// - `INJECTION_KERNEL_HISTOGRAMS`: writes per-kernel duration and metric histograms at exit.
+    /// - `INJECTION_ANOMALY_RULES`: comma separated rules like `achieved_occupancy<20` or `duration>3sigma` that flag kernels (`1` for the defaults).
+    /// - `INJECTION_ENERGY_INTERVAL_MS`: interval NVML energy counters are sampled at for per-kernel `energy_mj` (default 0, off).
+    /// - `INJECTION_CLOCK_INTERVAL_MS`: interval NVML clocks are sampled at for per-kernel `sm_clock_mhz` and `memory_clock_mhz` (default 0, off).
+    /// - `INJECTION_MEMORY_POOLS`: traces the reserved and used bytes of `cuMemAllocAsync` pools per device.
+    /// - `INJECTION_DEVICE_COPIES`: traces device-to-device and peer-to-peer copies with their devices and link.
+    /// - `INJECTION_NVTX_NAMES`: names stream queues and contexts after their `nvtxNameCuStreamA`, `nvtxNameCudaStreamA` and `nvtxNameCuContextA` names.
+    /// - `INJECTION_LIBRARY_CALLS`: traces calls into CUDA libraries, or into the comma separated NVTX domains given (`*` for all).
+    /// - `INJECTION_FATAL_ERROR`: `disable` (default) stops profiling on a fatal CUPTI error, `exit` ends the process and `panic` aborts it.
+    /// - `INJECTION_AGGREGATE_WINDOW_MS`: writes the launches of each kernel and launch configuration in this window as one event.
     pub fn from_env() -> Self {
         let verbose = env::var("INJECTION_VERBOSE").is_ok();
         let metrics_str = env::var("INJECTION_METRICS").unwrap_or_default();
-        let metrics = parse_metrics(&metrics_str);
+        let roofline = env::var("INJECTION_ROOFLINE").is_ok();
+        let metric_expressions = env::var("INJECTION_METRIC_EXPRESSIONS")
+            .map(|s| parse_expressions(&s))
+            .unwrap_or_default();
+        let raw_counters = env::var("INJECTION_RAW_COUNTERS").is_ok();
+        let metrics = collected_metrics(
+            parse_metrics(&metrics_str),
+            roofline,
+            &metric_expressions,
+            raw_counters,
+        );
+        let counter_aliases = env::var("INJECTION_COUNTER_ALIASES")
+            .map(|s| parse_counter_aliases(&s))
+            .unwrap_or_default();
+        let detach_signal = env::var("INJECTION_DETACH_SIGNAL")
+            .ok()
+            .and_then(|s| parse_signal(&s));
+        let flush_signals = env::var("INJECTION_FLUSH_SIGNALS")
+            .map(|s| parse_signals(&s))
+            .unwrap_or_else(|_| DEFAULT_FLUSH_SIGNALS.to_vec());
+        let max_ranges = env::var("INJECTION_MAX_RANGES")
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .filter(|&n| n > 0)
+            .unwrap_or(DEFAULT_MAX_RANGES);
+        let max_ranges_per_pass = env::var("INJECTION_MAX_RANGES_PER_PASS")
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .unwrap_or(0);
+        let fixed_ranges = env::var("INJECTION_FIXED_RANGES").is_ok();
+        let decode_interval = env::var("INJECTION_DECODE_INTERVAL_MS")
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_DECODE_INTERVAL);
+        let max_kernels = env::var("INJECTION_MAX_KERNELS")
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .unwrap_or(DEFAULT_MAX_KERNELS);
+        let spill_threshold = env::var("INJECTION_SPILL_THRESHOLD")
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .unwrap_or(DEFAULT_SPILL_THRESHOLD);
+        let overhead_budget = env::var("INJECTION_OVERHEAD_BUDGET")
+            .ok()
+            .and_then(|s| s.trim().trim_end_matches('%').parse().ok())
+            .filter(|&pct: &f64| pct.is_finite() && pct >= 0.0)
+            .unwrap_or(DEFAULT_OVERHEAD_BUDGET);
+        let emit_interval = env::var("INJECTION_EMIT_INTERVAL_MS")
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_EMIT_INTERVAL);
+        let flush_interval = env::var("INJECTION_FLUSH_INTERVAL_MS")
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .map(Duration::from_millis)
+            .unwrap_or_default();
+        let exit_deadline = env::var("INJECTION_EXIT_DEADLINE_MS")
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_EXIT_DEADLINE);
+        let single_pass = env::var("INJECTION_SINGLE_PASS").is_ok();
+        let replay = env::var("INJECTION_REPLAY")
+            .ok()
+            .and_then(|s| Replay::parse(&s))
+            .unwrap_or(Replay::Kernel);
+        let trace_file = env::var_os("INJECTION_TRACE_FILE")
+            .filter(|s| !s.is_empty())
+            .map(PathBuf::from);
+        let dump_dir = env::var_os("INJECTION_DUMP_DIR")
+            .filter(|s| !s.is_empty())
+            .map(PathBuf::from);
+        let json_file = env::var_os("INJECTION_JSON_FILE")
+            .filter(|s| !s.is_empty())
+            .map(PathBuf::from);
+        let json_format = env::var("INJECTION_JSON_FORMAT")
+            .ok()
+            .and_then(|s| JsonFormat::parse(&s))
+            .unwrap_or(JsonFormat::Lines);
+        let baseline_file = env::var_os("INJECTION_BASELINE")
+            .filter(|s| !s.is_empty())
+            .map(PathBuf::from);
+        let baseline_threshold = env::var("INJECTION_BASELINE_THRESHOLD")
+            .ok()
+            .and_then(|s| s.trim().trim_end_matches('%').parse().ok())
+            .filter(|&pct: &f64| pct.is_finite() && pct >= 0.0)
+            .unwrap_or(DEFAULT_THRESHOLD_PCT);
+        let prometheus_addr = env::var("INJECTION_PROMETHEUS_ADDR")
+            .ok()
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty());
+        let status_addr = env::var("INJECTION_STATUS_ADDR")
+            .ok()
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty());
+        let control_socket = env::var_os("INJECTION_CONTROL_SOCKET")
+            .filter(|s| !s.is_empty())
+            .map(PathBuf::from);
+        let top_kernels = env::var("INJECTION_TOP_KERNELS")
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .unwrap_or(DEFAULT_TOP_KERNELS);
+        let top_kernels_file = env::var_os("INJECTION_TOP_KERNELS_FILE")
+            .filter(|s| !s.is_empty())
+            .map(PathBuf::from);
+        let library_domains = env::var("INJECTION_LIBRARY_CALLS")
+            .map(|s| parse_library_domains(&s))
+            .unwrap_or_default();
+        let fatal_error = env::var("INJECTION_FATAL_ERROR")
+            .ok()
+            .and_then(|s| FatalErrorPolicy::parse(&s))
+            .unwrap_or_default();
+        let aggregate_window = env::var("INJECTION_AGGREGATE_WINDOW_MS")
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .map(Duration::from_millis)
+            .unwrap_or_default();
+        let kernel_histograms = env::var("INJECTION_KERNEL_HISTOGRAMS").is_ok();
+        let anomaly_rules = env::var("INJECTION_ANOMALY_RULES")
+            .map(|s| parse_rules(&s))
+            .unwrap_or_default();
+        let energy_interval = env::var("INJECTION_ENERGY_INTERVAL_MS")
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .map(Duration::from_millis)
+            .unwrap_or_default();
+        let clock_interval = env::var("INJECTION_CLOCK_INTERVAL_MS")
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .map(Duration::from_millis)
+            .unwrap_or_default();
+        let memory_pools = env::var("INJECTION_MEMORY_POOLS").is_ok();
+        let device_copies = env::var("INJECTION_DEVICE_COPIES").is_ok();
+        let nvtx_names = env::var("INJECTION_NVTX_NAMES").is_ok();
+
+        Self {
+            verbose,
+            metrics: metrics.into(),
+            detach_signal,
+            flush_signals,
+            max_ranges,
+            max_ranges_per_pass,
+            fixed_ranges,
+            decode_interval,
+            max_kernels,
+            spill_threshold,
+            overhead_budget,
+            emit_interval,
+            exit_deadline,
+            single_pass,
+            replay,
+            trace_file,
+            dump_dir,
+            json_file,
+            json_format,
+            baseline_file,
+            baseline_threshold,
+            prometheus_addr,
+            status_addr,
+            control_socket,
+            top_kernels,
+            top_kernels_file,
+            library_domains,
+            fatal_error,
+            aggregate_window,
+            kernel_histograms,
+            anomaly_rules,
+            energy_interval,
+            clock_interval,
+            memory_pools,
+            device_copies,
+            nvtx_names,
+            roofline,
+            raw_counters,
+            metric_expressions,
+            counter_aliases,
+            flush_interval,
+        }
+    }
+
+    /// Metrics collected when `requested` are asked for, e.g. on the control
+    /// socket, with what the configuration adds to them.
+    pub fn collected_metrics(&self, requested: Vec<String>) -> Arc<[String]> {
+        collected_metrics(
+            requested,
+            self.roofline,
+            &self.metric_expressions,
+            self.raw_counters,
+        )
+        .into()
+    }
+}
+
+/// The `requested` metrics, followed by the roofline metrics if `roofline`
+/// and those the `expressions` use, as raw counters if `raw_counters`.
+fn collected_metrics(
+    requested: Vec<String>,
+    roofline: bool,
+    expressions: &[Expression],
+    raw_counters: bool,
+) -> Vec<String> {
+    let mut metrics = requested;
+    if roofline {
+        metrics = append_metrics(metrics, ROOFLINE_METRICS);
+    }
+    metrics = append_metrics(metrics, &expressions::metrics(expressions));
+    if raw_counters {
+        metrics = raw_counter_metrics(&metrics);
+    }
+    metrics
+}
+
+/// Parses the NVTX domains of `INJECTION_LIBRARY_CALLS`, where `1` or
+/// nothing stands for the domains of the known CUDA libraries.
+fn parse_library_domains(s: &str) -> Vec<String> {
+    let domains: Vec<String> = s
+        .split(',')
+        .map(str::trim)
+        .filter(|domain| !domain.is_empty())
+        .map(str::to_string)
+        .collect();
+    if domains.is_empty() || domains == ["1"] {
+        LIBRARY_DOMAINS.iter().map(|s| s.to_string()).collect()
+    } else {
+        domains
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace_emitter::DURATION_METRIC;
+
+    #[test]
+    fn test_parse_fatal_error_policy() {
+        assert_eq!(
+            FatalErrorPolicy::parse("exit"),
+            Some(FatalErrorPolicy::Exit)
+        );
+        assert_eq!(
+            FatalErrorPolicy::parse(" Panic "),
+            Some(FatalErrorPolicy::Panic)
+        );
+        assert_eq!(
+            FatalErrorPolicy::parse("continue"),
+            Some(FatalErrorPolicy::Disable)
+        );
+        assert_eq!(FatalErrorPolicy::parse("ignore"), None);
+        assert_eq!(Config::default().fatal_error, FatalErrorPolicy::Disable);
+    }
+
+    #[test]
+    fn test_parse_replay() {
+        assert_eq!(Replay::parse("off"), Some(Replay::Off));
+        assert_eq!(Replay::parse(" OFF "), Some(Replay::Off));
+        assert_eq!(Replay::parse("kernel"), Some(Replay::Kernel));
+        assert_eq!(Replay::parse("application"), None);
+        assert_eq!(
+            Replay::Off.mode(),
+            CUpti_ProfilerReplayMode_CUPTI_UserReplay
+        );
+    }
+
+    #[test]
+    fn test_collected_metrics() {
+        let config = Config {
+            roofline: true,
+            metric_expressions: parse_expressions("ipc = sm__inst_executed.sum / 2"),
+            ..Config::default()
+        };
+        let metrics = config.collected_metrics(vec![DURATION_METRIC.to_string()]);
+        assert_eq!(metrics[0], DURATION_METRIC);
+        assert!(ROOFLINE_METRICS
+            .iter()
+            .all(|m| metrics.contains(&m.to_string())));
+        assert_eq!(metrics.last().unwrap(), "sm__inst_executed.sum");
+        let config = Config {
+            raw_counters: true,
+            ..config
+        };
+        let metrics = config.collected_metrics(vec!["dram__bytes.sum".to_string()]);
+        assert!(metrics.contains(&"dram__bytes.sum".to_string()));
+        assert!(metrics.contains(&"sm__inst_executed.sum".to_string()));
+        assert!(metrics.iter().all(|m| m.ends_with(".sum")));
+    }
 
-        Self { verbose, metrics }
+    #[test]
+    fn test_parse_library_domains() {
+        assert_eq!(parse_library_domains("1"), LIBRARY_DOMAINS);
+        assert_eq!(parse_library_domains(""), LIBRARY_DOMAINS);
+        assert_eq!(parse_library_domains("cuBLAS, NCCL,"), ["cuBLAS", "NCCL"]);
+        assert_eq!(parse_library_domains("*"), ["*"]);
     }
 }
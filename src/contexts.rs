@@ -0,0 +1,111 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Names of CUDA contexts, for the messages, status and exports that refer
+//! to them.
+//!
+//! A context named with `nvtxNameCuContextA` goes by that name, e.g.
+//! "decode context"; any other by its CUPTI ID and device, as in
+//! "ctx 1 on GPU 0", which tells the contexts of an inference server apart
+//! better than the ID alone.
+
+use cupti_profiler::bindings::*;
+use once_cell::sync::Lazy;
+use std::{
+    collections::HashMap,
+    ffi::{c_char, CStr},
+    sync::{Mutex, MutexGuard},
+};
+
+/// NVTX calls that name contexts, with `NvtxNameCuContextAParams`.
+pub const NAME_CALLBACKS: [CUpti_CallbackId; 1] =
+    [CUpti_nvtx_api_trace_cbid_CUPTI_CBID_NVTX_nvtxNameCuContextA];
+
+/// `nvtxNameCuContextA_params` of the CUPTI NVTX callback data.
+#[repr(C)]
+pub struct NvtxNameCuContextAParams {
+    pub context: CUcontext,
+    pub name: *const c_char,
+}
+
+/// Device and NVTX name of a context.
+#[derive(Debug, Default)]
+struct Context {
+    device_id: CUdevice,
+    name: Option<String>,
+}
+
+/// Contexts by CUPTI context ID.
+static CONTEXTS: Lazy<Mutex<HashMap<u32, Context>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn contexts() -> MutexGuard<'static, HashMap<u32, Context>> {
+    CONTEXTS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Records that the context with ID `ctx_id` was created on `device_id`.
+pub fn add(ctx_id: u32, device_id: CUdevice) {
+    contexts().entry(ctx_id).or_default().device_id = device_id;
+}
+
+/// Names the context with ID `ctx_id`.
+pub fn set_name(ctx_id: u32, name: &str) {
+    contexts().entry(ctx_id).or_default().name = Some(name.to_string());
+}
+
+/// The NVTX name of the context with ID `ctx_id`, if it was named.
+pub fn nvtx_name(ctx_id: u32) -> Option<String> {
+    contexts().get(&ctx_id)?.name.clone()
+}
+
+/// The NVTX name of the context with ID `ctx_id`, or its ID and device.
+pub fn name(ctx_id: u32) -> String {
+    let contexts = contexts();
+    match contexts.get(&ctx_id) {
+        Some(Context {
+            name: Some(name), ..
+        }) => name.clone(),
+        Some(context) => format!("ctx {} on GPU {}", ctx_id, context.device_id),
+        None => format!("ctx {}", ctx_id),
+    }
+}
+
+/// Handles a callback of `NAME_CALLBACKS`, naming the context.
+///
+/// # Safety
+///
+/// `data` must be the callback data CUPTI passed for one of `NAME_CALLBACKS`.
+pub unsafe fn handle_name_callback(data: &CUpti_NvtxData) {
+    let params = &*(data.functionParams as *const NvtxNameCuContextAParams);
+    if params.context.is_null() || params.name.is_null() {
+        return;
+    }
+    let ctx_id = cupti_profiler::get_context_id(params.context);
+    set_name(ctx_id, &CStr::from_ptr(params.name).to_string_lossy());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_name() {
+        assert_eq!(name(1001), "ctx 1001");
+        add(1001, 2);
+        assert_eq!(name(1001), "ctx 1001 on GPU 2");
+        assert_eq!(nvtx_name(1001), None);
+        set_name(1001, "decode context");
+        assert_eq!(name(1001), "decode context");
+        assert_eq!(nvtx_name(1001).as_deref(), Some("decode context"));
+    }
+}
@@ -0,0 +1,276 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Control socket for steering the profiler while the application runs.
+//!
+//! Clients send one command per line and get back lines of output ending
+//! with `ok` or `error: <reason>`, e.g. with
+//! `socat - UNIX-CONNECT:/tmp/cupti.sock`.
+//!
+//! Range profilers may only be driven from the threads launching kernels, so
+//! `enable`, `disable` and `set-metrics` just change `GlobalState`, and the
+//! launch callback of each context applies them at its next kernel.
+
+use crate::metrics::parse_metrics;
+use crate::state::lock_global_state;
+use crate::tracing::{self, is_tracing};
+use crate::{detach, emit_completed, status, worker};
+use cupti_profiler as profiler;
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    thread,
+};
+
+/// Text returned by `help`.
+const HELP: &str = "\
+enable             profile kernels again after disable
+disable            stop profiling kernels; they are still traced
+set-metrics LIST   profile the comma separated metrics, the defaults if empty
+flush              write the completed kernels to the trace now
+dump DIR|off       save counter data images to DIR, or stop saving them
+status             show the profiler state
+detach             emit everything and stop profiling for good
+help               show this text
+";
+
+/// A line sent to the control socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Enable,
+    Disable,
+    SetMetrics(Vec<String>),
+    Flush,
+    Dump(Option<PathBuf>),
+    Status,
+    Detach,
+    Help,
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let line = line.trim();
+        let (name, arg) = line
+            .split_once(char::is_whitespace)
+            .map_or((line, ""), |(name, arg)| (name, arg.trim()));
+        let command = match name {
+            "enable" => Command::Enable,
+            "disable" => Command::Disable,
+            "set-metrics" => return Ok(Command::SetMetrics(parse_metrics(arg))),
+            "flush" => Command::Flush,
+            "dump" => {
+                return match arg {
+                    "" => Err("dump needs a directory or `off`".to_string()),
+                    "off" => Ok(Command::Dump(None)),
+                    dir => Ok(Command::Dump(Some(PathBuf::from(dir)))),
+                }
+            }
+            "status" => Command::Status,
+            "detach" => Command::Detach,
+            "help" => Command::Help,
+            _ => return Err(format!("unknown command `{}`, try `help`", name)),
+        };
+        if arg.is_empty() {
+            Ok(command)
+        } else {
+            Err(format!("{} takes no argument", name))
+        }
+    }
+}
+
+/// Runs a command and returns its output.
+fn execute(command: Command) -> Result<String, String> {
+    match command {
+        Command::Enable | Command::Disable => {
+            let enabled = command == Command::Enable;
+            let mut state = lock_global_state();
+            if enabled && state.fatal_error.is_some() {
+                return Err("profiling was disabled after a fatal CUPTI error".to_string());
+            }
+            state.profiling_enabled = enabled;
+            Ok(String::new())
+        }
+        Command::SetMetrics(metrics) => {
+            let mut state = lock_global_state();
+            state.config.metrics = state.config.collected_metrics(metrics);
+            let state = &mut *state;
+            if let Some(json) = &mut state.json_export {
+                json.set_metric_names(&state.config.metrics);
+            }
+            tracing::metrics_changed();
+            Ok(String::new())
+        }
+        Command::Flush => {
+            // Like at exit, activity buffers are flushed before the state lock
+            // is taken, since `buffer_completed` needs the same locks.
+            let _ = profiler::activity_flush_all(0);
+            let mut state = lock_global_state();
+            if !is_tracing() && state.json_export.is_none() {
+                return Err("no tracing session to flush to".to_string());
+            }
+            emit_completed(&mut state);
+            Ok(String::new())
+        }
+        Command::Dump(dir) => {
+            let mut state = lock_global_state();
+            state.config.dump_dir = dir.clone();
+            worker::set_dump_dir(dir);
+            Ok(String::new())
+        }
+        Command::Status => Ok(status::report()),
+        Command::Detach => {
+            detach();
+            Ok(String::new())
+        }
+        Command::Help => Ok(HELP.to_string()),
+    }
+}
+
+/// Answers the commands of one client until it disconnects.
+fn serve(stream: UnixStream) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match Command::parse(&line).and_then(execute) {
+            Ok(output) => writeln!(writer, "{}ok", output)?,
+            Err(e) => writeln!(writer, "error: {}", e)?,
+        }
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+/// Listens for commands on the unix socket at `path`. A socket left there
+/// by an earlier process is replaced, but any other file is kept and
+/// fails the start.
+pub fn start(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "a file that is not a socket is in the way",
+            ))
+        }
+        Err(_) => {}
+    }
+    let listener = UnixListener::bind(path)?;
+    thread::Builder::new()
+        .name("cupti-control".to_string())
+        .spawn(move || {
+            for stream in listener.incoming().flatten() {
+                // One client at a time, so commands never run concurrently.
+                let _ = serve(stream);
+            }
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::GLOBAL_STATE_TESTS;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(Command::parse(" enable\n"), Ok(Command::Enable));
+        assert_eq!(
+            Command::parse("set-metrics sm__cycles_elapsed.avg; dram__bytes.sum"),
+            Ok(Command::SetMetrics(vec![
+                "sm__cycles_elapsed.avg".to_string(),
+                "dram__bytes.sum".to_string()
+            ]))
+        );
+        assert_eq!(
+            Command::parse("set-metrics"),
+            Ok(Command::SetMetrics(parse_metrics("")))
+        );
+        assert_eq!(
+            Command::parse("dump /tmp/images"),
+            Ok(Command::Dump(Some(PathBuf::from("/tmp/images"))))
+        );
+        assert_eq!(Command::parse("dump off"), Ok(Command::Dump(None)));
+        assert!(Command::parse("dump").is_err());
+        assert!(Command::parse("flush now").is_err());
+        assert!(Command::parse("restart").is_err());
+    }
+
+    #[test]
+    fn test_serve() {
+        // The commands change the global state that other tests check.
+        let _guard = GLOBAL_STATE_TESTS.lock().unwrap_or_else(|e| e.into_inner());
+        let path = std::env::temp_dir().join(format!("control-{}.sock", std::process::id()));
+        // Other files are never removed.
+        fs::write(&path, b"").unwrap();
+        assert_eq!(
+            start(&path).unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        assert!(path.exists());
+        fs::remove_file(&path).unwrap();
+        // A stale socket is replaced.
+        drop(UnixListener::bind(&path).unwrap());
+        start(&path).unwrap();
+        let stream = UnixStream::connect(&path).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        let mut request = |command: &str| {
+            writeln!(writer, "{}", command).unwrap();
+            let mut lines = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end().to_string();
+                let done = line == "ok" || line.starts_with("error: ");
+                lines.push(line);
+                if done {
+                    return lines;
+                }
+            }
+        };
+        assert_eq!(request("disable"), ["ok"]);
+        let status = request("status");
+        assert!(status.contains(&"profiling: disabled".to_string()));
+        assert_eq!(status.last().unwrap(), "ok");
+        assert_eq!(request("enable"), ["ok"]);
+        assert!(request("status").contains(&"profiling: enabled".to_string()));
+        // After a fatal CUPTI error, profiling stays disabled.
+        crate::callbacks::handle_fatal_error("CUPTI_ERROR_UNKNOWN: device lost".to_string());
+        assert_eq!(
+            request("enable"),
+            ["error: profiling was disabled after a fatal CUPTI error"]
+        );
+        assert!(request("status")
+            .iter()
+            .any(|line| line.contains("fatal CUPTI error: CUPTI_ERROR_UNKNOWN: device lost")));
+        {
+            let mut state = lock_global_state();
+            state.fatal_error = None;
+            state.profiling_enabled = true;
+        }
+        assert_eq!(
+            request("bogus"),
+            ["error: unknown command `bogus`, try `help`"]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,196 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Copies within and between GPUs, for `INJECTION_DEVICE_COPIES`.
+//!
+//! CUPTI records device-to-device copies as memcpy activity and copies
+//! between GPUs as peer-to-peer activity, with the devices on either side.
+//! Each becomes a "Copy" render stage on the queue of its stream, with its
+//! source and destination devices and, for peer copies, whether they went
+//! over NVLink or PCIe, which is what collectives spanning GPUs come down to.
+
+use crate::nvml;
+use crate::tracing::cupti_trace_time;
+use cupti_profiler::bindings::*;
+use once_cell::sync::Lazy;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Mutex, MutexGuard},
+};
+
+/// Copies kept until they are written, after which the oldest are dropped.
+pub const MAX_COPIES: usize = 1 << 16;
+
+/// A copy within or between GPUs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CopyActivity {
+    /// Start on the trace clock.
+    pub timestamp: u64,
+    pub duration: u64,
+    /// Device that ran the copy, whose queues it goes on.
+    pub device_id: u32,
+    /// CUPTI ID of the stream it ran on.
+    pub stream_id: u32,
+    pub bytes: u64,
+    pub src_device_id: u32,
+    pub dst_device_id: u32,
+}
+
+impl CopyActivity {
+    /// Whether the copy went from one GPU to another.
+    pub fn is_peer(&self) -> bool {
+        self.src_device_id != self.dst_device_id
+    }
+}
+
+/// How two GPUs reach each other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Link {
+    NvLink,
+    Pcie,
+    /// NVML is not available to tell.
+    Unknown,
+}
+
+impl Link {
+    pub fn name(self) -> &'static str {
+        match self {
+            Link::NvLink => "NVLink",
+            Link::Pcie => "PCIe",
+            Link::Unknown => "unknown",
+        }
+    }
+}
+
+static COPIES: Lazy<Mutex<VecDeque<CopyActivity>>> = Lazy::new(Mutex::default);
+
+/// Links between pairs of devices, as NVML reported them.
+static LINKS: Lazy<Mutex<HashMap<(u32, u32), Link>>> = Lazy::new(Mutex::default);
+
+fn copies() -> MutexGuard<'static, VecDeque<CopyActivity>> {
+    COPIES.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn add(copy: CopyActivity) {
+    let mut copies = copies();
+    if copies.len() == MAX_COPIES {
+        copies.pop_front();
+    }
+    copies.push_back(copy);
+}
+
+/// Records a device-to-device copy. Other memcpy records are ignored.
+pub fn add_memcpy_record(record: &CUpti_ActivityMemcpy4) {
+    if record.copyKind as CUpti_ActivityMemcpyKind
+        != CUpti_ActivityMemcpyKind_CUPTI_ACTIVITY_MEMCPY_KIND_DTOD
+    {
+        return;
+    }
+    add(CopyActivity {
+        timestamp: cupti_trace_time(record.start),
+        duration: record.end.saturating_sub(record.start),
+        device_id: record.deviceId,
+        stream_id: record.streamId,
+        bytes: record.bytes,
+        src_device_id: record.deviceId,
+        dst_device_id: record.deviceId,
+    });
+}
+
+/// Records a copy between GPUs.
+pub fn add_peer_record(record: &CUpti_ActivityMemcpyPtoP2) {
+    add(CopyActivity {
+        timestamp: cupti_trace_time(record.start),
+        duration: record.end.saturating_sub(record.start),
+        device_id: record.deviceId,
+        stream_id: record.streamId,
+        bytes: record.bytes,
+        src_device_id: record.srcDeviceId,
+        dst_device_id: record.dstDeviceId,
+    });
+}
+
+/// Removes and returns the copies not yet written, oldest first.
+pub fn take() -> Vec<CopyActivity> {
+    copies().drain(..).collect()
+}
+
+/// How `src` and `dst` reach each other, asking NVML the first time.
+pub fn link(src: u32, dst: u32) -> Link {
+    let key = (src.min(dst), src.max(dst));
+    let mut links = LINKS.lock().unwrap_or_else(|e| e.into_inner());
+    *links.entry(key).or_insert_with(|| {
+        let query = || {
+            let nvml = nvml::load().ok()?;
+            let a = nvml.device(src as CUdevice).ok()?;
+            let b = nvml.device(dst as CUdevice).ok()?;
+            nvml.is_nvlink(a, b).ok()
+        };
+        match query() {
+            Some(true) => Link::NvLink,
+            Some(false) => Link::Pcie,
+            None => Link::Unknown,
+        }
+    })
+}
+
+/// Extra data of a copy: its size, the devices on either side and, between
+/// GPUs, the link between them.
+pub fn build_copy_data(copy: &CopyActivity, link: Option<Link>) -> Vec<(&'static str, String)> {
+    let kind = if copy.is_peer() { "PtoP" } else { "DtoD" };
+    let mut data = vec![
+        ("copy_kind", kind.to_string()),
+        ("bytes", copy.bytes.to_string()),
+        ("src_device", copy.src_device_id.to_string()),
+        ("dst_device", copy.dst_device_id.to_string()),
+    ];
+    if let Some(link) = link {
+        data.push(("link", link.name().to_string()));
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_copy_data() {
+        let mut copy = CopyActivity {
+            timestamp: 0,
+            duration: 100,
+            device_id: 0,
+            stream_id: 7,
+            bytes: 1 << 20,
+            src_device_id: 0,
+            dst_device_id: 0,
+        };
+        assert!(!copy.is_peer());
+        assert_eq!(
+            build_copy_data(&copy, None),
+            [
+                ("copy_kind", "DtoD".to_string()),
+                ("bytes", "1048576".to_string()),
+                ("src_device", "0".to_string()),
+                ("dst_device", "0".to_string()),
+            ]
+        );
+        copy.dst_device_id = 1;
+        assert!(copy.is_peer());
+        let data = build_copy_data(&copy, Some(Link::NvLink));
+        assert_eq!(data[0], ("copy_kind", "PtoP".to_string()));
+        assert_eq!(data[3], ("dst_device", "1".to_string()));
+        assert_eq!(data[4], ("link", "NVLink".to_string()));
+    }
+}
@@ -0,0 +1,187 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Per-kernel energy estimates from NVML's energy counters.
+//!
+//! NVML is loaded when `INJECTION_ENERGY_INTERVAL_MS` is set. A thread then
+//! samples the total energy counter
+//! of every device a context is created on at that interval, and a kernel's
+//! energy is the counter interpolated at its end minus the counter
+//! interpolated at its start.
+//!
+//! This is an estimate: the driver updates the counter only as often as it
+//! measures power, so kernels shorter than the sampling interval get the
+//! mean power around them times their duration. Power of kernels running
+//! concurrently, of copies and of the idle GPU is included too. Sums over
+//! many launches of a kernel are meaningful, single short launches are not.
+
+use crate::nvml::{self, Nvml, NvmlDevice};
+use crate::tracing::trace_time_ns;
+use cupti_profiler::bindings::*;
+use once_cell::sync::Lazy;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Mutex, OnceLock},
+    thread,
+    time::Duration,
+};
+
+/// Samples kept per device, about three hours at a 10 ms interval. Kernels
+/// older than the oldest sample get no energy.
+pub const MAX_ENERGY_SAMPLES: usize = 1 << 20;
+
+/// Timestamped readings of a device's energy counter, oldest first.
+#[derive(Debug, Default)]
+pub struct EnergySamples {
+    /// Trace clock timestamps and total energy, in mJ.
+    samples: VecDeque<(u64, u64)>,
+}
+
+impl EnergySamples {
+    /// Adds a reading, dropping the oldest past `MAX_ENERGY_SAMPLES`.
+    /// Readings must come in timestamp order.
+    pub fn push(&mut self, timestamp: u64, energy: u64) {
+        if self.samples.len() == MAX_ENERGY_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((timestamp, energy));
+    }
+
+    /// The counter at `timestamp`, interpolated between the readings around
+    /// it, or nothing outside of the readings.
+    pub fn energy_at(&self, timestamp: u64) -> Option<f64> {
+        let next = self.samples.partition_point(|&(t, _)| t < timestamp);
+        let &(t1, e1) = self.samples.get(next)?;
+        if t1 == timestamp {
+            return Some(e1 as f64);
+        }
+        let &(t0, e0) = self.samples.get(next.checked_sub(1)?)?;
+        let fraction = (timestamp - t0) as f64 / (t1 - t0) as f64;
+        Some(e0 as f64 + fraction * (e1 as f64 - e0 as f64))
+    }
+
+    /// Energy consumed from `start` to `end`, in mJ.
+    pub fn energy_between(&self, start: u64, end: u64) -> Option<f64> {
+        Some(self.energy_at(end)? - self.energy_at(start)?)
+    }
+}
+
+struct Device {
+    handle: NvmlDevice,
+    samples: EnergySamples,
+}
+
+/// NVML, once energy is sampled.
+static NVML: OnceLock<&'static Nvml> = OnceLock::new();
+
+/// Sampled devices by CUDA device ordinal.
+static DEVICES: Lazy<Mutex<HashMap<CUdevice, Device>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Loads NVML and samples the energy of the devices added with `add_device`
+/// every `interval` from now on.
+pub fn start(interval: Duration) -> Result<(), String> {
+    let nvml = nvml::load()?;
+    if NVML.set(nvml).is_err() {
+        return Ok(());
+    }
+    thread::Builder::new()
+        .name("cupti-energy".to_string())
+        .spawn(move || loop {
+            thread::sleep(interval);
+            sample();
+        })
+        .map_err(|e| format!("Failed to start energy sampling: {}", e))?;
+    Ok(())
+}
+
+/// Samples `device` from now on, if energy is sampled. Devices whose energy
+/// NVML cannot read, such as those before Volta, are skipped with a warning.
+pub fn add_device(device: CUdevice) {
+    let Some(nvml) = NVML.get() else {
+        return;
+    };
+    let Ok(mut devices) = DEVICES.lock() else {
+        return;
+    };
+    if devices.contains_key(&device) {
+        return;
+    }
+    let handle = match nvml.device(device) {
+        Ok(handle) => handle,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    let energy = match nvml.total_energy(handle) {
+        Ok(energy) => energy,
+        Err(result) => {
+            eprintln!(
+                "Failed to read the energy of device {}: NVML error {}",
+                device, result
+            );
+            return;
+        }
+    };
+    let mut samples = EnergySamples::default();
+    samples.push(trace_time_ns(), energy);
+    devices.insert(device, Device { handle, samples });
+}
+
+/// Reads the energy counter of every sampled device, which the exit handler
+/// also does so that the last kernels have a reading after them.
+pub fn sample() {
+    let Some(nvml) = NVML.get() else {
+        return;
+    };
+    let Ok(mut devices) = DEVICES.lock() else {
+        return;
+    };
+    for device in devices.values_mut() {
+        let before = trace_time_ns();
+        if let Ok(energy) = nvml.total_energy(device.handle) {
+            let after = trace_time_ns();
+            device.samples.push(before + (after - before) / 2, energy);
+        }
+    }
+}
+
+/// Estimated energy of a kernel that ran on `device` from `start` to `end`
+/// on the trace clock, in mJ, if the device is sampled around it.
+pub fn kernel_energy(device: CUdevice, start: u64, end: u64) -> Option<f64> {
+    NVML.get()?;
+    let devices = DEVICES.lock().ok()?;
+    devices.get(&device)?.samples.energy_between(start, end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_energy_between() {
+        let mut samples = EnergySamples::default();
+        assert_eq!(samples.energy_at(0), None);
+        samples.push(1000, 10);
+        samples.push(2000, 30);
+        samples.push(4000, 50);
+        assert_eq!(samples.energy_at(1000), Some(10.0));
+        assert_eq!(samples.energy_at(1500), Some(20.0));
+        assert_eq!(samples.energy_at(3000), Some(40.0));
+        assert_eq!(samples.energy_at(999), None);
+        assert_eq!(samples.energy_at(4001), None);
+        assert_eq!(samples.energy_between(1500, 3000), Some(20.0));
+        assert_eq!(samples.energy_between(1500, 5000), None);
+    }
+}
@@ -0,0 +1,157 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Detection of environments where GPU performance counters are commonly
+//! blocked, so that a failing range profiler can say what to change.
+
+use once_cell::sync::Lazy;
+use std::{fs, path::Path};
+
+/// Something in the environment that keeps the range profiler from the GPU
+/// performance counters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CounterBlocker {
+    /// The driver only lets admin users read counters, and this process
+    /// does not run as one.
+    AdminOnly,
+    /// WSL2, where the Windows driver decides whether counters are exposed.
+    Wsl2,
+    /// A vGPU guest, which only sees counters if the host enabled them.
+    VirtualGpu,
+}
+
+impl CounterBlocker {
+    /// Whether the profiler is known to fail, rather than commonly does.
+    pub fn is_certain(self) -> bool {
+        self == CounterBlocker::AdminOnly
+    }
+
+    /// What blocks the counters and how to unblock them.
+    pub fn diagnostic(self) -> &'static str {
+        match self {
+            CounterBlocker::AdminOnly => {
+                "the driver restricts GPU performance counters to admin users; run as root or \
+                 load the nvidia module with NVreg_RestrictProfilingToAdminUsers=0"
+            }
+            CounterBlocker::Wsl2 => {
+                "WSL2 only exposes GPU performance counters when the Windows host allows it; \
+                 select \"Allow access to the GPU performance counters to all users\" in the \
+                 Developer settings of the NVIDIA Control Panel"
+            }
+            CounterBlocker::VirtualGpu => {
+                "vGPU guests only see GPU performance counters when profiling is enabled for \
+                 the vGPU on the host, with enable_profiling=1 in its vgpu_params"
+            }
+        }
+    }
+}
+
+/// What the kernel and the NVIDIA driver report about the system.
+#[derive(Debug, Default)]
+pub struct Probe {
+    /// Contents of `/proc/sys/kernel/osrelease`.
+    pub os_release: String,
+    /// Whether `/dev/dxg`, the WSL2 GPU paravirtualization device, exists.
+    pub has_dxg: bool,
+    /// Contents of `/proc/driver/nvidia/version`.
+    pub driver_version: String,
+    /// Contents of `/proc/driver/nvidia/params`.
+    pub driver_params: String,
+    /// Whether the process runs as root.
+    pub is_admin: bool,
+}
+
+impl Probe {
+    /// Reads the system this process runs on.
+    pub fn read() -> Self {
+        let read = |path: &str| fs::read_to_string(path).unwrap_or_default();
+        Self {
+            os_release: read("/proc/sys/kernel/osrelease"),
+            has_dxg: Path::new("/dev/dxg").exists(),
+            driver_version: read("/proc/driver/nvidia/version"),
+            driver_params: read("/proc/driver/nvidia/params"),
+            is_admin: unsafe { libc::geteuid() } == 0,
+        }
+    }
+
+    /// The first thing that blocks or commonly blocks the counters, if any.
+    pub fn blocker(&self) -> Option<CounterBlocker> {
+        let admin_only = self
+            .driver_params
+            .lines()
+            .any(|line| line.replace(' ', "") == "RmProfilingAdminOnly:1");
+        if admin_only && !self.is_admin {
+            Some(CounterBlocker::AdminOnly)
+        } else if self.has_dxg || self.os_release.to_lowercase().contains("microsoft") {
+            Some(CounterBlocker::Wsl2)
+        } else if self.driver_version.contains("GRID") || self.driver_version.contains("vGPU") {
+            Some(CounterBlocker::VirtualGpu)
+        } else {
+            None
+        }
+    }
+}
+
+/// What blocks the counters of this process, detected once. The simulated
+/// device of the stubs is not the host's GPU, so nothing blocks it.
+static BLOCKER: Lazy<Option<CounterBlocker>> = Lazy::new(|| {
+    if cfg!(feature = "stubs") {
+        None
+    } else {
+        Probe::read().blocker()
+    }
+});
+
+/// What blocks the GPU performance counters of this process, if anything.
+pub fn blocker() -> Option<CounterBlocker> {
+    *BLOCKER
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocker() {
+        assert_eq!(Probe::default().blocker(), None);
+        let admin_only = Probe {
+            driver_params: "ResmanDebugLevel: 4294967295\nRmProfilingAdminOnly: 1\n".to_string(),
+            ..Probe::default()
+        };
+        assert_eq!(admin_only.blocker(), Some(CounterBlocker::AdminOnly));
+        assert!(admin_only.blocker().unwrap().is_certain());
+        let root = Probe {
+            is_admin: true,
+            ..admin_only
+        };
+        assert_eq!(root.blocker(), None);
+        let wsl = Probe {
+            os_release: "5.15.167.4-microsoft-standard-WSL2\n".to_string(),
+            ..Probe::default()
+        };
+        assert_eq!(wsl.blocker(), Some(CounterBlocker::Wsl2));
+        assert!(!CounterBlocker::Wsl2.is_certain());
+        let vgpu = Probe {
+            driver_version: "NVRM version: NVIDIA UNIX x86_64 Kernel Module for GRID 550.54.14"
+                .to_string(),
+            ..Probe::default()
+        };
+        assert_eq!(vgpu.blocker(), Some(CounterBlocker::VirtualGpu));
+        let not_restricted = Probe {
+            driver_params: "RmProfilingAdminOnly: 0\n".to_string(),
+            ..Probe::default()
+        };
+        assert_eq!(not_restricted.blocker(), None);
+    }
+}
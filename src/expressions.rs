@@ -0,0 +1,342 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! User-defined metric expressions.
+//!
+//! An expression like `my_bw = dram__bytes_read.sum / gpu__time_duration.sum`
+//! names a value computed from the metrics of a profiled kernel with `+`,
+//! `-`, `*`, `/` and parentheses. Each kernel gets the value as a derived
+//! counter and as extra data, like the values derived by the library itself.
+
+use crate::trace_emitter::{
+    ACHIEVED_OCCUPANCY, DRAM_BW_PCT_OF_PEAK, FLOPS_COUNTERS, TENSOR_FLOPS_COUNTER,
+};
+use cupti_profiler::MetricValuePair;
+use std::fmt;
+
+/// Arithmetic on metric values.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    Metric(String),
+    Neg(Box<Expr>),
+    Binary(Box<Expr>, Op, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+/// A named expression, as in `my_bw = dram__bytes_read.sum / gpu__time_duration.sum`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expression {
+    /// Counter and extra data name of the value. Leaked once when parsed, as
+    /// derived values are named by static strings and expressions are only
+    /// read from the environment.
+    pub name: &'static str,
+    pub expr: Expr,
+}
+
+impl Expr {
+    /// Value of the expression for a kernel with `metrics`, unless a metric
+    /// it uses was not collected or the result is not finite.
+    pub fn evaluate(&self, metrics: &[MetricValuePair]) -> Option<f64> {
+        let value = match self {
+            Expr::Number(value) => *value,
+            Expr::Metric(name) => metrics.iter().find(|m| m.metric_name == *name)?.value,
+            Expr::Neg(expr) => -expr.evaluate(metrics)?,
+            Expr::Binary(lhs, op, rhs) => {
+                let (lhs, rhs) = (lhs.evaluate(metrics)?, rhs.evaluate(metrics)?);
+                match op {
+                    Op::Add => lhs + rhs,
+                    Op::Sub => lhs - rhs,
+                    Op::Mul => lhs * rhs,
+                    Op::Div => lhs / rhs,
+                }
+            }
+        };
+        Some(value).filter(|value| value.is_finite())
+    }
+
+    /// Appends the metrics the expression uses to `metrics`.
+    fn collect_metrics<'a>(&'a self, metrics: &mut Vec<&'a str>) {
+        match self {
+            Expr::Number(_) => {}
+            Expr::Metric(name) => metrics.push(name),
+            Expr::Neg(expr) => expr.collect_metrics(metrics),
+            Expr::Binary(lhs, _, rhs) => {
+                lhs.collect_metrics(metrics);
+                rhs.collect_metrics(metrics);
+            }
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Number(value) => write!(f, "{}", value),
+            Expr::Metric(name) => write!(f, "{}", name),
+            Expr::Neg(expr) => write!(f, "-{}", expr),
+            Expr::Binary(lhs, op, rhs) => {
+                let op = match op {
+                    Op::Add => '+',
+                    Op::Sub => '-',
+                    Op::Mul => '*',
+                    Op::Div => '/',
+                };
+                write!(f, "({} {} {})", lhs, op, rhs)
+            }
+        }
+    }
+}
+
+/// Recursive descent parser of `Expr`, over the characters of the text after
+/// the `=`.
+struct Parser<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        let rest = &self.s[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.s[self.pos..].chars().next()
+    }
+
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> &str {
+        let start = self.pos;
+        let rest = &self.s[start..];
+        self.pos += rest.find(|c| !f(c)).unwrap_or(rest.len());
+        &self.s[start..self.pos]
+    }
+
+    /// `sum := product (('+' | '-') product)*`
+    fn sum(&mut self) -> Result<Expr, String> {
+        let mut expr = self.product()?;
+        while let Some(op) = self.peek().and_then(|c| match c {
+            '+' => Some(Op::Add),
+            '-' => Some(Op::Sub),
+            _ => None,
+        }) {
+            self.pos += 1;
+            expr = Expr::Binary(Box::new(expr), op, Box::new(self.product()?));
+        }
+        Ok(expr)
+    }
+
+    /// `product := factor (('*' | '/') factor)*`
+    fn product(&mut self) -> Result<Expr, String> {
+        let mut expr = self.factor()?;
+        while let Some(op) = self.peek().and_then(|c| match c {
+            '*' => Some(Op::Mul),
+            '/' => Some(Op::Div),
+            _ => None,
+        }) {
+            self.pos += 1;
+            expr = Expr::Binary(Box::new(expr), op, Box::new(self.factor()?));
+        }
+        Ok(expr)
+    }
+
+    /// `factor := '-' factor | '(' sum ')' | number | metric`
+    fn factor(&mut self) -> Result<Expr, String> {
+        match self.peek() {
+            Some('-') => {
+                self.pos += 1;
+                Ok(Expr::Neg(Box::new(self.factor()?)))
+            }
+            Some('(') => {
+                self.pos += 1;
+                let expr = self.sum()?;
+                if self.peek() != Some(')') {
+                    return Err("a ( is not closed".to_string());
+                }
+                self.pos += 1;
+                Ok(expr)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let number = self.take_while(|c| c.is_ascii_alphanumeric() || c == '.');
+                number
+                    .parse()
+                    .map(Expr::Number)
+                    .map_err(|_| format!("{:?} is not a number", number))
+            }
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+                let name = self.take_while(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+                Ok(Expr::Metric(name.to_string()))
+            }
+            Some(c) => Err(format!("unexpected {:?}", c)),
+            None => Err("it ends early".to_string()),
+        }
+    }
+}
+
+impl Expression {
+    /// Parses an expression like `my_bw = dram__bytes_read.sum / gpu__time_duration.sum`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let Some((name, expr)) = s.split_once('=') else {
+            return Err(format!("Metric expression {:?} has no =", s));
+        };
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(format!("Metric expression {:?} has no name", s));
+        }
+        let mut parser = Parser { s: expr, pos: 0 };
+        let expr = parser
+            .sum()
+            .and_then(|expr| match parser.peek() {
+                None => Ok(expr),
+                Some(c) => Err(format!("unexpected {:?}", c)),
+            })
+            .map_err(|e| format!("Metric expression {:?} is invalid: {}", s, e))?;
+        Ok(Self {
+            name: Box::leak(name.to_string().into_boxed_str()),
+            expr,
+        })
+    }
+}
+
+/// Parses the semicolon separated expressions of
+/// `INJECTION_METRIC_EXPRESSIONS`. Invalid expressions, and those named like
+/// an earlier one or a value the library derives itself, are skipped with a
+/// warning, so that each name is the counter of one expression.
+pub fn parse_expressions(s: &str) -> Vec<Expression> {
+    let mut expressions: Vec<Expression> = Vec::new();
+    for text in s.split(';').map(str::trim) {
+        if text.is_empty() {
+            continue;
+        }
+        match Expression::parse(text) {
+            Ok(expression) if is_taken(&expressions, expression.name) => eprintln!(
+                "Metric expression {:?} is skipped: {:?} is already a counter",
+                text, expression.name
+            ),
+            Ok(expression) => expressions.push(expression),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+    expressions
+}
+
+/// Whether `name` is that of one of the `expressions` or of a derived counter.
+fn is_taken(expressions: &[Expression], name: &str) -> bool {
+    [
+        ACHIEVED_OCCUPANCY,
+        DRAM_BW_PCT_OF_PEAK,
+        TENSOR_FLOPS_COUNTER,
+    ]
+    .iter()
+    .chain(&FLOPS_COUNTERS)
+    .any(|counter| *counter == name)
+        || expressions.iter().any(|expression| expression.name == name)
+}
+
+/// The metrics `expressions` use, in order of first use.
+pub fn metrics(expressions: &[Expression]) -> Vec<&str> {
+    let mut metrics = Vec::new();
+    for expression in expressions {
+        expression.expr.collect_metrics(&mut metrics);
+    }
+    let mut seen = Vec::new();
+    metrics.retain(|metric| {
+        let first = !seen.contains(metric);
+        seen.push(*metric);
+        first
+    });
+    metrics
+}
+
+/// Values of the `expressions` that can be evaluated for a kernel with
+/// `metrics`, by name. An expression that cannot be evaluated leaves out only
+/// its own value; the counter IDs of the others follow from the position of
+/// their names in `expressions`.
+pub fn evaluate(
+    expressions: &[Expression],
+    metrics: &[MetricValuePair],
+) -> Vec<(&'static str, f64)> {
+    expressions
+        .iter()
+        .filter_map(|expression| Some((expression.name, expression.expr.evaluate(metrics)?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metric(name: &str, value: f64) -> MetricValuePair {
+        MetricValuePair {
+            metric_name: name.to_string(),
+            value,
+        }
+    }
+
+    #[test]
+    fn test_parse_expression() {
+        let expression =
+            Expression::parse("my_bw = dram__bytes_read.sum / gpu__time_duration.sum").unwrap();
+        assert_eq!(expression.name, "my_bw");
+        assert_eq!(
+            expression.expr.to_string(),
+            "(dram__bytes_read.sum / gpu__time_duration.sum)"
+        );
+        // Products bind tighter than sums, and parentheses tighter still.
+        let expression = Expression::parse("x = -a + 2 * (b - 1.5) / c").unwrap();
+        assert_eq!(expression.expr.to_string(), "(-a + ((2 * (b - 1.5)) / c))");
+        assert!(Expression::parse("x").is_err());
+        assert!(Expression::parse(" = a").is_err());
+        assert!(Expression::parse("x = a +").is_err());
+        assert!(Expression::parse("x = (a").is_err());
+        assert!(Expression::parse("x = a b").is_err());
+        assert!(Expression::parse("x = 1.2.3").is_err());
+    }
+
+    #[test]
+    fn test_parse_expressions() {
+        let expressions = parse_expressions(
+            "a = x / y; bad; ; b = 1e3 * x; a = y; achieved_occupancy = x; fp32_flops = y",
+        );
+        let names: Vec<&str> = expressions.iter().map(|e| e.name).collect();
+        assert_eq!(names, vec!["a", "b"]);
+        assert_eq!(metrics(&expressions), vec!["x", "y"]);
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let expressions = parse_expressions(
+            "bw = bytes / time; neg = -bytes; missing = bytes / z; inf = bytes / zero",
+        );
+        let metrics = [
+            metric("bytes", 4096.0),
+            metric("time", 1024.0),
+            metric("zero", 0.0),
+        ];
+        // Expressions using metrics that were not collected, or that come out
+        // infinite, are left out.
+        assert_eq!(
+            evaluate(&expressions, &metrics),
+            vec![("bw", 4.0), ("neg", -4096.0)]
+        );
+    }
+}
@@ -0,0 +1,111 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Periodic flushes with `INJECTION_FLUSH_INTERVAL_MS`.
+//!
+//! A background thread decodes the counter data collected so far, waits for
+//! the worker to evaluate it and writes the completed kernels on a fixed
+//! cadence, however kernels are launched, which bounds both how much is held
+//! in memory and how stale a live trace gets.
+
+use crate::state::{lock_context, lock_global_state, CtxProfilerData, CONTEXT_DATA};
+use crate::tracing::is_tracing;
+use crate::{emit_completed, worker};
+use cupti_profiler as profiler;
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Whether the flush thread is running.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Flushes everything collected every `interval` from now on, until the
+/// profiler detaches.
+pub fn start(interval: Duration) -> Result<(), String> {
+    if RUNNING.swap(true, Ordering::SeqCst) {
+        return Ok(());
+    }
+    thread::Builder::new()
+        .name("cupti-flush".to_string())
+        .spawn(move || {
+            loop {
+                thread::sleep(interval);
+                if !flush() {
+                    break;
+                }
+            }
+            RUNNING.store(false, Ordering::SeqCst);
+        })
+        .map_err(|e| {
+            RUNNING.store(false, Ordering::SeqCst);
+            format!("Failed to start periodic flushes: {}", e)
+        })?;
+    Ok(())
+}
+
+/// Flushes activities, decodes the pending ranges of every context, collects
+/// what the worker evaluated and writes the completed kernels to the tracing
+/// sessions and the JSON export.
+///
+/// Returns false once the profiler has detached.
+fn flush() -> bool {
+    let spill_threshold = {
+        let state = lock_global_state();
+        if state.detached {
+            return false;
+        }
+        state.config.spill_threshold
+    };
+    // Like at exit, activity buffers are flushed before the state lock is
+    // taken, since `buffer_completed` needs the same locks.
+    let _ = profiler::activity_flush_all(0);
+    for data in CONTEXT_DATA.all() {
+        let mut data = lock_context(&data);
+        if data.pending_ranges > 0 {
+            decode(&mut data);
+        }
+    }
+    worker::flush();
+    for data in CONTEXT_DATA.all() {
+        let mut data = lock_context(&data);
+        let ranges = worker::take_results(data.ctx_id);
+        data.add_ranges(ranges);
+        data.spill_completed(spill_threshold);
+    }
+    let mut state = lock_global_state();
+    if state.detached {
+        return false;
+    }
+    if is_tracing() || state.json_export.is_some() {
+        emit_completed(&mut state);
+        state.last_emit = Instant::now();
+    }
+    true
+}
+
+/// Decodes the ranges `data` collected since the last decode, with its
+/// context current on this thread. The context lock keeps launches on it
+/// from driving the range profiler meanwhile.
+fn decode(data: &mut CtxProfilerData) {
+    // SAFETY: `emit_destroyed_context` ends the range profiler of contexts
+    // the application destroys, so only live ones have pending ranges.
+    if unsafe { profiler::push_current_context(data.ctx) }.is_err() {
+        return;
+    }
+    data.decode_ranges();
+    data.last_decode = Instant::now();
+    let _ = profiler::pop_current_context();
+}
@@ -0,0 +1,385 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Per-kernel histograms of durations and key metrics, written at exit.
+//!
+//! Like the Prometheus aggregates, durations are added as activity records
+//! come in and metric values as ranges are evaluated, keyed by kernel name.
+//! Histograms show the spread and outliers of a kernel that launched millions
+//! of times, which means and sums hide.
+
+use crate::prometheus::MAX_KERNEL_NAMES;
+use crate::top_kernels::OCCUPANCY_METRIC;
+use crate::trace_emitter::demangle;
+use cupti_profiler::RangeInfo;
+use once_cell::sync::Lazy;
+use std::{collections::HashMap, fmt::Write as _, sync::Mutex};
+
+/// Metrics that get a histogram, all in percent.
+pub const HISTOGRAM_METRICS: &[&str] = &[
+    "sm__throughput.avg.pct_of_peak_sustained_elapsed",
+    "gpu__compute_memory_throughput.avg.pct_of_peak_sustained_elapsed",
+    OCCUPANCY_METRIC,
+];
+
+/// Width of the buckets of the percent metrics.
+const PCT_BUCKET_WIDTH: f64 = 10.0;
+
+/// Label of the kernels past `MAX_KERNEL_NAMES`.
+const OTHER_KERNELS: &str = "other";
+
+/// Longest bar of a bucket, for the bucket with the most values.
+const BAR_WIDTH: u64 = 40;
+
+/// How values map to buckets.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Buckets {
+    /// Bucket `i` holds values in `[2^i, 2^(i+1))`, and the first one also
+    /// those below 1.
+    Log2,
+    /// Bucket `i` holds values in `[i * width, (i + 1) * width)`.
+    Linear(f64),
+}
+
+impl Buckets {
+    fn index(self, value: f64) -> usize {
+        let index = match self {
+            Buckets::Log2 => value.max(1.0).log2(),
+            Buckets::Linear(width) => value.max(0.0) / width,
+        };
+        index as usize
+    }
+
+    /// Lower and upper bound of bucket `index`.
+    fn bounds(self, index: usize) -> (f64, f64) {
+        match self {
+            Buckets::Log2 if index == 0 => (0.0, 2.0),
+            Buckets::Log2 => (2f64.powi(index as i32), 2f64.powi(index as i32 + 1)),
+            Buckets::Linear(width) => (index as f64 * width, (index + 1) as f64 * width),
+        }
+    }
+}
+
+/// Counts of values per bucket, with their minimum, maximum and sum.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    buckets: Buckets,
+    counts: Vec<u64>,
+    pub count: u64,
+    pub sum: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Histogram {
+    pub fn new(buckets: Buckets) -> Self {
+        Self {
+            buckets,
+            counts: Vec::new(),
+            count: 0,
+            sum: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    pub fn add(&mut self, value: f64) {
+        let index = self.buckets.index(value);
+        if index >= self.counts.len() {
+            self.counts.resize(index + 1, 0);
+        }
+        self.counts[index] += 1;
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.sum / self.count as f64
+    }
+
+    /// Upper bound of the bucket that holds the `quantile` of the values,
+    /// within their minimum and maximum.
+    pub fn quantile(&self, quantile: f64) -> f64 {
+        let rank = (quantile * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (index, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return self.buckets.bounds(index).1.clamp(self.min, self.max);
+            }
+        }
+        self.max
+    }
+
+    /// Appends the statistics under `name`, then a line per bucket from the
+    /// first to the last that has values, with bounds formatted by `format`.
+    fn format(&self, out: &mut String, name: &str, format: impl Fn(f64) -> String) {
+        let _ = writeln!(
+            out,
+            "  {}: min {} mean {} p50 {} p90 {} p99 {} max {}",
+            name,
+            format(self.min),
+            format(self.mean()),
+            format(self.quantile(0.5)),
+            format(self.quantile(0.9)),
+            format(self.quantile(0.99)),
+            format(self.max)
+        );
+        let Some(first) = self.counts.iter().position(|&count| count > 0) else {
+            return;
+        };
+        let most = self.counts.iter().copied().max().unwrap_or(1);
+        for (index, &count) in self.counts.iter().enumerate().skip(first) {
+            let (lower, upper) = self.buckets.bounds(index);
+            let bar = (count * BAR_WIDTH).div_ceil(most) as usize;
+            let _ = writeln!(
+                out,
+                "    [{:>9}, {:>9}) {:>10} {}",
+                format(lower),
+                format(upper),
+                count,
+                "#".repeat(bar)
+            );
+        }
+    }
+}
+
+/// Formats nanoseconds in the largest unit they have a whole one of.
+fn format_ns(ns: f64) -> String {
+    if ns >= 1e9 {
+        format!("{:.1}s", ns / 1e9)
+    } else if ns >= 1e6 {
+        format!("{:.1}ms", ns / 1e6)
+    } else if ns >= 1e3 {
+        format!("{:.1}us", ns / 1e3)
+    } else {
+        format!("{}ns", ns)
+    }
+}
+
+fn format_pct(pct: f64) -> String {
+    format!("{:.1}%", pct)
+}
+
+#[derive(Debug)]
+struct KernelHistograms {
+    /// Durations, in nanoseconds.
+    duration: Histogram,
+    /// Values of each of the `HISTOGRAM_METRICS` the kernel was profiled
+    /// with, in the order first seen.
+    metrics: Vec<(&'static str, Histogram)>,
+}
+
+impl Default for KernelHistograms {
+    fn default() -> Self {
+        Self {
+            duration: Histogram::new(Buckets::Log2),
+            metrics: Vec::new(),
+        }
+    }
+}
+
+/// Per-kernel histograms since collection was started.
+#[derive(Debug, Default)]
+pub struct Histograms {
+    kernels: HashMap<String, KernelHistograms>,
+}
+
+impl Histograms {
+    fn kernel(&mut self, name: &str) -> &mut KernelHistograms {
+        let name = if self.kernels.contains_key(name) || self.kernels.len() < MAX_KERNEL_NAMES {
+            name
+        } else {
+            OTHER_KERNELS
+        };
+        self.kernels.entry(name.to_string()).or_default()
+    }
+
+    /// Adds a kernel that ran for `duration` nanoseconds.
+    pub fn add_kernel(&mut self, name: &str, duration: u64) {
+        self.kernel(name).duration.add(duration as f64);
+    }
+
+    /// Adds the values of the `HISTOGRAM_METRICS` of evaluated ranges to the
+    /// kernels they profiled.
+    pub fn add_ranges(&mut self, ranges: &[RangeInfo]) {
+        for range in ranges {
+            let histograms = self.kernel(range.leaf_name());
+            for metric in &range.metric_and_values {
+                let Some(&name) = HISTOGRAM_METRICS
+                    .iter()
+                    .find(|&&name| name == metric.metric_name)
+                else {
+                    continue;
+                };
+                if !metric.value.is_finite() {
+                    continue;
+                }
+                match histograms.metrics.iter_mut().find(|(n, _)| *n == name) {
+                    Some((_, histogram)) => histogram.add(metric.value),
+                    None => {
+                        let mut histogram = Histogram::new(Buckets::Linear(PCT_BUCKET_WIDTH));
+                        histogram.add(metric.value);
+                        histograms.metrics.push((name, histogram));
+                    }
+                }
+            }
+        }
+    }
+
+    /// One summary per kernel that ran, most GPU time first: its launch
+    /// count, then the duration histogram and that of each metric.
+    pub fn summaries(&self) -> Vec<String> {
+        let mut kernels: Vec<(&String, &KernelHistograms)> = self
+            .kernels
+            .iter()
+            .filter(|(_, histograms)| histograms.duration.count > 0)
+            .collect();
+        kernels.sort_by(|a, b| {
+            b.1.duration
+                .sum
+                .total_cmp(&a.1.duration.sum)
+                .then_with(|| a.0.cmp(b.0))
+        });
+        kernels
+            .into_iter()
+            .map(|(name, histograms)| {
+                let mut out = String::new();
+                let _ = writeln!(
+                    out,
+                    "{}: {} launches, {} in total",
+                    demangle(name),
+                    histograms.duration.count,
+                    format_ns(histograms.duration.sum)
+                );
+                histograms.duration.format(&mut out, "duration", format_ns);
+                for (metric, histogram) in &histograms.metrics {
+                    histogram.format(&mut out, metric, format_pct);
+                }
+                out
+            })
+            .collect()
+    }
+}
+
+/// Histograms written at exit, `None` unless collection was started.
+static HISTOGRAMS: Lazy<Mutex<Option<Histograms>>> = Lazy::new(|| Mutex::new(None));
+
+/// Adds a completed kernel, if histograms are collected.
+pub fn record_kernel(name: &str, duration: u64) {
+    if let Ok(mut histograms) = HISTOGRAMS.lock() {
+        if let Some(histograms) = histograms.as_mut() {
+            histograms.add_kernel(name, duration);
+        }
+    }
+}
+
+/// Adds evaluated ranges, if histograms are collected.
+pub fn record_ranges(ranges: &[RangeInfo]) {
+    if let Ok(mut histograms) = HISTOGRAMS.lock() {
+        if let Some(histograms) = histograms.as_mut() {
+            histograms.add_ranges(ranges);
+        }
+    }
+}
+
+/// Starts collecting histograms.
+pub fn collect() {
+    if let Ok(mut histograms) = HISTOGRAMS.lock() {
+        histograms.get_or_insert_with(Histograms::default);
+    }
+}
+
+/// The per-kernel summaries so far, see `Histograms::summaries`, or none if
+/// histograms are not collected.
+pub fn summaries() -> Vec<String> {
+    HISTOGRAMS
+        .lock()
+        .ok()
+        .and_then(|histograms| histograms.as_ref().map(Histograms::summaries))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cupti_profiler::MetricValuePair;
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::new(Buckets::Log2);
+        for value in [0.5, 3.0, 3.5, 5.0, 1000.0] {
+            histogram.add(value);
+        }
+        assert_eq!(histogram.counts, [1, 2, 1, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(histogram.count, 5);
+        assert_eq!(histogram.min, 0.5);
+        assert_eq!(histogram.max, 1000.0);
+        assert_eq!(histogram.quantile(0.0), 2.0);
+        assert_eq!(histogram.quantile(0.5), 4.0);
+        assert_eq!(histogram.quantile(0.8), 8.0);
+        // The last bucket's upper bound is past the largest value.
+        assert_eq!(histogram.quantile(1.0), 1000.0);
+
+        let mut histogram = Histogram::new(Buckets::Linear(10.0));
+        histogram.add(100.0);
+        histogram.add(-1.0);
+        assert_eq!(histogram.counts.len(), 11);
+        assert_eq!((histogram.counts[0], histogram.counts[10]), (1, 1));
+    }
+
+    #[test]
+    fn test_summaries() {
+        let mut histograms = Histograms::default();
+        histograms.add_kernel("copy", 100);
+        for duration in [1_100, 1_500, 3_000] {
+            histograms.add_kernel("_Z5scalePf", duration);
+        }
+        let range = |value| RangeInfo {
+            range_name: "_Z5scalePf".to_string(),
+            parent_ranges: Vec::new(),
+            depth: 0,
+            metric_and_values: vec![
+                MetricValuePair {
+                    metric_name: OCCUPANCY_METRIC.to_string(),
+                    value,
+                },
+                MetricValuePair {
+                    metric_name: "sm__cycles_elapsed.avg".to_string(),
+                    value: 1.0,
+                },
+            ],
+        };
+        histograms.add_ranges(&[range(45.0), range(f64::NAN), range(62.5)]);
+        let summaries = histograms.summaries();
+        assert_eq!(summaries.len(), 2);
+        let lines: Vec<&str> = summaries[0].lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "scale(float*): 3 launches, 5.6us in total",
+                "  duration: min 1.1us mean 1.9us p50 2.0us p90 3.0us p99 3.0us max 3.0us",
+                "    [    1.0us,     2.0us)          2 ########################################",
+                "    [    2.0us,     4.1us)          1 ####################",
+                "  sm__warps_active.avg.pct_of_peak_sustained_active: min 45.0% mean 53.8% p50 50.0% p90 62.5% p99 62.5% max 62.5%",
+                "    [    40.0%,     50.0%)          1 ########################################",
+                "    [    50.0%,     60.0%)          0 ",
+                "    [    60.0%,     70.0%)          1 ########################################",
+            ]
+        );
+        assert!(summaries[1].starts_with("copy: 1 launches, 100ns in total\n"));
+    }
+}
@@ -0,0 +1,80 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Minimal HTTP server for the endpoints the library embeds.
+//!
+//! Only `GET` requests are answered, one at a time and each on a connection
+//! of its own, which is all scrapers and browsers need.
+
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    thread,
+    time::Duration,
+};
+
+/// How long a client may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Content type and body of a page.
+pub type Page = (&'static str, String);
+
+/// Answers one request with the page `route` returns for its path.
+fn serve(stream: TcpStream, route: fn(&str) -> Option<Page>) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // The headers are not needed, but are read so the client sees the whole
+    // request consumed.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    let mut parts = request_line.split_whitespace();
+    let page = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(path)) => route(path),
+        _ => None,
+    };
+    let (status, (content_type, body)) = match page {
+        Some(page) => ("200 OK", page),
+        None => ("404 Not Found", ("text/plain", "Not found\n".to_string())),
+    };
+    let mut stream = reader.into_inner();
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// Serves the pages `route` returns on `addr`, from a thread called `name`.
+/// Returns the address bound, which has the port picked when `addr` asks
+/// for port 0.
+pub fn start(addr: &str, name: &str, route: fn(&str) -> Option<Page>) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    thread::Builder::new()
+        .name(name.to_string())
+        .spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let _ = serve(stream, route);
+            }
+        })?;
+    Ok(local_addr)
+}
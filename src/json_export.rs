@@ -0,0 +1,571 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! JSON export of the kernels written to the trace.
+//!
+//! By default each kernel is one line holding its timestamp, duration, the
+//! extra data of its render stage event and its metric values, so runs can be
+//! compared by tools that don't read Perfetto traces. The Chrome format
+//! writes the same kernels as complete events that chrome://tracing loads,
+//! with one thread per context, named like `contexts::name`. The ncu CSV format writes one row per kernel
+//! with the columns of `ncu --csv --page raw`, so Nsight Compute
+//! post-processing scripts can read it.
+
+use crate::contexts;
+use cupti_profiler::MetricValuePair;
+use std::{
+    fmt::Write as _,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+/// Layout of the JSON export.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JsonFormat {
+    /// One object per kernel and line.
+    Lines,
+    /// A Chrome trace event array.
+    Chrome,
+    /// CSV laid out like `ncu --csv --page raw`.
+    NcuCsv,
+}
+
+impl JsonFormat {
+    /// Parses an `INJECTION_JSON_FORMAT` value.
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "lines" | "jsonl" => Some(JsonFormat::Lines),
+            "chrome" => Some(JsonFormat::Chrome),
+            "csv" | "ncu-csv" => Some(JsonFormat::NcuCsv),
+            _ => None,
+        }
+    }
+}
+
+/// Appends `s` to `out` as a JSON string.
+pub(crate) fn append_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Appends the metric values as object members. Values that JSON can't
+/// represent, such as NaN, are written as `null`.
+fn append_metrics(out: &mut String, metrics: &[MetricValuePair]) {
+    for (i, metric) in metrics.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        append_string(out, &metric.metric_name);
+        if metric.value.is_finite() {
+            let _ = write!(out, ":{}", metric.value);
+        } else {
+            out.push_str(":null");
+        }
+    }
+}
+
+/// Appends the extra data as object members with string values.
+fn append_extra_data(out: &mut String, extra_data: &[(&str, String)]) {
+    for (i, (name, value)) in extra_data.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        append_string(out, name);
+        out.push(':');
+        append_string(out, value);
+    }
+}
+
+/// Formats nanoseconds as the microseconds of Chrome trace events.
+fn micros(ns: u64) -> String {
+    format!("{}.{:03}", ns / 1000, ns % 1000)
+}
+
+/// Formats one kernel as a single line JSON object.
+pub fn kernel_json(
+    ctx_id: u32,
+    timestamp: u64,
+    duration: u64,
+    extra_data: &[(&str, String)],
+    metrics: &[MetricValuePair],
+) -> String {
+    let mut out = format!(
+        "{{\"ctx_id\":{},\"timestamp\":{},\"duration\":{},\"extra_data\":{{",
+        ctx_id, timestamp, duration
+    );
+    append_extra_data(&mut out, extra_data);
+    out.push_str("},\"metrics\":{");
+    append_metrics(&mut out, metrics);
+    out.push_str("}}");
+    out
+}
+
+/// Formats one kernel as a Chrome trace complete event on the thread of its
+/// context, with the extra data and metric values as arguments.
+pub fn chrome_event_json(
+    pid: u32,
+    ctx_id: u32,
+    timestamp: u64,
+    duration: u64,
+    extra_data: &[(&str, String)],
+    metrics: &[MetricValuePair],
+) -> String {
+    let value = |key| extra_data.iter().find(|(name, _)| *name == key);
+    let name = value("kernel_demangled_name")
+        .or_else(|| value("kernel_name"))
+        .map_or("", |(_, value)| value.as_str());
+    let mut out = String::from("{\"name\":");
+    append_string(&mut out, name);
+    let _ = write!(
+        out,
+        ",\"cat\":\"kernel\",\"ph\":\"X\",\"ts\":{},\"dur\":{},\"pid\":{},\"tid\":{},\"args\":{{",
+        micros(timestamp),
+        micros(duration),
+        pid,
+        ctx_id
+    );
+    append_extra_data(&mut out, extra_data);
+    if !extra_data.is_empty() && !metrics.is_empty() {
+        out.push(',');
+    }
+    append_metrics(&mut out, metrics);
+    out.push_str("}}");
+    out
+}
+
+/// Formats the metadata event that names the thread of a context `name`.
+fn chrome_thread_name_json(pid: u32, ctx_id: u32, name: &str) -> String {
+    let mut out = format!(
+        "{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":{},\"tid\":{},\"args\":{{\"name\":",
+        pid, ctx_id
+    );
+    append_string(&mut out, name);
+    out.push_str("}}");
+    out
+}
+
+/// Columns of `ncu --csv --page raw` ahead of the metric columns.
+const NCU_COLUMNS: &[&str] = &[
+    "ID",
+    "Process ID",
+    "Process Name",
+    "Host Name",
+    "Kernel Name",
+    "Context",
+    "Stream",
+    "Block Size",
+    "Grid Size",
+    "Device",
+    "CC",
+];
+
+/// Formats fields as a CSV row with every field quoted, like ncu does.
+fn csv_row<'a>(fields: impl IntoIterator<Item = &'a str>) -> String {
+    let fields: Vec<String> = fields
+        .into_iter()
+        .map(|field| format!("\"{}\"", field.replace('"', "\"\"")))
+        .collect();
+    fields.join(",")
+}
+
+/// Formats the header rows of the ncu CSV format: the column names, then
+/// the units. Units are not known here and left empty, which keeps the rows
+/// where scripts expect them.
+pub fn ncu_csv_header(metric_names: &[String]) -> String {
+    let names = NCU_COLUMNS
+        .iter()
+        .copied()
+        .chain(metric_names.iter().map(String::as_str));
+    let units = std::iter::repeat_n("", NCU_COLUMNS.len() + metric_names.len());
+    format!("{}\n{}", csv_row(names), csv_row(units))
+}
+
+/// Formats one kernel, launched on the stream with ID `stream_id`, as a row of
+/// the ncu CSV format, with a column for each of `metric_names`. Metrics the
+/// kernel lacks or that have no value are `n/a`, as in ncu.
+pub fn ncu_csv_row(
+    id: u64,
+    ctx_id: u32,
+    stream_id: u32,
+    host_name: &str,
+    extra_data: &[(&str, String)],
+    metric_names: &[String],
+    metrics: &[MetricValuePair],
+) -> String {
+    let value = |key| {
+        extra_data
+            .iter()
+            .find(|(name, _)| *name == key)
+            .map_or("", |(_, value)| value.as_str())
+    };
+    let dims = |keys: [&'static str; 3]| {
+        let [x, y, z] = keys.map(value);
+        if x.is_empty() {
+            return String::new();
+        }
+        format!("({}, {}, {})", x, y, z)
+    };
+    // `arch` is `CC_<major><minor>`, ncu writes `<major>.<minor>`.
+    let cc = value("arch")
+        .strip_prefix("CC_")
+        .map_or(String::new(), |cc| {
+            let (major, minor) = cc.split_at(cc.len().saturating_sub(1));
+            format!("{}.{}", major, minor)
+        });
+    let name = match value("kernel_demangled_name") {
+        "" => value("kernel_name"),
+        name => name,
+    };
+    let metric_values: Vec<String> = metric_names
+        .iter()
+        .map(|metric_name| {
+            metrics
+                .iter()
+                .find(|m| m.metric_name == *metric_name)
+                .filter(|m| m.value.is_finite())
+                .map_or("n/a".to_string(), |m| m.value.to_string())
+        })
+        .collect();
+    let (id, ctx_id, stream_id) = (id.to_string(), ctx_id.to_string(), stream_id.to_string());
+    let block = dims([
+        "launch__block_size_x",
+        "launch__block_size_y",
+        "launch__block_size_z",
+    ]);
+    let grid = dims([
+        "launch__grid_size_x",
+        "launch__grid_size_y",
+        "launch__grid_size_z",
+    ]);
+    let fields = [
+        id.as_str(),
+        value("process_id"),
+        value("process_name"),
+        host_name,
+        name,
+        ctx_id.as_str(),
+        stream_id.as_str(),
+        block.as_str(),
+        grid.as_str(),
+        value("device_name"),
+        cc.as_str(),
+    ];
+    csv_row(
+        fields
+            .into_iter()
+            .chain(metric_values.iter().map(String::as_str)),
+    )
+}
+
+/// Name of the host, as ncu reports it.
+fn host_name() -> String {
+    let mut buf = [0u8; 256];
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if ret != 0 {
+        return String::new();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+/// File the kernels are written to, as they are written to the trace.
+pub struct JsonExport {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    format: JsonFormat,
+    pid: u32,
+    /// Contexts whose thread has been named, in the Chrome format.
+    named_contexts: Vec<u32>,
+    /// Whether an event was written, so the next one needs a separator.
+    wrote_event: bool,
+    /// Metric columns, in the ncu CSV format.
+    metric_names: Vec<String>,
+    /// Whether the header rows were written, which fixes the metric columns,
+    /// in the ncu CSV format.
+    wrote_header: bool,
+    /// ID of the next kernel, in the ncu CSV format.
+    next_id: u64,
+    host_name: String,
+    /// Whether the event array was closed, in the Chrome format.
+    finished: bool,
+}
+
+impl JsonExport {
+    /// Creates or truncates the file at `path`. The ncu CSV format has a
+    /// column for each of `metric_names`, unless `set_metric_names` changes
+    /// them before the first kernel is written.
+    pub fn create(path: &Path, format: JsonFormat, metric_names: &[String]) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        if format == JsonFormat::Chrome {
+            writer.write_all(b"[\n")?;
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            writer,
+            format,
+            pid: std::process::id(),
+            named_contexts: Vec::new(),
+            wrote_event: false,
+            metric_names: metric_names.to_vec(),
+            wrote_header: false,
+            next_id: 0,
+            host_name: if format == JsonFormat::NcuCsv {
+                host_name()
+            } else {
+                String::new()
+            },
+            finished: false,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Sets the metric columns of the ncu CSV format to the metrics that are
+    /// collected. Once the first kernel is written they stay as they are.
+    pub fn set_metric_names(&mut self, metric_names: &[String]) {
+        if !self.wrote_header {
+            self.metric_names = metric_names.to_vec();
+        }
+    }
+
+    /// Writes the header rows of the ncu CSV format, if not done yet.
+    fn write_header(&mut self) -> io::Result<()> {
+        if self.format != JsonFormat::NcuCsv || std::mem::replace(&mut self.wrote_header, true) {
+            return Ok(());
+        }
+        writeln!(self.writer, "{}", ncu_csv_header(&self.metric_names))
+    }
+
+    /// Appends a kernel launched on the stream with ID `stream_id`, see
+    /// `kernel_json`, `chrome_event_json` and `ncu_csv_row`.
+    pub fn write_kernel(
+        &mut self,
+        ctx_id: u32,
+        stream_id: u32,
+        timestamp: u64,
+        duration: u64,
+        extra_data: &[(&str, String)],
+        metrics: &[MetricValuePair],
+    ) -> io::Result<()> {
+        match self.format {
+            JsonFormat::Lines => {
+                let line = kernel_json(ctx_id, timestamp, duration, extra_data, metrics);
+                writeln!(self.writer, "{}", line)
+            }
+            JsonFormat::Chrome => {
+                if !self.named_contexts.contains(&ctx_id) {
+                    self.named_contexts.push(ctx_id);
+                    let name = contexts::name(ctx_id);
+                    self.write_event(&chrome_thread_name_json(self.pid, ctx_id, &name))?;
+                }
+                let event =
+                    chrome_event_json(self.pid, ctx_id, timestamp, duration, extra_data, metrics);
+                self.write_event(&event)
+            }
+            JsonFormat::NcuCsv => {
+                self.write_header()?;
+                let row = ncu_csv_row(
+                    self.next_id,
+                    ctx_id,
+                    stream_id,
+                    &self.host_name,
+                    extra_data,
+                    &self.metric_names,
+                    metrics,
+                );
+                self.next_id += 1;
+                writeln!(self.writer, "{}", row)
+            }
+        }
+    }
+
+    fn write_event(&mut self, event: &str) -> io::Result<()> {
+        if self.wrote_event {
+            self.writer.write_all(b",\n")?;
+        }
+        self.wrote_event = true;
+        self.writer.write_all(event.as_bytes())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Completes the file once nothing more is exported. Chrome trace
+    /// viewers also load an event array that was never closed, in case the
+    /// process doesn't get this far. Only the first call completes it.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.write_header()?;
+        if self.format == JsonFormat::Chrome && !std::mem::replace(&mut self.finished, true) {
+            self.writer.write_all(b"\n]\n")?;
+        }
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kernel_json() {
+        let extra_data = [
+            ("kernel_name", "_Z5scalePf".to_string()),
+            ("kernel_demangled_name", "scale<\"a\\b\">\t".to_string()),
+        ];
+        let metrics = [
+            MetricValuePair {
+                metric_name: "gpu__time_duration.sum".to_string(),
+                value: 1500.0,
+            },
+            MetricValuePair {
+                metric_name: "sm__throughput.avg.pct_of_peak_sustained_elapsed".to_string(),
+                value: f64::NAN,
+            },
+        ];
+        assert_eq!(
+            kernel_json(3, 100, 1500, &extra_data, &metrics),
+            concat!(
+                r#"{"ctx_id":3,"timestamp":100,"duration":1500,"extra_data":{"#,
+                r#""kernel_name":"_Z5scalePf","#,
+                r#""kernel_demangled_name":"scale<\"a\\b\">\t"},"#,
+                r#""metrics":{"gpu__time_duration.sum":1500,"#,
+                r#""sm__throughput.avg.pct_of_peak_sustained_elapsed":null}}"#,
+            )
+        );
+        assert_eq!(
+            kernel_json(1, 0, 0, &[("k", "\u{1}".to_string())], &[]),
+            r#"{"ctx_id":1,"timestamp":0,"duration":0,"extra_data":{"k":"\u0001"},"metrics":{}}"#
+        );
+    }
+
+    #[test]
+    fn test_chrome_export() {
+        let path = std::env::temp_dir().join(format!("chrome-{}.json", std::process::id()));
+        let mut export = JsonExport::create(&path, JsonFormat::Chrome, &[]).unwrap();
+        let extra_data = [("kernel_name", "_Z5scalePf".to_string())];
+        let metrics = [MetricValuePair {
+            metric_name: "sm__cycles".to_string(),
+            value: 42.0,
+        }];
+        export
+            .write_kernel(2, 7, 1_500_250, 2_000, &extra_data, &metrics)
+            .unwrap();
+        export.write_kernel(2, 7, 1_600_000, 7, &[], &[]).unwrap();
+        export.finish().unwrap();
+        // Finishing again leaves the completed file as it is.
+        export.finish().unwrap();
+        let json = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let pid = std::process::id();
+        assert_eq!(
+            json,
+            format!(
+                concat!(
+                    "[\n",
+                    r#"{{"name":"thread_name","ph":"M","pid":{0},"tid":2,"args":{{"name":"ctx 2"}}}},"#,
+                    "\n",
+                    r#"{{"name":"_Z5scalePf","cat":"kernel","ph":"X","ts":1500.250,"dur":2.000,"pid":{0},"tid":2,"#,
+                    r#""args":{{"kernel_name":"_Z5scalePf","sm__cycles":42}}}},"#,
+                    "\n",
+                    r#"{{"name":"","cat":"kernel","ph":"X","ts":1600.000,"dur":0.007,"pid":{0},"tid":2,"args":{{}}}}"#,
+                    "\n]\n",
+                ),
+                pid
+            )
+        );
+        assert_eq!(JsonFormat::parse(" Chrome "), Some(JsonFormat::Chrome));
+        assert_eq!(JsonFormat::parse("jsonl"), Some(JsonFormat::Lines));
+        assert_eq!(JsonFormat::parse("xml"), None);
+    }
+
+    #[test]
+    fn test_ncu_csv_export() {
+        let path = std::env::temp_dir().join(format!("ncu-{}.csv", std::process::id()));
+        let metric_names = [
+            "gpu__time_duration.sum".to_string(),
+            "sm__throughput.avg.pct_of_peak_sustained_elapsed".to_string(),
+        ];
+        let mut export = JsonExport::create(&path, JsonFormat::NcuCsv, &[]).unwrap();
+        // The columns are those of the metrics collected by the time the
+        // first kernel is written.
+        export.set_metric_names(&metric_names);
+        let extra_data = [
+            ("kernel_name", "_Z5scalePf".to_string()),
+            ("kernel_demangled_name", "scale(\"float*\")".to_string()),
+            ("process_id", "42".to_string()),
+            ("process_name", "app".to_string()),
+            ("arch", "CC_90".to_string()),
+            ("device_name", "NVIDIA H100".to_string()),
+            ("launch__grid_size_x", "4".to_string()),
+            ("launch__grid_size_y", "2".to_string()),
+            ("launch__grid_size_z", "1".to_string()),
+            ("launch__block_size_x", "256".to_string()),
+            ("launch__block_size_y", "1".to_string()),
+            ("launch__block_size_z", "1".to_string()),
+        ];
+        let metrics = [MetricValuePair {
+            metric_name: "gpu__time_duration.sum".to_string(),
+            value: 1536.0,
+        }];
+        export
+            .write_kernel(3, 7, 100, 1500, &extra_data, &metrics)
+            .unwrap();
+        export.set_metric_names(&[]);
+        export.write_kernel(3, 8, 200, 10, &[], &[]).unwrap();
+        export.finish().unwrap();
+        let csv = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(
+            lines[0],
+            concat!(
+                r#""ID","Process ID","Process Name","Host Name","Kernel Name","Context","Stream","#,
+                r#""Block Size","Grid Size","Device","CC","gpu__time_duration.sum","#,
+                r#""sm__throughput.avg.pct_of_peak_sustained_elapsed""#,
+            )
+        );
+        assert_eq!(lines[1], r#""","","","","","","","","","","","","""#);
+        let host = host_name();
+        assert_eq!(
+            lines[2],
+            format!(
+                r#""0","42","app","{}","scale(""float*"")","3","7","(256, 1, 1)","(4, 2, 1)","NVIDIA H100","9.0","1536","n/a""#,
+                host
+            )
+        );
+        assert_eq!(
+            lines[3],
+            format!(r#""1","","","{}","","3","8","","","","","n/a","n/a""#, host)
+        );
+        assert_eq!(JsonFormat::parse("ncu-csv"), Some(JsonFormat::NcuCsv));
+    }
+}
@@ -12,253 +12,1016 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub mod aggregate;
+pub mod anomaly;
+pub mod baseline;
 pub mod callbacks;
+pub mod clocks;
 pub mod config;
+pub mod contexts;
+pub mod control;
+pub mod copies;
+pub mod energy;
+pub mod environment;
+pub mod expressions;
+pub mod flush;
+pub mod histograms;
+pub mod http;
+pub mod json_export;
+pub mod library_calls;
+pub mod memory_pools;
+pub mod merge;
 pub mod metrics;
+pub mod nvml;
+pub mod overhead;
+pub mod prometheus;
+pub mod signals;
+#[cfg(feature = "tracing-spans")]
+pub mod spans;
+pub mod spill;
 pub mod state;
+pub mod status;
+pub mod streams;
+pub mod summary;
+pub mod top_kernels;
+pub mod trace_emitter;
 pub mod tracing;
+pub mod worker;
 
+use aggregate::{Aggregate, Aggregator, KernelSignature};
+use anomaly::Rule;
+use baseline::Baseline;
 use callbacks::{buffer_completed, buffer_requested, profiler_callback_handler};
 use config::Config;
-use state::GLOBAL_STATE;
-use tracing::{get_data_source, get_next_event_id, GOT_FIRST_COUNTERS};
+use expressions::Expression;
+use json_export::JsonExport;
+use memory_pools::PoolSample;
+use overhead::{OverheadTracker, StatsSample};
+use state::{
+    lock_context, lock_global_state, try_lock_context, try_lock_global_state, CtxProfilerData,
+    GlobalState, KernelActivity, KernelLaunch, CONTEXT_DATA,
+};
+use tracing::{get_data_source, get_render_stages_data_source, trace_time_ns, TraceContext};
 
-use cpp_demangle::Symbol;
 use cupti_profiler as profiler;
 use cupti_profiler::bindings::*;
-use perfetto_sdk::{
-    data_source::TraceContext,
-    producer::{Backends, Producer, ProducerInitArgsBuilder},
-    protos::{common::builtin_clock::BuiltinClock, trace::trace_packet::TracePacket},
+use cupti_profiler::{MetricValuePair, RangeInfo};
+use perfetto_sdk::producer::{Backends, Producer, ProducerInitArgsBuilder};
+use std::{
+    collections::HashMap,
+    panic, ptr,
+    sync::{Arc, MutexGuard},
+    thread,
+    time::{Duration, Instant},
 };
-use perfetto_sdk_protos_gpu::protos::{
-    common::gpu_counter_descriptor::{
-        GpuCounterDescriptor, GpuCounterDescriptorGpuCounterGroup, GpuCounterSpec,
-    },
-    trace::{
-        gpu::{
-            gpu_counter_event::{GpuCounter, GpuCounterEvent},
-            gpu_render_stage_event::{Description, ExtraData, GpuRenderStageEvent, Specifications},
-        },
-        trace_packet::TracePacketExt,
-    },
+use trace_emitter::{
+    achieved_occupancy, build_cache_data, build_dram_data, build_external_id_data,
+    build_extra_data, build_flops_data, build_ipc_data, build_roofline_data, build_smem_data,
+    build_sol_data, build_stall_data, build_tuning_data, derived_counters, dram_bw_pct_of_peak,
+    emit_counter_descriptor, emit_counters, emit_data_loss, emit_info, emit_memory_pool_descriptor,
+    emit_memory_pool_sample, emit_range_loss, emit_render_stage_event, emit_stats,
+    emit_stats_descriptor, emit_warning, place_on_queue, DeviceProperties, ExtraDataCache,
+    FlopCounts, FunctionProperties, FunctionPropertiesCache, ProcessInfo, ACHIEVED_OCCUPANCY,
+    COPY_STAGE_ID, DRAM_BW_PCT_OF_PEAK, DURATION_METRIC, KERNEL_STAGE_ID,
 };
-use std::{panic, ptr, sync::atomic::Ordering};
 
-extern "C" fn end_execution() {
-    let _ = panic::catch_unwind(|| {
-        let _ = profiler::activity_flush_all(0);
-        let process_id = unsafe { libc::getpid() };
-        let process_name = std::fs::read_to_string("/proc/self/comm")
-            .unwrap_or_else(|_| "unknown".to_string())
-            .trim_end_matches('\n')
-            .to_owned();
-        let mut state = match GLOBAL_STATE.lock() {
-            Ok(s) => s,
-            Err(_) => return,
+/// Signals that end the process after a crash, on which what was collected
+/// is written out first.
+const CRASH_SIGNALS: [i32; 5] = [
+    libc::SIGABRT,
+    libc::SIGSEGV,
+    libc::SIGBUS,
+    libc::SIGFPE,
+    libc::SIGILL,
+];
+
+/// How long a crashing process waits for what was collected to be written,
+/// which includes the flush of `INJECTION_TRACE_FILE`.
+const CRASH_FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Writes kernels of one context to the trace, and to the JSON export if
+/// given.
+struct KernelEmitter<'a> {
+    process: &'a ProcessInfo,
+    device_id: CUdevice,
+    device: DeviceProperties,
+    /// Name of the device, e.g. "NVIDIA H100 80GB HBM3".
+    device_name: String,
+    extra_data_cache: ExtraDataCache,
+    function_properties: FunctionPropertiesCache,
+    baseline: Option<Arc<Baseline>>,
+    anomaly_rules: &'a [Rule],
+    /// Values computed from the metrics of profiled kernels.
+    expressions: &'a [Expression],
+    writer: KernelWriter<'a>,
+    /// End of the last kernel written on each stream, see
+    /// `CtxProfilerData::queue_ends`.
+    queue_ends: HashMap<u32, u64>,
+    /// Launches aggregated before they are written, if aggregating.
+    aggregator: Option<Aggregator>,
+    /// Whether metric values are raw counters, written under their
+    /// configured names with nothing derived from them.
+    raw_counters: bool,
+}
+
+/// Metric and derived counter values of a profiled kernel.
+type Counters<'a> = (&'a [MetricValuePair], &'a [(&'static str, f64)]);
+
+/// Where a kernel goes in the trace.
+#[derive(Clone, Copy)]
+struct Slice {
+    /// CUPTI ID of the stream, whose queue the kernel goes on.
+    stream_id: u32,
+    timestamp: u64,
+    duration: u64,
+}
+
+/// Where `KernelEmitter` writes kernels to, apart from the trace.
+struct KernelWriter<'a> {
+    ctx_id: u32,
+    /// Description of the queues of the context's streams, e.g.
+    /// "GPU 0: NVIDIA H100 80GB HBM3".
+    device: String,
+    json: Option<&'a mut JsonExport>,
+    verbose: bool,
+    /// Display names of counters in the UI, by counter name.
+    counter_aliases: &'a [(String, String)],
+    /// Expressions whose values are derived counters, none for raw
+    /// counters.
+    expressions: &'a [Expression],
+}
+
+impl KernelEmitter<'_> {
+    fn emit(
+        &mut self,
+        launch: &KernelLaunch,
+        activity: &KernelActivity,
+        range: Option<&RangeInfo>,
+    ) {
+        // Kernels launched outside a tracing session were not profiled and
+        // only have the duration of their activity.
+        let duration = match range {
+            Some(range) => {
+                let Some(duration) = range
+                    .metric_and_values
+                    .iter()
+                    .find(|metric| metric.metric_name == DURATION_METRIC)
+                else {
+                    return;
+                };
+                duration.value as u64
+            }
+            None => activity.duration,
         };
-        let metric_names = state.config.metrics.clone();
-        for (_, data) in state.context_data.iter_mut() {
-            if data.is_active {
-                if let Some(rp) = &mut data.range_profiler {
-                    let _ = rp.stop();
-                    let _ = rp.decode_counter_data();
-                    if let Some(me) = &data.metric_evaluator {
-                        if let Ok(infos) =
-                            me.evaluate_all_ranges(&data.counter_data_image, &metric_names)
-                        {
-                            data.range_info.extend(infos);
-                        }
-                    }
-                }
+        let queue_end = self.queue_ends.entry(activity.stream_id).or_default();
+        let timestamp = place_on_queue(queue_end, launch.timestamp, duration);
+        let (process, device, device_name) = (self.process, &self.device, &self.device_name);
+        let function =
+            self.function_properties
+                .get_or_query(launch.function, activity, || unsafe {
+                    FunctionProperties::query(launch.function, activity)
+                });
+        let extra_data = self
+            .extra_data_cache
+            .get_or_build(launch.function, activity, || {
+                build_extra_data(process, activity, device, device_name, &function)
+            });
+        // Regressions are flagged on the kernel itself, so they show up in
+        // the UI and the JSON export alike.
+        let regression = self.baseline.as_ref().and_then(|baseline| {
+            let (_, name) = extra_data
+                .iter()
+                .find(|(key, _)| *key == "kernel_demangled_name")?;
+            let pct = baseline.regression_pct(name, duration as f64)?;
+            Some((pct, baseline.kernels[name].duration))
+        });
+        let mut annotations = build_external_id_data(&activity.external_ids);
+        if let Some((pct, baseline_duration)) = regression {
+            annotations.push(("duration_vs_baseline_pct", format!("{:+.1}", pct)));
+            annotations.push(("baseline_duration", format!("{:.0}", baseline_duration)));
+        }
+        // Derived like Nsight Compute does, so nobody has to do the math,
+        // unless raw counters are wanted to do it oneself.
+        let derive_from = range.filter(|_| !self.raw_counters);
+        let occupancy =
+            derive_from.and_then(|range| achieved_occupancy(&range.metric_and_values, device));
+        let flops =
+            derive_from.and_then(|range| FlopCounts::estimate(&range.metric_and_values, device));
+        let mut derived: Vec<_> = occupancy
+            .map(|pct| (ACHIEVED_OCCUPANCY, pct))
+            .into_iter()
+            .collect();
+        if let Some(pct) = occupancy.filter(|pct| pct.is_finite()) {
+            annotations.push((ACHIEVED_OCCUPANCY, format!("{:.1}", pct)));
+        }
+        let dram_bw = derive_from
+            .and_then(|range| dram_bw_pct_of_peak(&range.metric_and_values, duration, device));
+        if let Some(pct) = dram_bw {
+            derived.push((DRAM_BW_PCT_OF_PEAK, pct));
+            annotations.push((DRAM_BW_PCT_OF_PEAK, format!("{:.1}", pct)));
+        }
+        annotations.extend(build_tuning_data(activity, device, &function, occupancy));
+        if let Some(mj) = energy::kernel_energy(self.device_id, timestamp, timestamp + duration) {
+            annotations.push(("energy_mj", format!("{:.3}", mj)));
+        }
+        if let Some(clocks) = clocks::kernel_clocks(self.device_id, timestamp, timestamp + duration)
+        {
+            annotations.push(("sm_clock_mhz", clocks.sm.to_string()));
+            annotations.push(("memory_clock_mhz", clocks.memory.to_string()));
+        }
+        if let Some(flops) = &flops {
+            derived.extend(flops.counters());
+            annotations.extend(build_flops_data(flops, duration, device));
+        }
+        if let Some(range) = derive_from {
+            for (name, value) in expressions::evaluate(self.expressions, &range.metric_and_values) {
+                derived.push((name, value));
+                annotations.push((name, value.to_string()));
             }
+            annotations.extend(build_ipc_data(&range.metric_and_values));
+            annotations.extend(build_sol_data(&range.metric_and_values));
+            annotations.extend(build_smem_data(&range.metric_and_values));
+            annotations.extend(build_cache_data(&range.metric_and_values));
+            annotations.extend(build_stall_data(&range.metric_and_values));
+            annotations.extend(build_dram_data(&range.metric_and_values, duration));
+            annotations.extend(build_roofline_data(
+                &range.metric_and_values,
+                duration,
+                device,
+            ));
         }
+        let raw_counters;
+        let metrics = match range {
+            Some(range) if self.raw_counters => {
+                raw_counters = metrics::raw_counter_values(&range.metric_and_values);
+                Some(&raw_counters[..])
+            }
+            range => range.map(|range| &range.metric_and_values[..]),
+        };
+        if let Some(anomaly) = anomaly::check(
+            self.anomaly_rules,
+            &activity.kernel_name,
+            duration,
+            extra_data.iter().chain(&annotations),
+            metrics.unwrap_or_default(),
+        ) {
+            annotations.push(("anomaly", anomaly));
+        }
+        let annotated;
+        let extra_data = if annotations.is_empty() {
+            extra_data
+        } else {
+            annotated = [extra_data, &annotations[..]].concat();
+            &annotated[..]
+        };
+        if let Some(aggregator) = &mut self.aggregator {
+            let signature = KernelSignature {
+                name: activity.kernel_name.clone(),
+                grid_size: activity.grid_size,
+                block_size: activity.block_size,
+                stream_id: activity.stream_id,
+            };
+            let closed = aggregator.add(
+                signature,
+                timestamp,
+                duration,
+                extra_data,
+                metrics.unwrap_or_default(),
+                &derived,
+            );
+            for aggregate in closed {
+                self.writer.write_aggregate(&aggregate);
+            }
+            return;
+        }
+        let range_name = range.map(|range| range.range_name.as_str());
+        let slice = Slice {
+            stream_id: activity.stream_id,
+            timestamp,
+            duration,
+        };
+        let counters = metrics.map(|metrics| (metrics, &derived[..]));
+        self.writer.write(range_name, slice, extra_data, counters);
+    }
+
+    /// Writes out the aggregates of the window still open.
+    fn finish(&mut self) {
+        let Some(aggregator) = &mut self.aggregator else {
+            return;
+        };
+        for aggregate in aggregator.take() {
+            self.writer.write_aggregate(&aggregate);
+        }
+    }
+}
+
+impl KernelWriter<'_> {
+    /// Writes the launches of a kernel aggregated over a window as one, with
+    /// their mean duration and metric values.
+    fn write_aggregate(&mut self, aggregate: &Aggregate) {
+        let (names, values): (Vec<String>, Vec<String>) =
+            aggregate.extra_data().into_iter().unzip();
+        let extra_data: Vec<(&str, String)> =
+            names.iter().map(String::as_str).zip(values).collect();
+        let metrics = aggregate.mean_metrics();
+        let derived = aggregate.mean_derived();
+        let profiled = !metrics.is_empty();
+        let slice = Slice {
+            stream_id: aggregate.stream_id,
+            timestamp: aggregate.timestamp,
+            duration: aggregate.mean_duration(),
+        };
+        self.write(
+            None,
+            slice,
+            &extra_data,
+            profiled.then_some((&metrics[..], &derived[..])),
+        );
+    }
+
+    /// Writes a kernel to the verbose output, the JSON export and the tracing
+    /// sessions, as a render stage with the metric and derived counter values
+    /// of `counters` if it was profiled.
+    fn write(
+        &mut self,
+        range_name: Option<&str>,
+        slice: Slice,
+        extra_data: &[(&str, String)],
+        counters: Option<Counters>,
+    ) {
+        let Slice {
+            stream_id,
+            timestamp,
+            duration,
+        } = slice;
+        let metrics = counters.map(|(metrics, _)| metrics);
+        if self.verbose {
+            if let Some(range_name) = range_name {
+                println!("Range Name: {}", range_name);
+            }
+            println!("Timestamp: {}", timestamp);
+            println!("Duration: {}", duration);
+            println!("-----------------------------------------------------------------------------------");
+            for (name, value) in extra_data {
+                println!("{}: {}", name, value);
+            }
+            for metric in metrics.unwrap_or_default() {
+                println!("{}: {}", metric.metric_name, metric.value);
+            }
+            println!("-----------------------------------------------------------------------------------\n");
+        }
+        if let Some(json) = &mut self.json {
+            let metrics = metrics.unwrap_or_default();
+            if let Err(e) = json.write_kernel(
+                self.ctx_id,
+                stream_id,
+                timestamp,
+                duration,
+                extra_data,
+                metrics,
+            ) {
+                eprintln!("Failed to write {}: {}", json.path().display(), e);
+                self.json = None;
+            }
+        }
+        if !tracing::is_tracing() {
+            return;
+        }
+        let queue = streams::queue(stream_id, &self.device);
+        get_render_stages_data_source().trace(|ctx: &mut TraceContext| {
+            ctx.with_incremental_state(|ctx: &mut TraceContext, state| {
+                let queues = streams::queues_if_changed(&mut state.sent_queues);
+                emit_render_stage_event(
+                    ctx,
+                    KERNEL_STAGE_ID,
+                    timestamp,
+                    duration,
+                    queue,
+                    extra_data,
+                    queues.as_deref(),
+                );
+            });
+        });
+        let Some((metrics, derived)) = counters else {
+            return;
+        };
+        let derived_counters = derived_counters(metrics, self.expressions);
         get_data_source().trace(|ctx: &mut TraceContext| {
-            let inst_id = ctx.instance_index();
-            for (_, data) in state.context_data.iter() {
-                for (range, (launch, activity)) in data
-                    .range_info
-                    .iter()
-                    .zip(data.kernel_launches.iter().zip(data.kernel_activities.iter()))
-                {
-                    let duration = range.metric_and_values.iter().find(|metric| metric.metric_name == "gpu__time_duration.sum");
-                    if duration.is_none() {
-                        continue;
+            ctx.with_incremental_state(|ctx: &mut TraceContext, state| {
+                let inst_id = ctx.instance_index();
+                let requested = state
+                    .counter_ids
+                    .get_or_insert_with(|| tracing::requested_counter_ids(inst_id));
+                if tracing::counter_descriptor_needed(&mut state.sent_counter_descriptor) {
+                    emit_counter_descriptor(
+                        ctx,
+                        timestamp,
+                        metrics,
+                        &derived_counters,
+                        requested,
+                        self.counter_aliases,
+                    );
+                }
+                emit_counters(
+                    ctx,
+                    timestamp,
+                    duration,
+                    metrics,
+                    derived,
+                    &derived_counters,
+                    requested,
+                );
+            });
+        });
+    }
+}
+
+/// Writes the spilled kernels of a context, then up to `limit` completed
+/// kernels from memory, to the tracing sessions and `json`, stopping early
+/// once `deadline` has passed.
+///
+/// Returns how many kernels were written.
+fn emit_context(
+    data: &mut CtxProfilerData,
+    process: &ProcessInfo,
+    config: &Config,
+    json: Option<&mut JsonExport>,
+    limit: usize,
+    deadline: Option<Instant>,
+) -> usize {
+    let mut emitter = KernelEmitter {
+        process,
+        device_id: data.device_id,
+        device: DeviceProperties::query(data.device_id, data.num_sms),
+        device_name: profiler::get_device_name(data.device_id).unwrap_or_default(),
+        extra_data_cache: ExtraDataCache::default(),
+        // Put back below, as the kernels are borrowed from `data` as well.
+        function_properties: std::mem::take(&mut data.function_properties),
+        baseline: baseline::baseline(),
+        anomaly_rules: &config.anomaly_rules,
+        expressions: &config.metric_expressions,
+        writer: KernelWriter {
+            ctx_id: data.ctx_id,
+            device: streams::device_description(data.device_id),
+            json,
+            verbose: config.verbose,
+            counter_aliases: &config.counter_aliases,
+            expressions: if config.raw_counters {
+                &[]
+            } else {
+                &config.metric_expressions
+            },
+        },
+        queue_ends: std::mem::take(&mut data.queue_ends),
+        aggregator: Aggregator::new(config.aggregate_window),
+        raw_counters: config.raw_counters,
+    };
+    let past_deadline = || deadline.is_some_and(|deadline| Instant::now() >= deadline);
+    let mut emitted = 0;
+    if !data.range_losses.is_empty() {
+        let context = contexts::name(data.ctx_id);
+        get_data_source().trace(|ctx: &mut TraceContext| {
+            ctx.with_incremental_state(|ctx: &mut TraceContext, state| {
+                for loss in &data.range_losses {
+                    emit_range_loss(
+                        ctx,
+                        loss.timestamp,
+                        &context,
+                        loss.ranges,
+                        loss.total,
+                        !std::mem::replace(&mut state.sent_data_loss_descriptor, true),
+                    );
+                }
+            });
+        });
+    }
+    // Spilled kernels were launched before the ones still in memory.
+    if let Some(spill) = &mut data.spill {
+        match spill.records() {
+            Ok(records) => {
+                for record in records {
+                    if past_deadline() {
+                        break;
                     }
-                    let duration = duration.unwrap();
-                    let demangled = if let Ok(sym) = Symbol::new(&activity.kernel_name) {
-                        sym.demangle().map(|d| d.to_string()).unwrap_or(activity.kernel_name.clone())
-                    } else {
-                        activity.kernel_name.clone()
-                    };
-                    let grid_size = activity.grid_size.0 * activity.grid_size.1 * activity.grid_size.2;
-                    let block_size = activity.block_size.0 * activity.block_size.1 * activity.block_size.2;
-                    let thread_count = grid_size * block_size;
-                    let mut cache_mode = 0;
-                    let _ = unsafe { profiler::get_func_attribute(launch.function, CUfunction_attribute_enum_CU_FUNC_ATTRIBUTE_CACHE_MODE_CA) }.map(|v| cache_mode = v);
-                    let max_active_blocks = unsafe { profiler::occupancy_max_active_blocks_per_multiprocessor(launch.function, block_size, activity.dynamic_shared_memory as usize) }.unwrap_or(0);
-                    let waves_per_multiprocessor = if data.num_sms > 0 && max_active_blocks > 0 { grid_size as f64 / (data.num_sms * max_active_blocks) as f64 } else { 0.0 };
-                    let regs_per_thread = unsafe { profiler::get_func_attribute(launch.function, CUfunction_attribute_enum_CU_FUNC_ATTRIBUTE_NUM_REGS) }.unwrap_or(0);
-                    let smem_per_block = activity.dynamic_shared_memory + activity.static_shared_memory;
-                    let warp_size = profiler::get_device_attribute(data.device_id, CUdevice_attribute_enum_CU_DEVICE_ATTRIBUTE_WARP_SIZE).unwrap_or(32);
-                    let max_threads_sm = profiler::get_device_attribute(data.device_id, CUdevice_attribute_enum_CU_DEVICE_ATTRIBUTE_MAX_THREADS_PER_MULTIPROCESSOR).unwrap_or(0);
-                    let max_blocks_sm = profiler::get_device_attribute(data.device_id, CUdevice_attribute_enum_CU_DEVICE_ATTRIBUTE_MAX_BLOCKS_PER_MULTIPROCESSOR).unwrap_or(0);
-                    let regs_per_sm = profiler::get_device_attribute(data.device_id, CUdevice_attribute_enum_CU_DEVICE_ATTRIBUTE_MAX_REGISTERS_PER_MULTIPROCESSOR).unwrap_or(0);
-                    let smem_per_sm = profiler::get_device_attribute(data.device_id, CUdevice_attribute_enum_CU_DEVICE_ATTRIBUTE_MAX_SHARED_MEMORY_PER_MULTIPROCESSOR).unwrap_or(0);
-                    let major = profiler::get_device_attribute(data.device_id, CUdevice_attribute_enum_CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MAJOR).unwrap_or(0);
-                    let minor = profiler::get_device_attribute(data.device_id, CUdevice_attribute_enum_CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MINOR).unwrap_or(0);
-                    let warps_per_block = if warp_size > 0 { block_size / warp_size } else { 0 };
-                    let max_active_warps = max_active_blocks * warps_per_block;
-                    let regs_per_block = (regs_per_thread as i32) * block_size;
-                    let max_warps_sm = if warp_size > 0 { max_threads_sm / warp_size } else { 0 };
-                    let max_active_warps_pct = if max_warps_sm > 0 { 100.0 * max_active_warps as f64 / max_warps_sm as f64 } else { 0.0 };
-                    let occupancy_limit_shared_mem = if smem_per_block != 0 { smem_per_sm / smem_per_block } else { 16 };
-                    let occupancy_limit_warps = if warps_per_block > 0 { max_warps_sm / warps_per_block } else { 0 };
-                    let occupancy_limit_registers = if regs_per_block != 0 { regs_per_sm / regs_per_block } else { 16 };
-                    // Emit static metrics as extra data of the render stage event.
-                    let extra_data = |emit: &mut dyn FnMut(&str, &str)| {
-                        emit("kernel_name", &activity.kernel_name);
-                        emit("kernel_demangled_name", &demangled);
-                        emit("kernel_type", "Compute");
-                        emit("process_id", &process_id.to_string());
-                        emit("process_name", &process_name);
-                        emit("arch", &format!("CC_{}{}", major, minor));
-                        #[allow(nonstandard_style)]
-                        match cache_mode as u32 {
-                            CUfunc_cache_enum_CU_FUNC_CACHE_PREFER_NONE => emit("launch__func_cache_config", "CachePreferNone"),
-                            CUfunc_cache_enum_CU_FUNC_CACHE_PREFER_SHARED => emit("launch__func_cache_config", "CachePreferShared"),
-                            CUfunc_cache_enum_CU_FUNC_CACHE_PREFER_L1 => emit("launch__func_cache_config", "CachePreferL1"),
-                            CUfunc_cache_enum_CU_FUNC_CACHE_PREFER_EQUAL => emit("launch__func_cache_config", "CachePreferEqual"),
-                            _ => emit("launch__func_cache_config", "n/a"),
+                    match record {
+                        Ok(record) => {
+                            emitter.emit(&record.launch, &record.activity, record.range.as_ref());
+                            emitted += 1;
                         }
-                        emit("launch__waves_per_multiprocessor", &waves_per_multiprocessor.to_string());
-                        emit("launch__grid_size", &grid_size.to_string());
-                        emit("launch__grid_size_x", &activity.grid_size.0.to_string());
-                        emit("launch__grid_size_y", &activity.grid_size.1.to_string());
-                        emit("launch__grid_size_z", &activity.grid_size.2.to_string());
-                        emit("launch__block_size", &block_size.to_string());
-                        emit("launch__block_size_x", &activity.block_size.0.to_string());
-                        emit("launch__block_size_y", &activity.block_size.1.to_string());
-                        emit("launch__block_size_z", &activity.block_size.2.to_string());
-                        emit("launch__thread_count", &thread_count.to_string());
-                        emit("launch__registers_per_thread", &activity.registers_per_thread.to_string());
-                        // TODO: Take shared mem config and carve-out into account.
-                        emit("launch__shared_mem_config_size", "49152");
-                        emit("launch__shared_mem_per_block_driver", &smem_per_block.to_string());
-                        emit("launch__shared_mem_per_block_dynamic", &activity.dynamic_shared_memory.to_string());
-                        emit("launch__shared_mem_per_block_static", &activity.static_shared_memory.to_string());
-                        emit("launch__occupancy_limit_shared_mem", &occupancy_limit_shared_mem.to_string());
-                        emit("launch__occupancy_limit_warps", &occupancy_limit_warps.to_string());
-                        emit("launch__occupancy_limit_blocks", &max_blocks_sm.to_string());
-                        emit("launch__occupancy_limit_registers", &occupancy_limit_registers.to_string());
-                        emit("sm__maximum_warps_avg_per_active_cycle", &max_active_warps.to_string());
-                        emit("sm__maximum_warps_per_active_cycle_pct", &max_active_warps_pct.to_string());
-                    };
-                    if state.config.verbose {
-                        println!("Range Name: {}", range.range_name);
-                        println!("Timestamp: {}", launch.timestamp);
-                        println!("Duration: {}", duration.value);
-                        println!("-----------------------------------------------------------------------------------");
-                        extra_data(&mut|name: &str, value: &str| {
-                            println!("{}: {}", name, value);
-                        });
-                        for metric in &range.metric_and_values {
-                            println!("{}: {}", metric.metric_name, metric.value);
+                        Err(e) => {
+                            eprintln!("Failed to read spilled kernel: {}", e);
+                            break;
                         }
-                        println!("-----------------------------------------------------------------------------------\n");
                     }
-                    let got_first_counters = GOT_FIRST_COUNTERS.fetch_or(1 << inst_id, Ordering::SeqCst);
-                    ctx.with_incremental_state(|ctx: &mut TraceContext, state| {
-                        let was_cleared = std::mem::replace(&mut state.was_cleared, false);
-                        ctx.add_packet(|packet: &mut TracePacket| {
-                            packet
-                                .set_timestamp(launch.timestamp)
-                                .set_timestamp_clock_id(BuiltinClock::BuiltinClockBoottime.into())
-                                .set_gpu_render_stage_event(|event: &mut GpuRenderStageEvent| {
-                                    event
-                                        .set_event_id(get_next_event_id())
-                                        .set_duration(duration.value as u64)
-                                        .set_hw_queue_id(0)
-                                        .set_stage_id(0);
-                                    extra_data(&mut|name: &str, value: &str| {
-                                        event.set_extra_data(|extra_data: &mut ExtraData| {
-                                            extra_data.set_name(name);
-                                            extra_data.set_value(value);
-                                        });
-                                    });
-                                    if was_cleared {
-                                        event.set_specifications(|specs: &mut Specifications| {
-                                            specs
-                                                .set_hw_queue(|desc: &mut Description| {
-                                                    desc.set_name("Queue (0)");
-                                                })
-                                                .set_stage(|desc: &mut Description| {
-                                                    desc.set_name("Kernel");
-                                                });
-                                        });
-                                    }
-                                });
-                        });
-                        if got_first_counters & (1 << inst_id) == 0 {
-                            ctx.add_packet(|packet: &mut TracePacket| {
-                                packet
-                                    .set_timestamp(launch.timestamp)
-                                    .set_timestamp_clock_id(BuiltinClock::BuiltinClockBoottime.into())
-                                    .set_gpu_counter_event(|event: &mut GpuCounterEvent| {
-                                        event.set_counter_descriptor(|desc: &mut GpuCounterDescriptor| {
-                                            for (i, metric) in range.metric_and_values.iter().enumerate() {
-                                                desc.set_specs(|desc: &mut GpuCounterSpec| {
-                                                    desc.set_counter_id(i as u32);
-                                                    desc.set_name(&metric.metric_name);
-                                                    desc.set_groups(GpuCounterDescriptorGpuCounterGroup::Compute);
-                                                });
-                                            }
-                                        });
-                                    });
-                                });
-                        }
-                        ctx.add_packet(|packet: &mut TracePacket| {
-                            packet
-                                .set_timestamp(launch.timestamp)
-                                .set_timestamp_clock_id(BuiltinClock::BuiltinClockBoottime.into())
-                                .set_gpu_counter_event(|event: &mut GpuCounterEvent| {
-                                    for (i, _metric) in range.metric_and_values.iter().enumerate() {
-                                        event.set_counters(|counter: &mut GpuCounter| {
-                                            counter.set_counter_id(i as u32).set_int_value(0);
-                                        });
-                                    }
-                                });
-                            });
-                        ctx.add_packet(|packet: &mut TracePacket| {
-                            packet
-                                .set_timestamp(launch.timestamp + duration.value as u64)
-                                .set_timestamp_clock_id(BuiltinClock::BuiltinClockBoottime.into())
-                                .set_gpu_counter_event(|event: &mut GpuCounterEvent| {
-                                    for (i, metric) in range.metric_and_values.iter().enumerate() {
-                                        event.set_counters(|counter: &mut GpuCounter| {
-                                            counter.set_counter_id(i as u32).set_double_value(metric.value);
-                                        });
-                                    }
-                                });
-                            });
-                    });
                 }
             }
-        });
+            Err(e) => eprintln!("Failed to read spill file: {}", e),
+        }
+    }
+    for (launch, activity, range) in data.completed_kernels().take(limit) {
+        if past_deadline() {
+            break;
+        }
+        emitter.emit(launch, activity, range);
+        emitted += 1;
+    }
+    emitter.finish();
+    data.function_properties = emitter.function_properties;
+    data.queue_ends = emitter.queue_ends;
+    emitted
+}
+
+/// Writes samples of the overhead counters, preceded by their descriptor the
+/// first time for a tracing session.
+fn emit_stats_samples(ctx: &mut TraceContext, samples: &[StatsSample]) {
+    let Some(first) = samples.first() else {
+        return;
+    };
+    ctx.with_incremental_state(|ctx: &mut TraceContext, state| {
+        if !std::mem::replace(&mut state.sent_stats_descriptor, true) {
+            emit_stats_descriptor(ctx, first.timestamp);
+        }
+        for sample in samples {
+            emit_stats(ctx, sample);
+        }
+    });
+}
+
+/// Writes samples of the memory pool counters, preceded by their descriptor
+/// the first time for a tracing session.
+fn emit_memory_pool_samples(ctx: &mut TraceContext, samples: &[PoolSample]) {
+    let Some(first) = samples.first() else {
+        return;
+    };
+    ctx.with_incremental_state(|ctx: &mut TraceContext, state| {
+        if !std::mem::replace(&mut state.sent_memory_pool_descriptor, true) {
+            emit_memory_pool_descriptor(ctx, first.timestamp);
+        }
+        for sample in samples {
+            emit_memory_pool_sample(ctx, sample);
+        }
+    });
+}
+
+/// A copy within or between GPUs, ready to be written on the queue of its
+/// stream.
+struct CopyEvent {
+    timestamp: u64,
+    duration: u64,
+    queue: u32,
+    extra_data: Vec<(&'static str, String)>,
+}
+
+/// Takes the copies not yet written, looking up their queues and, for copies
+/// between GPUs, the link between them.
+fn take_copy_events() -> Vec<CopyEvent> {
+    copies::take()
+        .into_iter()
+        .map(|copy| {
+            let link = copy
+                .is_peer()
+                .then(|| copies::link(copy.src_device_id, copy.dst_device_id));
+            CopyEvent {
+                timestamp: copy.timestamp,
+                duration: copy.duration,
+                queue: streams::queue(
+                    copy.stream_id,
+                    &streams::device_description(copy.device_id as CUdevice),
+                ),
+                extra_data: copies::build_copy_data(&copy, link),
+            }
+        })
+        .collect()
+}
+
+/// Writes copies as render stage events of the copy stage.
+fn emit_copy_events(ctx: &mut TraceContext, events: &[CopyEvent]) {
+    if events.is_empty() {
+        return;
+    }
+    ctx.with_incremental_state(|ctx: &mut TraceContext, state| {
+        for event in events {
+            let queues = streams::queues_if_changed(&mut state.sent_queues);
+            emit_render_stage_event(
+                ctx,
+                COPY_STAGE_ID,
+                event.timestamp,
+                event.duration,
+                event.queue,
+                &event.extra_data,
+                queues.as_deref(),
+            );
+        }
+    });
+}
+
+/// Writes the kernels completed so far to the active tracing sessions and
+/// drops them, so that only the rest is left for exit.
+pub fn emit_completed(state: &mut GlobalState) {
+    let contexts = CONTEXT_DATA.all();
+    let contexts = contexts.iter().map(|data| lock_context(data)).collect();
+    emit_completed_of(state, contexts);
+}
+
+/// Emits the completed kernels of `contexts`.
+fn emit_completed_of(state: &mut GlobalState, mut contexts: Vec<MutexGuard<CtxProfilerData>>) {
+    let process = ProcessInfo::current();
+    let config = &state.config;
+    let completed: Vec<usize> = contexts
+        .iter()
+        .map(|data| data.completed_kernels().count())
+        .collect();
+    let stats_samples = std::mem::take(&mut state.overhead.stats_samples);
+    let pool_samples = memory_pools::take_samples();
+    let copy_events = take_copy_events();
+    get_data_source().trace(|ctx: &mut TraceContext| {
+        emit_stats_samples(ctx, &stats_samples);
+        emit_memory_pool_samples(ctx, &pool_samples);
     });
+    get_render_stages_data_source().trace(|ctx: &mut TraceContext| {
+        emit_copy_events(ctx, &copy_events);
+    });
+    let mut json = state.json_export.as_mut();
+    for (data, &completed) in contexts.iter_mut().zip(&completed) {
+        emit_context(data, &process, config, json.as_deref_mut(), completed, None);
+    }
+    for (data, completed) in contexts.iter_mut().zip(completed) {
+        data.drop_emitted(completed);
+    }
+    flush_json_export(state, false);
+}
+
+/// Emits everything collected on a context that is being destroyed, e.g. by
+/// `cudaDeviceReset`, while the driver still has it.
+///
+/// Its activity records are flushed and its ranges decoded and evaluated
+/// now, as later flushes would reference a context that no longer exists,
+/// and the context is forgotten once its kernels are written, with a warning
+/// for the ones that were dropped or never completed. Without a
+/// session or JSON export to write to, the kernels are kept for the exit
+/// handler, with the function properties it would otherwise query from the
+/// driver cached while the context's functions are still valid.
+pub fn emit_destroyed_context(ctx: CUcontext, ctx_id: u32) {
+    let _ = profiler::activity_flush_all(0);
+    let Some(data) = CONTEXT_DATA.get(ctx_id) else {
+        return;
+    };
+    lock_context(&data).flush_ranges();
+    worker::flush();
+    let mut state = lock_global_state();
+    if state.active_ctx == Some(ctx) {
+        state.active_ctx = None;
+    }
+    let mut data = lock_context(&data);
+    data.add_ranges(worker::take_results(ctx_id));
+    if tracing::is_tracing() || state.json_export.is_some() {
+        // Kernels still waiting for their activity or range go with the
+        // context, as do the ones the kernel limit dropped.
+        let completed = data.completed_kernels().count();
+        let incomplete = data.kernel_launches.len() - completed;
+        if incomplete > 0 || data.dropped_kernels > 0 {
+            let timestamp = data
+                .kernel_launches
+                .get(completed)
+                .map_or_else(trace_time_ns, |launch| launch.timestamp);
+            let context = contexts::name(ctx_id);
+            get_render_stages_data_source().trace(|ctx: &mut TraceContext| {
+                if data.dropped_kernels > 0 {
+                    emit_data_loss(ctx, timestamp, &context, data.dropped_kernels);
+                }
+                if incomplete > 0 {
+                    emit_warning(
+                        ctx,
+                        timestamp,
+                        &format!(
+                            "Dropped {} incomplete kernels of {} when it was destroyed",
+                            incomplete, context
+                        ),
+                    );
+                }
+            });
+        }
+        emit_completed_of(&mut state, vec![data]);
+        CONTEXT_DATA.remove(ctx_id);
+    } else {
+        data.cache_function_properties();
+    }
+}
+
+/// Writes out what was added to the JSON export, if any, and completes the
+/// file when `finish` is set.
+fn flush_json_export(state: &mut GlobalState, finish: bool) {
+    if let Some(json) = &mut state.json_export {
+        let result = if finish { json.finish() } else { json.flush() };
+        if let Err(e) = result {
+            eprintln!("Failed to write {}: {}", json.path().display(), e);
+        }
+    }
 }
 
-fn register_profiler_callbacks() -> Result<(), CUptiResult> {
+/// Ends all range profiler sessions, evaluates outstanding ranges and emits all
+/// collected kernels to the trace.
+///
+/// Whatever is left once `deadline` has passed is abandoned, and reported in
+/// the trace.
+///
+/// Activity buffers must be flushed before the state lock is taken, since
+/// `buffer_completed` needs the same locks.
+fn emit_all(state: &mut GlobalState, deadline: Option<Instant>) {
+    let process = ProcessInfo::current();
+    // The application is done launching kernels, so the remaining ranges can
+    // use every core.
+    worker::set_threads(thread::available_parallelism().map_or(1, |n| n.get()));
+    let contexts = CONTEXT_DATA.all();
+    let mut contexts: Vec<MutexGuard<CtxProfilerData>> =
+        contexts.iter().map(|data| lock_context(data)).collect();
+    for data in contexts.iter_mut() {
+        data.flush_ranges();
+    }
+    if !worker::flush_until(deadline) {
+        eprintln!("Exit deadline reached while evaluating ranges");
+    }
+    worker::set_threads(1);
+    for data in contexts.iter_mut() {
+        let ranges = worker::take_results(data.ctx_id);
+        data.add_ranges(ranges);
+    }
+    // The last kernels need a reading after them for their energy and clocks.
+    energy::sample();
+    clocks::sample();
+    let config = &state.config;
+    let throttle_events = std::mem::take(&mut state.overhead.events);
+    // A last sample covers the ranges evaluated on the way out.
+    let mut stats_samples = std::mem::take(&mut state.overhead.stats_samples);
+    stats_samples.push(StatsSample {
+        timestamp: trace_time_ns(),
+        values: overhead::stats_ns(),
+    });
+    let pool_samples = memory_pools::take_samples();
+    let copy_events = take_copy_events();
+    let histograms = histograms::summaries();
+    if config.verbose {
+        for summary in &histograms {
+            print!("{}", summary);
+        }
+    }
+    get_data_source().trace(|ctx: &mut TraceContext| {
+        for event in &throttle_events {
+            emit_warning(ctx, event.timestamp, &event.message);
+        }
+        emit_stats_samples(ctx, &stats_samples);
+        emit_memory_pool_samples(ctx, &pool_samples);
+        let timestamp = trace_time_ns();
+        for summary in &histograms {
+            emit_info(ctx, timestamp, summary);
+        }
+    });
+    get_render_stages_data_source().trace(|ctx: &mut TraceContext| {
+        emit_copy_events(ctx, &copy_events);
+        for data in &contexts {
+            if data.dropped_kernels > 0 {
+                let timestamp = data
+                    .kernel_launches
+                    .front()
+                    .map_or_else(trace_time_ns, |launch| launch.timestamp);
+                emit_data_loss(
+                    ctx,
+                    timestamp,
+                    &contexts::name(data.ctx_id),
+                    data.dropped_kernels,
+                );
+            }
+        }
+    });
+    let mut json = state.json_export.as_mut();
+    if !tracing::is_tracing() && json.is_none() {
+        return;
+    }
+    for data in contexts.iter_mut() {
+        let collected =
+            data.spill.as_ref().map_or(0, |spill| spill.len()) + data.kernel_launches.len();
+        let emitted = emit_context(
+            data,
+            &process,
+            config,
+            json.as_deref_mut(),
+            usize::MAX,
+            deadline,
+        );
+        if emitted < collected && deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            let message = format!(
+                "Exit deadline reached: {} kernels of {} were not emitted",
+                collected - emitted,
+                contexts::name(data.ctx_id)
+            );
+            get_render_stages_data_source().trace(|ctx: &mut TraceContext| {
+                emit_warning(ctx, trace_time_ns(), &message);
+            });
+        }
+    }
+    flush_json_export(state, true);
+}
+
+extern "C" fn end_execution() {
+    let _ = panic::catch_unwind(|| {
+        let started = Instant::now();
+        let _ = profiler::activity_flush_all(0);
+        let mut state = lock_global_state();
+        if state.detached {
+            return;
+        }
+        let exit_deadline = state.config.exit_deadline;
+        let deadline = (!exit_deadline.is_zero()).then(|| started + exit_deadline);
+        emit_all(&mut state, deadline);
+        // Whatever runs after this, e.g. the exit handler after a flush
+        // signal the application survived, finds everything written.
+        state.detached = true;
+        let verbose = state.config.verbose;
+        let top_kernels = state.config.top_kernels;
+        let top_kernels_file = state.config.top_kernels_file.clone();
+        drop(state);
+        write_trace_file(verbose);
+        if top_kernels > 0 {
+            top_kernels::report(top_kernels, top_kernels_file.as_deref());
+        }
+    });
+}
+
+/// Does what the exit handler does for a signal that would end the process
+/// without running it, e.g. when a scheduler stops the job. The application's
+/// own handler may let the process go on, so the profiler detaches, leaving
+/// nothing for the exit handler to write again.
+fn flush_before_signal() {
+    let (top_kernels, top_kernels_file) = {
+        let state = lock_global_state();
+        if state.detached {
+            return;
+        }
+        (
+            state.config.top_kernels,
+            state.config.top_kernels_file.clone(),
+        )
+    };
+    detach();
+    if top_kernels > 0 {
+        top_kernels::report(top_kernels, top_kernels_file.as_deref());
+    }
+}
+
+/// Emits the kernels whose ranges are already evaluated when a panic may be
+/// about to end the process. The panicking thread may hold any of the locks,
+/// so whatever is locked is skipped rather than waited for.
+fn emit_on_panic() {
+    let _ = panic::catch_unwind(|| {
+        let Some(mut state) = try_lock_global_state() else {
+            return;
+        };
+        if state.detached || !state.injection_initialized {
+            return;
+        }
+        let contexts = CONTEXT_DATA.all();
+        let contexts = contexts
+            .iter()
+            .filter_map(|data| try_lock_context(data))
+            .collect();
+        emit_completed_of(&mut state, contexts);
+    });
+}
+
+/// Emits the kernels whose ranges are already evaluated and writes the
+/// trace file when a fatal signal ends the process. Nothing is decoded or
+/// evaluated, as the process may be in any state.
+pub(crate) fn emit_before_crash() {
+    let _ = panic::catch_unwind(|| {
+        let mut state = lock_global_state();
+        if state.detached {
+            return;
+        }
+        emit_completed(&mut state);
+        let verbose = state.config.verbose;
+        drop(state);
+        write_trace_file(verbose);
+    });
+}
+
+/// Emits what it can when the process panics or crashes, so that debugging
+/// a crash still gives a partial trace.
+fn install_crash_handlers() {
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        previous(info);
+        emit_on_panic();
+    }));
+    if let Err(e) = signals::install_fatal(&CRASH_SIGNALS, emit_before_crash, CRASH_FLUSH_TIMEOUT) {
+        eprintln!("Failed to install crash signal handlers: {}", e);
+    }
+}
+
+/// Writes the in-process trace to `INJECTION_TRACE_FILE`, if one is recorded.
+fn write_trace_file(verbose: bool) {
+    match tracing::finish_file_session() {
+        Ok(Some(path)) if verbose => {
+            println!("Trace written to {}", path.display());
+            // Tracing through the system service keeps working next to the
+            // file, and when it was misconfigured the file is all there is.
+            match tracing::started_sessions().saturating_sub(1) {
+                0 => println!(
+                    "No system tracing session recorded {}",
+                    tracing::get_data_source_name()
+                ),
+                n => println!(
+                    "{} system tracing sessions also recorded {}",
+                    n,
+                    tracing::get_data_source_name()
+                ),
+            }
+        }
+        Ok(_) => {}
+        Err(e) => eprintln!("Failed to write trace file: {}", e),
+    }
+}
+
+/// Emits everything collected so far and detaches from CUPTI.
+///
+/// Range profilers are torn down, the callback subscriber is removed and CUPTI
+/// is finalized, so the process keeps running without any profiling overhead.
+pub fn detach() {
+    let _ = panic::catch_unwind(|| {
+        let _ = profiler::activity_flush_all(0);
+        let (subscriber, verbose) = {
+            let mut state = lock_global_state();
+            if state.detached || !state.injection_initialized {
+                return;
+            }
+            emit_all(&mut state, None);
+            CONTEXT_DATA.clear();
+            state.active_ctx = None;
+            state.detached = true;
+            (state.subscriber.take(), state.config.verbose)
+        };
+        // Nothing is written to the trace after detaching.
+        write_trace_file(verbose);
+        if let Some(subscriber) = subscriber {
+            let _ = unsafe { profiler::unsubscribe(subscriber) };
+        }
+        let _ = profiler::activity_flush_all(0);
+        if let Err(e) = profiler::finalize() {
+            eprintln!("Failed to finalize CUPTI: {:?}", e);
+        }
+    });
+}
+
+/// Checks that the runtime CUPTI and driver match the bindings this library was
+/// built with, since mismatched parameter struct layouts silently produce garbage.
+fn check_runtime_versions(verbose: bool) -> bool {
+    let (cupti_version, driver_version) = match (
+        profiler::get_cupti_version(),
+        profiler::get_driver_version(),
+    ) {
+        (Ok(cupti_version), Ok(driver_version)) => (cupti_version, driver_version),
+        _ => {
+            eprintln!("Failed to query CUPTI and CUDA driver versions");
+            return false;
+        }
+    };
+    if verbose {
+        println!(
+            "CUPTI API version: {}, CUDA driver version: {}",
+            cupti_version, driver_version
+        );
+    }
+    if let Err(e) = profiler::check_versions(cupti_version, driver_version) {
+        eprintln!("Profiling disabled: {}", e);
+        return false;
+    }
+    true
+}
+
+fn register_profiler_callbacks() -> Result<CUpti_SubscriberHandle, CUptiResult> {
     let subscriber =
         unsafe { profiler::subscribe(Some(profiler_callback_handler), ptr::null_mut()) }?;
-    unsafe {
-        profiler::enable_callback(
-            1,
-            subscriber,
-            CUpti_CallbackDomain_CUPTI_CB_DOMAIN_DRIVER_API,
-            CUpti_driver_api_trace_cbid_enum_CUPTI_DRIVER_TRACE_CBID_cuLaunchKernel,
-        )
-    }?;
+    for cbid in profiler::LAUNCH_CALLBACKS {
+        unsafe {
+            profiler::enable_callback(
+                1,
+                subscriber,
+                CUpti_CallbackDomain_CUPTI_CB_DOMAIN_DRIVER_API,
+                cbid,
+            )
+        }?;
+    }
+    // Kernels go on the queue of their stream, named after it if it is
+    // named with NVTX.
+    for cbid in streams::CREATE_CALLBACKS {
+        unsafe {
+            profiler::enable_callback(
+                1,
+                subscriber,
+                CUpti_CallbackDomain_CUPTI_CB_DOMAIN_DRIVER_API,
+                cbid,
+            )
+        }?;
+    }
     unsafe {
         profiler::enable_callback(
             1,
@@ -277,33 +1040,234 @@ fn register_profiler_callbacks() -> Result<(), CUptiResult> {
     }?;
     unsafe { profiler::enable_domain(1, subscriber, CUpti_CallbackDomain_CUPTI_CB_DOMAIN_STATE) }?;
     profiler::activity_enable(CUpti_ActivityKind_CUPTI_ACTIVITY_KIND_KERNEL)?;
+    // Records only come for the calls frameworks such as PyTorch's Kineto
+    // pushed an external correlation ID for.
+    profiler::activity_enable(CUpti_ActivityKind_CUPTI_ACTIVITY_KIND_EXTERNAL_CORRELATION)?;
     unsafe {
         profiler::activity_register_callbacks(Some(buffer_requested), Some(buffer_completed))
     }?;
     unsafe { libc::atexit(end_execution) };
+    Ok(subscriber)
+}
+
+/// Traces the calls into the libraries of `library_domains` through the NVTX
+/// ranges they push, which CUPTI reports once NVTX is injected with it.
+fn register_library_callbacks(
+    subscriber: CUpti_SubscriberHandle,
+    library_domains: &[String],
+) -> Result<(), CUptiResult> {
+    if !library_calls::start(library_domains) {
+        eprintln!("Library calls not traced: CUPTI is not loaded for NVTX to inject");
+        return Ok(());
+    }
+    for cbid in [
+        CUpti_nvtx_api_trace_cbid_CUPTI_CBID_NVTX_nvtxDomainCreateA,
+        CUpti_nvtx_api_trace_cbid_CUPTI_CBID_NVTX_nvtxDomainRegisterStringA,
+        CUpti_nvtx_api_trace_cbid_CUPTI_CBID_NVTX_nvtxDomainRangePushEx,
+        CUpti_nvtx_api_trace_cbid_CUPTI_CBID_NVTX_nvtxDomainRangePop,
+    ] {
+        unsafe {
+            profiler::enable_callback(
+                1,
+                subscriber,
+                CUpti_CallbackDomain_CUPTI_CB_DOMAIN_NVTX,
+                cbid,
+            )
+        }?;
+    }
     Ok(())
 }
 
+/// Names the queues of the streams and the contexts named with NVTX, which
+/// CUPTI reports once NVTX is injected with it.
+fn register_name_callbacks(subscriber: CUpti_SubscriberHandle) -> Result<(), CUptiResult> {
+    if !library_calls::inject_nvtx() {
+        eprintln!("NVTX names not used: CUPTI is not loaded for NVTX to inject");
+        return Ok(());
+    }
+    for cbid in streams::NAME_CALLBACKS
+        .into_iter()
+        .chain(contexts::NAME_CALLBACKS)
+    {
+        unsafe {
+            profiler::enable_callback(
+                1,
+                subscriber,
+                CUpti_CallbackDomain_CUPTI_CB_DOMAIN_NVTX,
+                cbid,
+            )
+        }?;
+    }
+    Ok(())
+}
+
+/// Records the pools of the stream-ordered allocator from the activity of
+/// their creation, trimming and destruction, and of the asynchronous
+/// allocations made from them.
+fn enable_memory_pool_activity() -> Result<(), CUptiResult> {
+    profiler::activity_enable(CUpti_ActivityKind_CUPTI_ACTIVITY_KIND_MEMORY_POOL)?;
+    profiler::activity_enable(CUpti_ActivityKind_CUPTI_ACTIVITY_KIND_MEMORY2)
+}
+
+/// Records copies within and between GPUs from their memcpy and peer-to-peer
+/// activity.
+fn enable_copy_activity() -> Result<(), CUptiResult> {
+    profiler::activity_enable(CUpti_ActivityKind_CUPTI_ACTIVITY_KIND_MEMCPY)?;
+    profiler::activity_enable(CUpti_ActivityKind_CUPTI_ACTIVITY_KIND_MEMCPY2)
+}
+
 /// Entry point for the injection library.
 ///
 /// Initializes the Perfetto producer, sets up global state, and registers CUPTI callbacks.
 /// This function is intended to be called by a preload mechanism or manually at the start of the application.
+/// The CUDA driver looks up this export in the library named by `CUDA_INJECTION64_PATH`, which is
+/// a `.so` on Linux and would be a `.dll` on Windows.
 #[no_mangle]
 pub extern "C" fn InitializeInjection() -> i32 {
     let result = panic::catch_unwind(|| {
-        let producer_args = ProducerInitArgsBuilder::new().backends(Backends::SYSTEM);
+        let config = Config::from_env();
+        // Recording to a file needs a session in this process.
+        let backends = if config.trace_file.is_some() {
+            Backends::SYSTEM | Backends::IN_PROCESS
+        } else {
+            Backends::SYSTEM
+        };
+        let producer_args = ProducerInitArgsBuilder::new().backends(backends);
         Producer::init(producer_args.build());
         let _ = get_data_source();
-        if let Ok(mut state) = GLOBAL_STATE.lock() {
-            if !state.injection_initialized {
-                state.injection_initialized = true;
-                state.config = Config::from_env();
+        let _ = get_render_stages_data_source();
+        let mut state = lock_global_state();
+        if !state.injection_initialized {
+            state.injection_initialized = true;
+            state.config = config;
+            state.overhead = OverheadTracker::new(
+                state.config.overhead_budget,
+                Instant::now(),
+                overhead::spent_ns(),
+            );
+            if !check_runtime_versions(state.config.verbose) {
+                return 0;
+            }
 
-                if let Err(e) = register_profiler_callbacks() {
+            match register_profiler_callbacks() {
+                Ok(subscriber) => state.subscriber = Some(subscriber),
+                Err(e) => {
                     eprintln!("Failed to register callbacks: {:?}", e);
                     return 0;
                 }
             }
+            if !state.config.library_domains.is_empty() {
+                if let Some(subscriber) = state.subscriber {
+                    if let Err(e) =
+                        register_library_callbacks(subscriber, &state.config.library_domains)
+                    {
+                        eprintln!("Failed to trace library calls: {:?}", e);
+                    }
+                }
+            }
+            if state.config.nvtx_names {
+                if let Some(subscriber) = state.subscriber {
+                    if let Err(e) = register_name_callbacks(subscriber) {
+                        eprintln!("Failed to use NVTX names: {:?}", e);
+                    }
+                }
+            }
+            if state.config.memory_pools {
+                if let Err(e) = enable_memory_pool_activity() {
+                    eprintln!("Failed to trace memory pools: {:?}", e);
+                }
+            }
+            if state.config.device_copies {
+                if let Err(e) = enable_copy_activity() {
+                    eprintln!("Failed to trace device copies: {:?}", e);
+                }
+            }
+            if let Some(signum) = state.config.detach_signal {
+                if let Err(e) = signals::install(signum, detach) {
+                    eprintln!("Failed to install detach signal handler: {}", e);
+                }
+            }
+            install_crash_handlers();
+            for &signum in &state.config.flush_signals {
+                if Some(signum) == state.config.detach_signal {
+                    continue;
+                }
+                if let Err(e) = signals::install_chained(signum, flush_before_signal) {
+                    eprintln!("Failed to install flush on signal {}: {}", signum, e);
+                }
+            }
+            worker::set_dump_dir(state.config.dump_dir.clone());
+            if let Some(path) = &state.config.baseline_file {
+                match Baseline::load(path) {
+                    Ok(mut baseline) => {
+                        baseline.threshold_pct = state.config.baseline_threshold;
+                        baseline::set_baseline(Some(baseline));
+                    }
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+            if state.config.top_kernels > 0 {
+                top_kernels::collect();
+            }
+            if state.config.kernel_histograms {
+                histograms::collect();
+            }
+            if anomaly::needs_durations(&state.config.anomaly_rules) {
+                anomaly::collect();
+            }
+            if !state.config.energy_interval.is_zero() {
+                if let Err(e) = energy::start(state.config.energy_interval) {
+                    eprintln!("{}", e);
+                }
+            }
+            if !state.config.clock_interval.is_zero() {
+                if let Err(e) = clocks::start(state.config.clock_interval) {
+                    eprintln!("{}", e);
+                }
+            }
+            if !state.config.flush_interval.is_zero() {
+                if let Err(e) = flush::start(state.config.flush_interval) {
+                    eprintln!("{}", e);
+                }
+            }
+            if let Some(addr) = &state.config.prometheus_addr {
+                match prometheus::start(addr) {
+                    Ok(addr) if state.config.verbose => {
+                        println!("Serving metrics on http://{}/metrics", addr)
+                    }
+                    Ok(_) => {}
+                    Err(e) => eprintln!("Failed to serve metrics on {}: {}", addr, e),
+                }
+            }
+            if let Some(addr) = &state.config.status_addr {
+                match status::start(addr) {
+                    Ok(addr) if state.config.verbose => {
+                        println!("Serving status on http://{}/status", addr)
+                    }
+                    Ok(_) => {}
+                    Err(e) => eprintln!("Failed to serve status on {}: {}", addr, e),
+                }
+            }
+            if let Some(path) = &state.config.control_socket {
+                match control::start(path) {
+                    Ok(()) if state.config.verbose => {
+                        println!("Listening for commands on {}", path.display())
+                    }
+                    Ok(()) => {}
+                    Err(e) => eprintln!("Failed to listen on {}: {}", path.display(), e),
+                }
+            }
+            if let Some(path) = &state.config.json_file {
+                match JsonExport::create(path, state.config.json_format, &state.config.metrics) {
+                    Ok(json) => state.json_export = Some(json),
+                    Err(e) => eprintln!("Failed to create {}: {}", path.display(), e),
+                }
+            }
+            if let Some(path) = &state.config.trace_file {
+                if let Err(e) = tracing::start_file_session(path) {
+                    eprintln!("Failed to start tracing to {}: {}", path.display(), e);
+                }
+            }
         }
         1
     });
@@ -0,0 +1,434 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Slices for the calls applications make into CUDA libraries.
+//!
+//! cuBLAS, cuDNN, NCCL and other NVIDIA libraries annotate their API calls
+//! with NVTX ranges in a domain of their own. With `NVTX_INJECTION64_PATH`
+//! pointing at CUPTI, CUPTI implements NVTX and reports its calls in the
+//! NVTX callback domain. The push/pop ranges of library domains become track
+//! event slices in the `cuda.library` category, named after the call and on
+//! the thread that made it, so the kernels launched inside can be attributed
+//! to e.g. `cublasGemmEx`.
+
+use crate::tracing;
+use cupti_profiler::bindings::*;
+use perfetto_sdk::track_event::{EventContext, TrackEventDebugArg, TrackEventType};
+use perfetto_sdk::{track_event, track_event_categories};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    env,
+    ffi::{c_char, c_void, CStr, CString},
+    fs,
+    sync::Mutex,
+};
+
+track_event_categories! {
+    pub mod perfetto_te_ns {
+        ("cuda.library", "Calls into CUDA libraries, from their NVTX ranges", []),
+    }
+}
+
+/// Track event category of the library call slices.
+pub const CATEGORY: &str = "cuda.library";
+
+/// NVTX domains of the libraries whose ranges become slices by default.
+pub const LIBRARY_DOMAINS: &[&str] = &[
+    "cuBLAS", "cuBLASLt", "cuDNN", "NCCL", "cuFFT", "cuSPARSE", "cuSOLVER", "cuRAND",
+];
+
+/// `nvtxMessageType_t` of a message given as an ASCII string.
+const NVTX_MESSAGE_TYPE_ASCII: i32 = 1;
+
+/// `nvtxMessageType_t` of a message given as a registered string handle.
+const NVTX_MESSAGE_TYPE_REGISTERED: i32 = 3;
+
+/// `nvtxEventAttributes_t`, version 2 or 3, which share this layout.
+#[repr(C)]
+pub struct NvtxEventAttributes {
+    pub version: u16,
+    pub size: u16,
+    pub category: u32,
+    pub color_type: i32,
+    pub color: u32,
+    pub payload_type: i32,
+    pub reserved0: i32,
+    pub payload: u64,
+    pub message_type: i32,
+    /// An ASCII string or a registered string handle, by `message_type`.
+    pub message: *const c_void,
+}
+
+/// `nvtxDomainCreateA_params` of the CUPTI NVTX callback data.
+#[repr(C)]
+pub struct NvtxDomainCreateAParams {
+    pub name: *const c_char,
+}
+
+/// `nvtxDomainRegisterStringA_params` of the CUPTI NVTX callback data.
+#[repr(C)]
+pub struct NvtxDomainRegisterStringAParams {
+    pub domain: *const c_void,
+    pub string: *const c_char,
+}
+
+/// `nvtxDomainRangePushEx_params` of the CUPTI NVTX callback data.
+#[repr(C)]
+pub struct NvtxDomainRangePushExParams {
+    pub domain: *const c_void,
+    pub event_attrib: *const NvtxEventAttributes,
+}
+
+/// `nvtxDomainRangePop_params` of the CUPTI NVTX callback data.
+#[repr(C)]
+pub struct NvtxDomainRangePopParams {
+    pub domain: *const c_void,
+}
+
+/// What an NVTX call means for the trace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// A call into `library` named `name` started.
+    Begin { library: String, name: String },
+    /// The innermost call into the library of the domain ended.
+    End,
+}
+
+/// NVTX domains and strings seen so far, by handle.
+#[derive(Debug, Default)]
+pub struct LibraryCalls {
+    /// Domains traced as libraries, matched case-insensitively.
+    library_domains: Vec<String>,
+    /// Library names of the domains that matched.
+    domains: HashMap<usize, String>,
+    /// Registered strings of those domains.
+    strings: HashMap<usize, String>,
+}
+
+impl LibraryCalls {
+    /// Traces the ranges of the NVTX domains named in `library_domains`, or
+    /// of every domain if it holds `*`.
+    pub fn new(library_domains: &[String]) -> Self {
+        Self {
+            library_domains: library_domains.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    fn is_library(&self, name: &str) -> bool {
+        self.library_domains
+            .iter()
+            .any(|domain| domain == "*" || domain.eq_ignore_ascii_case(name))
+    }
+
+    /// Handles the NVTX callback `cbid`, returning what it means for the
+    /// trace.
+    ///
+    /// # Safety
+    ///
+    /// `data` must be the callback data CUPTI passed for `cbid`.
+    pub unsafe fn handle(
+        &mut self,
+        cbid: CUpti_CallbackId,
+        data: &CUpti_NvtxData,
+    ) -> Option<Action> {
+        if data.functionParams.is_null() {
+            return None;
+        }
+        if cbid == CUpti_nvtx_api_trace_cbid_CUPTI_CBID_NVTX_nvtxDomainCreateA {
+            let params = &*(data.functionParams as *const NvtxDomainCreateAParams);
+            if params.name.is_null() || data.functionReturnValue.is_null() {
+                return None;
+            }
+            let name = CStr::from_ptr(params.name).to_string_lossy();
+            if self.is_library(&name) {
+                let domain = *(data.functionReturnValue as *const *const c_void);
+                self.domains.insert(domain as usize, name.into_owned());
+            }
+        } else if cbid == CUpti_nvtx_api_trace_cbid_CUPTI_CBID_NVTX_nvtxDomainRegisterStringA {
+            let params = &*(data.functionParams as *const NvtxDomainRegisterStringAParams);
+            if params.string.is_null()
+                || data.functionReturnValue.is_null()
+                || !self.domains.contains_key(&(params.domain as usize))
+            {
+                return None;
+            }
+            let handle = *(data.functionReturnValue as *const *const c_void);
+            let string = CStr::from_ptr(params.string).to_string_lossy();
+            self.strings.insert(handle as usize, string.into_owned());
+        } else if cbid == CUpti_nvtx_api_trace_cbid_CUPTI_CBID_NVTX_nvtxDomainRangePushEx {
+            let params = &*(data.functionParams as *const NvtxDomainRangePushExParams);
+            let library = self.domains.get(&(params.domain as usize))?.clone();
+            let name = params
+                .event_attrib
+                .as_ref()
+                .and_then(|attrib| self.message(attrib))
+                .unwrap_or_else(|| library.clone());
+            return Some(Action::Begin { library, name });
+        } else if cbid == CUpti_nvtx_api_trace_cbid_CUPTI_CBID_NVTX_nvtxDomainRangePop {
+            let params = &*(data.functionParams as *const NvtxDomainRangePopParams);
+            if self.domains.contains_key(&(params.domain as usize)) {
+                return Some(Action::End);
+            }
+        }
+        None
+    }
+
+    unsafe fn message(&self, attrib: &NvtxEventAttributes) -> Option<String> {
+        if attrib.message.is_null() {
+            return None;
+        }
+        match attrib.message_type {
+            NVTX_MESSAGE_TYPE_ASCII => Some(
+                CStr::from_ptr(attrib.message as *const c_char)
+                    .to_string_lossy()
+                    .into_owned(),
+            ),
+            NVTX_MESSAGE_TYPE_REGISTERED => self.strings.get(&(attrib.message as usize)).cloned(),
+            _ => None,
+        }
+    }
+}
+
+static LIBRARY_CALLS: Mutex<Option<LibraryCalls>> = Mutex::new(None);
+
+thread_local! {
+    /// Whether each library range open on this thread got a begin event, so
+    /// that only those get an end event.
+    static OPEN_SLICES: RefCell<Vec<bool>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Whether a tracing session has the `cuda.library` category enabled.
+fn is_enabled() -> bool {
+    perfetto_te_ns::is_category_enabled(perfetto_te_ns::category_index(CATEGORY))
+}
+
+/// Handles an NVTX callback, emitting the slices of library calls.
+///
+/// # Safety
+///
+/// `data` must be the callback data CUPTI passed for `cbid`.
+pub unsafe fn handle_callback(cbid: CUpti_CallbackId, data: &CUpti_NvtxData) {
+    let action = match LIBRARY_CALLS.lock() {
+        Ok(mut calls) => match calls.as_mut() {
+            Some(calls) => calls.handle(cbid, data),
+            None => return,
+        },
+        Err(_) => return,
+    };
+    match action {
+        Some(Action::Begin { library, name }) => {
+            let emitted = is_enabled();
+            if emitted {
+                let name = CString::new(name).unwrap_or_default();
+                track_event!(
+                    "cuda.library",
+                    TrackEventType::SliceBegin(name.as_ptr()),
+                    |ctx: &mut EventContext| {
+                        ctx.add_debug_arg("library", TrackEventDebugArg::String(&library));
+                    }
+                );
+            }
+            OPEN_SLICES.with(|open| open.borrow_mut().push(emitted));
+        }
+        Some(Action::End) if OPEN_SLICES.with(|open| open.borrow_mut().pop()) == Some(true) => {
+            track_event!("cuda.library", TrackEventType::SliceEnd);
+        }
+        _ => {}
+    }
+}
+
+/// Path of the CUPTI library loaded in the process, for
+/// `NVTX_INJECTION64_PATH`.
+fn loaded_cupti_path() -> Option<String> {
+    let maps = fs::read_to_string("/proc/self/maps").ok()?;
+    maps.lines()
+        .filter_map(|line| line.split_whitespace().nth(5))
+        .find(|path| {
+            path.rsplit('/')
+                .next()
+                .is_some_and(|name| name.starts_with("libcupti.so"))
+        })
+        .map(str::to_string)
+}
+
+/// Has CUPTI implement NVTX, so that NVTX calls are reported in the NVTX
+/// callback domain.
+///
+/// NVTX reads `NVTX_INJECTION64_PATH` at its first call in the process, so
+/// it is pointed at the loaded CUPTI here unless already set; calls made
+/// before are not seen. Returns false if CUPTI could not be found.
+pub fn inject_nvtx() -> bool {
+    if env::var_os("NVTX_INJECTION64_PATH").is_none() {
+        let Some(path) = loaded_cupti_path() else {
+            return false;
+        };
+        env::set_var("NVTX_INJECTION64_PATH", path);
+    }
+    true
+}
+
+/// Starts tracing the calls into the libraries of `library_domains`, see
+/// `inject_nvtx`. Returns false if CUPTI could not be found.
+pub fn start(library_domains: &[String]) -> bool {
+    if !inject_nvtx() {
+        return false;
+    }
+    tracing::init_track_events();
+    let _ = perfetto_te_ns::register();
+    if let Ok(mut calls) = LIBRARY_CALLS.lock() {
+        *calls = Some(LibraryCalls::new(library_domains));
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ptr;
+
+    fn nvtx_data(params: *const c_void, ret: *const c_void) -> CUpti_NvtxData {
+        CUpti_NvtxData {
+            functionName: ptr::null(),
+            functionParams: params,
+            functionReturnValue: ret,
+        }
+    }
+
+    unsafe fn create_domain(calls: &mut LibraryCalls, name: &CStr, handle: usize) {
+        let params = NvtxDomainCreateAParams {
+            name: name.as_ptr(),
+        };
+        let ret = handle as *const c_void;
+        let data = nvtx_data(
+            &params as *const _ as *const c_void,
+            &ret as *const *const c_void as *const c_void,
+        );
+        let action = calls.handle(
+            CUpti_nvtx_api_trace_cbid_CUPTI_CBID_NVTX_nvtxDomainCreateA,
+            &data,
+        );
+        assert_eq!(action, None);
+    }
+
+    unsafe fn push(
+        calls: &mut LibraryCalls,
+        domain: usize,
+        message_type: i32,
+        message: *const c_void,
+    ) -> Option<Action> {
+        let attrib = NvtxEventAttributes {
+            version: 3,
+            size: std::mem::size_of::<NvtxEventAttributes>() as u16,
+            category: 0,
+            color_type: 0,
+            color: 0,
+            payload_type: 0,
+            reserved0: 0,
+            payload: 0,
+            message_type,
+            message,
+        };
+        let params = NvtxDomainRangePushExParams {
+            domain: domain as *const c_void,
+            event_attrib: &attrib,
+        };
+        let data = nvtx_data(&params as *const _ as *const c_void, ptr::null());
+        calls.handle(
+            CUpti_nvtx_api_trace_cbid_CUPTI_CBID_NVTX_nvtxDomainRangePushEx,
+            &data,
+        )
+    }
+
+    unsafe fn pop(calls: &mut LibraryCalls, domain: usize) -> Option<Action> {
+        let params = NvtxDomainRangePopParams {
+            domain: domain as *const c_void,
+        };
+        let data = nvtx_data(&params as *const _ as *const c_void, ptr::null());
+        calls.handle(
+            CUpti_nvtx_api_trace_cbid_CUPTI_CBID_NVTX_nvtxDomainRangePop,
+            &data,
+        )
+    }
+
+    #[test]
+    fn test_library_calls() {
+        let domains: Vec<String> = LIBRARY_DOMAINS.iter().map(|d| d.to_string()).collect();
+        let mut calls = LibraryCalls::new(&domains);
+        unsafe {
+            create_domain(&mut calls, c"cublas", 0x10);
+            create_domain(&mut calls, c"MyApp", 0x20);
+            assert_eq!(
+                push(
+                    &mut calls,
+                    0x10,
+                    NVTX_MESSAGE_TYPE_ASCII,
+                    c"cublasGemmEx".as_ptr() as _
+                ),
+                Some(Action::Begin {
+                    library: "cublas".to_string(),
+                    name: "cublasGemmEx".to_string()
+                })
+            );
+            assert_eq!(pop(&mut calls, 0x10), Some(Action::End));
+
+            // Registered strings of library domains name the calls too.
+            let params = NvtxDomainRegisterStringAParams {
+                domain: 0x10 as *const c_void,
+                string: c"cublasSgemm".as_ptr(),
+            };
+            let handle = 0x30 as *const c_void;
+            let data = nvtx_data(
+                &params as *const _ as *const c_void,
+                &handle as *const *const c_void as *const c_void,
+            );
+            calls.handle(
+                CUpti_nvtx_api_trace_cbid_CUPTI_CBID_NVTX_nvtxDomainRegisterStringA,
+                &data,
+            );
+            assert_eq!(
+                push(&mut calls, 0x10, NVTX_MESSAGE_TYPE_REGISTERED, handle),
+                Some(Action::Begin {
+                    library: "cublas".to_string(),
+                    name: "cublasSgemm".to_string()
+                })
+            );
+            // Without a message, the call is named after the library.
+            assert_eq!(
+                push(&mut calls, 0x10, 0, ptr::null()),
+                Some(Action::Begin {
+                    library: "cublas".to_string(),
+                    name: "cublas".to_string()
+                })
+            );
+
+            // Other domains are left alone.
+            assert_eq!(
+                push(
+                    &mut calls,
+                    0x20,
+                    NVTX_MESSAGE_TYPE_ASCII,
+                    c"step".as_ptr() as _
+                ),
+                None
+            );
+            assert_eq!(pop(&mut calls, 0x20), None);
+
+            let mut all = LibraryCalls::new(&["*".to_string()]);
+            create_domain(&mut all, c"MyApp", 0x20);
+            assert_eq!(pop(&mut all, 0x20), Some(Action::End));
+        }
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reserved and used memory of the pools of the stream-ordered allocator,
+//! which `cuMemAllocAsync` allocates from.
+//!
+//! With `INJECTION_MEMORY_POOLS` set, CUPTI records the creation, trimming
+//! and destruction of pools, and every asynchronous allocation and free with
+//! the size and used bytes of its pool at the time. Their totals per device
+//! become the `memory_pool.reserved` and `memory_pool.used` counters, which
+//! show when an allocator on top, such as PyTorch's caching allocator with
+//! `backend:cudaMallocAsync`, grows, trims or fragments its pools.
+
+use crate::tracing::cupti_trace_time;
+use cupti_profiler::bindings::*;
+use once_cell::sync::Lazy;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Mutex, MutexGuard},
+};
+
+/// Samples kept until they are written, after which the oldest are dropped.
+pub const MAX_POOL_SAMPLES: usize = 1 << 16;
+
+/// Reserved and used bytes of all pools of a device after a change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolSample {
+    /// Trace clock timestamp of the change.
+    pub timestamp: u64,
+    pub device_id: u32,
+    pub reserved: u64,
+    pub used: u64,
+}
+
+/// Pools seen so far and the samples of their totals not yet written.
+#[derive(Debug, Default)]
+pub struct MemoryPools {
+    /// Reserved and used bytes of each pool, by device and pool address.
+    pools: HashMap<(u32, u64), (u64, u64)>,
+    /// Totals of a device after each change, oldest first.
+    samples: VecDeque<PoolSample>,
+}
+
+impl MemoryPools {
+    /// Sets the reserved and used bytes of the pool at `address` on
+    /// `device_id` as of `timestamp`.
+    pub fn set(&mut self, timestamp: u64, device_id: u32, address: u64, reserved: u64, used: u64) {
+        let pool = self.pools.entry((device_id, address)).or_default();
+        if *pool == (reserved, used) {
+            return;
+        }
+        *pool = (reserved, used);
+        self.push_totals(timestamp, device_id);
+    }
+
+    /// Removes the pool at `address` on `device_id`, destroyed at
+    /// `timestamp`.
+    pub fn remove(&mut self, timestamp: u64, device_id: u32, address: u64) {
+        if self.pools.remove(&(device_id, address)).is_some() {
+            self.push_totals(timestamp, device_id);
+        }
+    }
+
+    fn push_totals(&mut self, timestamp: u64, device_id: u32) {
+        let (reserved, used) = self
+            .pools
+            .iter()
+            .filter(|((device, _), _)| *device == device_id)
+            .fold((0, 0), |(reserved, used), (_, pool)| {
+                (reserved + pool.0, used + pool.1)
+            });
+        if self.samples.len() == MAX_POOL_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(PoolSample {
+            timestamp,
+            device_id,
+            reserved,
+            used,
+        });
+    }
+
+    /// Removes and returns the samples, oldest first.
+    pub fn take_samples(&mut self) -> Vec<PoolSample> {
+        self.samples.drain(..).collect()
+    }
+}
+
+static POOLS: Lazy<Mutex<MemoryPools>> = Lazy::new(Mutex::default);
+
+fn pools() -> MutexGuard<'static, MemoryPools> {
+    POOLS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Records the creation, trimming or destruction of a pool.
+pub fn add_pool_record(record: &CUpti_ActivityMemoryPool2) {
+    let timestamp = cupti_trace_time(record.timestamp);
+    // The size of a pool imported from another process is that process's.
+    if record.memoryPoolType != CUpti_ActivityMemoryPoolType_CUPTI_ACTIVITY_MEMORY_POOL_TYPE_LOCAL {
+        return;
+    }
+    let mut pools = pools();
+    if record.memoryPoolOperationType
+        == CUpti_ActivityMemoryPoolOperationType_CUPTI_ACTIVITY_MEMORY_POOL_OPERATION_TYPE_DESTROYED
+    {
+        pools.remove(timestamp, record.deviceId, record.address);
+    } else {
+        pools.set(
+            timestamp,
+            record.deviceId,
+            record.address,
+            record.size,
+            record.utilizedSize,
+        );
+    }
+}
+
+/// Records the size and used bytes of the pool of an asynchronous
+/// allocation or free. Other memory records are ignored.
+pub fn add_memory_record(record: &CUpti_ActivityMemory3) {
+    let config = &record.memoryPoolConfig;
+    if record.isAsync == 0
+        || config.memoryPoolType
+            != CUpti_ActivityMemoryPoolType_CUPTI_ACTIVITY_MEMORY_POOL_TYPE_LOCAL
+    {
+        return;
+    }
+    let timestamp = cupti_trace_time(record.timestamp);
+    // `size` is the member of local pools.
+    let reserved = unsafe { config.pool.size };
+    pools().set(
+        timestamp,
+        record.deviceId,
+        config.address,
+        reserved,
+        config.utilizedSize,
+    );
+}
+
+/// Removes and returns the samples not yet written, oldest first.
+pub fn take_samples() -> Vec<PoolSample> {
+    pools().take_samples()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_pools() {
+        let sample = |timestamp, device_id, reserved, used| PoolSample {
+            timestamp,
+            device_id,
+            reserved,
+            used,
+        };
+        let mut pools = MemoryPools::default();
+        pools.set(100, 0, 0x1000, 2 << 20, 0);
+        pools.set(200, 0, 0x1000, 2 << 20, 1 << 20);
+        // Unchanged pools add no sample.
+        pools.set(250, 0, 0x1000, 2 << 20, 1 << 20);
+        pools.set(300, 0, 0x2000, 4 << 20, 4 << 20);
+        pools.set(400, 1, 0x3000, 8 << 20, 0);
+        pools.remove(500, 0, 0x1000);
+        pools.remove(600, 0, 0x1000);
+        assert_eq!(
+            pools.take_samples(),
+            [
+                sample(100, 0, 2 << 20, 0),
+                sample(200, 0, 2 << 20, 1 << 20),
+                sample(300, 0, 6 << 20, 5 << 20),
+                sample(400, 1, 8 << 20, 0),
+                sample(500, 0, 4 << 20, 4 << 20),
+            ]
+        );
+        assert!(pools.take_samples().is_empty());
+    }
+}
@@ -0,0 +1,291 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Merging of the traces processes wrote on their own into one trace.
+//!
+//! Every process of an MPI job records its kernels to the same GPU and queue
+//! IDs, and its producer numbers its packet sequences from the same start.
+//! Packets are copied as they are, except that sequences get IDs unique to
+//! the merged trace and each GPU of each trace gets a GPU ID of its own, with
+//! its queue named after the trace, so that every process has its own tracks.
+
+use crate::tracing::{append_delimited, append_varint};
+use perfetto_sdk::pb_decoder::{PbDecoder, PbDecoderError, PbDecoderField};
+use std::collections::HashMap;
+
+const TRACE_PACKET: u32 = 1;
+const PACKET_TRUSTED_PACKET_SEQUENCE_ID: u32 = 10;
+const PACKET_GPU_COUNTER_EVENT: u32 = 52;
+const PACKET_GPU_RENDER_STAGE_EVENT: u32 = 53;
+const COUNTER_EVENT_GPU_ID: u32 = 3;
+const RENDER_STAGE_SPECIFICATIONS: u32 = 7;
+const RENDER_STAGE_GPU_ID: u32 = 11;
+const SPECIFICATIONS_HW_QUEUE: u32 = 2;
+const DESCRIPTION_NAME: u32 = 1;
+
+fn append_field(buf: &mut Vec<u8>, field_id: u32, field: PbDecoderField) {
+    let tag = (field_id as u64) << 3;
+    match field {
+        PbDecoderField::Varint(v) => {
+            append_varint(buf, tag);
+            append_varint(buf, v);
+        }
+        PbDecoderField::Fixed64(v) => {
+            append_varint(buf, tag | 1);
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        PbDecoderField::Delimited(v) => append_delimited(buf, field_id, v),
+        PbDecoderField::Fixed32(v) => {
+            append_varint(buf, tag | 5);
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+    }
+}
+
+/// IDs in the merged trace of what each trace numbered on its own.
+#[derive(Default)]
+struct Ids {
+    sequences: HashMap<(usize, u64), u64>,
+    gpus: HashMap<(usize, u64), u64>,
+}
+
+impl Ids {
+    fn sequence(&mut self, trace: usize, id: u64) -> u64 {
+        let next = self.sequences.len() as u64 + 1;
+        *self.sequences.entry((trace, id)).or_insert(next)
+    }
+
+    fn gpu(&mut self, trace: usize, id: u64) -> u64 {
+        let next = self.gpus.len() as u64;
+        *self.gpus.entry((trace, id)).or_insert(next)
+    }
+}
+
+/// Copies `event`, with the GPU ID in field `gpu_id_field` replaced, or added
+/// when it was left at its default of 0.
+fn rewrite_gpu_event(
+    event: &[u8],
+    gpu_id_field: u32,
+    gpu: impl FnOnce(u64) -> u64,
+    mut rewrite: impl FnMut(&mut Vec<u8>, u32, PbDecoderField) -> Result<(), PbDecoderError>,
+) -> Result<Vec<u8>, PbDecoderError> {
+    let mut out = Vec::with_capacity(event.len() + 2);
+    let mut gpu_id = 0;
+    for field in PbDecoder::new(event) {
+        match field? {
+            (id, PbDecoderField::Varint(v)) if id == gpu_id_field => gpu_id = v,
+            (id, field) => rewrite(&mut out, id, field)?,
+        }
+    }
+    append_field(&mut out, gpu_id_field, PbDecoderField::Varint(gpu(gpu_id)));
+    Ok(out)
+}
+
+/// Copies render stage specifications with the queue name prefixed by
+/// `label`.
+fn rewrite_specifications(specs: &[u8], label: &str) -> Result<Vec<u8>, PbDecoderError> {
+    let mut out = Vec::with_capacity(specs.len() + label.len() + 2);
+    for field in PbDecoder::new(specs) {
+        match field? {
+            (SPECIFICATIONS_HW_QUEUE, PbDecoderField::Delimited(desc)) => {
+                let mut queue = Vec::with_capacity(desc.len() + label.len() + 2);
+                for field in PbDecoder::new(desc) {
+                    match field? {
+                        (DESCRIPTION_NAME, PbDecoderField::Delimited(name)) => {
+                            let name = format!("{}: {}", label, String::from_utf8_lossy(name));
+                            append_delimited(&mut queue, DESCRIPTION_NAME, name.as_bytes());
+                        }
+                        (id, field) => append_field(&mut queue, id, field),
+                    }
+                }
+                append_delimited(&mut out, SPECIFICATIONS_HW_QUEUE, &queue);
+            }
+            (id, field) => append_field(&mut out, id, field),
+        }
+    }
+    Ok(out)
+}
+
+fn rewrite_packet(
+    packet: &[u8],
+    trace: usize,
+    label: &str,
+    ids: &mut Ids,
+) -> Result<Vec<u8>, PbDecoderError> {
+    let mut out = Vec::with_capacity(packet.len() + 8);
+    for field in PbDecoder::new(packet) {
+        match field? {
+            (PACKET_TRUSTED_PACKET_SEQUENCE_ID, PbDecoderField::Varint(v)) => append_field(
+                &mut out,
+                PACKET_TRUSTED_PACKET_SEQUENCE_ID,
+                PbDecoderField::Varint(ids.sequence(trace, v)),
+            ),
+            (PACKET_GPU_RENDER_STAGE_EVENT, PbDecoderField::Delimited(event)) => {
+                let event = rewrite_gpu_event(
+                    event,
+                    RENDER_STAGE_GPU_ID,
+                    |id| ids.gpu(trace, id),
+                    |out, id, field| {
+                        match (id, field) {
+                            (RENDER_STAGE_SPECIFICATIONS, PbDecoderField::Delimited(specs)) => {
+                                let specs = rewrite_specifications(specs, label)?;
+                                append_delimited(out, RENDER_STAGE_SPECIFICATIONS, &specs);
+                            }
+                            (id, field) => append_field(out, id, field),
+                        }
+                        Ok(())
+                    },
+                )?;
+                append_delimited(&mut out, PACKET_GPU_RENDER_STAGE_EVENT, &event);
+            }
+            (PACKET_GPU_COUNTER_EVENT, PbDecoderField::Delimited(event)) => {
+                let event = rewrite_gpu_event(
+                    event,
+                    COUNTER_EVENT_GPU_ID,
+                    |id| ids.gpu(trace, id),
+                    |out, id, field| {
+                        append_field(out, id, field);
+                        Ok(())
+                    },
+                )?;
+                append_delimited(&mut out, PACKET_GPU_COUNTER_EVENT, &event);
+            }
+            (id, field) => append_field(&mut out, id, field),
+        }
+    }
+    Ok(out)
+}
+
+/// Merges serialized traces into one, each given with the label its queues
+/// are named after, e.g. the rank of the process that wrote it.
+pub fn merge(traces: &[(&str, &[u8])]) -> Result<Vec<u8>, PbDecoderError> {
+    let mut merged = Vec::with_capacity(traces.iter().map(|(_, trace)| trace.len()).sum());
+    let mut ids = Ids::default();
+    for (index, &(label, trace)) in traces.iter().enumerate() {
+        for field in PbDecoder::new(trace) {
+            match field? {
+                (TRACE_PACKET, PbDecoderField::Delimited(packet)) => {
+                    let packet = rewrite_packet(packet, index, label, &mut ids)?;
+                    append_delimited(&mut merged, TRACE_PACKET, &packet);
+                }
+                (id, field) => append_field(&mut merged, id, field),
+            }
+        }
+    }
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::summary::summarize;
+
+    fn varint_field(buf: &mut Vec<u8>, field_id: u32, value: u64) {
+        append_field(buf, field_id, PbDecoderField::Varint(value));
+    }
+
+    /// A trace of one kernel named `name` on sequence 1, described on queue
+    /// "Stream 7" of GPU 0 like `emit_render_stage_event` does.
+    fn trace(name: &str) -> Vec<u8> {
+        let mut desc = Vec::new();
+        append_delimited(&mut desc, DESCRIPTION_NAME, b"Stream 7");
+        let mut specs = Vec::new();
+        append_delimited(&mut specs, SPECIFICATIONS_HW_QUEUE, &desc);
+        let mut extra = Vec::new();
+        append_delimited(&mut extra, 1, b"kernel_demangled_name");
+        append_delimited(&mut extra, 2, name.as_bytes());
+        let mut event = Vec::new();
+        varint_field(&mut event, 2, 50);
+        append_delimited(&mut event, 6, &extra);
+        append_delimited(&mut event, RENDER_STAGE_SPECIFICATIONS, &specs);
+        let mut packet = Vec::new();
+        varint_field(&mut packet, 8, 100);
+        varint_field(&mut packet, PACKET_TRUSTED_PACKET_SEQUENCE_ID, 1);
+        append_delimited(&mut packet, PACKET_GPU_RENDER_STAGE_EVENT, &event);
+        let mut counters = Vec::new();
+        varint_field(&mut counters, PACKET_TRUSTED_PACKET_SEQUENCE_ID, 1);
+        append_delimited(&mut counters, PACKET_GPU_COUNTER_EVENT, &[]);
+        let mut trace = Vec::new();
+        append_delimited(&mut trace, TRACE_PACKET, &packet);
+        append_delimited(&mut trace, TRACE_PACKET, &counters);
+        trace
+    }
+
+    /// The sequence and GPU IDs of each packet, and the queue names.
+    fn ids(trace: &[u8]) -> (Vec<(u64, u64)>, Vec<String>) {
+        let (mut ids, mut queues) = (Vec::new(), Vec::new());
+        for field in PbDecoder::new(trace) {
+            let (TRACE_PACKET, PbDecoderField::Delimited(packet)) = field.unwrap() else {
+                continue;
+            };
+            let (mut sequence, mut gpu) = (0, None);
+            for field in PbDecoder::new(packet) {
+                let (id, event) = match field.unwrap() {
+                    (PACKET_TRUSTED_PACKET_SEQUENCE_ID, PbDecoderField::Varint(v)) => {
+                        sequence = v;
+                        continue;
+                    }
+                    (PACKET_GPU_RENDER_STAGE_EVENT, PbDecoderField::Delimited(v)) => {
+                        (RENDER_STAGE_GPU_ID, v)
+                    }
+                    (PACKET_GPU_COUNTER_EVENT, PbDecoderField::Delimited(v)) => {
+                        (COUNTER_EVENT_GPU_ID, v)
+                    }
+                    _ => continue,
+                };
+                for field in PbDecoder::new(event) {
+                    match field.unwrap() {
+                        (field_id, PbDecoderField::Varint(v)) if field_id == id => gpu = Some(v),
+                        (RENDER_STAGE_SPECIFICATIONS, PbDecoderField::Delimited(specs))
+                            if id == RENDER_STAGE_GPU_ID =>
+                        {
+                            let (_, PbDecoderField::Delimited(desc)) =
+                                PbDecoder::new(specs).next().unwrap().unwrap()
+                            else {
+                                panic!("no queue description");
+                            };
+                            let (_, PbDecoderField::Delimited(name)) =
+                                PbDecoder::new(desc).next().unwrap().unwrap()
+                            else {
+                                panic!("no queue name");
+                            };
+                            queues.push(String::from_utf8_lossy(name).into_owned());
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            ids.push((sequence, gpu.unwrap()));
+        }
+        (ids, queues)
+    }
+
+    #[test]
+    fn test_merge() {
+        let (rank0, rank1) = (trace("scale"), trace("copy"));
+        let merged = merge(&[("rank0", &rank0), ("rank1", &rank1)]).unwrap();
+        let (ids, queues) = ids(&merged);
+        assert_eq!(ids, [(1, 0), (1, 0), (2, 1), (2, 1)]);
+        assert_eq!(queues, ["rank0: Stream 7", "rank1: Stream 7"]);
+        // The kernels themselves are copied as they were.
+        let names: Vec<String> = summarize(&merged)
+            .unwrap()
+            .into_iter()
+            .map(|s| s.name)
+            .collect();
+        assert_eq!(names.len(), 2);
+        assert!(names.contains(&"scale".to_string()));
+        assert!(names.contains(&"copy".to_string()));
+    }
+}
@@ -12,6 +12,9 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::trace_emitter::DURATION_METRIC;
+use cupti_profiler::MetricValuePair;
+
 /// Default metrics to collect if none are specified via environment variable.
 ///
 /// These metrics are selected to provide a broad overview of GPU performance,
@@ -45,24 +48,243 @@ pub const DEFAULT_METRICS: &[&str] = &[
     "sm__warps_active.avg.per_cycle_active",
 ];
 
-/// Parses a comma or semicolon separated string of metrics.
+/// Metrics the roofline extra data is derived from: the floating point
+/// instructions executed per precision, with FMAs counting as two operations,
+/// and the bytes moved to and from DRAM.
+pub const ROOFLINE_METRICS: &[&str] = &[
+    "smsp__sass_thread_inst_executed_op_fadd_pred_on.sum",
+    "smsp__sass_thread_inst_executed_op_fmul_pred_on.sum",
+    "smsp__sass_thread_inst_executed_op_ffma_pred_on.sum",
+    "smsp__sass_thread_inst_executed_op_dadd_pred_on.sum",
+    "smsp__sass_thread_inst_executed_op_dmul_pred_on.sum",
+    "smsp__sass_thread_inst_executed_op_dfma_pred_on.sum",
+    "smsp__sass_thread_inst_executed_op_hadd_pred_on.sum",
+    "smsp__sass_thread_inst_executed_op_hmul_pred_on.sum",
+    "smsp__sass_thread_inst_executed_op_hfma_pred_on.sum",
+    "dram__bytes.sum",
+];
+
+/// Metrics of the `flops` preset, the per-precision FLOPs of a kernel are
+/// estimated from: the floating point instructions executed per precision and
+/// the instructions issued to the tensor pipe.
+pub const FLOPS_METRICS: &[&str] = &[
+    "smsp__sass_thread_inst_executed_op_fadd_pred_on.sum",
+    "smsp__sass_thread_inst_executed_op_fmul_pred_on.sum",
+    "smsp__sass_thread_inst_executed_op_ffma_pred_on.sum",
+    "smsp__sass_thread_inst_executed_op_dadd_pred_on.sum",
+    "smsp__sass_thread_inst_executed_op_dmul_pred_on.sum",
+    "smsp__sass_thread_inst_executed_op_dfma_pred_on.sum",
+    "smsp__sass_thread_inst_executed_op_hadd_pred_on.sum",
+    "smsp__sass_thread_inst_executed_op_hmul_pred_on.sum",
+    "smsp__sass_thread_inst_executed_op_hfma_pred_on.sum",
+    "sm__inst_executed_pipe_tensor.sum",
+];
+
+/// Metrics of the `dram` preset: the bytes read from and written to DRAM,
+/// which the effective bandwidth of a kernel is derived from.
+pub const DRAM_METRICS: &[&str] = &["dram__bytes_read.sum", "dram__bytes_write.sum"];
+
+/// Metrics of the `smem` preset: the shared memory load and store bank
+/// conflicts and the wavefronts they are part of, which the bank conflict
+/// ratio of a kernel is derived from.
+pub const SMEM_METRICS: &[&str] = &[
+    "l1tex__data_bank_conflicts_pipe_lsu_mem_shared_op_ld.sum",
+    "l1tex__data_bank_conflicts_pipe_lsu_mem_shared_op_st.sum",
+    "l1tex__data_pipe_lsu_wavefronts_mem_shared_op_ld.sum",
+    "l1tex__data_pipe_lsu_wavefronts_mem_shared_op_st.sum",
+];
+
+/// Metrics of the `cache` preset: the sectors looked up in the L1/TEX cache
+/// and in L2 that hit and missed, which the hit rates of a kernel are
+/// derived from.
+pub const CACHE_METRICS: &[&str] = &[
+    "l1tex__t_sectors_lookup_hit.sum",
+    "l1tex__t_sectors_lookup_miss.sum",
+    "lts__t_sectors_lookup_hit.sum",
+    "lts__t_sectors_lookup_miss.sum",
+];
+
+/// Metrics of the `stalls` preset: the cycles warps spent stalled for each
+/// reason per issued instruction, as in the warp state section of Nsight
+/// Compute.
+pub const STALL_METRICS: &[&str] = &[
+    "smsp__average_warps_issue_stalled_barrier_per_issue_active.ratio",
+    "smsp__average_warps_issue_stalled_branch_resolving_per_issue_active.ratio",
+    "smsp__average_warps_issue_stalled_dispatch_stall_per_issue_active.ratio",
+    "smsp__average_warps_issue_stalled_drain_per_issue_active.ratio",
+    "smsp__average_warps_issue_stalled_imc_miss_per_issue_active.ratio",
+    "smsp__average_warps_issue_stalled_lg_throttle_per_issue_active.ratio",
+    "smsp__average_warps_issue_stalled_long_scoreboard_per_issue_active.ratio",
+    "smsp__average_warps_issue_stalled_math_pipe_throttle_per_issue_active.ratio",
+    "smsp__average_warps_issue_stalled_membar_per_issue_active.ratio",
+    "smsp__average_warps_issue_stalled_mio_throttle_per_issue_active.ratio",
+    "smsp__average_warps_issue_stalled_misc_per_issue_active.ratio",
+    "smsp__average_warps_issue_stalled_no_instruction_per_issue_active.ratio",
+    "smsp__average_warps_issue_stalled_not_selected_per_issue_active.ratio",
+    "smsp__average_warps_issue_stalled_selected_per_issue_active.ratio",
+    "smsp__average_warps_issue_stalled_short_scoreboard_per_issue_active.ratio",
+    "smsp__average_warps_issue_stalled_sleeping_per_issue_active.ratio",
+    "smsp__average_warps_issue_stalled_tex_throttle_per_issue_active.ratio",
+    "smsp__average_warps_issue_stalled_wait_per_issue_active.ratio",
+];
+
+/// Metrics of the `sol` preset, the "Speed of Light" of Nsight Compute: SM
+/// and memory throughput as a percentage of their peak, which the
+/// `bottleneck` extra data is derived from.
+pub const SOL_METRICS: &[&str] = &[
+    "gpu__time_duration.sum",
+    "sm__throughput.avg.pct_of_peak_sustained_elapsed",
+    "gpu__compute_memory_throughput.avg.pct_of_peak_sustained_elapsed",
+];
+
+/// Longest metric name accepted, in bytes. CUPTI metric names are well
+/// under this, so longer ones are mistakes, such as a list missing its
+/// separators.
+pub const MAX_METRIC_NAME_LEN: usize = 256;
+
+/// Metric list names that expand to a set of metrics.
+const PRESETS: &[(&str, &[&str])] = &[
+    ("sol", SOL_METRICS),
+    ("roofline", ROOFLINE_METRICS),
+    ("flops", FLOPS_METRICS),
+    ("dram", DRAM_METRICS),
+    ("smem", SMEM_METRICS),
+    ("cache", CACHE_METRICS),
+    ("stalls", STALL_METRICS),
+];
+
+/// Appends the `extra` metrics that `metrics` lacks.
+pub fn append_metrics(mut metrics: Vec<String>, extra: &[&str]) -> Vec<String> {
+    for metric in extra {
+        if !metrics.iter().any(|m| m == metric) {
+            metrics.push(metric.to_string());
+        }
+    }
+    metrics
+}
+
+/// Removes the `dropped` metrics from `metrics`, keeping the duration metric
+/// even if it was dropped, or adding it if nothing else is left.
+pub fn remove_metrics(metrics: &[String], dropped: &[String]) -> Vec<String> {
+    let kept: Vec<String> = metrics
+        .iter()
+        .filter(|m| *m == DURATION_METRIC || !dropped.contains(m))
+        .cloned()
+        .collect();
+    if kept.is_empty() {
+        vec![DURATION_METRIC.to_string()]
+    } else {
+        kept
+    }
+}
+
+/// Rollup raw counters are collected with in raw counter mode: the sum of
+/// the counter over every unit, which needs no evaluation beyond reading it.
+pub const RAW_COUNTER_ROLLUP: &str = ".sum";
+
+/// The raw counters `metrics` are based on, each collected with
+/// `RAW_COUNTER_ROLLUP`, for raw counter mode. Rollups, submetrics and
+/// repeated counters are dropped, and the duration metric is always
+/// collected, as kernels are placed on their queues with it.
+pub fn raw_counter_metrics(metrics: &[String]) -> Vec<String> {
+    let counters: Vec<String> = metrics
+        .iter()
+        .map(|m| m.split('.').next().unwrap_or(m))
+        .map(|counter| format!("{}{}", counter, RAW_COUNTER_ROLLUP))
+        .collect();
+    let counters: Vec<&str> = counters.iter().map(String::as_str).collect();
+    append_metrics(append_metrics(Vec::new(), &counters), &[DURATION_METRIC])
+}
+
+/// Name of the raw counter collected as `metric`, as it was configured.
+pub fn raw_counter_name(metric: &str) -> &str {
+    metric.strip_suffix(RAW_COUNTER_ROLLUP).unwrap_or(metric)
+}
+
+/// The values of `metrics` under the names of their raw counters.
+pub fn raw_counter_values(metrics: &[MetricValuePair]) -> Vec<MetricValuePair> {
+    metrics
+        .iter()
+        .map(|metric| MetricValuePair {
+            metric_name: raw_counter_name(&metric.metric_name).to_string(),
+            value: metric.value,
+        })
+        .collect()
+}
+
+/// Parses the semicolon separated `counter=alias` pairs of
+/// `INJECTION_COUNTER_ALIASES`. Pairs without both are skipped with a
+/// warning, and a counter listed again keeps its first alias.
+pub fn parse_counter_aliases(input: &str) -> Vec<(String, String)> {
+    let mut aliases: Vec<(String, String)> = Vec::new();
+    for pair in input.split(';').map(str::trim).filter(|p| !p.is_empty()) {
+        match pair.split_once('=') {
+            Some((counter, alias)) if !counter.trim().is_empty() && !alias.trim().is_empty() => {
+                let counter = counter.trim();
+                if !aliases.iter().any(|(c, _)| c == counter) {
+                    aliases.push((counter.to_string(), alias.trim().to_string()));
+                }
+            }
+            _ => eprintln!(
+                "Skipping counter alias {:.64?}: it is not counter=alias",
+                pair
+            ),
+        }
+    }
+    aliases
+}
+
+/// Why `name` cannot be a metric name, if it cannot.
+pub fn malformed_metric_name(name: &str) -> Option<&'static str> {
+    if name.contains('\0') {
+        Some("it has a NUL character")
+    } else if name.len() > MAX_METRIC_NAME_LEN {
+        Some("it is too long")
+    } else {
+        None
+    }
+}
+
+/// The first `n` of `metrics`, with the duration metric moved to the front
+/// so that it is always one of them.
+pub fn first_metrics(metrics: &[String], n: usize) -> Vec<String> {
+    let (duration, others): (Vec<String>, Vec<String>) =
+        metrics.iter().cloned().partition(|m| m == DURATION_METRIC);
+    duration.into_iter().chain(others).take(n).collect()
+}
+
+/// Parses a comma or semicolon separated string of metrics, in which the
+/// names of `PRESETS` stand for their metrics. Malformed names are skipped
+/// with a warning, and repeated metrics are only listed once. The duration
+/// metric is collected if nothing else is left.
 ///
 /// If input is empty or whitespace-only, returns `DEFAULT_METRICS`.
 pub fn parse_metrics(input: &str) -> Vec<String> {
     if input.trim().is_empty() {
         return DEFAULT_METRICS.iter().map(|s| s.to_string()).collect();
     }
-    input
+    let metrics = input
         .split(&[';', ','][..])
-        .filter_map(|m| {
-            let trimmed = m.trim();
-            if trimmed.is_empty() {
-                None
-            } else {
-                Some(trimmed.to_string())
+        .map(str::trim)
+        .filter(|m| !m.is_empty())
+        .filter(|m| match malformed_metric_name(m) {
+            Some(reason) => {
+                eprintln!("Skipping metric {:.64?}: {}", m, reason);
+                false
             }
+            None => true,
         })
-        .collect()
+        .fold(Vec::new(), |metrics, m| {
+            match PRESETS.iter().find(|(name, _)| *name == m) {
+                Some((_, preset)) => append_metrics(metrics, preset),
+                None => append_metrics(metrics, &[m]),
+            }
+        });
+    if metrics.is_empty() {
+        vec![DURATION_METRIC.to_string()]
+    } else {
+        metrics
+    }
 }
 
 #[cfg(test)]
@@ -89,4 +311,122 @@ mod tests {
         let metrics = parse_metrics(input);
         assert_eq!(metrics, vec!["metric1", "metric2"]);
     }
+
+    #[test]
+    fn test_parse_metrics_presets() {
+        let metrics = parse_metrics("sol");
+        assert_eq!(metrics, SOL_METRICS);
+        let metrics = parse_metrics("dram__bytes.sum, sol, gpu__time_duration.sum");
+        assert_eq!(metrics.len(), SOL_METRICS.len() + 1);
+        assert_eq!(metrics[0], "dram__bytes.sum");
+        assert_eq!(metrics[1..], *SOL_METRICS);
+        // The floating point instructions are shared with `roofline`.
+        let metrics = parse_metrics("roofline,flops");
+        assert_eq!(metrics.len(), ROOFLINE_METRICS.len() + 1);
+        assert_eq!(metrics.last().unwrap(), FLOPS_METRICS.last().unwrap());
+        assert_eq!(parse_metrics("dram"), DRAM_METRICS);
+        assert_eq!(parse_metrics("smem"), SMEM_METRICS);
+        assert_eq!(parse_metrics("cache"), CACHE_METRICS);
+    }
+
+    #[test]
+    fn test_parse_metrics_malformed() {
+        let long = "a".repeat(MAX_METRIC_NAME_LEN + 1);
+        let input = format!("metric1,metric\0two;{},metric1, metric2,metric2", long);
+        assert_eq!(parse_metrics(&input), vec!["metric1", "metric2"]);
+        assert_eq!(malformed_metric_name(&long), Some("it is too long"));
+        assert_eq!(malformed_metric_name(&long[1..]), None);
+        assert_eq!(parse_metrics("metric\0two"), vec![DURATION_METRIC]);
+    }
+
+    #[test]
+    fn test_remove_metrics() {
+        let metrics: Vec<String> = ["gpu__time_duration.sum", "typo", "sm__cycles"]
+            .map(String::from)
+            .to_vec();
+        let dropped = vec!["typo".to_string(), "gpu__time_duration.sum".to_string()];
+        assert_eq!(
+            remove_metrics(&metrics, &dropped),
+            vec!["gpu__time_duration.sum", "sm__cycles"]
+        );
+        assert_eq!(
+            remove_metrics(&["typo".to_string()], &dropped),
+            vec![DURATION_METRIC]
+        );
+    }
+
+    #[test]
+    fn test_first_metrics() {
+        let metrics: Vec<String> = ["sm__cycles", "dram__bytes.sum", "gpu__time_duration.sum"]
+            .map(String::from)
+            .to_vec();
+        assert_eq!(
+            first_metrics(&metrics, 2),
+            vec!["gpu__time_duration.sum", "sm__cycles"]
+        );
+        assert_eq!(first_metrics(&metrics[..2], 1), vec!["sm__cycles"]);
+        assert_eq!(first_metrics(&metrics, 5).len(), 3);
+    }
+
+    #[test]
+    fn test_append_metrics() {
+        let metrics = append_metrics(
+            vec![
+                "dram__bytes.sum".to_string(),
+                "gpu__time_duration.sum".to_string(),
+            ],
+            ROOFLINE_METRICS,
+        );
+        assert_eq!(metrics.len(), ROOFLINE_METRICS.len() + 1);
+        assert_eq!(metrics[0], "dram__bytes.sum");
+        assert_eq!(metrics[2], ROOFLINE_METRICS[0]);
+    }
+
+    #[test]
+    fn test_raw_counter_metrics() {
+        let metrics: Vec<String> = [
+            "dram__bytes_read",
+            "dram__bytes_read.sum",
+            "sm__cycles_active.avg.pct_of_peak_sustained_active",
+        ]
+        .map(String::from)
+        .to_vec();
+        let counters = raw_counter_metrics(&metrics);
+        assert_eq!(
+            counters,
+            vec![
+                "dram__bytes_read.sum",
+                "sm__cycles_active.sum",
+                DURATION_METRIC
+            ]
+        );
+        assert_eq!(
+            raw_counter_metrics(&[DURATION_METRIC.to_string()]),
+            vec![DURATION_METRIC]
+        );
+        let values = raw_counter_values(&[MetricValuePair {
+            metric_name: counters[0].clone(),
+            value: 4096.0,
+        }]);
+        assert_eq!(values[0].metric_name, "dram__bytes_read");
+        assert_eq!(values[0].value, 4096.0);
+    }
+
+    #[test]
+    fn test_parse_counter_aliases() {
+        let aliases = parse_counter_aliases(
+            "sm__throughput.avg.pct_of_peak_sustained_elapsed = SM busy %; typo; =x; \
+             gpu__time_duration.sum=Duration (ns);gpu__time_duration.sum=Again",
+        );
+        assert_eq!(
+            aliases,
+            vec![
+                (
+                    "sm__throughput.avg.pct_of_peak_sustained_elapsed".to_string(),
+                    "SM busy %".to_string()
+                ),
+                (DURATION_METRIC.to_string(), "Duration (ns)".to_string()),
+            ]
+        );
+    }
 }
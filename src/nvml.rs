@@ -0,0 +1,174 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The NVML functions the energy and clock samplers read devices with, and
+//! copies between devices are classified with.
+//!
+//! NVML is loaded at runtime, rather than linked, and only once something
+//! needs it, so that the library works without it.
+
+use cupti_profiler::bindings::*;
+use std::{
+    ffi::{c_char, c_int, c_uint, c_void, CString},
+    sync::OnceLock,
+};
+
+const NVML_LIBRARY: &str = "libnvidia-ml.so.1";
+const NVML_SUCCESS: c_int = 0;
+
+/// `nvmlClockType_t` of the SM clock.
+pub const NVML_CLOCK_SM: c_int = 1;
+/// `nvmlClockType_t` of the memory clock.
+pub const NVML_CLOCK_MEM: c_int = 2;
+
+/// `nvmlGpuP2PCapsIndex_t` of peer access over NVLink.
+const NVML_P2P_CAPS_INDEX_NVLINK: c_int = 2;
+/// `nvmlGpuP2PStatus_t` of a supported capability.
+const NVML_P2P_STATUS_OK: c_int = 0;
+
+/// An NVML device handle, as `Nvml::device` returns it.
+#[derive(Debug, Clone, Copy)]
+pub struct NvmlDevice(*mut c_void);
+
+// NVML handles can be used from any thread.
+unsafe impl Send for NvmlDevice {}
+
+type NvmlInit = unsafe extern "C" fn() -> c_int;
+type NvmlDeviceGetHandleByPciBusId = unsafe extern "C" fn(*const c_char, *mut *mut c_void) -> c_int;
+type NvmlDeviceGetTotalEnergyConsumption = unsafe extern "C" fn(*mut c_void, *mut u64) -> c_int;
+type NvmlDeviceGetClockInfo = unsafe extern "C" fn(*mut c_void, c_int, *mut c_uint) -> c_int;
+type NvmlDeviceGetP2PStatus =
+    unsafe extern "C" fn(*mut c_void, *mut c_void, c_int, *mut c_int) -> c_int;
+
+/// The NVML functions devices are read with.
+pub struct Nvml {
+    device_get_handle_by_pci_bus_id: NvmlDeviceGetHandleByPciBusId,
+    device_get_total_energy_consumption: NvmlDeviceGetTotalEnergyConsumption,
+    device_get_clock_info: NvmlDeviceGetClockInfo,
+    device_get_p2p_status: NvmlDeviceGetP2PStatus,
+}
+
+static NVML: OnceLock<Result<Nvml, String>> = OnceLock::new();
+
+/// Loads and initializes NVML the first time, and returns it or why it
+/// could not be loaded.
+pub fn load() -> Result<&'static Nvml, String> {
+    NVML.get_or_init(Nvml::load).as_ref().map_err(Clone::clone)
+}
+
+impl Nvml {
+    fn load() -> Result<Self, String> {
+        let path = CString::new(NVML_LIBRARY).unwrap();
+        let library = unsafe { libc::dlopen(path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        if library.is_null() {
+            return Err(format!("Failed to load {}", NVML_LIBRARY));
+        }
+        let lookup = |name: &str| {
+            let c_name = CString::new(name).unwrap();
+            let symbol = unsafe { libc::dlsym(library, c_name.as_ptr()) };
+            if symbol.is_null() {
+                Err(format!("{} has no {}", NVML_LIBRARY, name))
+            } else {
+                Ok(symbol)
+            }
+        };
+        unsafe {
+            let init = std::mem::transmute::<*mut c_void, NvmlInit>(lookup("nvmlInit_v2")?);
+            let nvml = Self {
+                device_get_handle_by_pci_bus_id: std::mem::transmute::<
+                    *mut c_void,
+                    NvmlDeviceGetHandleByPciBusId,
+                >(lookup(
+                    "nvmlDeviceGetHandleByPciBusId_v2",
+                )?),
+                device_get_total_energy_consumption: std::mem::transmute::<
+                    *mut c_void,
+                    NvmlDeviceGetTotalEnergyConsumption,
+                >(lookup(
+                    "nvmlDeviceGetTotalEnergyConsumption",
+                )?),
+                device_get_clock_info: std::mem::transmute::<*mut c_void, NvmlDeviceGetClockInfo>(
+                    lookup("nvmlDeviceGetClockInfo")?,
+                ),
+                device_get_p2p_status: std::mem::transmute::<*mut c_void, NvmlDeviceGetP2PStatus>(
+                    lookup("nvmlDeviceGetP2PStatus")?,
+                ),
+            };
+            let result = init();
+            if result != NVML_SUCCESS {
+                return Err(format!("Failed to initialize NVML: error {}", result));
+            }
+            Ok(nvml)
+        }
+    }
+
+    /// The NVML handle of the CUDA device `device`.
+    pub fn device(&self, device: CUdevice) -> Result<NvmlDevice, String> {
+        // CUDA and NVML may number devices differently, but agree on PCI IDs.
+        let attribute = |attr| cupti_profiler::get_device_attribute(device, attr).unwrap_or(0);
+        let bus_id = format!(
+            "{:08X}:{:02X}:{:02X}.0",
+            attribute(CUdevice_attribute_enum_CU_DEVICE_ATTRIBUTE_PCI_DOMAIN_ID),
+            attribute(CUdevice_attribute_enum_CU_DEVICE_ATTRIBUTE_PCI_BUS_ID),
+            attribute(CUdevice_attribute_enum_CU_DEVICE_ATTRIBUTE_PCI_DEVICE_ID)
+        );
+        let c_bus_id = CString::new(bus_id.clone()).unwrap();
+        let mut handle = std::ptr::null_mut();
+        let result =
+            unsafe { (self.device_get_handle_by_pci_bus_id)(c_bus_id.as_ptr(), &mut handle) };
+        if result != NVML_SUCCESS {
+            return Err(format!(
+                "Failed to find device {} ({}) in NVML: error {}",
+                device, bus_id, result
+            ));
+        }
+        Ok(NvmlDevice(handle))
+    }
+
+    /// Total energy `device` consumed since the driver was loaded, in mJ.
+    pub fn total_energy(&self, device: NvmlDevice) -> Result<u64, c_int> {
+        let mut energy = 0;
+        let result = unsafe { (self.device_get_total_energy_consumption)(device.0, &mut energy) };
+        if result == NVML_SUCCESS {
+            Ok(energy)
+        } else {
+            Err(result)
+        }
+    }
+
+    /// Current clock of `device` of the type `clock`, e.g. `NVML_CLOCK_SM`,
+    /// in MHz.
+    pub fn clock(&self, device: NvmlDevice, clock: c_int) -> Result<u32, c_int> {
+        let mut mhz = 0;
+        let result = unsafe { (self.device_get_clock_info)(device.0, clock, &mut mhz) };
+        if result == NVML_SUCCESS {
+            Ok(mhz)
+        } else {
+            Err(result)
+        }
+    }
+
+    /// Whether `a` and `b` reach each other over NVLink.
+    pub fn is_nvlink(&self, a: NvmlDevice, b: NvmlDevice) -> Result<bool, c_int> {
+        let mut status = 0;
+        let result = unsafe {
+            (self.device_get_p2p_status)(a.0, b.0, NVML_P2P_CAPS_INDEX_NVLINK, &mut status)
+        };
+        if result == NVML_SUCCESS {
+            Ok(status == NVML_P2P_STATUS_OK)
+        } else {
+            Err(result)
+        }
+    }
+}
@@ -0,0 +1,343 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Profiling overhead accounting.
+//!
+//! Time spent in our callbacks, including the kernel replay that happens
+//! between the enter and exit callbacks of a profiled launch, is added up and
+//! compared to wall time once per window. While the share exceeds the budget,
+//! fewer kernels are profiled, until range profiling is turned off altogether.
+//!
+//! Some of that time is also broken down by kind of work, and sampled into
+//! counters that are written to the trace next to the kernels.
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Wall time over which overhead is measured before throttling is considered.
+pub const OVERHEAD_WINDOW: Duration = Duration::from_secs(1);
+
+/// Largest sampling interval before range profiling is disabled instead.
+pub const MAX_SAMPLE_INTERVAL: u64 = 64;
+
+static SPENT_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Adds time spent on profiling to the total.
+pub fn record(spent: Duration) {
+    SPENT_NS.fetch_add(spent.as_nanos() as u64, Ordering::Relaxed);
+}
+
+/// Total time spent on profiling so far, in nanoseconds.
+pub fn spent_ns() -> u64 {
+    SPENT_NS.load(Ordering::Relaxed)
+}
+
+/// Work that is timed for the overhead counters in the trace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stat {
+    /// Kernel launch callbacks, without kernel replay.
+    LaunchCallback,
+    /// Decoding counter data, part of the launch callbacks.
+    Decode,
+    /// Evaluating metrics on the worker thread.
+    Evaluate,
+    /// Callbacks waiting for the global or a context lock.
+    LockWait,
+}
+
+/// Number of overhead counters.
+pub const NUM_STATS: usize = 4;
+
+impl Stat {
+    /// All overhead counters, in counter ID order.
+    pub const ALL: [Stat; NUM_STATS] = [
+        Stat::LaunchCallback,
+        Stat::Decode,
+        Stat::Evaluate,
+        Stat::LockWait,
+    ];
+
+    /// Name of the counter in the trace.
+    pub fn name(self) -> &'static str {
+        match self {
+            Stat::LaunchCallback => "injection.launch_callback_time",
+            Stat::Decode => "injection.decode_time",
+            Stat::Evaluate => "injection.evaluate_time",
+            Stat::LockWait => "injection.lock_wait_time",
+        }
+    }
+}
+
+static STATS_NS: [AtomicU64; NUM_STATS] = [const { AtomicU64::new(0) }; NUM_STATS];
+
+/// Adds time spent on `stat` to its counter.
+pub fn record_stat(stat: Stat, spent: Duration) {
+    STATS_NS[stat as usize].fetch_add(spent.as_nanos() as u64, Ordering::Relaxed);
+}
+
+/// Total time spent on each kind of work so far, in nanoseconds.
+pub fn stats_ns() -> [u64; NUM_STATS] {
+    std::array::from_fn(|i| STATS_NS[i].load(Ordering::Relaxed))
+}
+
+/// Values of the overhead counters at a point in time, kept until they are
+/// written to the trace.
+#[derive(Debug, Clone)]
+pub struct StatsSample {
+    pub timestamp: u64,
+    /// Nanoseconds spent so far, indexed by `Stat`.
+    pub values: [u64; NUM_STATS],
+}
+
+/// What to do with the range profiler for a kernel launch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sampling {
+    /// Profile the kernel.
+    Profile,
+    /// Let the kernel run without profiling it.
+    Skip,
+    /// Range profiling is off for good.
+    Disabled,
+}
+
+/// A throttling decision, kept so that it can be written to the trace.
+#[derive(Debug, Clone)]
+pub struct ThrottleEvent {
+    pub timestamp: u64,
+    pub message: String,
+}
+
+/// Decides which kernel launches are profiled to stay within an overhead
+/// budget.
+pub struct OverheadTracker {
+    /// Share of wall time profiling may take, in percent. 0 disables
+    /// throttling.
+    budget_pct: f64,
+    started: Instant,
+    started_spent_ns: u64,
+    window_start: Instant,
+    window_start_spent_ns: u64,
+    sample_interval: u64,
+    launches: u64,
+    disabled: bool,
+    pub events: Vec<ThrottleEvent>,
+    last_stats_sample: Option<Instant>,
+    pub stats_samples: Vec<StatsSample>,
+}
+
+impl OverheadTracker {
+    pub fn new(budget_pct: f64, now: Instant, spent_ns: u64) -> Self {
+        Self {
+            budget_pct,
+            started: now,
+            started_spent_ns: spent_ns,
+            window_start: now,
+            window_start_spent_ns: spent_ns,
+            sample_interval: 1,
+            launches: 0,
+            disabled: false,
+            events: Vec::new(),
+            last_stats_sample: None,
+            stats_samples: Vec::new(),
+        }
+    }
+
+    /// Samples the overhead counters if a window has passed since the last
+    /// sample. `timestamp` gives the trace time and is only called then.
+    pub fn sample_stats(&mut self, now: Instant, timestamp: impl FnOnce() -> u64) {
+        if self
+            .last_stats_sample
+            .is_some_and(|last| now.saturating_duration_since(last) < OVERHEAD_WINDOW)
+        {
+            return;
+        }
+        self.last_stats_sample = Some(now);
+        self.stats_samples.push(StatsSample {
+            timestamp: timestamp(),
+            values: stats_ns(),
+        });
+    }
+
+    /// Number of launches per profiled launch.
+    pub fn sample_interval(&self) -> u64 {
+        self.sample_interval
+    }
+
+    /// Share of wall time profiling may take, in percent, 0 for no limit.
+    pub fn budget_pct(&self) -> f64 {
+        self.budget_pct
+    }
+
+    /// Whether the budget was exceeded at the largest sampling interval, so
+    /// range profiling stays disabled.
+    pub fn is_disabled(&self) -> bool {
+        self.disabled
+    }
+
+    /// Time since the tracker was created.
+    pub fn elapsed(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.started)
+    }
+
+    /// Share of wall time, in percent, spent on profiling since the tracker
+    /// was created.
+    pub fn total_overhead_pct(&self, now: Instant, spent_ns: u64) -> f64 {
+        let elapsed = self.elapsed(now).as_nanos();
+        if elapsed == 0 {
+            return 0.0;
+        }
+        100.0 * spent_ns.saturating_sub(self.started_spent_ns) as f64 / elapsed as f64
+    }
+
+    /// Decides whether the next kernel launch is profiled, given the current
+    /// time and the total time spent on profiling.
+    ///
+    /// `timestamp` is the trace time that throttling decisions are logged at.
+    pub fn next_launch(&mut self, now: Instant, spent_ns: u64, timestamp: u64) -> Sampling {
+        if self.budget_pct <= 0.0 {
+            return Sampling::Profile;
+        }
+        if self.disabled {
+            return Sampling::Disabled;
+        }
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed >= OVERHEAD_WINDOW {
+            let spent = spent_ns.saturating_sub(self.window_start_spent_ns);
+            let overhead_pct = 100.0 * spent as f64 / elapsed.as_nanos() as f64;
+            self.window_start = now;
+            self.window_start_spent_ns = spent_ns;
+            if overhead_pct > self.budget_pct {
+                if self.sample_interval >= MAX_SAMPLE_INTERVAL {
+                    self.disabled = true;
+                    self.log(
+                        timestamp,
+                        format!(
+                            "Range profiling disabled: overhead of {:.1}% exceeds budget of {}%",
+                            overhead_pct, self.budget_pct
+                        ),
+                    );
+                    return Sampling::Disabled;
+                }
+                self.sample_interval *= 2;
+                self.log(
+                    timestamp,
+                    format!(
+                        "Profiling 1 of {} kernels: overhead of {:.1}% exceeds budget of {}%",
+                        self.sample_interval, overhead_pct, self.budget_pct
+                    ),
+                );
+            }
+        }
+        let launch = self.launches;
+        self.launches += 1;
+        if launch.is_multiple_of(self.sample_interval) {
+            Sampling::Profile
+        } else {
+            Sampling::Skip
+        }
+    }
+
+    fn log(&mut self, timestamp: u64, message: String) {
+        self.events.push(ThrottleEvent { timestamp, message });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: u64 = 1_000_000;
+
+    #[test]
+    fn test_unlimited_budget() {
+        let start = Instant::now();
+        let mut tracker = OverheadTracker::new(0.0, start, 0);
+        let now = start + OVERHEAD_WINDOW;
+        assert_eq!(tracker.next_launch(now, 1000 * MS, 0), Sampling::Profile);
+        assert!(tracker.events.is_empty());
+    }
+
+    #[test]
+    fn test_total_overhead() {
+        let start = Instant::now();
+        let tracker = OverheadTracker::new(5.0, start, 100 * MS);
+        assert_eq!(tracker.total_overhead_pct(start, 200 * MS), 0.0);
+        let now = start + 4 * OVERHEAD_WINDOW;
+        assert_eq!(tracker.elapsed(now), 4 * OVERHEAD_WINDOW);
+        assert_eq!(tracker.total_overhead_pct(now, 300 * MS), 5.0);
+        assert!(!tracker.is_disabled());
+    }
+
+    #[test]
+    fn test_sample_stats() {
+        let start = Instant::now();
+        let mut tracker = OverheadTracker::new(0.0, start, 0);
+        let before = stats_ns()[Stat::Decode as usize];
+        record_stat(Stat::Decode, Duration::from_micros(3));
+        tracker.sample_stats(start, || 1);
+        // Within the same window nothing is sampled.
+        tracker.sample_stats(start + OVERHEAD_WINDOW / 2, || unreachable!());
+        tracker.sample_stats(start + OVERHEAD_WINDOW, || 2);
+        assert_eq!(tracker.stats_samples.len(), 2);
+        assert_eq!(tracker.stats_samples[0].timestamp, 1);
+        assert_eq!(tracker.stats_samples[1].timestamp, 2);
+        assert!(tracker.stats_samples[0].values[Stat::Decode as usize] >= before + 3000);
+    }
+
+    #[test]
+    fn test_throttle() {
+        let start = Instant::now();
+        let mut tracker = OverheadTracker::new(10.0, start, 0);
+        // Within the budget: every kernel is profiled.
+        let now = start + OVERHEAD_WINDOW;
+        assert_eq!(tracker.next_launch(now, 50 * MS, 1), Sampling::Profile);
+        assert_eq!(tracker.next_launch(now, 50 * MS, 1), Sampling::Profile);
+        assert!(tracker.events.is_empty());
+
+        // 50% overhead over the next window halves the sampling rate.
+        let now = now + OVERHEAD_WINDOW;
+        let samples: Vec<Sampling> = (0..4)
+            .map(|_| tracker.next_launch(now, 550 * MS, 2))
+            .collect();
+        assert_eq!(tracker.sample_interval(), 2);
+        assert_eq!(
+            samples.iter().filter(|&&s| s == Sampling::Profile).count(),
+            2
+        );
+        assert_eq!(tracker.events.len(), 1);
+        assert_eq!(tracker.events[0].timestamp, 2);
+        assert_eq!(
+            tracker.events[0].message,
+            "Profiling 1 of 2 kernels: overhead of 50.0% exceeds budget of 10%"
+        );
+
+        // Staying over budget eventually turns range profiling off.
+        let mut now = now;
+        let mut spent_ns = 550 * MS;
+        while tracker.sample_interval() < MAX_SAMPLE_INTERVAL {
+            now += OVERHEAD_WINDOW;
+            spent_ns += 500 * MS;
+            tracker.next_launch(now, spent_ns, 3);
+        }
+        now += OVERHEAD_WINDOW;
+        spent_ns += 500 * MS;
+        assert_eq!(tracker.next_launch(now, spent_ns, 4), Sampling::Disabled);
+        assert_eq!(tracker.next_launch(now, spent_ns, 4), Sampling::Disabled);
+        let last = tracker.events.last().unwrap();
+        assert_eq!(last.timestamp, 4);
+        assert!(last.message.starts_with("Range profiling disabled"));
+    }
+}
@@ -0,0 +1,262 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Prometheus endpoint for live per-kernel aggregates.
+//!
+//! Kernel durations are added as activity records come in and metric values
+//! as ranges are evaluated, keyed by kernel name. Both are exposed as
+//! summaries, so `rate(x_sum[5m]) / rate(x_count[5m])` gives the mean over a
+//! rolling window while the application runs.
+
+use crate::http::{self, Page};
+use crate::trace_emitter::demangle;
+use cupti_profiler::RangeInfo;
+use once_cell::sync::Lazy;
+use std::{collections::HashMap, fmt::Write as _, io, net::SocketAddr, sync::Mutex};
+
+/// Kernel names tracked before further kernels are counted as `OTHER_KERNELS`,
+/// which bounds the number of series.
+pub const MAX_KERNEL_NAMES: usize = 1000;
+
+/// Label of the kernels past `MAX_KERNEL_NAMES`.
+const OTHER_KERNELS: &str = "other";
+
+/// Sum and number of the values of one series.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct Summary {
+    sum: f64,
+    count: u64,
+}
+
+impl Summary {
+    fn add(&mut self, value: f64) {
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+#[derive(Debug, Default)]
+struct KernelStats {
+    /// Durations, in seconds.
+    duration: Summary,
+    /// Values of each metric, in the order first seen.
+    metrics: Vec<(String, Summary)>,
+}
+
+/// Per-kernel aggregates since the endpoint was started.
+#[derive(Debug, Default)]
+pub struct Aggregates {
+    kernels: HashMap<String, KernelStats>,
+}
+
+impl Aggregates {
+    fn kernel(&mut self, name: &str) -> &mut KernelStats {
+        let name = if self.kernels.contains_key(name) || self.kernels.len() < MAX_KERNEL_NAMES {
+            name
+        } else {
+            OTHER_KERNELS
+        };
+        self.kernels.entry(name.to_string()).or_default()
+    }
+
+    /// Adds a kernel that ran for `duration` nanoseconds.
+    pub fn add_kernel(&mut self, name: &str, duration: u64) {
+        self.kernel(name).duration.add(duration as f64 / 1e9);
+    }
+
+    /// Adds the metric values of evaluated ranges to the kernels they
+    /// profiled.
+    pub fn add_ranges(&mut self, ranges: &[RangeInfo]) {
+        for range in ranges {
+            let stats = self.kernel(range.leaf_name());
+            for metric in &range.metric_and_values {
+                if !metric.value.is_finite() {
+                    continue;
+                }
+                match stats
+                    .metrics
+                    .iter_mut()
+                    .find(|(name, _)| *name == metric.metric_name)
+                {
+                    Some((_, summary)) => summary.add(metric.value),
+                    None => {
+                        let mut summary = Summary::default();
+                        summary.add(metric.value);
+                        stats.metrics.push((metric.metric_name.clone(), summary));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Formats the aggregates in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut kernels: Vec<(String, &KernelStats)> = self
+            .kernels
+            .iter()
+            .map(|(name, stats)| (escape_label(&demangle(name)), stats))
+            .collect();
+        kernels.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut out = String::new();
+        out.push_str("# HELP cupti_kernel_duration_seconds GPU execution time of kernels.\n");
+        out.push_str("# TYPE cupti_kernel_duration_seconds summary\n");
+        for (kernel, stats) in kernels.iter().filter(|(_, s)| s.duration.count > 0) {
+            let labels = format!("kernel=\"{}\"", kernel);
+            append_summary(
+                &mut out,
+                "cupti_kernel_duration_seconds",
+                &labels,
+                stats.duration,
+            );
+        }
+        out.push_str("# HELP cupti_kernel_metric Metric values of profiled kernels.\n");
+        out.push_str("# TYPE cupti_kernel_metric summary\n");
+        for (kernel, stats) in &kernels {
+            for (metric, summary) in &stats.metrics {
+                let labels = format!("kernel=\"{}\",metric=\"{}\"", kernel, escape_label(metric));
+                append_summary(&mut out, "cupti_kernel_metric", &labels, *summary);
+            }
+        }
+        out
+    }
+}
+
+fn append_summary(out: &mut String, name: &str, labels: &str, summary: Summary) {
+    let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, summary.sum);
+    let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, summary.count);
+}
+
+/// Escapes a label value of the text exposition format.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Aggregates served by the endpoint, `None` until it is started so that
+/// nothing is collected otherwise.
+static AGGREGATES: Lazy<Mutex<Option<Aggregates>>> = Lazy::new(|| Mutex::new(None));
+
+/// Adds a completed kernel, if the endpoint is running.
+pub fn record_kernel(name: &str, duration: u64) {
+    if let Ok(mut aggregates) = AGGREGATES.lock() {
+        if let Some(aggregates) = aggregates.as_mut() {
+            aggregates.add_kernel(name, duration);
+        }
+    }
+}
+
+/// Adds evaluated ranges, if the endpoint is running.
+pub fn record_ranges(ranges: &[RangeInfo]) {
+    if let Ok(mut aggregates) = AGGREGATES.lock() {
+        if let Some(aggregates) = aggregates.as_mut() {
+            aggregates.add_ranges(ranges);
+        }
+    }
+}
+
+fn render() -> String {
+    AGGREGATES
+        .lock()
+        .ok()
+        .and_then(|aggregates| aggregates.as_ref().map(Aggregates::render))
+        .unwrap_or_default()
+}
+
+fn route(path: &str) -> Option<Page> {
+    (path == "/metrics").then(|| ("text/plain; version=0.0.4", render()))
+}
+
+/// Starts collecting aggregates and serving them on `addr`, e.g.
+/// `127.0.0.1:9464`. Returns the address bound, which has the port picked
+/// when `addr` asks for port 0.
+pub fn start(addr: &str) -> io::Result<SocketAddr> {
+    if let Ok(mut aggregates) = AGGREGATES.lock() {
+        aggregates.get_or_insert_with(Aggregates::default);
+    }
+    http::start(addr, "cupti-prometheus", route)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cupti_profiler::MetricValuePair;
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+    };
+
+    fn range(name: &str, value: f64) -> RangeInfo {
+        RangeInfo {
+            range_name: format!("outer/{}", name),
+            parent_ranges: vec!["outer".to_string()],
+            depth: 1,
+            metric_and_values: vec![MetricValuePair {
+                metric_name: "sm__cycles".to_string(),
+                value,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let mut aggregates = Aggregates::default();
+        aggregates.add_kernel("_Z5scalePf", 1_000_000);
+        aggregates.add_kernel("_Z5scalePf", 3_000_000);
+        aggregates.add_kernel("copy\"a\"", 500);
+        aggregates.add_ranges(&[range("_Z5scalePf", 10.0), range("_Z5scalePf", 30.0)]);
+        aggregates.add_ranges(&[range("_Z5scalePf", f64::NAN)]);
+        assert_eq!(
+            aggregates.render(),
+            concat!(
+                "# HELP cupti_kernel_duration_seconds GPU execution time of kernels.\n",
+                "# TYPE cupti_kernel_duration_seconds summary\n",
+                "cupti_kernel_duration_seconds_sum{kernel=\"copy\\\"a\\\"\"} 0.0000005\n",
+                "cupti_kernel_duration_seconds_count{kernel=\"copy\\\"a\\\"\"} 1\n",
+                "cupti_kernel_duration_seconds_sum{kernel=\"scale(float*)\"} 0.004\n",
+                "cupti_kernel_duration_seconds_count{kernel=\"scale(float*)\"} 2\n",
+                "# HELP cupti_kernel_metric Metric values of profiled kernels.\n",
+                "# TYPE cupti_kernel_metric summary\n",
+                "cupti_kernel_metric_sum{kernel=\"scale(float*)\",metric=\"sm__cycles\"} 40\n",
+                "cupti_kernel_metric_count{kernel=\"scale(float*)\",metric=\"sm__cycles\"} 2\n",
+            )
+        );
+
+        // Names past the limit share one series.
+        let mut aggregates = Aggregates::default();
+        for i in 0..MAX_KERNEL_NAMES + 2 {
+            aggregates.add_kernel(&format!("k{}", i), 1);
+        }
+        assert_eq!(aggregates.kernels.len(), MAX_KERNEL_NAMES + 1);
+        assert_eq!(aggregates.kernels[OTHER_KERNELS].duration.count, 2);
+    }
+
+    #[test]
+    fn test_serve() {
+        let addr = start("127.0.0.1:0").unwrap();
+        record_kernel("served", 2_000_000_000);
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("cupti_kernel_duration_seconds_sum{kernel=\"served\"} 2\n"));
+        assert!(get("/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -0,0 +1,321 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use libc::{c_int, c_void};
+use once_cell::sync::Lazy;
+use std::{
+    collections::HashMap,
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicU64, Ordering},
+        Mutex, Once,
+    },
+    thread,
+    time::Duration,
+};
+
+/// Work to run in response to a signal, outside of signal-handler context.
+pub type SignalAction = fn();
+
+/// Write end of the self-pipe used to hand signals over to the watcher thread.
+static PIPE_WRITE_FD: AtomicI32 = AtomicI32::new(-1);
+static WATCHER_STARTED: Once = Once::new();
+
+/// Actions to run on the watcher thread, keyed by signal number.
+static ACTIONS: Lazy<Mutex<HashMap<c_int, SignalAction>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Handlers that `install_chained` replaced, run after the action.
+static PREVIOUS: Lazy<Mutex<HashMap<c_int, libc::sigaction>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Number of actions the watcher thread has finished, which the handler of
+/// a fatal signal waits on.
+static ACTIONS_RUN: AtomicU32 = AtomicU32::new(0);
+
+/// Whether a fatal signal is being handled, so that a crash in its action
+/// does not wait on itself.
+static CRASHING: AtomicBool = AtomicBool::new(false);
+
+/// How long the handler of a fatal signal waits for its action.
+static FATAL_TIMEOUT_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Async-signal-safe handler that forwards the signal number to the watcher thread.
+extern "C" fn forward_signal(signum: c_int) {
+    let fd = PIPE_WRITE_FD.load(Ordering::SeqCst);
+    if fd >= 0 {
+        let byte = signum as u8;
+        unsafe { libc::write(fd, &byte as *const u8 as *const c_void, 1) };
+    }
+}
+
+fn start_watcher() -> io::Result<()> {
+    let mut result = Ok(());
+    WATCHER_STARTED.call_once(|| {
+        let mut fds = [0 as c_int; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            result = Err(io::Error::last_os_error());
+            return;
+        }
+        for fd in fds {
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        }
+        let read_fd = fds[0];
+        PIPE_WRITE_FD.store(fds[1], Ordering::SeqCst);
+        let spawned = thread::Builder::new()
+            .name("cupti-signals".to_string())
+            .spawn(move || loop {
+                let mut byte = 0u8;
+                let n = unsafe { libc::read(read_fd, &mut byte as *mut u8 as *mut c_void, 1) };
+                if n == 1 {
+                    let action = ACTIONS
+                        .lock()
+                        .ok()
+                        .and_then(|a| a.get(&(byte as c_int)).copied());
+                    if let Some(action) = action {
+                        action();
+                    }
+                    ACTIONS_RUN.fetch_add(1, Ordering::SeqCst);
+                    chain(byte as c_int);
+                } else if n == 0 || io::Error::last_os_error().kind() != io::ErrorKind::Interrupted
+                {
+                    break;
+                }
+            });
+        if let Err(e) = spawned {
+            PIPE_WRITE_FD.store(-1, Ordering::SeqCst);
+            result = Err(e);
+        }
+    });
+    result
+}
+
+/// Runs `action` on a dedicated thread whenever `signum` is delivered.
+///
+/// The signal handler itself only writes to a pipe, so `action` is free to
+/// take locks and call into CUPTI.
+pub fn install(signum: c_int, action: SignalAction) -> io::Result<()> {
+    start_watcher()?;
+    if let Ok(mut actions) = ACTIONS.lock() {
+        actions.insert(signum, action);
+    }
+    let mut sa: libc::sigaction = unsafe { std::mem::zeroed() };
+    sa.sa_sigaction = forward_signal as extern "C" fn(c_int) as libc::sighandler_t;
+    sa.sa_flags = libc::SA_RESTART;
+    unsafe { libc::sigemptyset(&mut sa.sa_mask) };
+    if unsafe { libc::sigaction(signum, &sa, std::ptr::null_mut()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Runs the handler `install_chained` replaced for `signum`, if any, by
+/// raising the signal again with it in place. A default action that ends
+/// the process does so here.
+fn chain(signum: c_int) {
+    let Some(previous) = PREVIOUS.lock().ok().and_then(|p| p.get(&signum).copied()) else {
+        return;
+    };
+    let mut ours: libc::sigaction = unsafe { std::mem::zeroed() };
+    unsafe {
+        libc::sigaction(signum, &previous, &mut ours);
+        libc::raise(signum);
+        libc::sigaction(signum, &ours, std::ptr::null_mut());
+    }
+}
+
+/// Runs `action` on the watcher thread whenever `signum` is delivered, and
+/// then whatever handled the signal before, so that a signal that ended the
+/// process still does. Signals the process ignores are left alone.
+pub fn install_chained(signum: c_int, action: SignalAction) -> io::Result<()> {
+    let mut previous: libc::sigaction = unsafe { std::mem::zeroed() };
+    if unsafe { libc::sigaction(signum, std::ptr::null(), &mut previous) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if previous.sa_sigaction == libc::SIG_IGN {
+        return Ok(());
+    }
+    if let Ok(mut handlers) = PREVIOUS.lock() {
+        handlers.insert(signum, previous);
+    }
+    install(signum, action)
+}
+
+/// Handler of a fatal signal, which has the watcher thread run the action
+/// and then ends the process with the default action of the signal.
+///
+/// The thread that got the signal cannot go on, so it waits here for the
+/// action, at most `FATAL_TIMEOUT_MS`, in case it holds a lock the action
+/// needs.
+extern "C" fn on_fatal_signal(signum: c_int) {
+    if !CRASHING.swap(true, Ordering::SeqCst) {
+        let actions_run = ACTIONS_RUN.load(Ordering::SeqCst);
+        forward_signal(signum);
+        let pause = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 1_000_000,
+        };
+        let mut waited_ms = 0;
+        while ACTIONS_RUN.load(Ordering::SeqCst) == actions_run
+            && waited_ms < FATAL_TIMEOUT_MS.load(Ordering::SeqCst)
+        {
+            unsafe { libc::nanosleep(&pause, std::ptr::null_mut()) };
+            waited_ms += 1;
+        }
+    }
+    unsafe {
+        libc::signal(signum, libc::SIG_DFL);
+        libc::raise(signum);
+    }
+}
+
+/// Runs `action` on the watcher thread, waiting for it at most `timeout`,
+/// when one of `signals` is about to end the process, e.g. `SIGSEGV` or the
+/// `SIGABRT` of `abort`. This is best effort, since the process may be in
+/// any state. Signals the application handles itself are left alone.
+pub fn install_fatal(signals: &[c_int], action: SignalAction, timeout: Duration) -> io::Result<()> {
+    start_watcher()?;
+    FATAL_TIMEOUT_MS.store(timeout.as_millis() as u64, Ordering::SeqCst);
+    for &signum in signals {
+        let mut previous: libc::sigaction = unsafe { std::mem::zeroed() };
+        if unsafe { libc::sigaction(signum, std::ptr::null(), &mut previous) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if previous.sa_sigaction != libc::SIG_DFL {
+            continue;
+        }
+        if let Ok(mut actions) = ACTIONS.lock() {
+            actions.insert(signum, action);
+        }
+        let mut sa: libc::sigaction = unsafe { std::mem::zeroed() };
+        sa.sa_sigaction = on_fatal_signal as extern "C" fn(c_int) as libc::sighandler_t;
+        // A stack overflow leaves no stack to handle it on, other than the
+        // alternate one if the thread has one.
+        sa.sa_flags = libc::SA_ONSTACK;
+        unsafe { libc::sigemptyset(&mut sa.sa_mask) };
+        if unsafe { libc::sigaction(signum, &sa, std::ptr::null_mut()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Parses a signal given either by number or by name (`SIGUSR2`, `USR2`).
+pub fn parse_signal(input: &str) -> Option<c_int> {
+    let input = input.trim();
+    if let Ok(signum) = input.parse::<c_int>() {
+        return (signum > 0).then_some(signum);
+    }
+    let name = input.to_ascii_uppercase();
+    match name.strip_prefix("SIG").unwrap_or(&name) {
+        "USR1" => Some(libc::SIGUSR1),
+        "USR2" => Some(libc::SIGUSR2),
+        "HUP" => Some(libc::SIGHUP),
+        "URG" => Some(libc::SIGURG),
+        "INT" => Some(libc::SIGINT),
+        "TERM" => Some(libc::SIGTERM),
+        "QUIT" => Some(libc::SIGQUIT),
+        _ => None,
+    }
+}
+
+/// Parses a comma separated list of signals, skipping unknown ones. `none`
+/// gives no signals.
+pub fn parse_signals(input: &str) -> Vec<c_int> {
+    if input.trim().eq_ignore_ascii_case("none") {
+        return Vec::new();
+    }
+    input.split(',').filter_map(parse_signal).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::Command;
+    use std::time::Instant;
+
+    static ACTION_RAN: AtomicBool = AtomicBool::new(false);
+    static PREVIOUS_RAN_AFTER_ACTION: AtomicBool = AtomicBool::new(false);
+
+    fn action() {
+        ACTION_RAN.store(true, Ordering::SeqCst);
+    }
+
+    fn report_crash() {
+        let message = b"action ran\n";
+        unsafe { libc::write(1, message.as_ptr() as *const c_void, message.len()) };
+    }
+
+    extern "C" fn previous_handler(_signum: c_int) {
+        PREVIOUS_RAN_AFTER_ACTION.store(ACTION_RAN.load(Ordering::SeqCst), Ordering::SeqCst);
+    }
+
+    #[test]
+    fn test_parse_signal() {
+        assert_eq!(parse_signal("SIGUSR2"), Some(libc::SIGUSR2));
+        assert_eq!(parse_signal("usr1"), Some(libc::SIGUSR1));
+        assert_eq!(parse_signal(" 12 "), Some(12));
+        assert_eq!(parse_signal("0"), None);
+        assert_eq!(parse_signal("SIGFOO"), None);
+        assert_eq!(
+            parse_signals("SIGINT, term,bogus"),
+            vec![libc::SIGINT, libc::SIGTERM]
+        );
+        assert_eq!(parse_signals("none"), Vec::<c_int>::new());
+        assert_eq!(parse_signals(""), Vec::<c_int>::new());
+    }
+
+    #[test]
+    fn test_install_chained() {
+        let previous = previous_handler as extern "C" fn(c_int) as libc::sighandler_t;
+        unsafe { libc::signal(libc::SIGUSR1, previous) };
+        install_chained(libc::SIGUSR1, action).unwrap();
+        unsafe { libc::raise(libc::SIGUSR1) };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !PREVIOUS_RAN_AFTER_ACTION.load(Ordering::SeqCst) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(PREVIOUS_RAN_AFTER_ACTION.load(Ordering::SeqCst));
+        // The action stays installed for the next signal.
+        let mut current: libc::sigaction = unsafe { std::mem::zeroed() };
+        unsafe { libc::sigaction(libc::SIGUSR1, std::ptr::null(), &mut current) };
+        assert_ne!(current.sa_sigaction, previous);
+
+        unsafe { libc::signal(libc::SIGUSR2, libc::SIG_IGN) };
+        install_chained(libc::SIGUSR2, action).unwrap();
+        unsafe { libc::sigaction(libc::SIGUSR2, std::ptr::null(), &mut current) };
+        assert_eq!(current.sa_sigaction, libc::SIG_IGN);
+    }
+
+    #[test]
+    fn test_install_fatal() {
+        // The process aborts, so it is run again on its own.
+        if std::env::var_os("SIGNALS_TEST_ABORT").is_some() {
+            install_fatal(&[libc::SIGABRT], report_crash, Duration::from_secs(5)).unwrap();
+            std::process::abort();
+        }
+        let output = Command::new(std::env::current_exe().unwrap())
+            .args([
+                "signals::tests::test_install_fatal",
+                "--exact",
+                "--nocapture",
+            ])
+            .env("SIGNALS_TEST_ABORT", "1")
+            .output()
+            .unwrap();
+        assert_eq!(output.status.signal(), Some(libc::SIGABRT));
+        assert!(String::from_utf8_lossy(&output.stdout).contains("action ran"));
+    }
+}
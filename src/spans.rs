@@ -0,0 +1,223 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Track events for the `tracing` spans of a Rust application.
+//!
+//! An application that links this crate and calls `InitializeInjection`
+//! itself can add `PerfettoLayer` to its subscriber. Every time a span is
+//! entered and exited becomes a slice in the `host` category, on the thread
+//! it ran on and with its fields as arguments, and every event an instant.
+//! They go through the same producer as the kernels, so one trace has both
+//! the CPU and the GPU side of the application.
+
+use crate::tracing;
+use perfetto_sdk::track_event::{EventContext, TrackEventDebugArg, TrackEventType};
+use perfetto_sdk::{track_event, track_event_categories};
+use std::{cell::RefCell, collections::HashMap, ffi::CString, fmt, sync::Mutex};
+use tracing_core::{
+    field::{Field, Visit},
+    span, Event, Subscriber,
+};
+use tracing_subscriber::layer::{Context, Layer};
+
+track_event_categories! {
+    pub mod perfetto_te_ns {
+        ("host", "Spans and events of the application's tracing subscriber", []),
+    }
+}
+
+/// Track event category of the spans and events.
+pub const CATEGORY: &str = "host";
+
+/// A field value of a span or event.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    U64(u64),
+    I64(i64),
+    F64(f64),
+    Str(String),
+}
+
+impl Value {
+    fn debug_arg(&self) -> TrackEventDebugArg<'_> {
+        match self {
+            Value::Bool(v) => TrackEventDebugArg::Bool(*v),
+            Value::U64(v) => TrackEventDebugArg::Uint64(*v),
+            Value::I64(v) => TrackEventDebugArg::Int64(*v),
+            Value::F64(v) => TrackEventDebugArg::Double(*v),
+            Value::Str(v) => TrackEventDebugArg::String(v),
+        }
+    }
+}
+
+/// Fields of a span or event, in the order recorded.
+#[derive(Debug, Default, PartialEq)]
+pub struct Fields(pub Vec<(&'static str, Value)>);
+
+impl Fields {
+    fn set(&mut self, field: &Field, value: Value) {
+        match self.0.iter_mut().find(|(name, _)| *name == field.name()) {
+            Some((_, old)) => *old = value,
+            None => self.0.push((field.name(), value)),
+        }
+    }
+
+    /// Removes the `message` of an event, which names its instant.
+    fn take_message(&mut self) -> Option<String> {
+        let index = self.0.iter().position(|(name, _)| *name == "message")?;
+        match self.0.remove(index).1 {
+            Value::Str(message) => Some(message),
+            _ => None,
+        }
+    }
+
+    fn add_to(&self, ctx: &mut EventContext) {
+        for (name, value) in &self.0 {
+            ctx.add_debug_arg(name, value.debug_arg());
+        }
+    }
+}
+
+impl Visit for Fields {
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.set(field, Value::Bool(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.set(field, Value::U64(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.set(field, Value::I64(value));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.set(field, Value::F64(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.set(field, Value::Str(value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.set(field, Value::Str(format!("{:?}", value)));
+    }
+}
+
+struct Span {
+    name: CString,
+    fields: Fields,
+}
+
+thread_local! {
+    /// Whether each span entered on this thread got a begin event, so that
+    /// only those get an end event.
+    static OPEN_SLICES: RefCell<Vec<bool>> = const { RefCell::new(Vec::new()) };
+}
+
+fn is_enabled() -> bool {
+    perfetto_te_ns::is_category_enabled(perfetto_te_ns::category_index(CATEGORY))
+}
+
+/// `tracing_subscriber` layer that emits spans and events as track events.
+pub struct PerfettoLayer {
+    /// Name and fields of the spans that are open, by ID.
+    spans: Mutex<HashMap<u64, Span>>,
+}
+
+impl PerfettoLayer {
+    /// Registers the `host` category with the producer, which
+    /// `InitializeInjection` starts.
+    pub fn new() -> Self {
+        tracing::init_track_events();
+        let _ = perfetto_te_ns::register();
+        Self {
+            spans: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Default for PerfettoLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S: Subscriber> Layer<S> for PerfettoLayer {
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, _ctx: Context<'_, S>) {
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        let span = Span {
+            name: CString::new(attrs.metadata().name()).unwrap_or_default(),
+            fields,
+        };
+        if let Ok(mut spans) = self.spans.lock() {
+            spans.insert(id.into_u64(), span);
+        }
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, _ctx: Context<'_, S>) {
+        if let Ok(mut spans) = self.spans.lock() {
+            if let Some(span) = spans.get_mut(&id.into_u64()) {
+                values.record(&mut span.fields);
+            }
+        }
+    }
+
+    fn on_enter(&self, id: &span::Id, _ctx: Context<'_, S>) {
+        let emitted = is_enabled();
+        if emitted {
+            if let Ok(spans) = self.spans.lock() {
+                if let Some(span) = spans.get(&id.into_u64()) {
+                    track_event!(
+                        "host",
+                        TrackEventType::SliceBegin(span.name.as_ptr()),
+                        |ctx: &mut EventContext| span.fields.add_to(ctx)
+                    );
+                }
+            }
+        }
+        OPEN_SLICES.with(|open| open.borrow_mut().push(emitted));
+    }
+
+    fn on_exit(&self, _id: &span::Id, _ctx: Context<'_, S>) {
+        if OPEN_SLICES.with(|open| open.borrow_mut().pop()) == Some(true) {
+            track_event!("host", TrackEventType::SliceEnd);
+        }
+    }
+
+    fn on_close(&self, id: span::Id, _ctx: Context<'_, S>) {
+        if let Ok(mut spans) = self.spans.lock() {
+            spans.remove(&id.into_u64());
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if !is_enabled() {
+            return;
+        }
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let name = fields
+            .take_message()
+            .unwrap_or_else(|| event.metadata().name().to_string());
+        let name = CString::new(name).unwrap_or_default();
+        track_event!(
+            "host",
+            TrackEventType::Instant(name.as_ptr()),
+            |ctx: &mut EventContext| fields.add_to(ctx)
+        );
+    }
+}
@@ -0,0 +1,359 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! On-disk storage for kernels that are ready to be emitted.
+//!
+//! Once a kernel has its launch, activity and, if it was profiled, evaluated
+//! range, nothing about it changes until the trace is written. Long running processes move such
+//! kernels to a temporary file and read them back at emission time, so memory
+//! use does not grow with the number of kernels.
+
+use crate::state::{KernelActivity, KernelLaunch};
+use cupti_profiler::bindings::*;
+use cupti_profiler::{MetricValuePair, RangeInfo};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+/// A kernel with everything needed to emit it.
+pub struct KernelRecord {
+    pub launch: KernelLaunch,
+    pub activity: KernelActivity,
+    /// The evaluated range, if the kernel was profiled.
+    pub range: Option<RangeInfo>,
+}
+
+/// An append-only file of kernel records.
+///
+/// The file is unlinked as soon as it is created, so it goes away with the
+/// process no matter how the process ends.
+pub struct SpillFile {
+    writer: BufWriter<File>,
+    len: usize,
+    /// Set when records were read back, which moves the file position.
+    needs_seek_to_end: bool,
+}
+
+impl SpillFile {
+    /// Creates a spill file for a context in `dir`.
+    pub fn create(dir: &Path, ctx_id: u32) -> io::Result<Self> {
+        let path = dir.join(format!(
+            "perfetto-cupti-{}-{}.spill",
+            std::process::id(),
+            ctx_id
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        fs::remove_file(&path)?;
+        Ok(Self {
+            writer: BufWriter::new(file),
+            len: 0,
+            needs_seek_to_end: false,
+        })
+    }
+
+    /// Number of records in the file.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Appends a record.
+    pub fn append(&mut self, record: &KernelRecord) -> io::Result<()> {
+        if self.needs_seek_to_end {
+            self.writer.seek(SeekFrom::End(0))?;
+            self.needs_seek_to_end = false;
+        }
+        write_record(&mut self.writer, record)?;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Returns an iterator over all records, oldest first.
+    ///
+    /// Can be called repeatedly, and records may still be appended afterwards.
+    pub fn records(&mut self) -> io::Result<impl Iterator<Item = io::Result<KernelRecord>> + '_> {
+        self.writer.flush()?;
+        let mut file = self.writer.get_ref();
+        file.seek(SeekFrom::Start(0))?;
+        self.needs_seek_to_end = true;
+        let mut reader = BufReader::new(file);
+        Ok((0..self.len).map(move |_| read_record(&mut reader)))
+    }
+}
+
+fn write_u8(w: &mut impl Write, value: u8) -> io::Result<()> {
+    w.write_all(&[value])
+}
+
+fn write_u16(w: &mut impl Write, value: u16) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+fn write_u32(w: &mut impl Write, value: u32) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+fn write_u64(w: &mut impl Write, value: u64) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+fn write_i32(w: &mut impl Write, value: i32) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+fn write_f64(w: &mut impl Write, value: f64) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+fn write_str(w: &mut impl Write, value: &str) -> io::Result<()> {
+    write_u32(w, value.len() as u32)?;
+    w.write_all(value.as_bytes())
+}
+
+fn write_dim(w: &mut impl Write, (x, y, z): (i32, i32, i32)) -> io::Result<()> {
+    write_i32(w, x)?;
+    write_i32(w, y)?;
+    write_i32(w, z)
+}
+
+fn write_record(w: &mut impl Write, record: &KernelRecord) -> io::Result<()> {
+    let KernelRecord {
+        launch,
+        activity,
+        range,
+    } = record;
+    write_u64(w, launch.timestamp)?;
+    // The function handle stays valid for as long as its module is loaded,
+    // which is how long it would have been usable from memory too.
+    write_u64(w, launch.function as u64)?;
+    write_u8(w, launch.profiled as u8)?;
+    write_u32(w, launch.correlation_id)?;
+    write_str(w, &activity.kernel_name)?;
+    write_dim(w, activity.grid_size)?;
+    write_dim(w, activity.block_size)?;
+    write_u16(w, activity.registers_per_thread)?;
+    write_i32(w, activity.dynamic_shared_memory)?;
+    write_i32(w, activity.static_shared_memory)?;
+    write_u64(w, activity.duration)?;
+    write_u32(w, activity.stream_id)?;
+    write_u32(w, activity.external_ids.len() as u32)?;
+    for &(kind, id) in &activity.external_ids {
+        write_u32(w, kind)?;
+        write_u64(w, id)?;
+    }
+    let Some(range) = range else {
+        return write_u8(w, 0);
+    };
+    write_u8(w, 1)?;
+    write_str(w, &range.range_name)?;
+    write_u32(w, range.parent_ranges.len() as u32)?;
+    for parent in &range.parent_ranges {
+        write_str(w, parent)?;
+    }
+    write_u64(w, range.depth as u64)?;
+    write_u32(w, range.metric_and_values.len() as u32)?;
+    for metric in &range.metric_and_values {
+        write_str(w, &metric.metric_name)?;
+        write_f64(w, metric.value)?;
+    }
+    Ok(())
+}
+
+fn read_bytes<const N: usize>(r: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    r.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_u8(r: &mut impl Read) -> io::Result<u8> {
+    read_bytes(r).map(|[value]| value)
+}
+
+fn read_u16(r: &mut impl Read) -> io::Result<u16> {
+    read_bytes(r).map(u16::from_le_bytes)
+}
+
+fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    read_bytes(r).map(u32::from_le_bytes)
+}
+
+fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    read_bytes(r).map(u64::from_le_bytes)
+}
+
+fn read_i32(r: &mut impl Read) -> io::Result<i32> {
+    read_bytes(r).map(i32::from_le_bytes)
+}
+
+fn read_f64(r: &mut impl Read) -> io::Result<f64> {
+    read_bytes(r).map(f64::from_le_bytes)
+}
+
+fn read_str(r: &mut impl Read) -> io::Result<String> {
+    let len = read_u32(r)? as usize;
+    let mut bytes = vec![0; len];
+    r.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn read_dim(r: &mut impl Read) -> io::Result<(i32, i32, i32)> {
+    Ok((read_i32(r)?, read_i32(r)?, read_i32(r)?))
+}
+
+fn read_record(r: &mut impl Read) -> io::Result<KernelRecord> {
+    let launch = KernelLaunch {
+        timestamp: read_u64(r)?,
+        function: read_u64(r)? as CUfunction,
+        profiled: read_u8(r)? != 0,
+        correlation_id: read_u32(r)?,
+    };
+    let activity = KernelActivity {
+        kernel_name: read_str(r)?,
+        grid_size: read_dim(r)?,
+        block_size: read_dim(r)?,
+        registers_per_thread: read_u16(r)?,
+        dynamic_shared_memory: read_i32(r)?,
+        static_shared_memory: read_i32(r)?,
+        duration: read_u64(r)?,
+        stream_id: read_u32(r)?,
+        external_ids: (0..read_u32(r)?)
+            .map(|_| Ok((read_u32(r)?, read_u64(r)?)))
+            .collect::<io::Result<_>>()?,
+        correlation_id: launch.correlation_id,
+    };
+    if read_u8(r)? == 0 {
+        return Ok(KernelRecord {
+            launch,
+            activity,
+            range: None,
+        });
+    }
+    let range_name = read_str(r)?;
+    let parent_ranges = (0..read_u32(r)?)
+        .map(|_| read_str(r))
+        .collect::<io::Result<_>>()?;
+    let depth = read_u64(r)? as usize;
+    let metric_and_values = (0..read_u32(r)?)
+        .map(|_| {
+            Ok(MetricValuePair {
+                metric_name: read_str(r)?,
+                value: read_f64(r)?,
+            })
+        })
+        .collect::<io::Result<_>>()?;
+    Ok(KernelRecord {
+        launch,
+        activity,
+        range: Some(RangeInfo {
+            range_name,
+            parent_ranges,
+            depth,
+            metric_and_values,
+        }),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(timestamp: u64, name: &str) -> KernelRecord {
+        KernelRecord {
+            launch: KernelLaunch {
+                function: 0x1234 as CUfunction,
+                timestamp,
+                profiled: true,
+                correlation_id: timestamp as u32,
+            },
+            activity: KernelActivity {
+                kernel_name: name.to_string(),
+                grid_size: (4, 2, 1),
+                block_size: (128, 1, 1),
+                registers_per_thread: 40,
+                dynamic_shared_memory: 256,
+                static_shared_memory: 1024,
+                duration: timestamp * 10,
+                stream_id: 7,
+                external_ids: vec![(3, timestamp + 100)],
+                correlation_id: timestamp as u32,
+            },
+            range: Some(RangeInfo {
+                range_name: format!("outer/{}", name),
+                parent_ranges: vec!["outer".to_string()],
+                depth: 1,
+                metric_and_values: vec![MetricValuePair {
+                    metric_name: "gpu__time_duration.sum".to_string(),
+                    value: timestamp as f64 * 1.5,
+                }],
+            }),
+        }
+    }
+
+    fn timestamps(spill: &mut SpillFile) -> Vec<u64> {
+        spill
+            .records()
+            .unwrap()
+            .map(|record| record.unwrap().launch.timestamp)
+            .collect()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let dir = std::env::temp_dir();
+        let mut spill = SpillFile::create(&dir, 7).unwrap();
+        assert!(spill.is_empty());
+        spill.append(&record(1, "first")).unwrap();
+        spill.append(&record(2, "second")).unwrap();
+
+        let records: Vec<KernelRecord> = spill.records().unwrap().map(Result::unwrap).collect();
+        assert_eq!(records.len(), 2);
+        let second = &records[1];
+        assert_eq!(second.launch.function, 0x1234 as CUfunction);
+        assert_eq!(second.activity.kernel_name, "second");
+        assert_eq!(second.activity.grid_size, (4, 2, 1));
+        assert_eq!(second.activity.registers_per_thread, 40);
+        assert_eq!(second.activity.static_shared_memory, 1024);
+        assert_eq!(second.activity.duration, 20);
+        assert_eq!(second.activity.stream_id, 7);
+        assert_eq!(second.activity.external_ids, [(3, 102)]);
+        assert!(second.launch.profiled);
+        assert_eq!(second.activity.correlation_id, 2);
+        let range = second.range.as_ref().unwrap();
+        assert_eq!(range.range_name, "outer/second");
+        assert_eq!(range.parent_ranges, vec!["outer"]);
+        assert_eq!(range.depth, 1);
+        assert_eq!(range.metric_and_values[0].value, 3.0);
+
+        // Appending after reading continues at the end of the file.
+        let mut third = record(3, "third");
+        third.launch.profiled = false;
+        third.range = None;
+        spill.append(&third).unwrap();
+        assert_eq!(timestamps(&mut spill), vec![1, 2, 3]);
+        let last = spill.records().unwrap().last().unwrap().unwrap();
+        assert!(!last.launch.profiled);
+        assert!(last.range.is_none());
+        assert_eq!(spill.len(), 3);
+    }
+}
@@ -13,15 +13,89 @@
 // limitations under the License.
 
 use crate::config::Config;
+use crate::json_export::JsonExport;
+use crate::metrics;
+use crate::overhead::{self, OverheadTracker, Stat};
+use crate::spill::{KernelRecord, SpillFile};
+use crate::trace_emitter::{FunctionProperties, FunctionPropertiesCache};
+use crate::tracing::trace_time_ns;
+use crate::worker::{self, CounterDataPool};
 use cupti_profiler::bindings::*;
 use cupti_profiler::*;
 use once_cell::sync::Lazy;
-use std::{collections::HashMap, sync::Mutex};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+        TryLockError,
+    },
+    time::{Duration, Instant},
+};
+
+/// Most ranges a counter data image is grown to.
+pub const MAX_COUNTER_DATA_RANGES: usize = 1024;
+
+/// Fewest ranges a counter data image is shrunk to when the range profiler
+/// runs out of memory, before metrics are dropped instead.
+pub const MIN_RETRY_RANGES: usize = 16;
+
+/// What is given up to fit the range profiler in memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryReduction {
+    /// Counter data images hold this many ranges.
+    Ranges(usize),
+    /// Only this many metrics are collected.
+    Metrics(usize),
+}
+
+impl MemoryReduction {
+    /// The next smaller setup to retry with after running out of memory
+    /// with `max_num_ranges` ranges of `num_metrics` metrics, if any. Ranges
+    /// go first, since fewer only means decoding more often.
+    pub fn next(max_num_ranges: usize, num_metrics: usize) -> Option<Self> {
+        if max_num_ranges > MIN_RETRY_RANGES {
+            Some(Self::Ranges((max_num_ranges / 2).max(MIN_RETRY_RANGES)))
+        } else if num_metrics > 1 {
+            Some(Self::Metrics(num_metrics / 2))
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for MemoryReduction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Ranges(n) => write!(f, "{} ranges per counter data image", n),
+            Self::Metrics(n) => write!(f, "the first {} metrics", n),
+        }
+    }
+}
+
+/// Ranges dropped by the range profiler in all contexts so far.
+pub static DROPPED_RANGES: AtomicU64 = AtomicU64::new(0);
+
+/// Ranges that did not fit in the counter data image at a decode, so that
+/// the kernels from `timestamp` on lost their metrics.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RangeLoss {
+    /// Launch of the first kernel whose range was dropped.
+    pub timestamp: u64,
+    pub ranges: usize,
+    /// `DROPPED_RANGES` with these ranges counted.
+    pub total: u64,
+}
 
 /// Represents a specific kernel launch event.
 pub struct KernelLaunch {
     pub function: CUfunction,
     pub timestamp: u64,
+    /// Whether the range profiler collected a range for the kernel.
+    pub profiled: bool,
+    /// Correlation ID of the launch call, which its activity record carries.
+    pub correlation_id: u32,
 }
 
 /// Detailed activity information for a kernel execution.
@@ -34,6 +108,15 @@ pub struct KernelActivity {
     pub registers_per_thread: u16,
     pub dynamic_shared_memory: i32,
     pub static_shared_memory: i32,
+    /// Execution time reported by the activity record, in nanoseconds.
+    pub duration: u64,
+    /// CUPTI ID of the stream the kernel ran on.
+    pub stream_id: u32,
+    /// External correlation IDs pushed for the launch, e.g. by PyTorch's
+    /// Kineto, as `CUpti_ExternalCorrelationKind` and ID.
+    pub external_ids: Vec<(u32, u64)>,
+    /// Correlation ID of the launch call of the kernel.
+    pub correlation_id: u32,
 }
 
 /// Profiling data associated with a specific CUDA context.
@@ -41,41 +124,1160 @@ pub struct KernelActivity {
 /// Handles the lifecycle of the range profiler, metric evaluator, and stores collected
 /// ranges and kernel launch metadata.
 pub struct CtxProfilerData {
+    pub ctx: CUcontext,
+    pub ctx_id: u32,
     pub device_id: i32,
     pub num_sms: i32,
     pub max_num_ranges: usize,
+    /// Ranges recorded per pass of the range profiler, 0 for as many as the
+    /// counter data image holds.
+    pub max_ranges_per_pass: usize,
+    /// How the range profiler collects metrics that need several passes.
+    pub replay_mode: CUpti_ProfilerReplayMode,
+    /// Whether setups retried with less memory are reported.
+    pub verbose: bool,
+    /// Ranges collected by the range profiler since the last decode.
+    pub pending_ranges: usize,
+    /// Ranges collected since the current pass started or was decoded.
+    pub pass_ranges: usize,
+    pub last_decode: Instant,
     pub is_active: bool,
+    /// The image the range profiler is filling.
     pub counter_data_image: Vec<u8>,
-    pub metric_evaluator: Option<MetricEvaluator>,
+    /// Ranges at the start of `counter_data_image` that were queued for
+    /// evaluation already, which happens when the image could not be reset
+    /// after a decode.
+    pub evaluated_ranges: usize,
+    /// The other image, while it is not being evaluated.
+    pub counter_data_pool: Option<Arc<CounterDataPool>>,
+    pub metric_evaluator: Option<Arc<MetricEvaluator>>,
     pub range_profiler: Option<RangeProfiler>,
-    pub range_info: Vec<RangeInfo>,
-    pub kernel_launches: Vec<KernelLaunch>,
-    pub kernel_activities: Vec<KernelActivity>,
+    /// Why the range profiler cannot be used on the context, which leaves its
+    /// kernels to activity records.
+    pub counters_unavailable: Option<String>,
+    /// Metrics the range profiler was configured with, which its ranges are
+    /// evaluated for.
+    pub metric_names: Arc<[String]>,
+    /// Metrics the range profiler was restarted with, of which only some are
+    /// in `metric_names` if it ran out of memory.
+    pub requested_metrics: Arc<[String]>,
+    /// Ranges of the profiled kernels, in launch order.
+    pub range_info: VecDeque<RangeInfo>,
+    pub kernel_launches: VecDeque<KernelLaunch>,
+    pub kernel_activities: VecDeque<KernelActivity>,
+    /// Kernels launched on the context, and how many of them were profiled.
+    pub launched_kernels: u64,
+    pub profiled_kernels: u64,
+    /// Kernels evicted to stay within the configured limit.
+    pub dropped_kernels: u64,
+    /// Ranges dropped at decodes, not yet written to the trace.
+    pub range_losses: Vec<RangeLoss>,
+    /// Activities and ranges still to arrive for evicted kernels, which are
+    /// discarded instead of stored.
+    pub skipped_activities: usize,
+    pub skipped_ranges: usize,
+    /// Kernels moved to disk, which come before the ones kept in memory.
+    pub spill: Option<SpillFile>,
+    /// Kernels already written to the trace while the application ran, which
+    /// come before the spilled ones.
+    pub emitted_kernels: u64,
+    /// Driver queries made for emitted kernels, kept for the kernels to come.
+    pub function_properties: FunctionPropertiesCache,
+    /// End of the last kernel written to the trace on each stream, on the
+    /// trace clock, which the next one on it cannot start before.
+    pub queue_ends: HashMap<u32, u64>,
+}
+
+impl CtxProfilerData {
+    /// Creates the profiling data for a context, without a range profiler.
+    pub fn new(
+        ctx: CUcontext,
+        ctx_id: u32,
+        device_id: i32,
+        num_sms: i32,
+        max_num_ranges: usize,
+    ) -> Self {
+        Self {
+            ctx,
+            ctx_id,
+            device_id,
+            num_sms,
+            max_num_ranges,
+            max_ranges_per_pass: 0,
+            replay_mode: CUpti_ProfilerReplayMode_CUPTI_KernelReplay,
+            verbose: false,
+            pending_ranges: 0,
+            pass_ranges: 0,
+            last_decode: Instant::now(),
+            is_active: false,
+            counter_data_image: Vec::new(),
+            evaluated_ranges: 0,
+            counter_data_pool: None,
+            metric_evaluator: None,
+            range_profiler: None,
+            counters_unavailable: None,
+            metric_names: Arc::from(Vec::new()),
+            requested_metrics: Arc::from(Vec::new()),
+            range_info: VecDeque::new(),
+            kernel_launches: VecDeque::new(),
+            kernel_activities: VecDeque::new(),
+            launched_kernels: 0,
+            profiled_kernels: 0,
+            dropped_kernels: 0,
+            range_losses: Vec::new(),
+            skipped_activities: 0,
+            skipped_ranges: 0,
+            spill: None,
+            emitted_kernels: 0,
+            function_properties: FunctionPropertiesCache::default(),
+            queue_ends: HashMap::new(),
+        }
+    }
+
+    /// Records a kernel launch, evicting the oldest kernel if more than
+    /// `max_kernels` are stored. A limit of 0 means no limit.
+    pub fn add_launch(&mut self, launch: KernelLaunch, max_kernels: usize) {
+        self.launched_kernels += 1;
+        self.profiled_kernels += launch.profiled as u64;
+        self.kernel_launches.push_back(launch);
+        if max_kernels > 0 && self.kernel_launches.len() > max_kernels {
+            let Some(evicted) = self.kernel_launches.pop_front() else {
+                return;
+            };
+            // Launches, activities and ranges are matched up by position, so
+            // the evicted kernel's activity and range go as well, now or when
+            // they arrive.
+            if self.kernel_activities.pop_front().is_none() {
+                self.skipped_activities += 1;
+            }
+            if evicted.profiled && self.range_info.pop_front().is_none() {
+                self.skipped_ranges += 1;
+            }
+            self.dropped_kernels += 1;
+        }
+    }
+
+    /// Records the activity of a kernel still missing one.
+    ///
+    /// Launches are recorded in the order their callbacks ran, which threads
+    /// launching on the same context may not submit them in. Activities and
+    /// ranges both come in the order the kernels ran, so the launch an
+    /// activity is for, found by its correlation ID, is moved up to the
+    /// activity's position, which lines up the ranges as well.
+    pub fn add_activity(&mut self, activity: KernelActivity) {
+        let next = self.kernel_activities.len();
+        let launch = self
+            .kernel_launches
+            .iter()
+            .skip(next)
+            .position(|launch| launch.correlation_id == activity.correlation_id);
+        match launch {
+            Some(0) => {}
+            Some(offset) => {
+                if let Some(launch) = self.kernel_launches.remove(next + offset) {
+                    self.kernel_launches.insert(next, launch);
+                }
+            }
+            // The activity is for an evicted kernel.
+            None if self.skipped_activities > 0 => {
+                self.skipped_activities -= 1;
+                return;
+            }
+            None => {}
+        }
+        self.kernel_activities.push_back(activity);
+    }
+
+    /// Records evaluated ranges, in launch order.
+    pub fn add_ranges(&mut self, ranges: impl IntoIterator<Item = RangeInfo>) {
+        for range in ranges {
+            if self.skipped_ranges > 0 {
+                self.skipped_ranges -= 1;
+            } else {
+                self.range_info.push_back(range);
+            }
+        }
+    }
+
+    /// Accounts for `dropped` ranges that the last decode could not fit in
+    /// the counter data image. They are those of the latest profiled
+    /// kernels, which are marked unprofiled so that the ranges of the others
+    /// still go to the kernels they were collected for.
+    pub fn drop_ranges(&mut self, dropped: usize) {
+        if dropped == 0 {
+            return;
+        }
+        let mut left = dropped;
+        let mut timestamp = None;
+        for launch in self.kernel_launches.iter_mut().rev() {
+            if left == 0 {
+                break;
+            }
+            if launch.profiled {
+                launch.profiled = false;
+                timestamp = Some(launch.timestamp);
+                left -= 1;
+            }
+        }
+        // The rest were of evicted kernels, whose ranges are no longer due.
+        self.skipped_ranges = self.skipped_ranges.saturating_sub(left);
+        let total = DROPPED_RANGES.fetch_add(dropped as u64, Ordering::Relaxed) + dropped as u64;
+        self.range_losses.push(RangeLoss {
+            timestamp: timestamp.unwrap_or_else(trace_time_ns),
+            ranges: dropped,
+            total,
+        });
+    }
+
+    /// Returns the kernels whose activity, and range if they were profiled,
+    /// have arrived, in launch order.
+    pub fn completed_kernels(
+        &self,
+    ) -> impl Iterator<Item = (&KernelLaunch, &KernelActivity, Option<&RangeInfo>)> {
+        let mut ranges = self.range_info.iter();
+        self.kernel_launches
+            .iter()
+            .zip(self.kernel_activities.iter())
+            .map_while(move |(launch, activity)| {
+                if launch.profiled {
+                    ranges.next().map(|range| (launch, activity, Some(range)))
+                } else {
+                    Some((launch, activity, None))
+                }
+            })
+    }
+
+    /// Moves kernels that have their launch, activity and range to disk while
+    /// more than `threshold` kernels are kept in memory. A threshold of 0
+    /// disables spilling.
+    pub fn spill_completed(&mut self, threshold: usize) {
+        if threshold == 0 {
+            return;
+        }
+        while self.kernel_launches.len() > threshold
+            && !self.kernel_activities.is_empty()
+            && self
+                .kernel_launches
+                .front()
+                .is_some_and(|launch| !launch.profiled || !self.range_info.is_empty())
+        {
+            if self.spill.is_none() {
+                match SpillFile::create(&std::env::temp_dir(), self.ctx_id) {
+                    Ok(spill) => self.spill = Some(spill),
+                    Err(e) => {
+                        eprintln!("Failed to create spill file: {}", e);
+                        return;
+                    }
+                }
+            }
+            let (Some(launch), Some(activity)) = (
+                self.kernel_launches.pop_front(),
+                self.kernel_activities.pop_front(),
+            ) else {
+                return;
+            };
+            let range = if launch.profiled {
+                self.range_info.pop_front()
+            } else {
+                None
+            };
+            let record = KernelRecord {
+                launch,
+                activity,
+                range,
+            };
+            if let Some(spill) = &mut self.spill {
+                if spill.append(&record).is_err() {
+                    self.dropped_kernels += 1;
+                }
+            }
+        }
+    }
+
+    /// Drops the spilled kernels and the `count` oldest completed kernels
+    /// once they have been written to the trace.
+    pub fn drop_emitted(&mut self, count: usize) {
+        self.range_losses.clear();
+        if let Some(spill) = self.spill.take() {
+            self.emitted_kernels += spill.len() as u64;
+        }
+        for _ in 0..count {
+            let Some(launch) = self.kernel_launches.pop_front() else {
+                return;
+            };
+            self.kernel_activities.pop_front();
+            if launch.profiled {
+                self.range_info.pop_front();
+            }
+            self.emitted_kernels += 1;
+        }
+    }
+
+    /// Queries the function properties of the kernels kept in memory and on
+    /// disk, while their context still exists to answer the driver queries,
+    /// so that they can be written after it is gone.
+    pub fn cache_function_properties(&mut self) {
+        let Self {
+            spill,
+            kernel_launches,
+            kernel_activities,
+            function_properties,
+            ..
+        } = self;
+        let mut cache = |function: CUfunction, activity: &KernelActivity| {
+            function_properties.get_or_query(function, activity, || unsafe {
+                FunctionProperties::query(function, activity)
+            });
+        };
+        if let Some(Ok(records)) = spill.as_mut().map(SpillFile::records) {
+            for record in records.map_while(Result::ok) {
+                cache(record.launch.function, &record.activity);
+            }
+        }
+        for (launch, activity) in kernel_launches.iter().zip(kernel_activities.iter()) {
+            cache(launch.function, activity);
+        }
+    }
+
+    /// Starts a new range profiler session on the context.
+    ///
+    /// The profiler is only kept if it could be enabled and configured.
+    pub fn restart(&mut self, metric_names: &Arc<[String]>) -> Result<(), CUptiResult> {
+        self.evaluated_ranges = 0;
+        let mut rp = RangeProfiler::new(self.ctx);
+        rp.max_ranges_per_pass = self.max_ranges_per_pass;
+        rp.enable()?;
+        let mut collected = Arc::clone(metric_names);
+        loop {
+            // The previous session's ranges have been decoded already, and
+            // the image has to be sized for `max_num_ranges`, so start from
+            // scratch.
+            self.counter_data_image.clear();
+            let result = rp.set_config(
+                &collected,
+                &mut self.counter_data_image,
+                self.max_num_ranges,
+                self.replay_mode,
+            );
+            // Running out of memory is retried with fewer ranges and then
+            // fewer metrics, rather than leaving the context unprofiled.
+            let reduction = match result {
+                Err(e) if e == CUptiResult_CUPTI_ERROR_OUT_OF_MEMORY => {
+                    MemoryReduction::next(self.max_num_ranges, collected.len()).ok_or(e)?
+                }
+                result => break result?,
+            };
+            if self.verbose {
+                println!(
+                    "Context {}: out of memory configuring the range profiler, retrying with {}",
+                    self.ctx_id, reduction
+                );
+            }
+            match reduction {
+                MemoryReduction::Ranges(n) => self.max_num_ranges = n,
+                MemoryReduction::Metrics(n) => {
+                    collected = metrics::first_metrics(&collected, n).into()
+                }
+            }
+        }
+        // Right after configuration the image is empty, which is what
+        // evaluated images are reset to.
+        self.counter_data_pool = Some(Arc::new(CounterDataPool::new(
+            self.counter_data_image.clone(),
+            1,
+        )));
+        let _ = rp.start();
+        self.range_profiler = Some(rp);
+        self.metric_names = collected;
+        self.requested_metrics = Arc::clone(metric_names);
+        self.is_active = true;
+        self.pending_ranges = 0;
+        self.pass_ranges = 0;
+        self.last_decode = Instant::now();
+        Ok(())
+    }
+
+    /// Stops profiling kernels without tearing down the range profiler, so
+    /// collected ranges stay in the counter data image.
+    pub fn pause(&mut self) {
+        if let Some(rp) = &mut self.range_profiler {
+            if self.is_active {
+                let _ = rp.stop();
+                self.is_active = false;
+            }
+        }
+    }
+
+    /// Profiles kernels again after `pause`.
+    pub fn resume(&mut self) {
+        if let Some(rp) = &self.range_profiler {
+            if !self.is_active && rp.start().is_ok() {
+                self.is_active = true;
+                self.pass_ranges = 0;
+            }
+        }
+    }
+
+    /// Returns true if the range profiler collects other metrics than
+    /// `metric_names`, so it has to be flushed and restarted with them.
+    pub fn metrics_changed(&self, metric_names: &Arc<[String]>) -> bool {
+        self.range_profiler.is_some()
+            && !Arc::ptr_eq(&self.requested_metrics, metric_names)
+            && self.requested_metrics[..] != metric_names[..]
+    }
+
+    /// Returns true if kernels launched now are profiled.
+    pub fn is_profiling(&self) -> bool {
+        self.range_profiler.is_some() && self.is_active
+    }
+
+    /// Ends the range profiler session, if any, and queues the ranges it
+    /// collected for evaluation.
+    pub fn flush_ranges(&mut self) {
+        if let Some(rp) = &mut self.range_profiler {
+            if self.is_active {
+                let _ = rp.stop();
+            }
+            let decode_started = Instant::now();
+            let dropped = rp.decode_counter_data().unwrap_or(0);
+            overhead::record_stat(Stat::Decode, decode_started.elapsed());
+            let _ = rp.disable();
+            worker::submit(
+                self.ctx_id,
+                self.metric_evaluator.as_ref(),
+                std::mem::take(&mut self.counter_data_image),
+                self.evaluated_ranges,
+                &self.metric_names,
+                None,
+            );
+            self.drop_ranges(dropped);
+        }
+        self.evaluated_ranges = 0;
+        self.range_profiler = None;
+        self.counter_data_pool = None;
+        self.is_active = false;
+        self.pending_ranges = 0;
+    }
+
+    /// Decodes the collected ranges and queues them for evaluation.
+    ///
+    /// The range profiler carries on with the context's other image, so the
+    /// decoded one does not have to be copied or reinitialized here. Only
+    /// when that image is still being evaluated is the decoded one copied and
+    /// reinitialized in place.
+    pub fn decode_ranges(&mut self) {
+        let Some(rp) = &self.range_profiler else {
+            return;
+        };
+        let decode_started = Instant::now();
+        let dropped = rp.decode_counter_data().unwrap_or(0);
+        overhead::record_stat(Stat::Decode, decode_started.elapsed());
+        self.pending_ranges = 0;
+        self.pass_ranges = 0;
+        self.drop_ranges(dropped);
+        let Some(rp) = &mut self.range_profiler else {
+            return;
+        };
+        let spare = self.counter_data_pool.as_ref().and_then(|pool| pool.take());
+        let Some(spare) = spare else {
+            worker::submit(
+                self.ctx_id,
+                self.metric_evaluator.as_ref(),
+                self.counter_data_image.clone(),
+                self.evaluated_ranges,
+                &self.metric_names,
+                None,
+            );
+            // Should the image keep its ranges, the next decode must only
+            // queue the ones added after them.
+            self.evaluated_ranges =
+                match rp.initialize_counter_data_image(&mut self.counter_data_image) {
+                    Ok(()) => 0,
+                    Err(_) => self.metric_evaluator.as_ref().map_or(0, |me| {
+                        me.get_num_of_ranges(&self.counter_data_image)
+                            .unwrap_or(self.evaluated_ranges)
+                    }),
+                };
+            // An image full of ranges it kept has no room for the next
+            // kernel, which only a new session makes.
+            if self.ranges_left() == 0 {
+                let _ = self.resize(self.max_num_ranges);
+            }
+            return;
+        };
+        let decoded = std::mem::replace(&mut self.counter_data_image, spare);
+        if self.is_active {
+            let _ = rp.stop();
+        }
+        let _ = rp.set_counter_data_image(
+            &mut self.counter_data_image,
+            self.max_num_ranges,
+            self.replay_mode,
+        );
+        if self.is_active {
+            let _ = rp.start();
+        }
+        worker::submit(
+            self.ctx_id,
+            self.metric_evaluator.as_ref(),
+            decoded,
+            std::mem::take(&mut self.evaluated_ranges),
+            &self.metric_names,
+            self.counter_data_pool.clone(),
+        );
+    }
+
+    /// Returns true if counter data should be decoded before the next kernel
+    /// adds a range, either because the image is full or because `interval`
+    /// has passed since the last decode.
+    pub fn should_decode(&self, interval: Duration) -> bool {
+        self.ranges_left() == 0
+            || (self.pending_ranges > 0 && self.last_decode.elapsed() >= interval)
+    }
+
+    /// Ranges the counter data image has room for until the next decode.
+    /// Ranges it kept through the last decode take up room as well, so the
+    /// next launch decodes before the profiler would drop its range.
+    pub fn ranges_left(&self) -> usize {
+        self.max_num_ranges
+            .saturating_sub(self.evaluated_ranges + self.pending_ranges)
+    }
+
+    /// Ranges the range profiler records per pass.
+    pub fn ranges_per_pass(&self) -> usize {
+        match self.max_ranges_per_pass {
+            0 => self.max_num_ranges,
+            n => n.min(self.max_num_ranges),
+        }
+    }
+
+    /// Returns true if the current pass is full, so it has to end before the
+    /// next kernel adds a range.
+    pub fn should_end_pass(&self) -> bool {
+        self.pass_ranges >= self.ranges_per_pass()
+    }
+
+    /// Ends the current pass and starts the next one. The collected ranges
+    /// stay in the counter data image until it is decoded, so several passes
+    /// share one decode and one trip to the evaluator.
+    pub fn end_pass(&mut self) {
+        if let Some(rp) = &mut self.range_profiler {
+            if self.is_active {
+                let _ = rp.stop();
+                self.is_active = rp.start().is_ok();
+            }
+        }
+        self.pass_ranges = 0;
+    }
+
+    /// Returns how many ranges the counter data image should hold, given that
+    /// the pending ranges were collected over `elapsed`.
+    ///
+    /// The image is sized for the launches expected within one decode
+    /// `interval` at the observed launch rate. It at most doubles per decode,
+    /// and only shrinks, by half, once it is four times larger than needed.
+    pub fn adapted_max_num_ranges(&self, elapsed: Duration, interval: Duration) -> usize {
+        let current = self.max_num_ranges;
+        let expected = if elapsed.is_zero() {
+            usize::MAX
+        } else {
+            (self.pending_ranges as f64 * interval.as_secs_f64() / elapsed.as_secs_f64()).ceil()
+                as usize
+        };
+        let target = expected
+            .max(1)
+            .checked_next_power_of_two()
+            .unwrap_or(MAX_COUNTER_DATA_RANGES)
+            .min(MAX_COUNTER_DATA_RANGES);
+        if target > current {
+            target.min(current.saturating_mul(2)).max(current)
+        } else if target.saturating_mul(4) <= current {
+            (current / 2).max(1)
+        } else {
+            current
+        }
+    }
+
+    /// Reconfigures the range profiler for a counter data image holding
+    /// `max_num_ranges` ranges.
+    ///
+    /// Collected ranges are decoded and queued for evaluation with the old
+    /// image, which saves stopping the profiler once more to swap images.
+    pub fn resize(&mut self, max_num_ranges: usize) -> Result<(), CUptiResult> {
+        self.flush_ranges();
+        self.max_num_ranges = max_num_ranges;
+        let metric_names = Arc::clone(&self.requested_metrics);
+        self.restart(&metric_names)
+    }
 }
 
 unsafe impl Send for CtxProfilerData {}
 unsafe impl Sync for CtxProfilerData {}
 
+type Contexts = HashMap<u32, Arc<Mutex<CtxProfilerData>>>;
+
+/// Profiling data of every context, by context ID.
+///
+/// Each context has a lock of its own, and the map itself is only locked to
+/// look contexts up or add them, so callbacks on one context do not wait for
+/// work on another. A context lock may be taken while holding `GLOBAL_STATE`,
+/// never the other way around, and callbacks hold at most one context lock at
+/// a time.
+#[derive(Default)]
+pub struct ContextMap {
+    contexts: RwLock<Contexts>,
+}
+
+impl ContextMap {
+    /// Locks the map for looking contexts up, recovering it if a panic
+    /// poisoned it.
+    fn read(&self) -> RwLockReadGuard<'_, Contexts> {
+        self.contexts.read().unwrap_or_else(|poisoned| {
+            self.contexts.clear_poison();
+            recovered(poisoned)
+        })
+    }
+
+    /// Locks the map for changing it, recovering it if a panic poisoned it.
+    fn write(&self) -> RwLockWriteGuard<'_, Contexts> {
+        self.contexts.write().unwrap_or_else(|poisoned| {
+            self.contexts.clear_poison();
+            recovered(poisoned)
+        })
+    }
+
+    /// Returns the data of a context.
+    pub fn get(&self, ctx_id: u32) -> Option<Arc<Mutex<CtxProfilerData>>> {
+        self.read().get(&ctx_id).cloned()
+    }
+
+    pub fn contains(&self, ctx_id: u32) -> bool {
+        self.read().contains_key(&ctx_id)
+    }
+
+    /// Adds a context, replacing any previous data with the same ID.
+    pub fn insert(&self, data: CtxProfilerData) {
+        self.write().insert(data.ctx_id, Arc::new(Mutex::new(data)));
+    }
+
+    /// Returns the data of all contexts, ordered by context ID.
+    pub fn all(&self) -> Vec<Arc<Mutex<CtxProfilerData>>> {
+        let contexts = self.read();
+        let mut all: Vec<_> = contexts.iter().collect();
+        all.sort_unstable_by_key(|(ctx_id, _)| **ctx_id);
+        all.into_iter().map(|(_, data)| Arc::clone(data)).collect()
+    }
+
+    /// Removes a context, e.g. once it was destroyed and written out.
+    pub fn remove(&self, ctx_id: u32) {
+        self.write().remove(&ctx_id);
+    }
+
+    pub fn clear(&self) {
+        self.write().clear();
+    }
+}
+
+/// The profiling data of all contexts.
+pub static CONTEXT_DATA: Lazy<ContextMap> = Lazy::new(ContextMap::default);
+
 /// Global state shared across the application.
 ///
-/// Manages per-context profiler data, the currently active context, and global configuration.
+/// Manages the currently active context and global configuration. Per-context
+/// data lives in `CONTEXT_DATA`.
 pub struct GlobalState {
-    pub context_data: HashMap<u32, Box<CtxProfilerData>>,
     pub active_ctx: Option<CUcontext>,
     pub injection_initialized: bool,
+    pub detached: bool,
+    pub subscriber: Option<CUpti_SubscriberHandle>,
     pub config: Config,
+    pub overhead: OverheadTracker,
+    /// When completed kernels were last written to the trace.
+    pub last_emit: Instant,
+    /// Whether `config.metrics` has been trimmed to a single pass.
+    pub single_pass_scheduled: bool,
+    /// Whether the metrics the chip does not have were dropped from
+    /// `config.metrics`.
+    pub metrics_validated: bool,
+    /// File the emitted kernels are also written to, if any.
+    pub json_export: Option<JsonExport>,
+    /// Whether kernels are profiled while tracing, cleared by the `disable`
+    /// command of the control socket.
+    pub profiling_enabled: bool,
+    /// The fatal CUPTI error that profiling was disabled for, if any, after
+    /// which it cannot be enabled again.
+    pub fatal_error: Option<String>,
 }
 
 unsafe impl Send for GlobalState {}
 
 /// The singleton global state instance.
 ///
-/// Protected by a Mutex to ensure thread-safe access from callback handlers.
+/// Protected by a Mutex to ensure thread-safe access from callback handlers,
+/// which lock it with `lock_global_state`.
 pub static GLOBAL_STATE: Lazy<Mutex<GlobalState>> = Lazy::new(|| {
     Mutex::new(GlobalState {
-        context_data: HashMap::new(),
         active_ctx: None,
         injection_initialized: false,
+        detached: false,
+        subscriber: None,
         config: Config::default(),
+        overhead: OverheadTracker::new(0.0, Instant::now(), 0),
+        last_emit: Instant::now(),
+        single_pass_scheduled: false,
+        metrics_validated: false,
+        json_export: None,
+        profiling_enabled: true,
+        fatal_error: None,
     })
 });
+
+/// Serializes the tests that change `GLOBAL_STATE` or check what it holds,
+/// since tests run in parallel on the one global state.
+#[cfg(test)]
+pub static GLOBAL_STATE_TESTS: Mutex<()> = Mutex::new(());
+
+/// Whether `GLOBAL_STATE` was recovered from a panic while it was locked.
+static RECOVERED_FROM_POISON: AtomicBool = AtomicBool::new(false);
+
+/// Takes back a lock of the profiler state from a panic that poisoned it,
+/// saying so the first time. Every update leaves the state usable, and
+/// callbacks catch their panics, so profiling goes on rather than every
+/// later callback finding the lock poisoned.
+fn recovered<T>(poisoned: PoisonError<T>) -> T {
+    if !RECOVERED_FROM_POISON.swap(true, Ordering::Relaxed) {
+        eprintln!("Recovered the profiler state after a panic while it was locked");
+    }
+    poisoned.into_inner()
+}
+
+/// Takes back `GLOBAL_STATE` from a panic that poisoned it.
+fn recover(
+    poisoned: PoisonError<MutexGuard<'static, GlobalState>>,
+) -> MutexGuard<'static, GlobalState> {
+    GLOBAL_STATE.clear_poison();
+    recovered(poisoned)
+}
+
+/// Locks the data of a context, recovering it if a panic poisoned it.
+pub fn lock_context(data: &Mutex<CtxProfilerData>) -> MutexGuard<'_, CtxProfilerData> {
+    data.lock().unwrap_or_else(|poisoned| {
+        data.clear_poison();
+        recovered(poisoned)
+    })
+}
+
+/// Locks the data of a context if nothing holds it, recovering it if a
+/// panic poisoned it.
+pub fn try_lock_context(data: &Mutex<CtxProfilerData>) -> Option<MutexGuard<'_, CtxProfilerData>> {
+    match data.try_lock() {
+        Ok(data) => Some(data),
+        Err(TryLockError::Poisoned(poisoned)) => {
+            data.clear_poison();
+            Some(recovered(poisoned))
+        }
+        Err(TryLockError::WouldBlock) => None,
+    }
+}
+
+/// Locks `GLOBAL_STATE`, recovering it if a panic poisoned it.
+pub fn lock_global_state() -> MutexGuard<'static, GlobalState> {
+    GLOBAL_STATE.lock().unwrap_or_else(recover)
+}
+
+/// Locks `GLOBAL_STATE` if nothing holds it, recovering it if a panic
+/// poisoned it.
+pub fn try_lock_global_state() -> Option<MutexGuard<'static, GlobalState>> {
+    match GLOBAL_STATE.try_lock() {
+        Ok(state) => Some(state),
+        Err(TryLockError::Poisoned(poisoned)) => Some(recover(poisoned)),
+        Err(TryLockError::WouldBlock) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_should_decode() {
+        let mut data = CtxProfilerData::new(std::ptr::null_mut(), 1, 0, 0, 3);
+        let interval = Duration::from_secs(3600);
+        assert!(!data.should_decode(interval));
+        data.pending_ranges = 2;
+        assert!(!data.should_decode(interval));
+        data.pending_ranges = 3;
+        assert!(data.should_decode(interval));
+        data.pending_ranges = 1;
+        assert!(data.should_decode(Duration::ZERO));
+        data.pending_ranges = 0;
+        assert!(!data.should_decode(Duration::ZERO));
+        // Ranges an image kept through a decode leave less room.
+        data.evaluated_ranges = 2;
+        assert_eq!(data.ranges_left(), 1);
+        assert!(!data.should_decode(interval));
+        data.pending_ranges = 1;
+        assert_eq!(data.ranges_left(), 0);
+        assert!(data.should_decode(interval));
+    }
+
+    #[test]
+    fn test_memory_reduction() {
+        assert_eq!(
+            MemoryReduction::next(100, 24),
+            Some(MemoryReduction::Ranges(50))
+        );
+        assert_eq!(
+            MemoryReduction::next(MIN_RETRY_RANGES + 1, 24),
+            Some(MemoryReduction::Ranges(MIN_RETRY_RANGES))
+        );
+        assert_eq!(
+            MemoryReduction::next(MIN_RETRY_RANGES, 24),
+            Some(MemoryReduction::Metrics(12))
+        );
+        assert_eq!(
+            MemoryReduction::next(4, 3),
+            Some(MemoryReduction::Metrics(1))
+        );
+        assert_eq!(MemoryReduction::next(4, 1), None);
+        assert_eq!(
+            MemoryReduction::Ranges(16).to_string(),
+            "16 ranges per counter data image"
+        );
+    }
+
+    #[test]
+    fn test_adapted_max_num_ranges() {
+        let mut data = CtxProfilerData::new(std::ptr::null_mut(), 1, 0, 0, 8);
+        let interval = Duration::from_secs(1);
+        // A full image after 100ms: 80 launches per interval, grown gradually.
+        data.pending_ranges = 8;
+        let elapsed = Duration::from_millis(100);
+        assert_eq!(data.adapted_max_num_ranges(elapsed, interval), 16);
+        // An image four times larger than needed is halved.
+        data.max_num_ranges = 512;
+        assert_eq!(data.adapted_max_num_ranges(elapsed, interval), 256);
+        data.max_num_ranges = MAX_COUNTER_DATA_RANGES;
+        data.pending_ranges = MAX_COUNTER_DATA_RANGES;
+        assert_eq!(
+            data.adapted_max_num_ranges(Duration::ZERO, interval),
+            MAX_COUNTER_DATA_RANGES
+        );
+
+        // Steady launches that fit are left alone.
+        data.max_num_ranges = 8;
+        data.pending_ranges = 5;
+        assert_eq!(data.adapted_max_num_ranges(interval, interval), 8);
+        // Few launches over a whole interval shrink the image.
+        data.pending_ranges = 1;
+        assert_eq!(data.adapted_max_num_ranges(interval * 2, interval), 4);
+        data.max_num_ranges = 1;
+        assert_eq!(data.adapted_max_num_ranges(interval * 2, interval), 1);
+    }
+
+    fn launch(timestamp: u64) -> KernelLaunch {
+        KernelLaunch {
+            function: std::ptr::null_mut(),
+            timestamp,
+            profiled: true,
+            correlation_id: 0,
+        }
+    }
+
+    fn activity(name: &str) -> KernelActivity {
+        KernelActivity {
+            kernel_name: name.to_string(),
+            grid_size: (1, 1, 1),
+            block_size: (1, 1, 1),
+            registers_per_thread: 0,
+            dynamic_shared_memory: 0,
+            static_shared_memory: 0,
+            duration: 0,
+            stream_id: 0,
+            external_ids: Vec::new(),
+            correlation_id: 0,
+        }
+    }
+
+    fn range(name: &str) -> RangeInfo {
+        RangeInfo {
+            range_name: name.to_string(),
+            parent_ranges: Vec::new(),
+            depth: 0,
+            metric_and_values: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_evict_oldest_kernels() {
+        let mut data = CtxProfilerData::new(std::ptr::null_mut(), 1, 0, 0, 3);
+        data.add_launch(launch(1), 2);
+        data.add_activity(activity("k1"));
+        data.add_launch(launch(2), 2);
+        // k1 is evicted with its activity; its range has not arrived yet.
+        data.add_launch(launch(3), 2);
+        assert_eq!(data.dropped_kernels, 1);
+        data.add_activity(activity("k2"));
+        data.add_activity(activity("k3"));
+        data.add_ranges(["k1", "k2", "k3"].map(range));
+        let launches: Vec<u64> = data.kernel_launches.iter().map(|l| l.timestamp).collect();
+        let activities: Vec<&str> = data
+            .kernel_activities
+            .iter()
+            .map(|a| a.kernel_name.as_str())
+            .collect();
+        let ranges: Vec<&str> = data
+            .range_info
+            .iter()
+            .map(|r| r.range_name.as_str())
+            .collect();
+        assert_eq!(launches, vec![2, 3]);
+        assert_eq!(activities, vec!["k2", "k3"]);
+        assert_eq!(ranges, vec!["k2", "k3"]);
+
+        // No limit.
+        data.add_launch(launch(4), 0);
+        assert_eq!(data.kernel_launches.len(), 3);
+        assert_eq!(data.dropped_kernels, 1);
+        assert_eq!(data.launched_kernels, 4);
+        assert_eq!(data.profiled_kernels, 4);
+    }
+
+    #[test]
+    fn test_spill_completed_kernels() {
+        let mut data = CtxProfilerData::new(std::ptr::null_mut(), 2, 0, 0, 3);
+        for timestamp in 1..=3 {
+            data.add_launch(launch(timestamp), 0);
+        }
+        data.add_activity(activity("k1"));
+        data.add_activity(activity("k2"));
+        data.add_ranges(["k1"].map(range));
+        data.spill_completed(0);
+        assert!(data.spill.is_none());
+        // Only k1 is complete, so k2 stays in memory despite the threshold.
+        data.spill_completed(1);
+        assert_eq!(data.kernel_launches.len(), 2);
+        data.add_ranges(["k2", "k3"].map(range));
+        data.spill_completed(1);
+        assert_eq!(data.kernel_launches.len(), 1);
+        assert_eq!(data.range_info.len(), 1);
+        assert!(data.kernel_activities.is_empty());
+
+        let spill = data.spill.as_mut().unwrap();
+        let names: Vec<String> = spill
+            .records()
+            .unwrap()
+            .map(|record| record.unwrap().activity.kernel_name)
+            .collect();
+        assert_eq!(names, vec!["k1", "k2"]);
+    }
+
+    #[test]
+    fn test_completed_kernels() {
+        let mut data = CtxProfilerData::new(std::ptr::null_mut(), 3, 0, 0, 3);
+        data.add_launch(launch(1), 0);
+        // Launched while no tracing session was active.
+        data.add_launch(
+            KernelLaunch {
+                profiled: false,
+                ..launch(2)
+            },
+            0,
+        );
+        data.add_launch(launch(3), 0);
+        for name in ["k1", "k2", "k3"] {
+            data.add_activity(activity(name));
+        }
+        data.add_ranges(["k1"].map(range));
+        let kernels: Vec<(u64, Option<&str>)> = data
+            .completed_kernels()
+            .map(|(launch, _, range)| (launch.timestamp, range.map(|r| r.range_name.as_str())))
+            .collect();
+        assert_eq!(kernels, vec![(1, Some("k1")), (2, None)]);
+
+        // Evicting an unprofiled kernel leaves the ranges alone.
+        data.add_launch(launch(4), 3);
+        data.add_launch(launch(5), 3);
+        assert_eq!(data.skipped_ranges, 0);
+        assert!(data.range_info.is_empty());
+        data.add_ranges(["k3", "k4"].map(range));
+        let ranges: Vec<&str> = data
+            .completed_kernels()
+            .filter_map(|(_, _, range)| range.map(|r| r.range_name.as_str()))
+            .collect();
+        assert_eq!(ranges, vec!["k3"]);
+    }
+
+    #[test]
+    fn test_interleaved_launches() {
+        let mut data = CtxProfilerData::new(std::ptr::null_mut(), 6, 0, 0, 3);
+        // Threads enter their launch callbacks in the order 1, 2, 3, but the
+        // second kernel is submitted first.
+        for (timestamp, correlation_id) in [(1, 11), (2, 12), (3, 13)] {
+            data.add_launch(
+                KernelLaunch {
+                    correlation_id,
+                    ..launch(timestamp)
+                },
+                0,
+            );
+        }
+        for (name, correlation_id) in [("k2", 12), ("k1", 11)] {
+            data.add_activity(KernelActivity {
+                correlation_id,
+                ..activity(name)
+            });
+        }
+        data.add_ranges(["r2", "r1"].map(range));
+        let kernels: Vec<(u64, &str, Option<&str>)> = data
+            .completed_kernels()
+            .map(|(launch, activity, range)| {
+                (
+                    launch.timestamp,
+                    activity.kernel_name.as_str(),
+                    range.map(|r| r.range_name.as_str()),
+                )
+            })
+            .collect();
+        assert_eq!(kernels, vec![(2, "k2", Some("r2")), (1, "k1", Some("r1"))]);
+
+        // An activity no launch is waiting for is of an evicted kernel.
+        data.skipped_activities = 1;
+        data.add_activity(KernelActivity {
+            correlation_id: 5,
+            ..activity("evicted")
+        });
+        assert_eq!(data.skipped_activities, 0);
+        data.add_activity(KernelActivity {
+            correlation_id: 13,
+            ..activity("k3")
+        });
+        assert_eq!(data.kernel_activities.len(), 3);
+        assert_eq!(data.kernel_launches[2].timestamp, 3);
+    }
+
+    #[test]
+    fn test_drop_emitted() {
+        let mut data = CtxProfilerData::new(std::ptr::null_mut(), 4, 0, 0, 3);
+        data.add_launch(launch(1), 0);
+        data.add_launch(
+            KernelLaunch {
+                profiled: false,
+                ..launch(2)
+            },
+            0,
+        );
+        data.add_launch(launch(3), 0);
+        for name in ["k1", "k2", "k3"] {
+            data.add_activity(activity(name));
+        }
+        data.add_ranges(["k1", "k3"].map(range));
+        data.drop_emitted(2);
+        assert_eq!(data.emitted_kernels, 2);
+        let kernels: Vec<(u64, Option<&str>)> = data
+            .completed_kernels()
+            .map(|(launch, _, range)| (launch.timestamp, range.map(|r| r.range_name.as_str())))
+            .collect();
+        assert_eq!(kernels, vec![(3, Some("k3"))]);
+    }
+
+    #[test]
+    fn test_drop_ranges() {
+        let mut data = CtxProfilerData::new(std::ptr::null_mut(), 5, 0, 0, 2);
+        for timestamp in 1..=4 {
+            data.add_launch(launch(timestamp), 0);
+        }
+        data.kernel_launches[3].profiled = false;
+        for name in ["k1", "k2", "k3", "k4"] {
+            data.add_activity(activity(name));
+        }
+        // The image held the ranges of k1 only, so k2 and k3 lose theirs.
+        data.drop_ranges(0);
+        assert!(data.range_losses.is_empty());
+        data.drop_ranges(2);
+        data.add_ranges(["k1"].map(range));
+        let kernels: Vec<(u64, Option<&str>)> = data
+            .completed_kernels()
+            .map(|(launch, _, range)| (launch.timestamp, range.map(|r| r.range_name.as_str())))
+            .collect();
+        assert_eq!(
+            kernels,
+            vec![(1, Some("k1")), (2, None), (3, None), (4, None)]
+        );
+        assert_eq!(data.range_losses.len(), 1);
+        assert_eq!(data.range_losses[0].timestamp, 2);
+        assert_eq!(data.range_losses[0].ranges, 2);
+        assert!(data.range_losses[0].total >= 2);
+
+        data.drop_emitted(0);
+        assert!(data.range_losses.is_empty());
+
+        // Ranges of evicted kernels are no longer waited for.
+        let mut data = CtxProfilerData::new(std::ptr::null_mut(), 5, 0, 0, 2);
+        data.add_launch(launch(1), 1);
+        data.add_launch(launch(2), 1);
+        assert_eq!(data.skipped_ranges, 1);
+        data.drop_ranges(2);
+        assert_eq!(data.skipped_ranges, 0);
+        assert!(!data.kernel_launches[0].profiled);
+    }
+
+    #[test]
+    fn test_context_map() {
+        let contexts = ContextMap::default();
+        for ctx_id in [3, 1, 2] {
+            contexts.insert(CtxProfilerData::new(std::ptr::null_mut(), ctx_id, 0, 0, 3));
+        }
+        assert!(contexts.contains(2));
+        assert!(contexts.get(4).is_none());
+        // Contexts are locked independently of each other.
+        let first = contexts.get(1).unwrap();
+        let _first = first.lock().unwrap();
+        contexts.get(2).unwrap().lock().unwrap().dropped_kernels = 5;
+        let ids: Vec<u32> = contexts
+            .all()
+            .iter()
+            .filter_map(|data| data.try_lock().ok().map(|data| data.ctx_id))
+            .collect();
+        assert_eq!(ids, vec![2, 3]);
+        assert_eq!(contexts.get(2).unwrap().lock().unwrap().dropped_kernels, 5);
+        // A destroyed context is removed, even while its data is still held.
+        contexts.remove(2);
+        assert!(!contexts.contains(2));
+        assert!(contexts.contains(3));
+        contexts.clear();
+        assert!(!contexts.contains(1));
+    }
+
+    #[test]
+    fn test_recover_poisoned_global_state() {
+        let _guard = GLOBAL_STATE_TESTS.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = std::panic::catch_unwind(|| {
+            let _state = lock_global_state();
+            panic!("panic while holding the global state");
+        });
+        let state = lock_global_state();
+        assert!(!GLOBAL_STATE.is_poisoned());
+        assert!(RECOVERED_FROM_POISON.load(Ordering::Relaxed));
+        drop(state);
+    }
+
+    #[test]
+    fn test_recover_poisoned_contexts() {
+        let contexts = ContextMap::default();
+        contexts.insert(CtxProfilerData::new(std::ptr::null_mut(), 1, 0, 0, 3));
+        let data = contexts.get(1).unwrap();
+        let _ = std::panic::catch_unwind(|| {
+            let _data = data.lock().unwrap();
+            let _contexts = contexts.contexts.write().unwrap();
+            panic!("panic while holding a context");
+        });
+        // Neither the context nor the map is lost to the panic.
+        assert_eq!(contexts.all().len(), 1);
+        assert!(contexts.get(1).is_some());
+        lock_context(&data).dropped_kernels = 2;
+        assert!(!data.is_poisoned());
+        assert_eq!(try_lock_context(&data).unwrap().dropped_kernels, 2);
+        assert!(RECOVERED_FROM_POISON.load(Ordering::Relaxed));
+    }
+}
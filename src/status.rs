@@ -0,0 +1,301 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Status report of the profiler inside the process.
+//!
+//! The report shows the configuration, the contexts and their kernel counts,
+//! and the overhead so far, followed by notes on what keeps counters out of
+//! the trace. It is served over HTTP with `INJECTION_STATUS_ADDR` and
+//! returned by the `status` command of the control socket.
+
+use crate::contexts;
+use crate::http::{self, Page};
+use crate::overhead::{self, Stat};
+use crate::state::{lock_context, lock_global_state, GlobalState, CONTEXT_DATA};
+use crate::tracing::{get_data_source_name, is_tracing_counters};
+use std::{fmt::Write as _, io, net::SocketAddr, time::Instant};
+
+/// Profiling state of one context.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ContextStatus {
+    pub ctx_id: u32,
+    pub device_id: i32,
+    /// Name given with `nvtxNameCuContextA`, if any.
+    pub name: Option<String>,
+    pub profiling: bool,
+    pub launched: u64,
+    pub profiled: u64,
+    /// Kernels waiting to be written, in memory or spilled.
+    pub stored: usize,
+    pub spilled: usize,
+    pub emitted: u64,
+    pub dropped: u64,
+    pub max_num_ranges: usize,
+    /// Why the context has no hardware counters, if it cannot have any.
+    pub counters_unavailable: Option<String>,
+}
+
+fn yes_no(value: bool) -> &'static str {
+    if value {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+/// Reasons kernels of the process would have no counters in a trace.
+fn notes(state: &GlobalState, tracing: bool, contexts: &[ContextStatus]) -> Vec<String> {
+    let mut notes = Vec::new();
+    if !state.injection_initialized {
+        notes.push("InitializeInjection has not run: is CUDA_INJECTION64_PATH set?".to_string());
+    }
+    if state.detached {
+        notes.push("Detached: nothing is collected anymore.".to_string());
+    }
+    if !tracing {
+        notes.push(format!(
+            "No tracing session has the `{}` data source enabled: kernels are only traced from activity records, without counters.",
+            get_data_source_name()
+        ));
+    }
+    if let Some(error) = &state.fatal_error {
+        notes.push(format!(
+            "Profiling was disabled after a fatal CUPTI error: {}",
+            error
+        ));
+    } else if !state.profiling_enabled {
+        notes.push("Profiling was disabled on the control socket.".to_string());
+    }
+    if state.overhead.is_disabled() {
+        notes.push(format!(
+            "Profiling was disabled for exceeding INJECTION_OVERHEAD_BUDGET of {}%.",
+            state.overhead.budget_pct()
+        ));
+    }
+    if state.injection_initialized && contexts.is_empty() {
+        notes.push("No CUDA context has been created yet.".to_string());
+    }
+    for context in contexts {
+        if let Some(reason) = &context.counters_unavailable {
+            notes.push(format!(
+                "Context {} has no hardware counters: {}.",
+                context.ctx_id, reason
+            ));
+        }
+    }
+    let dropped: u64 = contexts.iter().map(|c| c.dropped).sum();
+    if dropped > 0 {
+        notes.push(format!(
+            "{} kernels were dropped: raise INJECTION_MAX_KERNELS or set INJECTION_SPILL_THRESHOLD.",
+            dropped
+        ));
+    }
+    notes
+}
+
+/// Formats the status report.
+pub fn format_report(
+    state: &GlobalState,
+    tracing: bool,
+    contexts: &[ContextStatus],
+    now: Instant,
+    spent_ns: u64,
+    stats_ns: &[u64],
+) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "pid: {}", std::process::id());
+    let _ = writeln!(
+        out,
+        "uptime: {:.1} s",
+        state.overhead.elapsed(now).as_secs_f64()
+    );
+    let _ = writeln!(out, "initialized: {}", yes_no(state.injection_initialized));
+    let _ = writeln!(out, "detached: {}", yes_no(state.detached));
+    let _ = writeln!(out, "tracing: {}", yes_no(tracing));
+    let _ = writeln!(
+        out,
+        "profiling: {}",
+        if state.profiling_enabled {
+            "enabled"
+        } else {
+            "disabled"
+        }
+    );
+    let _ = writeln!(
+        out,
+        "overhead: {:.2}% of wall time, profiling 1 of {} kernels",
+        state.overhead.total_overhead_pct(now, spent_ns),
+        state.overhead.sample_interval()
+    );
+    for (stat, ns) in Stat::ALL.iter().zip(stats_ns) {
+        let _ = writeln!(out, "  {}: {:.3} ms", stat.name(), *ns as f64 / 1e6);
+    }
+    let _ = writeln!(out, "metrics: {}", state.config.metrics.join(","));
+    let _ = writeln!(
+        out,
+        "dump dir: {}",
+        state
+            .config
+            .dump_dir
+            .as_ref()
+            .map_or("none".into(), |dir| dir.display().to_string())
+    );
+    for c in contexts {
+        let _ = writeln!(
+            out,
+            "context {} (device {}){}: profiling={} launched={} profiled={} stored={} spilled={} emitted={} dropped={} max_ranges={}",
+            c.ctx_id,
+            c.device_id,
+            c.name.as_ref().map_or(String::new(), |name| format!(" {:?}", name)),
+            yes_no(c.profiling),
+            c.launched,
+            c.profiled,
+            c.stored,
+            c.spilled,
+            c.emitted,
+            c.dropped,
+            c.max_num_ranges
+        );
+    }
+    for note in notes(state, tracing, contexts) {
+        let _ = writeln!(out, "note: {}", note);
+    }
+    let _ = writeln!(out, "config: {:#?}", state.config);
+    out
+}
+
+/// Returns the status report of the process.
+pub fn report() -> String {
+    let state = lock_global_state();
+    let contexts: Vec<ContextStatus> = CONTEXT_DATA
+        .all()
+        .iter()
+        .map(|data| {
+            let data = lock_context(data);
+            let spilled = data.spill.as_ref().map_or(0, |spill| spill.len());
+            ContextStatus {
+                ctx_id: data.ctx_id,
+                device_id: data.device_id,
+                name: contexts::nvtx_name(data.ctx_id),
+                profiling: data.is_profiling(),
+                launched: data.launched_kernels,
+                profiled: data.profiled_kernels,
+                stored: data.kernel_launches.len() + spilled,
+                spilled,
+                emitted: data.emitted_kernels,
+                dropped: data.dropped_kernels,
+                max_num_ranges: data.max_num_ranges,
+                counters_unavailable: data.counters_unavailable.clone(),
+            }
+        })
+        .collect();
+    format_report(
+        &state,
+        is_tracing_counters(),
+        &contexts,
+        Instant::now(),
+        overhead::spent_ns(),
+        &overhead::stats_ns(),
+    )
+}
+
+fn route(path: &str) -> Option<Page> {
+    matches!(path, "/" | "/status").then(|| ("text/plain; charset=utf-8", report()))
+}
+
+/// Serves the status report on `addr`, at `/` and `/status`. Returns the
+/// address bound.
+pub fn start(addr: &str) -> io::Result<SocketAddr> {
+    http::start(addr, "cupti-status", route)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::overhead::OverheadTracker;
+    use std::time::Duration;
+
+    fn state() -> GlobalState {
+        GlobalState {
+            active_ctx: None,
+            injection_initialized: true,
+            detached: false,
+            subscriber: None,
+            config: Config::default(),
+            overhead: OverheadTracker::new(0.0, Instant::now(), 0),
+            last_emit: Instant::now(),
+            single_pass_scheduled: false,
+            metrics_validated: false,
+            json_export: None,
+            profiling_enabled: true,
+            fatal_error: None,
+        }
+    }
+
+    #[test]
+    fn test_format_report() {
+        let state = state();
+        let context = ContextStatus {
+            ctx_id: 3,
+            launched: 10,
+            profiled: 4,
+            stored: 2,
+            emitted: 5,
+            dropped: 3,
+            max_num_ranges: 10,
+            ..Default::default()
+        };
+        let now = Instant::now() + Duration::from_secs(2);
+        let stats = [0; overhead::NUM_STATS];
+        let report = format_report(&state, false, &[context], now, 20_000_000, &stats);
+        let lines: Vec<&str> = report.lines().collect();
+        assert!(lines.contains(&"tracing: no"));
+        assert!(lines.contains(&"overhead: 1.00% of wall time, profiling 1 of 1 kernels"));
+        assert!(lines.contains(
+            &"context 3 (device 0): profiling=no launched=10 profiled=4 stored=2 spilled=0 emitted=5 dropped=3 max_ranges=10"
+        ));
+        let notes: Vec<&str> = lines
+            .iter()
+            .filter_map(|line| line.strip_prefix("note: "))
+            .collect();
+        assert_eq!(notes.len(), 2);
+        assert!(notes[0].starts_with("No tracing session has the `gpu.counters` data source"));
+        assert!(notes[1].starts_with("3 kernels were dropped"));
+
+        // Without contexts, that is what is reported.
+        let report = format_report(&state, true, &[], now, 0, &stats);
+        assert!(report.contains("note: No CUDA context has been created yet.\n"));
+        assert!(!report.contains("No tracing session"));
+
+        let context = ContextStatus {
+            ctx_id: 4,
+            counters_unavailable: Some("another profiler is using them".to_string()),
+            ..Default::default()
+        };
+        let report = format_report(&state, true, &[context], now, 0, &stats);
+        assert!(report.contains(
+            "note: Context 4 has no hardware counters: another profiler is using them.\n"
+        ));
+
+        // Contexts named with NVTX are listed with their name.
+        let context = ContextStatus {
+            ctx_id: 5,
+            name: Some("decode context".to_string()),
+            ..Default::default()
+        };
+        let report = format_report(&state, true, &[context], now, 0, &stats);
+        assert!(report.contains("context 5 (device 0) \"decode context\": profiling=no"));
+    }
+}
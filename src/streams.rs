@@ -0,0 +1,260 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! CUDA streams as the hardware queues of the trace.
+//!
+//! Kernels go on one queue per stream, numbered in the order the streams were
+//! first seen. The driver's `cuStreamCreate*` calls map stream handles to the
+//! CUPTI stream IDs of the activity records, so that streams named with
+//! `nvtxNameCuStreamA` or `nvtxNameCudaStreamA` get their queue named after
+//! them, e.g. "decode stream", instead of "Stream 13". Queues are described
+//! with the device their kernels ran on, e.g. "GPU 0: NVIDIA H100 80GB HBM3",
+//! as the compute capability alone does not tell a machine's GPUs apart.
+
+use cupti_profiler::bindings::*;
+use once_cell::sync::Lazy;
+use std::{
+    collections::HashMap,
+    ffi::{c_char, CStr},
+    sync::{Mutex, MutexGuard},
+};
+
+/// Driver API calls that create streams, whose parameters start with the
+/// `CUstream *` of the stream created.
+pub const CREATE_CALLBACKS: [CUpti_CallbackId; 2] = [
+    CUpti_driver_api_trace_cbid_enum_CUPTI_DRIVER_TRACE_CBID_cuStreamCreate,
+    CUpti_driver_api_trace_cbid_enum_CUPTI_DRIVER_TRACE_CBID_cuStreamCreateWithPriority,
+];
+
+/// NVTX calls that name streams, with `NvtxNameStreamAParams`.
+pub const NAME_CALLBACKS: [CUpti_CallbackId; 2] = [
+    CUpti_nvtx_api_trace_cbid_CUPTI_CBID_NVTX_nvtxNameCuStreamA,
+    CUpti_nvtx_api_trace_cbid_CUPTI_CBID_NVTX_nvtxNameCudaStreamA,
+];
+
+/// `nvtxNameCuStreamA_params` and `nvtxNameCudaStreamA_params` of the CUPTI
+/// NVTX callback data.
+#[repr(C)]
+pub struct NvtxNameStreamAParams {
+    pub stream: CUstream,
+    pub name: *const c_char,
+}
+
+/// Name and description of a queue.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Queue {
+    pub name: String,
+    pub description: String,
+}
+
+/// Streams seen so far and the queues they are on.
+#[derive(Debug, Default)]
+pub struct Streams {
+    /// CUPTI stream IDs by stream handle, of the streams created.
+    ids: HashMap<usize, u32>,
+    /// Queue of each stream ID.
+    queues: HashMap<u32, u32>,
+    /// Queues by queue ID.
+    list: Vec<Queue>,
+    /// Bumped whenever a queue is added or changed, so that sessions know to
+    /// send the queue specifications again.
+    generation: u64,
+}
+
+impl Streams {
+    /// Records that `stream` was created with the CUPTI ID `stream_id`.
+    pub fn add(&mut self, stream: usize, stream_id: u32) {
+        self.ids.insert(stream, stream_id);
+        self.queue(stream_id);
+    }
+
+    /// CUPTI ID of the stream `stream` was created as, if it was seen.
+    pub fn stream_id(&self, stream: usize) -> Option<u32> {
+        self.ids.get(&stream).copied()
+    }
+
+    /// Queue of the stream with ID `stream_id`, added if new.
+    pub fn queue(&mut self, stream_id: u32) -> u32 {
+        if let Some(&queue) = self.queues.get(&stream_id) {
+            return queue;
+        }
+        let queue = self.list.len() as u32;
+        self.queues.insert(stream_id, queue);
+        self.list.push(Queue {
+            name: format!("Stream {}", stream_id),
+            description: String::new(),
+        });
+        self.generation += 1;
+        queue
+    }
+
+    /// Names the queue of the stream with ID `stream_id`.
+    pub fn set_name(&mut self, stream_id: u32, name: &str) {
+        let queue = self.queue(stream_id) as usize;
+        if self.list[queue].name != name {
+            self.list[queue].name = name.to_string();
+            self.generation += 1;
+        }
+    }
+
+    /// Describes the queue of the stream with ID `stream_id`.
+    pub fn set_description(&mut self, stream_id: u32, description: &str) {
+        let queue = self.queue(stream_id) as usize;
+        if self.list[queue].description != description {
+            self.list[queue].description = description.to_string();
+            self.generation += 1;
+        }
+    }
+
+    /// All queues, by queue ID, unless `sent` is already their generation,
+    /// which it is afterwards.
+    pub fn queues_if_changed(&self, sent: &mut Option<u64>) -> Option<Vec<Queue>> {
+        (*sent != Some(self.generation)).then(|| {
+            *sent = Some(self.generation);
+            self.list.clone()
+        })
+    }
+}
+
+static STREAMS: Lazy<Mutex<Streams>> = Lazy::new(|| Mutex::new(Streams::default()));
+
+fn streams() -> MutexGuard<'static, Streams> {
+    STREAMS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Records that `stream` was created with the CUPTI ID `stream_id`, on the
+/// device `description`.
+pub fn add(stream: usize, stream_id: u32, description: &str) {
+    let mut streams = streams();
+    streams.add(stream, stream_id);
+    streams.set_description(stream_id, description);
+}
+
+/// Description of the queues of streams on `device_id`, with its name.
+pub fn device_description(device_id: CUdevice) -> String {
+    match cupti_profiler::get_device_name(device_id) {
+        Ok(name) => format!("GPU {}: {}", device_id, name),
+        Err(_) => format!("GPU {}", device_id),
+    }
+}
+
+/// Queue of the stream with ID `stream_id`, described as on the device
+/// `description`.
+pub fn queue(stream_id: u32, description: &str) -> u32 {
+    let mut streams = streams();
+    streams.set_description(stream_id, description);
+    streams.queue(stream_id)
+}
+
+/// All queues if they changed since `sent`, see `Streams::queues_if_changed`.
+pub fn queues_if_changed(sent: &mut Option<u64>) -> Option<Vec<Queue>> {
+    streams().queues_if_changed(sent)
+}
+
+/// Handles a callback of `CREATE_CALLBACKS`, recording the stream created
+/// once the call returns.
+///
+/// # Safety
+///
+/// `data` must be the callback data CUPTI passed for one of
+/// `CREATE_CALLBACKS`.
+pub unsafe fn handle_create_callback(data: &CUpti_CallbackData) {
+    if data.callbackSite != CUpti_ApiCallbackSite_CUPTI_API_EXIT
+        || *(data.functionReturnValue as *const CUresult) != cudaError_enum_CUDA_SUCCESS
+    {
+        return;
+    }
+    let params = &*(data.functionParams as *const cuStreamCreate_params);
+    let stream = *params.phStream;
+    let Ok(stream_id) = cupti_profiler::get_stream_id(data.context, stream) else {
+        return;
+    };
+    // Described right away, so that the queue specifications sent before
+    // the stream's first kernel is written are already complete.
+    let description = cupti_profiler::get_device(data.context)
+        .map(device_description)
+        .unwrap_or_default();
+    add(stream as usize, stream_id, &description);
+}
+
+/// Handles a callback of `NAME_CALLBACKS`, naming the queue of the stream.
+/// Streams not created while profiling cannot be matched to their ID and
+/// keep their name.
+///
+/// # Safety
+///
+/// `data` must be the callback data CUPTI passed for one of `NAME_CALLBACKS`.
+pub unsafe fn handle_name_callback(data: &CUpti_NvtxData) {
+    let params = &*(data.functionParams as *const NvtxNameStreamAParams);
+    if params.name.is_null() {
+        return;
+    }
+    let name = CStr::from_ptr(params.name).to_string_lossy();
+    let mut streams = streams();
+    if let Some(stream_id) = streams.stream_id(params.stream as usize) {
+        streams.set_name(stream_id, &name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(queues: Option<Vec<Queue>>) -> Vec<String> {
+        queues
+            .unwrap()
+            .into_iter()
+            .map(|queue| queue.name)
+            .collect()
+    }
+
+    #[test]
+    fn test_streams() {
+        let mut streams = Streams::default();
+        let mut sent = None;
+        assert_eq!(streams.queues_if_changed(&mut sent), Some(vec![]));
+        assert_eq!(streams.queues_if_changed(&mut sent), None);
+
+        // Queues are numbered in the order their streams were seen.
+        assert_eq!(streams.queue(7), 0);
+        streams.add(0x1000, 13);
+        streams.add(0x2000, 14);
+        assert_eq!(streams.queue(14), 2);
+        assert_eq!(streams.queue(7), 0);
+        assert_eq!(streams.stream_id(0x1000), Some(13));
+        assert_eq!(streams.stream_id(0x3000), None);
+        assert_eq!(
+            names(streams.queues_if_changed(&mut sent)),
+            ["Stream 7", "Stream 13", "Stream 14"]
+        );
+
+        streams.set_name(13, "decode stream");
+        streams.set_name(15, "compute stream");
+        assert_eq!(streams.queue(15), 3);
+        assert_eq!(
+            names(streams.queues_if_changed(&mut sent)),
+            ["Stream 7", "decode stream", "Stream 14", "compute stream"]
+        );
+        streams.set_name(13, "decode stream");
+        assert_eq!(streams.queues_if_changed(&mut sent), None);
+
+        // Queues are sent again when their device is first described.
+        streams.set_description(13, "GPU 0: NVIDIA H100");
+        streams.set_description(13, "GPU 0: NVIDIA H100");
+        let queues = streams.queues_if_changed(&mut sent).unwrap();
+        assert_eq!(queues[1].description, "GPU 0: NVIDIA H100");
+        assert_eq!(queues[0].description, "");
+        assert_eq!(streams.queues_if_changed(&mut sent), None);
+    }
+}
@@ -0,0 +1,327 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Per-kernel aggregates of a written trace.
+//!
+//! Kernels are read back from their render stage events, and the metric
+//! values of a profiled kernel from the counter event written at its end, as
+//! `emit_render_stage_event` and `emit_counters` lay them out. Render stages
+//! other than kernels, such as copies, are skipped.
+
+use crate::trace_emitter::{KERNEL_STAGE_ID, STATS_COUNTER_ID_BASE};
+use perfetto_sdk::pb_decoder::{PbDecoder, PbDecoderError, PbDecoderField};
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
+
+const TRACE_PACKET: u32 = 1;
+const PACKET_TIMESTAMP: u32 = 8;
+const PACKET_GPU_COUNTER_EVENT: u32 = 52;
+const PACKET_GPU_RENDER_STAGE_EVENT: u32 = 53;
+const RENDER_STAGE_DURATION: u32 = 2;
+const RENDER_STAGE_STAGE_ID: u32 = 4;
+const RENDER_STAGE_EXTRA_DATA: u32 = 6;
+const EXTRA_DATA_NAME: u32 = 1;
+const EXTRA_DATA_VALUE: u32 = 2;
+const COUNTER_EVENT_DESCRIPTOR: u32 = 1;
+const COUNTER_EVENT_COUNTERS: u32 = 2;
+const DESCRIPTOR_SPECS: u32 = 1;
+const SPEC_COUNTER_ID: u32 = 1;
+const SPEC_NAME: u32 = 2;
+const COUNTER_ID: u32 = 1;
+const COUNTER_DOUBLE_VALUE: u32 = 3;
+
+/// Aggregates of all launches of one kernel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KernelSummary {
+    /// Demangled kernel name.
+    pub name: String,
+    pub count: u64,
+    /// Sum of the kernel durations, in nanoseconds.
+    pub total_duration: u64,
+    /// Sum and number of values of each counter, in the order first seen.
+    counters: Vec<(String, f64, u64)>,
+}
+
+impl KernelSummary {
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            count: 0,
+            total_duration: 0,
+            counters: Vec::new(),
+        }
+    }
+
+    /// Mean kernel duration, in nanoseconds.
+    pub fn mean_duration(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.total_duration as f64 / self.count as f64
+    }
+
+    /// Mean of each counter over the launches that were profiled.
+    pub fn counter_means(&self) -> impl Iterator<Item = (&str, f64)> {
+        self.counters
+            .iter()
+            .map(|(name, sum, n)| (name.as_str(), sum / *n as f64))
+    }
+
+    fn add_counter(&mut self, name: &str, value: f64) {
+        match self.counters.iter_mut().find(|(n, _, _)| n == name) {
+            Some((_, sum, n)) => {
+                *sum += value;
+                *n += 1;
+            }
+            None => self.counters.push((name.to_string(), value, 1)),
+        }
+    }
+}
+
+struct Kernel {
+    name: String,
+    duration: u64,
+}
+
+fn string(data: &[u8]) -> String {
+    String::from_utf8_lossy(data).into_owned()
+}
+
+/// Reads a render stage event, or returns `None` if it is not a kernel's.
+fn parse_kernel(data: &[u8]) -> Result<Option<Kernel>, PbDecoderError> {
+    let mut duration = 0;
+    let mut stage_id = 0;
+    let (mut name, mut demangled_name) = (None, None);
+    for field in PbDecoder::new(data) {
+        match field? {
+            (RENDER_STAGE_DURATION, PbDecoderField::Varint(v)) => duration = v,
+            (RENDER_STAGE_STAGE_ID, PbDecoderField::Varint(v)) => stage_id = v,
+            (RENDER_STAGE_EXTRA_DATA, PbDecoderField::Delimited(extra)) => {
+                let (mut key, mut value) = (None, None);
+                for field in PbDecoder::new(extra) {
+                    match field? {
+                        (EXTRA_DATA_NAME, PbDecoderField::Delimited(v)) => key = Some(v),
+                        (EXTRA_DATA_VALUE, PbDecoderField::Delimited(v)) => value = Some(v),
+                        _ => {}
+                    }
+                }
+                match key {
+                    Some(b"kernel_name") => name = value.map(string),
+                    Some(b"kernel_demangled_name") => demangled_name = value.map(string),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    if stage_id != KERNEL_STAGE_ID as u64 {
+        return Ok(None);
+    }
+    Ok(Some(Kernel {
+        name: demangled_name.or(name).unwrap_or_default(),
+        duration,
+    }))
+}
+
+/// Reads a counter event, adding the names of a descriptor to
+/// `counter_names` and returning the metric values it carries.
+fn parse_counters(
+    data: &[u8],
+    counter_names: &mut HashMap<u64, String>,
+) -> Result<Vec<(u64, f64)>, PbDecoderError> {
+    let mut values = Vec::new();
+    for field in PbDecoder::new(data) {
+        match field? {
+            (COUNTER_EVENT_DESCRIPTOR, PbDecoderField::Delimited(desc)) => {
+                for field in PbDecoder::new(desc) {
+                    let (DESCRIPTOR_SPECS, PbDecoderField::Delimited(spec)) = field? else {
+                        continue;
+                    };
+                    let (mut id, mut name) = (0, String::new());
+                    for field in PbDecoder::new(spec) {
+                        match field? {
+                            (SPEC_COUNTER_ID, PbDecoderField::Varint(v)) => id = v,
+                            (SPEC_NAME, PbDecoderField::Delimited(v)) => name = string(v),
+                            _ => {}
+                        }
+                    }
+                    counter_names.insert(id, name);
+                }
+            }
+            (COUNTER_EVENT_COUNTERS, PbDecoderField::Delimited(counter)) => {
+                let mut id = 0;
+                for field in PbDecoder::new(counter) {
+                    match field? {
+                        (COUNTER_ID, PbDecoderField::Varint(v)) => id = v,
+                        // Overhead counters are integers, like the zeros at
+                        // the start of a kernel.
+                        (COUNTER_DOUBLE_VALUE, PbDecoderField::Fixed64(v))
+                            if id < STATS_COUNTER_ID_BASE as u64 =>
+                        {
+                            values.push((id, f64::from_bits(v)))
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(values)
+}
+
+/// Aggregates the kernels of a serialized trace by name, longest total
+/// duration first.
+pub fn summarize(trace: &[u8]) -> Result<Vec<KernelSummary>, PbDecoderError> {
+    let mut summaries: Vec<KernelSummary> = Vec::new();
+    let mut index_of: HashMap<String, usize> = HashMap::new();
+    let mut counter_names = HashMap::new();
+    // Kernels waiting for their counters, by the time they ended.
+    let mut pending: HashMap<u64, VecDeque<usize>> = HashMap::new();
+    for field in PbDecoder::new(trace) {
+        let (TRACE_PACKET, PbDecoderField::Delimited(packet)) = field? else {
+            continue;
+        };
+        let mut timestamp = 0;
+        let mut kernel = None;
+        let mut values = Vec::new();
+        for field in PbDecoder::new(packet) {
+            match field? {
+                (PACKET_TIMESTAMP, PbDecoderField::Varint(v)) => timestamp = v,
+                (PACKET_GPU_RENDER_STAGE_EVENT, PbDecoderField::Delimited(v)) => {
+                    kernel = parse_kernel(v)?
+                }
+                (PACKET_GPU_COUNTER_EVENT, PbDecoderField::Delimited(v)) => {
+                    values = parse_counters(v, &mut counter_names)?
+                }
+                _ => {}
+            }
+        }
+        if let Some(kernel) = kernel {
+            let index = *index_of.entry(kernel.name.clone()).or_insert_with(|| {
+                summaries.push(KernelSummary::new(kernel.name));
+                summaries.len() - 1
+            });
+            let summary = &mut summaries[index];
+            summary.count += 1;
+            summary.total_duration += kernel.duration;
+            pending
+                .entry(timestamp + kernel.duration)
+                .or_default()
+                .push_back(index);
+            continue;
+        }
+        if values.is_empty() {
+            continue;
+        }
+        let Some(index) = pending.get_mut(&timestamp).and_then(|k| k.pop_front()) else {
+            continue;
+        };
+        for (id, value) in values {
+            if let Some(name) = counter_names.get(&id) {
+                summaries[index].add_counter(name, value);
+            }
+        }
+    }
+    summaries.sort_by_key(|s| Reverse(s.total_duration));
+    Ok(summaries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace_emitter::COPY_STAGE_ID;
+    use crate::tracing::{append_delimited, append_varint};
+
+    fn varint_field(buf: &mut Vec<u8>, field_id: u32, value: u64) {
+        append_varint(buf, (field_id as u64) << 3);
+        append_varint(buf, value);
+    }
+
+    fn packet(trace: &mut Vec<u8>, timestamp: u64, field_id: u32, event: &[u8]) {
+        let mut packet = Vec::new();
+        varint_field(&mut packet, PACKET_TIMESTAMP, timestamp);
+        append_delimited(&mut packet, field_id, event);
+        append_delimited(trace, TRACE_PACKET, &packet);
+    }
+
+    fn kernel(trace: &mut Vec<u8>, timestamp: u64, duration: u64, name: &str) {
+        let mut extra = Vec::new();
+        append_delimited(&mut extra, EXTRA_DATA_NAME, b"kernel_demangled_name");
+        append_delimited(&mut extra, EXTRA_DATA_VALUE, name.as_bytes());
+        let mut event = Vec::new();
+        varint_field(&mut event, RENDER_STAGE_DURATION, duration);
+        append_delimited(&mut event, RENDER_STAGE_EXTRA_DATA, &extra);
+        packet(trace, timestamp, PACKET_GPU_RENDER_STAGE_EVENT, &event);
+    }
+
+    fn counters(trace: &mut Vec<u8>, timestamp: u64, values: &[(u64, f64)]) {
+        let mut event = Vec::new();
+        for &(id, value) in values {
+            let mut counter = Vec::new();
+            varint_field(&mut counter, COUNTER_ID, id);
+            append_varint(&mut counter, ((COUNTER_DOUBLE_VALUE as u64) << 3) | 1);
+            counter.extend_from_slice(&value.to_bits().to_le_bytes());
+            append_delimited(&mut event, COUNTER_EVENT_COUNTERS, &counter);
+        }
+        packet(trace, timestamp, PACKET_GPU_COUNTER_EVENT, &event);
+    }
+
+    fn descriptor(trace: &mut Vec<u8>, names: &[&str]) {
+        let mut desc = Vec::new();
+        for (id, name) in names.iter().enumerate() {
+            let mut spec = Vec::new();
+            varint_field(&mut spec, SPEC_COUNTER_ID, id as u64);
+            append_delimited(&mut spec, SPEC_NAME, name.as_bytes());
+            append_delimited(&mut desc, DESCRIPTOR_SPECS, &spec);
+        }
+        let mut event = Vec::new();
+        append_delimited(&mut event, COUNTER_EVENT_DESCRIPTOR, &desc);
+        packet(trace, 0, PACKET_GPU_COUNTER_EVENT, &event);
+    }
+
+    #[test]
+    fn test_summarize() {
+        let mut trace = Vec::new();
+        kernel(&mut trace, 100, 50, "scale");
+        descriptor(&mut trace, &["gpu__time_duration.sum", "sm__cycles"]);
+        counters(&mut trace, 150, &[(0, 50.0), (1, 10.0)]);
+        // Unprofiled kernels have no counters.
+        kernel(&mut trace, 200, 300, "copy");
+        kernel(&mut trace, 600, 70, "scale");
+        // Copies are not kernels.
+        let mut copy = Vec::new();
+        varint_field(&mut copy, RENDER_STAGE_DURATION, 20);
+        varint_field(&mut copy, RENDER_STAGE_STAGE_ID, COPY_STAGE_ID as u64);
+        packet(&mut trace, 620, PACKET_GPU_RENDER_STAGE_EVENT, &copy);
+        // Overhead counters are not a kernel's.
+        counters(&mut trace, 670, &[(STATS_COUNTER_ID_BASE as u64, 5.0)]);
+        counters(&mut trace, 670, &[(0, 70.0), (1, 30.0)]);
+
+        let summaries = summarize(&trace).unwrap();
+        assert_eq!(summaries.len(), 2);
+        let copy = &summaries[0];
+        assert_eq!((copy.name.as_str(), copy.count), ("copy", 1));
+        assert_eq!(copy.counter_means().count(), 0);
+        let scale = &summaries[1];
+        assert_eq!(scale.count, 2);
+        assert_eq!(scale.total_duration, 120);
+        assert_eq!(scale.mean_duration(), 60.0);
+        let means: Vec<(&str, f64)> = scale.counter_means().collect();
+        assert_eq!(
+            means,
+            vec![("gpu__time_duration.sum", 60.0), ("sm__cycles", 20.0)]
+        );
+    }
+}
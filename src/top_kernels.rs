@@ -0,0 +1,309 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Table of the kernels that took the most GPU time, printed at exit.
+//!
+//! Like the Prometheus aggregates, durations are added as activity records
+//! come in and metric values as ranges are evaluated, keyed by kernel name,
+//! so the kernels and metric means are those of the whole run, also when
+//! they were never written to a trace. Only the metrics of the table are
+//! kept.
+
+use crate::prometheus::MAX_KERNEL_NAMES;
+use crate::trace_emitter::demangle;
+use cupti_profiler::RangeInfo;
+use once_cell::sync::Lazy;
+use std::{collections::HashMap, fmt::Write as _, fs, path::Path, sync::Mutex};
+
+/// Achieved occupancy, the mean share of the warp slots that are in use.
+pub const OCCUPANCY_METRIC: &str = "sm__warps_active.avg.pct_of_peak_sustained_active";
+
+/// Metrics whose means the table shows: occupancy, SM and memory throughput.
+const TABLE_METRICS: [&str; 3] = [
+    OCCUPANCY_METRIC,
+    "sm__throughput.avg.pct_of_peak_sustained_elapsed",
+    "gpu__compute_memory_throughput.avg.pct_of_peak_sustained_elapsed",
+];
+
+/// Label of the kernels past `MAX_KERNEL_NAMES`.
+const OTHER_KERNELS: &str = "other";
+
+/// GPU time and metric means of one kernel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopKernel {
+    /// Demangled kernel name.
+    pub name: String,
+    pub count: u64,
+    /// Sum of the kernel durations, in nanoseconds.
+    pub total_duration: f64,
+    /// Mean achieved occupancy of the profiled launches, in percent.
+    pub occupancy_pct: Option<f64>,
+    /// Mean SM throughput, in percent of peak.
+    pub sm_pct: Option<f64>,
+    /// Mean memory throughput, in percent of peak.
+    pub mem_pct: Option<f64>,
+}
+
+/// GPU time of one kernel, and sum and number of the values of each of the
+/// `TABLE_METRICS`.
+#[derive(Debug, Default)]
+struct KernelTotals {
+    count: u64,
+    /// Sum of the durations, in nanoseconds.
+    duration: f64,
+    metrics: [(f64, u64); TABLE_METRICS.len()],
+}
+
+/// Per-kernel totals since collection was started.
+#[derive(Debug, Default)]
+pub struct Totals {
+    kernels: HashMap<String, KernelTotals>,
+}
+
+impl Totals {
+    fn kernel(&mut self, name: &str) -> &mut KernelTotals {
+        let name = if self.kernels.contains_key(name) || self.kernels.len() < MAX_KERNEL_NAMES {
+            name
+        } else {
+            OTHER_KERNELS
+        };
+        self.kernels.entry(name.to_string()).or_default()
+    }
+
+    /// Adds a kernel that ran for `duration` nanoseconds.
+    pub fn add_kernel(&mut self, name: &str, duration: u64) {
+        let totals = self.kernel(name);
+        totals.count += 1;
+        totals.duration += duration as f64;
+    }
+
+    /// Adds the values of the `TABLE_METRICS` of evaluated ranges to the
+    /// kernels they profiled.
+    pub fn add_ranges(&mut self, ranges: &[RangeInfo]) {
+        for range in ranges {
+            let totals = self.kernel(range.leaf_name());
+            for metric in &range.metric_and_values {
+                let Some(i) = TABLE_METRICS
+                    .iter()
+                    .position(|&name| name == metric.metric_name)
+                else {
+                    continue;
+                };
+                if metric.value.is_finite() {
+                    totals.metrics[i].0 += metric.value;
+                    totals.metrics[i].1 += 1;
+                }
+            }
+        }
+    }
+
+    /// Total GPU time of all kernels, in nanoseconds.
+    pub fn total_duration(&self) -> f64 {
+        self.kernels.values().map(|totals| totals.duration).sum()
+    }
+
+    /// The `n` kernels with the most GPU time, most first.
+    pub fn top_kernels(&self, n: usize) -> Vec<TopKernel> {
+        let mut kernels: Vec<(&String, &KernelTotals)> = self
+            .kernels
+            .iter()
+            .filter(|(_, totals)| totals.count > 0)
+            .collect();
+        kernels.sort_by(|a, b| {
+            b.1.duration
+                .total_cmp(&a.1.duration)
+                .then_with(|| a.0.cmp(b.0))
+        });
+        kernels
+            .into_iter()
+            .take(n)
+            .map(|(name, totals)| {
+                let [occupancy_pct, sm_pct, mem_pct] = totals
+                    .metrics
+                    .map(|(sum, count)| (count > 0).then(|| sum / count as f64));
+                TopKernel {
+                    name: demangle(name),
+                    count: totals.count,
+                    total_duration: totals.duration,
+                    occupancy_pct,
+                    sm_pct,
+                    mem_pct,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Totals the table is built from, `None` unless collection was started.
+static TOTALS: Lazy<Mutex<Option<Totals>>> = Lazy::new(|| Mutex::new(None));
+
+/// Adds a completed kernel, if totals are collected.
+pub fn record_kernel(name: &str, duration: u64) {
+    if let Ok(mut totals) = TOTALS.lock() {
+        if let Some(totals) = totals.as_mut() {
+            totals.add_kernel(name, duration);
+        }
+    }
+}
+
+/// Adds evaluated ranges, if totals are collected.
+pub fn record_ranges(ranges: &[RangeInfo]) {
+    if let Ok(mut totals) = TOTALS.lock() {
+        if let Some(totals) = totals.as_mut() {
+            totals.add_ranges(ranges);
+        }
+    }
+}
+
+/// Starts collecting totals for the table.
+pub fn collect() {
+    if let Ok(mut totals) = TOTALS.lock() {
+        totals.get_or_insert_with(Totals::default);
+    }
+}
+
+fn pct(value: Option<f64>) -> String {
+    value.map_or("-".to_string(), |value| format!("{:.1}%", value))
+}
+
+/// Formats the kernels as a table under a header line, with each kernel's
+/// share of `total_duration` nanoseconds of GPU time. Names come last, so
+/// long ones do not push the numbers apart.
+pub fn format_table(kernels: &[TopKernel], total_duration: f64) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "Top {} kernels by GPU time, of {:.3} ms in total:",
+        kernels.len(),
+        total_duration / 1e6
+    );
+    let _ = writeln!(
+        out,
+        "{:>12}  {:>6}  {:>8}  {:>12}  {:>9}  {:>6}  {:>6}  kernel",
+        "total (ms)", "share", "count", "mean (us)", "occupancy", "sm", "memory"
+    );
+    for kernel in kernels {
+        let share = if total_duration > 0.0 {
+            100.0 * kernel.total_duration / total_duration
+        } else {
+            0.0
+        };
+        let _ = writeln!(
+            out,
+            "{:>12.3}  {:>5.1}%  {:>8}  {:>12.3}  {:>9}  {:>6}  {:>6}  {}",
+            kernel.total_duration / 1e6,
+            share,
+            kernel.count,
+            kernel.total_duration / kernel.count.max(1) as f64 / 1e3,
+            pct(kernel.occupancy_pct),
+            pct(kernel.sm_pct),
+            pct(kernel.mem_pct),
+            kernel.name
+        );
+    }
+    out
+}
+
+/// Prints the table of the `n` kernels with the most GPU time to stderr,
+/// and writes it to `file` too if given. Nothing is printed when no kernel
+/// ran.
+pub fn report(n: usize, file: Option<&Path>) {
+    let Some((kernels, total_duration)) = TOTALS.lock().ok().and_then(|totals| {
+        let totals = totals.as_ref()?;
+        Some((totals.top_kernels(n), totals.total_duration()))
+    }) else {
+        return;
+    };
+    if kernels.is_empty() {
+        return;
+    }
+    let table = format_table(&kernels, total_duration);
+    eprint!("{}", table);
+    if let Some(file) = file {
+        if let Err(e) = fs::write(file, &table) {
+            eprintln!("Failed to write {}: {}", file.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cupti_profiler::MetricValuePair;
+
+    #[test]
+    fn test_top_kernels() {
+        let mut totals = Totals::default();
+        totals.add_kernel("_Z5scalePf", 1_000);
+        totals.add_kernel("_Z5scalePf", 3_000);
+        totals.add_kernel("copy", 5_000);
+        totals.add_kernel("fill", 500);
+        let metric = |name: &str, value| MetricValuePair {
+            metric_name: name.to_string(),
+            value,
+        };
+        totals.add_ranges(&[RangeInfo {
+            range_name: "_Z5scalePf".to_string(),
+            parent_ranges: Vec::new(),
+            depth: 0,
+            metric_and_values: vec![
+                metric(OCCUPANCY_METRIC, 50.0),
+                metric("sm__cycles_elapsed.avg", 10.0),
+            ],
+        }]);
+        assert_eq!(totals.total_duration(), 9_500.0);
+        let top = totals.top_kernels(2);
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[0].name, "copy");
+        assert_eq!(top[0].occupancy_pct, None);
+        assert_eq!(top[1].name, "scale(float*)");
+        assert_eq!(top[1].count, 2);
+        assert_eq!(top[1].total_duration, 4_000.0);
+        assert_eq!(top[1].occupancy_pct, Some(50.0));
+        assert_eq!(top[1].sm_pct, None);
+    }
+
+    #[test]
+    fn test_format_table() {
+        let kernels = [
+            TopKernel {
+                name: "copy".to_string(),
+                count: 4,
+                total_duration: 3_000_000.0,
+                occupancy_pct: Some(87.25),
+                sm_pct: Some(12.0),
+                mem_pct: Some(91.5),
+            },
+            TopKernel {
+                name: "scale(float*)".to_string(),
+                count: 1,
+                total_duration: 1_000_000.0,
+                occupancy_pct: None,
+                sm_pct: None,
+                mem_pct: None,
+            },
+        ];
+        let table = format_table(&kernels, 5_000_000.0);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "Top 2 kernels by GPU time, of 5.000 ms in total:",
+                "  total (ms)   share     count     mean (us)  occupancy      sm  memory  kernel",
+                "       3.000   60.0%         4       750.000      87.2%   12.0%   91.5%  copy",
+                "       1.000   20.0%         1      1000.000          -       -       -  scale(float*)",
+            ]
+        );
+    }
+}
@@ -0,0 +1,2368 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Conversion of collected kernels into Perfetto trace packets.
+//!
+//! Driver queries are gathered into plain structs up front, so the occupancy
+//! math and packet layout below do not depend on CUDA or global state.
+
+use crate::expressions::Expression;
+use crate::memory_pools::PoolSample;
+use crate::metrics::FLOPS_METRICS;
+use crate::overhead::{Stat, StatsSample};
+use crate::state::KernelActivity;
+use crate::streams::Queue;
+use crate::tracing::{get_next_event_id, TraceContext};
+use cpp_demangle::Symbol;
+use cupti_profiler as profiler;
+use cupti_profiler::bindings::*;
+use cupti_profiler::MetricValuePair;
+use perfetto_sdk::protos::{common::builtin_clock::BuiltinClock, trace::trace_packet::TracePacket};
+use perfetto_sdk_protos_gpu::protos::{
+    common::gpu_counter_descriptor::{
+        GpuCounterDescriptor, GpuCounterDescriptorGpuCounterGroup, GpuCounterDescriptorMeasureUnit,
+        GpuCounterSpec,
+    },
+    trace::{
+        gpu::{
+            gpu_counter_event::{GpuCounter, GpuCounterEvent},
+            gpu_log::{GpuLog, GpuLogSeverity},
+            gpu_render_stage_event::{Description, ExtraData, GpuRenderStageEvent, Specifications},
+        },
+        trace_packet::TracePacketExt,
+    },
+};
+use std::collections::{hash_map::Entry, HashMap};
+use std::hash::Hash;
+
+/// Metric whose value is used as the duration of a kernel.
+pub const DURATION_METRIC: &str = "gpu__time_duration.sum";
+
+/// Metric the achieved occupancy is derived from: the warps resident on an
+/// SM per cycle it was active.
+pub const ACTIVE_WARPS_METRIC: &str = "sm__warps_active.avg.per_cycle_active";
+
+/// Name of the achieved occupancy, in extra data and as a counter.
+pub const ACHIEVED_OCCUPANCY: &str = "achieved_occupancy";
+
+/// Extra data and derived counter name of `dram_bw_pct_of_peak`.
+pub const DRAM_BW_PCT_OF_PEAK: &str = "dram_bw_pct_of_peak";
+
+/// Counter ID of the first overhead counter. Metrics use the IDs below it.
+pub const STATS_COUNTER_ID_BASE: u32 = 1000;
+
+/// Counter ID of the ranges the range profiler dropped, past the overhead
+/// counters.
+pub const DROPPED_RANGES_COUNTER_ID: u32 = 2000;
+
+/// Name of the dropped ranges counter in the trace.
+pub const DROPPED_RANGES_COUNTER: &str = "injection.dropped_ranges";
+
+/// Names of the render stages, by stage ID.
+pub const STAGES: [&str; 2] = ["Kernel", "Copy"];
+
+/// Stage ID of kernels.
+pub const KERNEL_STAGE_ID: u32 = 0;
+
+/// Stage ID of copies within and between GPUs.
+pub const COPY_STAGE_ID: u32 = 1;
+
+/// Counter IDs and names of the memory pool counters, past the dropped
+/// ranges counter.
+pub const MEMORY_POOL_COUNTERS: [(u32, &str); 2] =
+    [(2001, "memory_pool.reserved"), (2002, "memory_pool.used")];
+
+/// The process the kernels were launched from.
+#[derive(Debug, Clone)]
+pub struct ProcessInfo {
+    pub pid: i32,
+    pub name: String,
+    /// Command line, which tells runs of the same binary apart.
+    pub cmdline: String,
+}
+
+impl ProcessInfo {
+    /// Returns the current process.
+    pub fn current() -> Self {
+        Self {
+            pid: std::process::id() as i32,
+            name: process_name().unwrap_or_else(|| "unknown".to_string()),
+            cmdline: join_command_line(command_line()),
+        }
+    }
+}
+
+/// Name of the current process: its `comm` on Linux, as `ps` shows it, and
+/// the file name of its executable elsewhere.
+fn process_name() -> Option<String> {
+    #[cfg(target_os = "linux")]
+    if let Ok(comm) = std::fs::read_to_string("/proc/self/comm") {
+        return Some(comm.trim_end_matches('\n').to_owned());
+    }
+    let exe = std::env::current_exe().ok()?;
+    Some(exe.file_stem()?.to_string_lossy().into_owned())
+}
+
+/// Arguments the current process was started with. Linux has them in
+/// `/proc/self/cmdline` even when the standard library did not get them, as
+/// in a library preloaded into a program of another language.
+fn command_line() -> Vec<String> {
+    #[cfg(target_os = "linux")]
+    if let Ok(cmdline) = std::fs::read("/proc/self/cmdline") {
+        return cmdline
+            .split(|&b| b == 0)
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| String::from_utf8_lossy(arg).into_owned())
+            .collect();
+    }
+    std::env::args_os()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect()
+}
+
+/// Joins `args` with spaces, quoting those a shell would split or expand so
+/// that the command line can be pasted back into one.
+pub fn join_command_line(args: impl IntoIterator<Item = String>) -> String {
+    let quote = |arg: String| {
+        let plain = !arg.is_empty()
+            && arg
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c));
+        if plain {
+            arg
+        } else {
+            format!("'{}'", arg.replace('\'', r"'\''"))
+        }
+    };
+    args.into_iter().map(quote).collect::<Vec<_>>().join(" ")
+}
+
+/// Device attributes the occupancy figures are derived from.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DeviceProperties {
+    pub num_sms: i32,
+    pub warp_size: i32,
+    pub max_threads_per_sm: i32,
+    pub max_blocks_per_sm: i32,
+    pub registers_per_sm: i32,
+    pub shared_mem_per_sm: i32,
+    pub compute_capability: (i32, i32),
+    /// Peak SM clock, in kHz.
+    pub clock_rate: i32,
+    /// Peak memory clock, in kHz.
+    pub memory_clock_rate: i32,
+    /// Width of the global memory bus, in bits.
+    pub memory_bus_width: i32,
+}
+
+impl DeviceProperties {
+    /// Queries the attributes of `device_id` from the driver.
+    pub fn query(device_id: CUdevice, num_sms: i32) -> Self {
+        let attribute =
+            |attr, default| profiler::get_device_attribute(device_id, attr).unwrap_or(default);
+        Self {
+            num_sms,
+            warp_size: attribute(CUdevice_attribute_enum_CU_DEVICE_ATTRIBUTE_WARP_SIZE, 32),
+            max_threads_per_sm: attribute(
+                CUdevice_attribute_enum_CU_DEVICE_ATTRIBUTE_MAX_THREADS_PER_MULTIPROCESSOR,
+                0,
+            ),
+            max_blocks_per_sm: attribute(
+                CUdevice_attribute_enum_CU_DEVICE_ATTRIBUTE_MAX_BLOCKS_PER_MULTIPROCESSOR,
+                0,
+            ),
+            registers_per_sm: attribute(
+                CUdevice_attribute_enum_CU_DEVICE_ATTRIBUTE_MAX_REGISTERS_PER_MULTIPROCESSOR,
+                0,
+            ),
+            shared_mem_per_sm: attribute(
+                CUdevice_attribute_enum_CU_DEVICE_ATTRIBUTE_MAX_SHARED_MEMORY_PER_MULTIPROCESSOR,
+                0,
+            ),
+            compute_capability: (
+                attribute(
+                    CUdevice_attribute_enum_CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MAJOR,
+                    0,
+                ),
+                attribute(
+                    CUdevice_attribute_enum_CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MINOR,
+                    0,
+                ),
+            ),
+            clock_rate: attribute(CUdevice_attribute_enum_CU_DEVICE_ATTRIBUTE_CLOCK_RATE, 0),
+            memory_clock_rate: attribute(
+                CUdevice_attribute_enum_CU_DEVICE_ATTRIBUTE_MEMORY_CLOCK_RATE,
+                0,
+            ),
+            memory_bus_width: attribute(
+                CUdevice_attribute_enum_CU_DEVICE_ATTRIBUTE_GLOBAL_MEMORY_BUS_WIDTH,
+                0,
+            ),
+        }
+    }
+
+    /// FP32 and FP64 lanes per SM of the architecture, 0 if unknown.
+    fn fma_lanes_per_sm(&self) -> (i32, i32) {
+        match self.compute_capability {
+            (3, 5) | (3, 7) => (192, 64),
+            (3, _) => (192, 8),
+            (5, _) => (128, 4),
+            (6, 0) | (7, 0) | (8, 0) => (64, 32),
+            (6, _) => (128, 4),
+            (7, _) => (64, 2),
+            (8, _) => (128, 2),
+            (9, 0) | (10, 0) => (128, 64),
+            (major, _) if major >= 10 => (128, 2),
+            _ => (0, 0),
+        }
+    }
+
+    /// Warps an SM can hold, 0 if unknown.
+    pub fn max_warps_per_sm(&self) -> i32 {
+        if self.warp_size > 0 {
+            self.max_threads_per_sm / self.warp_size
+        } else {
+            0
+        }
+    }
+
+    /// FLOPs of one warp-wide tensor core instruction, 0 if unknown. Estimated
+    /// for dense FP16 MMAs, of shape m16n8k8 on Turing and m16n8k16 on Ampere
+    /// and Ada; other architectures issue instructions of other shapes.
+    fn tensor_flops_per_inst(&self) -> i32 {
+        match self.compute_capability {
+            (7, 5) => 2 * 16 * 8 * 8,
+            (8, _) => 2 * 16 * 8 * 16,
+            _ => 0,
+        }
+    }
+
+    /// Peak FP32 or FP64 rate, in GFLOP/s, with every lane issuing an FMA
+    /// per cycle at the peak clock.
+    pub fn peak_gflops(&self, fp64: bool) -> f64 {
+        let (fp32_lanes, fp64_lanes) = self.fma_lanes_per_sm();
+        let lanes = if fp64 { fp64_lanes } else { fp32_lanes };
+        2.0 * lanes as f64 * self.num_sms as f64 * self.clock_rate as f64 / 1e6
+    }
+
+    /// Peak DRAM bandwidth, in GB/s, transferring on both clock edges.
+    pub fn peak_dram_gbps(&self) -> f64 {
+        2.0 * self.memory_clock_rate as f64 * 1e3 * (self.memory_bus_width / 8) as f64 / 1e9
+    }
+}
+
+/// Extra data name of the external correlation IDs of a kind. `custom0` is
+/// the operator ID of PyTorch's Kineto, which its traces show as
+/// `External id`, and `custom1` its user annotation ID.
+fn external_id_name(kind: u32) -> &'static str {
+    if kind == CUpti_ExternalCorrelationKind_CUPTI_EXTERNAL_CORRELATION_KIND_CUSTOM0 {
+        "external_id"
+    } else if kind == CUpti_ExternalCorrelationKind_CUPTI_EXTERNAL_CORRELATION_KIND_CUSTOM1 {
+        "external_id_custom1"
+    } else if kind == CUpti_ExternalCorrelationKind_CUPTI_EXTERNAL_CORRELATION_KIND_CUSTOM2 {
+        "external_id_custom2"
+    } else if kind == CUpti_ExternalCorrelationKind_CUPTI_EXTERNAL_CORRELATION_KIND_OPENACC {
+        "external_id_openacc"
+    } else {
+        "external_id_unknown"
+    }
+}
+
+/// Builds the extra data of the external correlation IDs of a kernel, so it
+/// can be joined with the traces of the framework that pushed them.
+pub fn build_external_id_data(external_ids: &[(u32, u64)]) -> Vec<(&'static str, String)> {
+    external_ids
+        .iter()
+        .map(|&(kind, id)| (external_id_name(kind), id.to_string()))
+        .collect()
+}
+
+/// Value of the metric `name` among `metrics`, unless it was not collected
+/// or has no finite value.
+fn metric_value(metrics: &[MetricValuePair], name: &str) -> Option<f64> {
+    metrics
+        .iter()
+        .find(|m| m.metric_name == name)
+        .map(|m| m.value)
+        .filter(|v| v.is_finite())
+}
+
+/// Throughput, in percent of peak, from which a unit counts as busy. Below
+/// it for both SM and memory, a kernel does not keep the GPU busy.
+const SOL_BUSY_PCT: f64 = 60.0;
+
+/// Difference, in percentage points, under which SM and memory throughput
+/// count as balanced.
+const SOL_BALANCED_PCT: f64 = 10.0;
+
+/// Builds the Speed of Light verdict of a profiled kernel from its
+/// `SOL_METRICS` values: SM and memory throughput in percent of peak, and
+/// which of `compute`, `memory`, `balanced` or `latency` bounds the kernel.
+///
+/// Returns nothing unless the metrics were collected.
+pub fn build_sol_data(metrics: &[MetricValuePair]) -> Vec<(&'static str, String)> {
+    let value = |name: &str| metric_value(metrics, name);
+    let (Some(sm), Some(mem)) = (
+        value("sm__throughput.avg.pct_of_peak_sustained_elapsed"),
+        value("gpu__compute_memory_throughput.avg.pct_of_peak_sustained_elapsed"),
+    ) else {
+        return Vec::new();
+    };
+    let bottleneck = if sm.max(mem) < SOL_BUSY_PCT {
+        "latency"
+    } else if (sm - mem).abs() < SOL_BALANCED_PCT {
+        "balanced"
+    } else if sm > mem {
+        "compute"
+    } else {
+        "memory"
+    };
+    vec![
+        ("sol_sm_pct", format!("{:.1}", sm)),
+        ("sol_mem_pct", format!("{:.1}", mem)),
+        ("bottleneck", bottleneck.to_string()),
+    ]
+}
+
+/// Metrics the IPC extra data is read from, with the extra data names: the
+/// instructions an SM executed per cycle it was active and per elapsed cycle.
+const IPC_METRICS: &[(&str, &str)] = &[
+    ("sm__inst_executed.avg.per_cycle_active", "ipc_active"),
+    ("sm__inst_executed.avg.per_cycle_elapsed", "ipc_elapsed"),
+];
+
+/// Builds the instructions per cycle of a profiled kernel, `ipc_active` and
+/// `ipc_elapsed`, from whichever of `IPC_METRICS` were collected.
+pub fn build_ipc_data(metrics: &[MetricValuePair]) -> Vec<(&'static str, String)> {
+    IPC_METRICS
+        .iter()
+        .filter_map(|&(metric_name, name)| {
+            let value = metric_value(metrics, metric_name)?;
+            Some((name, format!("{:.2}", value)))
+        })
+        .collect()
+}
+
+/// Achieved occupancy of a profiled kernel, in percent: the warps active per
+/// active cycle of `ACTIVE_WARPS_METRIC` over the warps an SM can hold, as
+/// Nsight Compute presents it.
+///
+/// Returns nothing unless the metric was collected and the capacity is known.
+pub fn achieved_occupancy(metrics: &[MetricValuePair], device: &DeviceProperties) -> Option<f64> {
+    let max_warps = device.max_warps_per_sm();
+    if max_warps <= 0 {
+        return None;
+    }
+    let active_warps = metric_value(metrics, ACTIVE_WARPS_METRIC)?;
+    Some(100.0 * active_warps / max_warps as f64)
+}
+
+/// Metric of the instructions issued to the tensor pipe, which the tensor
+/// FLOPs are estimated from.
+pub const TENSOR_INST_METRIC: &str = "sm__inst_executed_pipe_tensor.sum";
+
+/// Counter names of the FLOPs of the CUDA cores per precision, see
+/// `FlopCounts::counters`.
+pub const FLOPS_COUNTERS: [&str; 3] = ["fp16_flops", "fp32_flops", "fp64_flops"];
+
+/// Counter name of the tensor core FLOPs.
+pub const TENSOR_FLOPS_COUNTER: &str = "tensor_flops";
+
+/// FLOPs of a profiled kernel per precision, estimated from its instruction
+/// counts with FMAs counting as two operations.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FlopCounts {
+    pub fp16: f64,
+    pub fp32: f64,
+    pub fp64: f64,
+    /// Tensor core FLOPs, if the tensor instructions were collected and the
+    /// FLOPs per instruction of the architecture are known.
+    pub tensor: Option<f64>,
+}
+
+impl FlopCounts {
+    /// Estimates the FLOPs of a kernel from its `FLOPS_METRICS` values.
+    ///
+    /// Returns nothing unless the floating point instructions of every
+    /// precision were collected.
+    pub fn estimate(metrics: &[MetricValuePair], device: &DeviceProperties) -> Option<Self> {
+        let value = |name: &str| metric_value(metrics, name);
+        let flops = |prefix: char| {
+            let op = |op: &str| {
+                value(&format!(
+                    "smsp__sass_thread_inst_executed_op_{}{}_pred_on.sum",
+                    prefix, op
+                ))
+            };
+            Some(op("add")? + op("mul")? + 2.0 * op("fma")?)
+        };
+        let flops_per_inst = device.tensor_flops_per_inst();
+        let tensor = value(TENSOR_INST_METRIC)
+            .filter(|_| flops_per_inst > 0)
+            .map(|insts| insts * flops_per_inst as f64);
+        Some(Self {
+            fp16: flops('h')?,
+            fp32: flops('f')?,
+            fp64: flops('d')?,
+            tensor,
+        })
+    }
+
+    /// FLOPs of the CUDA cores, leaving out those of the tensor cores.
+    pub fn total(&self) -> f64 {
+        self.fp16 + self.fp32 + self.fp64
+    }
+
+    /// The FLOPs as counter values, by counter name.
+    pub fn counters(&self) -> Vec<(&'static str, f64)> {
+        let mut counters: Vec<_> = FLOPS_COUNTERS
+            .into_iter()
+            .zip([self.fp16, self.fp32, self.fp64])
+            .collect();
+        if let Some(tensor) = self.tensor {
+            counters.push((TENSOR_FLOPS_COUNTER, tensor));
+        }
+        counters
+    }
+}
+
+/// Builds the FLOPs extra data of a profiled kernel: its estimated FLOPs per
+/// precision, and the rate of each over `duration` nanoseconds in percent of
+/// the peak of FP32 or FP64, where the peak is known.
+pub fn build_flops_data(
+    flops: &FlopCounts,
+    duration: u64,
+    device: &DeviceProperties,
+) -> Vec<(&'static str, String)> {
+    let mut data: Vec<_> = flops
+        .counters()
+        .into_iter()
+        .map(|(name, value)| (name, format!("{:.0}", value)))
+        .collect();
+    if duration > 0 {
+        // FLOPs per nanosecond are GFLOP/s.
+        let pct_of_peak = |flops: f64, fp64| {
+            let peak_gflops = device.peak_gflops(fp64);
+            (peak_gflops > 0.0).then(|| 100.0 * flops / duration as f64 / peak_gflops)
+        };
+        if let Some(pct) = pct_of_peak(flops.fp32, false) {
+            data.push(("fp32_pct_of_peak", format!("{:.1}", pct)));
+        }
+        if let Some(pct) = pct_of_peak(flops.fp64, true) {
+            data.push(("fp64_pct_of_peak", format!("{:.1}", pct)));
+        }
+    }
+    data
+}
+
+/// Builds the DRAM traffic of a profiled kernel from its `DRAM_METRICS`
+/// values: the bytes it read and wrote and the effective bandwidth they give
+/// over `duration` nanoseconds.
+///
+/// Returns nothing unless the metrics were collected.
+pub fn build_dram_data(metrics: &[MetricValuePair], duration: u64) -> Vec<(&'static str, String)> {
+    let value = |name: &str| metric_value(metrics, name);
+    let (Some(read), Some(write)) = (
+        value("dram__bytes_read.sum"),
+        value("dram__bytes_write.sum"),
+    ) else {
+        return Vec::new();
+    };
+    let mut data = vec![
+        ("dram_bytes_read", format!("{:.0}", read)),
+        ("dram_bytes_write", format!("{:.0}", write)),
+    ];
+    if duration > 0 {
+        // Bytes per nanosecond are GB/s.
+        let gbps = (read + write) / duration as f64;
+        data.push(("dram_bandwidth_gbps", format!("{:.3}", gbps)));
+    }
+    data
+}
+
+/// DRAM bandwidth of a profiled kernel in percent of the device's peak,
+/// computed from its memory clock and bus width rather than taken from
+/// CUPTI, so it can be checked against
+/// `gpu__dram_throughput.avg.pct_of_peak_sustained_elapsed`. The bytes are
+/// those of `dram__bytes.sum`, or else of the `DRAM_METRICS`.
+///
+/// Returns nothing unless the bytes were collected and the peak is known.
+pub fn dram_bw_pct_of_peak(
+    metrics: &[MetricValuePair],
+    duration: u64,
+    device: &DeviceProperties,
+) -> Option<f64> {
+    let peak_dram_gbps = device.peak_dram_gbps();
+    if duration == 0 || peak_dram_gbps <= 0.0 {
+        return None;
+    }
+    let value = |name: &str| metric_value(metrics, name);
+    let bytes = value("dram__bytes.sum")
+        .or_else(|| Some(value("dram__bytes_read.sum")? + value("dram__bytes_write.sum")?))?;
+    // Bytes per nanosecond are GB/s.
+    Some(100.0 * bytes / duration as f64 / peak_dram_gbps)
+}
+
+/// Builds the shared memory bank conflicts of a profiled kernel from its
+/// `SMEM_METRICS` values: the conflicts of its loads and stores, and
+/// `smem_bank_conflict_ratio`, the share of its shared memory wavefronts
+/// that were replayed for a conflict.
+///
+/// Returns nothing unless the metrics were collected.
+pub fn build_smem_data(metrics: &[MetricValuePair]) -> Vec<(&'static str, String)> {
+    let sum = |prefix: &str| {
+        let value =
+            |op: &str| metric_value(metrics, &format!("{}_mem_shared_op_{}.sum", prefix, op));
+        Some(value("ld")? + value("st")?)
+    };
+    let (Some(conflicts), Some(wavefronts)) = (
+        sum("l1tex__data_bank_conflicts_pipe_lsu"),
+        sum("l1tex__data_pipe_lsu_wavefronts"),
+    ) else {
+        return Vec::new();
+    };
+    let mut data = vec![("smem_bank_conflicts", format!("{:.0}", conflicts))];
+    if wavefronts > 0.0 {
+        data.push((
+            "smem_bank_conflict_ratio",
+            format!("{:.3}", conflicts / wavefronts),
+        ));
+    }
+    data
+}
+
+/// Builds the cache hit rates of a profiled kernel from its `CACHE_METRICS`
+/// values: `l1_hit_rate` and `l2_hit_rate`, the sectors looked up in the
+/// L1/TEX cache and in L2 that hit, in percent.
+///
+/// Each is left out unless its metrics were collected and sectors were
+/// looked up.
+pub fn build_cache_data(metrics: &[MetricValuePair]) -> Vec<(&'static str, String)> {
+    let hit_rate = |unit: &str| {
+        let value = |outcome: &str| {
+            metric_value(
+                metrics,
+                &format!("{}__t_sectors_lookup_{}.sum", unit, outcome),
+            )
+        };
+        let (hit, miss) = (value("hit")?, value("miss")?);
+        (hit + miss > 0.0).then(|| format!("{:.1}", 100.0 * hit / (hit + miss)))
+    };
+    [("l1_hit_rate", "l1tex"), ("l2_hit_rate", "lts")]
+        .into_iter()
+        .filter_map(|(name, unit)| Some((name, hit_rate(unit)?)))
+        .collect()
+}
+
+/// `STALL_METRICS` with the extra data names of their stall reasons.
+const STALL_REASONS: &[(&str, &str)] = &[
+    (
+        "smsp__average_warps_issue_stalled_barrier_per_issue_active.ratio",
+        "stall_barrier",
+    ),
+    (
+        "smsp__average_warps_issue_stalled_branch_resolving_per_issue_active.ratio",
+        "stall_branch_resolving",
+    ),
+    (
+        "smsp__average_warps_issue_stalled_dispatch_stall_per_issue_active.ratio",
+        "stall_dispatch_stall",
+    ),
+    (
+        "smsp__average_warps_issue_stalled_drain_per_issue_active.ratio",
+        "stall_drain",
+    ),
+    (
+        "smsp__average_warps_issue_stalled_imc_miss_per_issue_active.ratio",
+        "stall_imc_miss",
+    ),
+    (
+        "smsp__average_warps_issue_stalled_lg_throttle_per_issue_active.ratio",
+        "stall_lg_throttle",
+    ),
+    (
+        "smsp__average_warps_issue_stalled_long_scoreboard_per_issue_active.ratio",
+        "stall_long_scoreboard",
+    ),
+    (
+        "smsp__average_warps_issue_stalled_math_pipe_throttle_per_issue_active.ratio",
+        "stall_math_pipe_throttle",
+    ),
+    (
+        "smsp__average_warps_issue_stalled_membar_per_issue_active.ratio",
+        "stall_membar",
+    ),
+    (
+        "smsp__average_warps_issue_stalled_mio_throttle_per_issue_active.ratio",
+        "stall_mio_throttle",
+    ),
+    (
+        "smsp__average_warps_issue_stalled_misc_per_issue_active.ratio",
+        "stall_misc",
+    ),
+    (
+        "smsp__average_warps_issue_stalled_no_instruction_per_issue_active.ratio",
+        "stall_no_instruction",
+    ),
+    (
+        "smsp__average_warps_issue_stalled_not_selected_per_issue_active.ratio",
+        "stall_not_selected",
+    ),
+    (
+        "smsp__average_warps_issue_stalled_selected_per_issue_active.ratio",
+        "stall_selected",
+    ),
+    (
+        "smsp__average_warps_issue_stalled_short_scoreboard_per_issue_active.ratio",
+        "stall_short_scoreboard",
+    ),
+    (
+        "smsp__average_warps_issue_stalled_sleeping_per_issue_active.ratio",
+        "stall_sleeping",
+    ),
+    (
+        "smsp__average_warps_issue_stalled_tex_throttle_per_issue_active.ratio",
+        "stall_tex_throttle",
+    ),
+    (
+        "smsp__average_warps_issue_stalled_wait_per_issue_active.ratio",
+        "stall_wait",
+    ),
+];
+
+/// Builds the warp stall breakdown of a profiled kernel from whichever of
+/// its `STALL_METRICS` were collected: the cycles warps spent stalled for
+/// each reason per issued instruction, and `stall_top`, the reason they
+/// were stalled for the most. Being selected to issue is not a stall.
+pub fn build_stall_data(metrics: &[MetricValuePair]) -> Vec<(&'static str, String)> {
+    let stalls: Vec<(&'static str, f64)> = STALL_REASONS
+        .iter()
+        .filter_map(|&(metric_name, name)| {
+            let value = metric_value(metrics, metric_name)?;
+            Some((name, value))
+        })
+        .collect();
+    let top = stalls
+        .iter()
+        .filter(|&&(name, value)| name != "stall_selected" && value > 0.0)
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|&(name, _)| name.trim_start_matches("stall_").to_string());
+    let mut data: Vec<_> = stalls
+        .into_iter()
+        .map(|(name, value)| (name, format!("{:.2}", value)))
+        .collect();
+    if let Some(top) = top {
+        data.push(("stall_top", top));
+    }
+    data
+}
+
+/// Builds the roofline position of a profiled kernel from its
+/// `ROOFLINE_METRICS` values: its FLOPs and DRAM bytes, the arithmetic
+/// intensity and rate they give over `duration` nanoseconds, and which roof
+/// bounds it. The peaks are those of the precision with the most FLOPs.
+///
+/// Returns nothing unless the metrics were collected.
+pub fn build_roofline_data(
+    metrics: &[MetricValuePair],
+    duration: u64,
+    device: &DeviceProperties,
+) -> Vec<(&'static str, String)> {
+    let dram_bytes = metric_value(metrics, "dram__bytes.sum");
+    let (Some(flops), Some(dram_bytes)) = (FlopCounts::estimate(metrics, device), dram_bytes)
+    else {
+        return Vec::new();
+    };
+    let (fp16, fp32, fp64) = (flops.fp16, flops.fp32, flops.fp64);
+    let flop_count = flops.total();
+    let mut data = vec![
+        ("flop_count", format!("{:.0}", flop_count)),
+        ("dram_bytes", format!("{:.0}", dram_bytes)),
+    ];
+    if duration > 0 {
+        // FLOPs per nanosecond are GFLOP/s.
+        data.push((
+            "achieved_gflops",
+            format!("{:.3}", flop_count / duration as f64),
+        ));
+    }
+    if dram_bytes > 0.0 {
+        data.push((
+            "arithmetic_intensity",
+            format!("{:.3}", flop_count / dram_bytes),
+        ));
+    }
+    let peak_gflops = device.peak_gflops(fp64 > fp32 + fp16);
+    let peak_dram_gbps = device.peak_dram_gbps();
+    if peak_gflops > 0.0 && peak_dram_gbps > 0.0 {
+        // Below the ridge point, bandwidth caps the rate before compute does.
+        let ridge_point = peak_gflops / peak_dram_gbps;
+        let bound = if dram_bytes > 0.0 && flop_count / dram_bytes < ridge_point {
+            "memory"
+        } else {
+            "compute"
+        };
+        data.push(("roofline_bound", bound.to_string()));
+        data.push(("device_peak_gflops", format!("{:.1}", peak_gflops)));
+        data.push(("device_peak_dram_gbps", format!("{:.1}", peak_dram_gbps)));
+    }
+    data
+}
+
+/// Function attributes and occupancy queries for a launched kernel.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FunctionProperties {
+    pub cache_mode: i32,
+    pub registers_per_thread: i32,
+    pub max_active_blocks_per_sm: i32,
+    pub suggested_block_size: i32,
+}
+
+impl FunctionProperties {
+    /// Queries the attributes of `func` for the launch described by `activity`.
+    /// # Safety
+    ///
+    /// The `func` pointer must be a valid CUDA function handle.
+    pub unsafe fn query(func: CUfunction, activity: &KernelActivity) -> Self {
+        let block_size = activity.block_size.0 * activity.block_size.1 * activity.block_size.2;
+        let dynamic_smem = activity.dynamic_shared_memory as usize;
+        unsafe {
+            Self {
+                cache_mode: profiler::get_func_attribute(
+                    func,
+                    CUfunction_attribute_enum_CU_FUNC_ATTRIBUTE_CACHE_MODE_CA,
+                )
+                .unwrap_or(0),
+                registers_per_thread: profiler::get_func_attribute(
+                    func,
+                    CUfunction_attribute_enum_CU_FUNC_ATTRIBUTE_NUM_REGS,
+                )
+                .unwrap_or(0),
+                max_active_blocks_per_sm: profiler::occupancy_max_active_blocks_per_multiprocessor(
+                    func,
+                    block_size,
+                    dynamic_smem,
+                )
+                .unwrap_or(0),
+                suggested_block_size: profiler::occupancy_max_potential_block_size(
+                    func,
+                    dynamic_smem,
+                    0,
+                )
+                .map(|(_, block_size)| block_size)
+                .unwrap_or(0),
+            }
+        }
+    }
+}
+
+/// Returns the demangled kernel name, or the name itself if it is not mangled.
+pub fn demangle(name: &str) -> String {
+    Symbol::new(name)
+        .ok()
+        .and_then(|sym| sym.demangle().ok())
+        .unwrap_or_else(|| name.to_string())
+}
+
+/// Collectives of NCCL that reduce, for which the operation in a kernel name
+/// is not a placeholder.
+const NCCL_REDUCTIONS: [&str; 3] = ["AllReduce", "Reduce", "ReduceScatter"];
+
+/// Reduction operations of NCCL, as they appear in kernel names.
+const NCCL_REDUCTION_OPS: [&str; 5] = ["Sum", "Prod", "MinMax", "PreMulSum", "SumPostDiv"];
+
+/// Labels the kernel of an NCCL collective with its `collective_type` and,
+/// if it reduces, its `reduction_op` and `datatype`, as parsed from its
+/// demangled name. NCCL 2.18 and earlier name kernels
+/// `ncclKernel_<collective>_<algorithm>_<protocol>_<op>_<type>`, later
+/// versions `ncclDevKernel_<collective>_<op>_<type>_<algorithm>_<protocol>`.
+/// Other kernels get nothing.
+pub fn build_collective_data(demangled_name: &str) -> Vec<(&'static str, String)> {
+    // Template kernels are demangled with their return type.
+    let name = demangled_name.split('(').next().unwrap_or_default();
+    let name = name.rsplit(' ').next().unwrap_or_default();
+    let (collective, op, datatype) = if let Some(rest) = name.strip_prefix("ncclKernel_") {
+        // Types such as `int8_t` and `__nv_bfloat16` have underscores.
+        let mut parts = rest.splitn(5, '_');
+        let collective = parts.next();
+        (collective, parts.nth(2), parts.next())
+    } else if let Some(rest) = name.strip_prefix("ncclDevKernel_") {
+        let mut parts = rest.split('_');
+        let collective = parts.next();
+        let mut op = parts.next();
+        let datatype = if op.is_some_and(|op| NCCL_REDUCTION_OPS.contains(&op)) {
+            parts.next()
+        } else {
+            op = None;
+            None
+        };
+        (collective, op, datatype)
+    } else {
+        return Vec::new();
+    };
+    let Some(collective) = collective.filter(|c| !c.is_empty()) else {
+        return Vec::new();
+    };
+    let mut data = vec![("collective_type", collective.to_string())];
+    if NCCL_REDUCTIONS.contains(&collective) {
+        if let Some(op) = op.filter(|op| NCCL_REDUCTION_OPS.contains(op)) {
+            data.push(("reduction_op", op.to_string()));
+        }
+        if let Some(datatype) = datatype.filter(|t| !t.is_empty()) {
+            data.push(("datatype", datatype.to_string()));
+        }
+    }
+    data
+}
+
+#[allow(nonstandard_style)]
+fn cache_config_name(cache_mode: i32) -> &'static str {
+    match cache_mode as u32 {
+        CUfunc_cache_enum_CU_FUNC_CACHE_PREFER_NONE => "CachePreferNone",
+        CUfunc_cache_enum_CU_FUNC_CACHE_PREFER_SHARED => "CachePreferShared",
+        CUfunc_cache_enum_CU_FUNC_CACHE_PREFER_L1 => "CachePreferL1",
+        CUfunc_cache_enum_CU_FUNC_CACHE_PREFER_EQUAL => "CachePreferEqual",
+        _ => "n/a",
+    }
+}
+
+/// Builds the static launch metrics attached as extra data to a kernel's
+/// render stage event.
+pub fn build_extra_data(
+    process: &ProcessInfo,
+    activity: &KernelActivity,
+    device: &DeviceProperties,
+    device_name: &str,
+    function: &FunctionProperties,
+) -> Vec<(&'static str, String)> {
+    let grid_size = activity.grid_size.0 * activity.grid_size.1 * activity.grid_size.2;
+    let block_size = activity.block_size.0 * activity.block_size.1 * activity.block_size.2;
+    let thread_count = grid_size * block_size;
+    let max_active_blocks = function.max_active_blocks_per_sm;
+    let waves_per_multiprocessor = if device.num_sms > 0 && max_active_blocks > 0 {
+        grid_size as f64 / (device.num_sms * max_active_blocks) as f64
+    } else {
+        0.0
+    };
+    let smem_per_block = activity.dynamic_shared_memory + activity.static_shared_memory;
+    let warp_size = device.warp_size;
+    let warps_per_block = if warp_size > 0 {
+        block_size / warp_size
+    } else {
+        0
+    };
+    let max_active_warps = max_active_blocks * warps_per_block;
+    let regs_per_block = function.registers_per_thread * block_size;
+    let max_warps_sm = device.max_warps_per_sm();
+    let max_active_warps_pct = theoretical_occupancy(block_size, device, function);
+    let occupancy_limit_shared_mem = if smem_per_block != 0 {
+        device.shared_mem_per_sm / smem_per_block
+    } else {
+        16
+    };
+    let occupancy_limit_warps = if warps_per_block > 0 {
+        max_warps_sm / warps_per_block
+    } else {
+        0
+    };
+    let occupancy_limit_registers = if regs_per_block != 0 {
+        device.registers_per_sm / regs_per_block
+    } else {
+        16
+    };
+    // Resources the kernel does not use do not limit it.
+    let limits = [
+        ("regs", regs_per_block != 0, occupancy_limit_registers),
+        ("smem", smem_per_block != 0, occupancy_limit_shared_mem),
+        ("warps", warps_per_block > 0, occupancy_limit_warps),
+        (
+            "blocks",
+            device.max_blocks_per_sm > 0,
+            device.max_blocks_per_sm,
+        ),
+    ];
+    let binding = limits
+        .iter()
+        .filter(|&&(_, used, _)| used)
+        .map(|&(_, _, limit)| limit)
+        .min();
+    let occupancy_limiter = limits
+        .iter()
+        .filter(|&&(_, used, limit)| used && Some(limit) == binding)
+        .map(|&(name, _, _)| name)
+        .collect::<Vec<_>>()
+        .join(",");
+    let (major, minor) = device.compute_capability;
+    let demangled_name = demangle(&activity.kernel_name);
+    let collective_data = build_collective_data(&demangled_name);
+    let mut data = vec![
+        ("kernel_name", activity.kernel_name.clone()),
+        ("kernel_demangled_name", demangled_name),
+        ("kernel_type", "Compute".to_string()),
+        ("process_id", process.pid.to_string()),
+        ("process_name", process.name.clone()),
+        ("process_cmdline", process.cmdline.clone()),
+        ("arch", format!("CC_{}{}", major, minor)),
+        ("device_name", device_name.to_string()),
+        (
+            "launch__func_cache_config",
+            cache_config_name(function.cache_mode).to_string(),
+        ),
+        (
+            "launch__waves_per_multiprocessor",
+            waves_per_multiprocessor.to_string(),
+        ),
+        ("launch__grid_size", grid_size.to_string()),
+        ("launch__grid_size_x", activity.grid_size.0.to_string()),
+        ("launch__grid_size_y", activity.grid_size.1.to_string()),
+        ("launch__grid_size_z", activity.grid_size.2.to_string()),
+        ("launch__block_size", block_size.to_string()),
+        ("launch__block_size_x", activity.block_size.0.to_string()),
+        ("launch__block_size_y", activity.block_size.1.to_string()),
+        ("launch__block_size_z", activity.block_size.2.to_string()),
+        ("launch__thread_count", thread_count.to_string()),
+        (
+            "launch__registers_per_thread",
+            activity.registers_per_thread.to_string(),
+        ),
+        // TODO: Take shared mem config and carve-out into account.
+        ("launch__shared_mem_config_size", "49152".to_string()),
+        (
+            "launch__shared_mem_per_block_driver",
+            smem_per_block.to_string(),
+        ),
+        (
+            "launch__shared_mem_per_block_dynamic",
+            activity.dynamic_shared_memory.to_string(),
+        ),
+        (
+            "launch__shared_mem_per_block_static",
+            activity.static_shared_memory.to_string(),
+        ),
+        (
+            "launch__occupancy_limit_shared_mem",
+            occupancy_limit_shared_mem.to_string(),
+        ),
+        (
+            "launch__occupancy_limit_warps",
+            occupancy_limit_warps.to_string(),
+        ),
+        (
+            "launch__occupancy_limit_blocks",
+            device.max_blocks_per_sm.to_string(),
+        ),
+        (
+            "launch__occupancy_limit_registers",
+            occupancy_limit_registers.to_string(),
+        ),
+        (
+            "suggested_block_size",
+            function.suggested_block_size.to_string(),
+        ),
+        (
+            "sm__maximum_warps_avg_per_active_cycle",
+            max_active_warps.to_string(),
+        ),
+        (
+            "sm__maximum_warps_per_active_cycle_pct",
+            max_active_warps_pct.to_string(),
+        ),
+        (
+            "theoretical_occupancy",
+            format!("{:.1}", max_active_warps_pct),
+        ),
+    ];
+    if !occupancy_limiter.is_empty() {
+        data.push(("occupancy_limiter", occupancy_limiter));
+    }
+    // The clocks the device reports, which kernels run at unless throttled.
+    if device.clock_rate > 0 {
+        data.push((
+            "sm_clock_target_mhz",
+            (device.clock_rate / 1000).to_string(),
+        ));
+    }
+    if device.memory_clock_rate > 0 {
+        data.push((
+            "memory_clock_target_mhz",
+            (device.memory_clock_rate / 1000).to_string(),
+        ));
+    }
+    data.extend(collective_data);
+    data
+}
+
+/// Occupancy a launch with blocks of `block_size` threads allows, in percent:
+/// the warps of as many blocks as the driver says fit on an SM, over the
+/// warps an SM can hold.
+fn theoretical_occupancy(
+    block_size: i32,
+    device: &DeviceProperties,
+    function: &FunctionProperties,
+) -> f64 {
+    let max_warps_sm = device.max_warps_per_sm();
+    if max_warps_sm > 0 {
+        let warps_per_block = block_size / device.warp_size;
+        let max_active_warps = function.max_active_blocks_per_sm * warps_per_block;
+        100.0 * max_active_warps as f64 / max_warps_sm as f64
+    } else {
+        0.0
+    }
+}
+
+/// Builds the launch configuration recommendations of a kernel from the
+/// occupancy queries and, if it was profiled, its achieved occupancy:
+/// `suggested_grid_size_for_full_waves`, the grid rounded up to whole waves,
+/// and a `tuning_hint` naming the first thing worth changing, if any.
+///
+/// Returns nothing if the blocks an SM runs at once are unknown.
+pub fn build_tuning_data(
+    activity: &KernelActivity,
+    device: &DeviceProperties,
+    function: &FunctionProperties,
+    achieved_occupancy: Option<f64>,
+) -> Vec<(&'static str, String)> {
+    let grid_size = activity.grid_size.0 * activity.grid_size.1 * activity.grid_size.2;
+    let block_size = activity.block_size.0 * activity.block_size.1 * activity.block_size.2;
+    let blocks_per_wave = device.num_sms * function.max_active_blocks_per_sm;
+    if blocks_per_wave <= 0 || grid_size <= 0 {
+        return Vec::new();
+    }
+    let waves = (grid_size + blocks_per_wave - 1) / blocks_per_wave;
+    let full_waves_grid_size = waves * blocks_per_wave;
+    let last_wave = grid_size - (waves - 1) * blocks_per_wave;
+    let theoretical = theoretical_occupancy(block_size, device, function);
+    let suggested_block_size = function.suggested_block_size;
+    let pct = |n: i32| 100.0 * n as f64 / blocks_per_wave as f64;
+    let hint = if waves == 1 && grid_size < blocks_per_wave {
+        Some(format!(
+            "{} blocks fill {:.0}% of the GPU; launch at least {} to fill it",
+            grid_size,
+            pct(grid_size),
+            blocks_per_wave
+        ))
+    } else if device.warp_size > 0 && block_size % device.warp_size != 0 {
+        Some(format!(
+            "block size {} is not a multiple of the warp size {}",
+            block_size, device.warp_size
+        ))
+    } else if suggested_block_size > 0 && suggested_block_size != block_size && theoretical < 100.0
+    {
+        Some(format!(
+            "block size {} maximizes occupancy, which is {:.0}% at {}",
+            suggested_block_size, theoretical, block_size
+        ))
+    } else if achieved_occupancy.is_some_and(|achieved| achieved < theoretical / 2.0) {
+        Some(format!(
+            "achieved occupancy is under half of the theoretical {:.0}%; look for uneven \
+             blocks or warps that finish early",
+            theoretical
+        ))
+    } else if 2 * last_wave <= blocks_per_wave {
+        Some(format!(
+            "the last wave fills {:.0}% of the GPU; a grid of {} blocks runs whole waves",
+            pct(last_wave),
+            full_waves_grid_size
+        ))
+    } else {
+        None
+    };
+    let mut data = vec![(
+        "suggested_grid_size_for_full_waves",
+        full_waves_grid_size.to_string(),
+    )];
+    if let Some(hint) = hint {
+        data.push(("tuning_hint", hint));
+    }
+    data
+}
+
+/// Returns the entry for `key`, calling `build` if there is none or if it was
+/// built for another kernel.
+///
+/// Function handles are only unique while their module is loaded, so entries
+/// remember the name of the kernel they were built for.
+fn get_or_build_named<'a, K: Hash + Eq, V>(
+    entries: &'a mut HashMap<K, (String, V)>,
+    key: K,
+    kernel_name: &str,
+    build: impl FnOnce() -> V,
+) -> &'a V {
+    match entries.entry(key) {
+        Entry::Occupied(entry) => {
+            let (name, value) = entry.into_mut();
+            if name != kernel_name {
+                kernel_name.clone_into(name);
+                *value = build();
+            }
+            value
+        }
+        Entry::Vacant(entry) => &entry.insert((kernel_name.to_string(), build())).1,
+    }
+}
+
+/// Launch configuration that, together with the function, determines the
+/// extra data of a kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct LaunchKey {
+    function: usize,
+    grid_size: (i32, i32, i32),
+    block_size: (i32, i32, i32),
+    registers_per_thread: u16,
+    dynamic_shared_memory: i32,
+    static_shared_memory: i32,
+}
+
+/// Extra data of a kernel, as metric name and value pairs.
+type ExtraDataPairs = Vec<(&'static str, String)>;
+
+/// Extra data of the kernels of one device, built once per function and
+/// launch configuration.
+///
+/// Repeated launches of a kernel share all of their extra data, so caching it
+/// saves formatting the strings and querying the driver for every launch.
+#[derive(Default)]
+pub struct ExtraDataCache {
+    entries: HashMap<LaunchKey, (String, ExtraDataPairs)>,
+}
+
+impl ExtraDataCache {
+    /// Returns the extra data for a launch of `function`, calling `build` if
+    /// it has not been built yet.
+    pub fn get_or_build(
+        &mut self,
+        function: CUfunction,
+        activity: &KernelActivity,
+        build: impl FnOnce() -> ExtraDataPairs,
+    ) -> &[(&'static str, String)] {
+        let key = LaunchKey {
+            function: function as usize,
+            grid_size: activity.grid_size,
+            block_size: activity.block_size,
+            registers_per_thread: activity.registers_per_thread,
+            dynamic_shared_memory: activity.dynamic_shared_memory,
+            static_shared_memory: activity.static_shared_memory,
+        };
+        get_or_build_named(&mut self.entries, key, &activity.kernel_name, build).as_slice()
+    }
+}
+
+/// Launch parameters that the function attributes and occupancy queries of
+/// a kernel depend on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct FunctionKey {
+    function: usize,
+    block_size: i32,
+    dynamic_shared_memory: i32,
+}
+
+/// Function properties of the kernels of one context, queried once per
+/// function, block size and dynamic shared memory size.
+///
+/// Unlike extra data they do not depend on the grid, so launches of a kernel
+/// over differently sized grids share the driver queries.
+#[derive(Default)]
+pub struct FunctionPropertiesCache {
+    entries: HashMap<FunctionKey, (String, FunctionProperties)>,
+}
+
+impl FunctionPropertiesCache {
+    /// Returns the properties of `function` for the launch described by
+    /// `activity`, calling `query` if they have not been queried yet.
+    pub fn get_or_query(
+        &mut self,
+        function: CUfunction,
+        activity: &KernelActivity,
+        query: impl FnOnce() -> FunctionProperties,
+    ) -> FunctionProperties {
+        let key = FunctionKey {
+            function: function as usize,
+            block_size: activity.block_size.0 * activity.block_size.1 * activity.block_size.2,
+            dynamic_shared_memory: activity.dynamic_shared_memory,
+        };
+        *get_or_build_named(&mut self.entries, key, &activity.kernel_name, query)
+    }
+}
+
+/// Places a kernel launched at `launch_time` that ran for `duration` on its
+/// queue, behind the kernel that ended at `queue_end`, and returns its start.
+///
+/// Kernels are placed at their launch, but under load they wait for the ones
+/// launched before them, so a slice starting at its launch would overlap the
+/// previous one. `queue_end` moves to the end of the kernel.
+pub fn place_on_queue(queue_end: &mut u64, launch_time: u64, duration: u64) -> u64 {
+    let start = launch_time.max(*queue_end);
+    *queue_end = start.saturating_add(duration);
+    start
+}
+
+/// Emits a render stage event of the stage `stage_id`, e.g. a kernel, on the
+/// queue `hw_queue_id`.
+///
+/// Queue and stage specifications are included when `queues` are given, by
+/// queue ID, which should be the case whenever incremental state was cleared
+/// or a queue was added or changed.
+pub fn emit_render_stage_event(
+    ctx: &mut TraceContext,
+    stage_id: u32,
+    timestamp: u64,
+    duration: u64,
+    hw_queue_id: u32,
+    extra_data: &[(&str, String)],
+    queues: Option<&[Queue]>,
+) {
+    ctx.add_packet(|packet: &mut TracePacket| {
+        packet
+            .set_timestamp(timestamp)
+            .set_timestamp_clock_id(BuiltinClock::BuiltinClockBoottime.into())
+            .set_gpu_render_stage_event(|event: &mut GpuRenderStageEvent| {
+                event
+                    .set_event_id(get_next_event_id())
+                    .set_duration(duration)
+                    .set_hw_queue_id(hw_queue_id as i32)
+                    .set_stage_id(stage_id as i32);
+                for (name, value) in extra_data {
+                    event.set_extra_data(|extra_data: &mut ExtraData| {
+                        extra_data.set_name(*name);
+                        extra_data.set_value(value);
+                    });
+                }
+                if let Some(queues) = queues {
+                    event.set_specifications(|specs: &mut Specifications| {
+                        for queue in queues {
+                            specs.set_hw_queue(|desc: &mut Description| {
+                                desc.set_name(&queue.name);
+                                if !queue.description.is_empty() {
+                                    desc.set_description(&queue.description);
+                                }
+                            });
+                        }
+                        for name in STAGES {
+                            specs.set_stage(|desc: &mut Description| {
+                                desc.set_name(name);
+                            });
+                        }
+                    });
+                }
+            });
+    });
+}
+
+/// Counter IDs of `metrics`, in their order. A metric that is listed again
+/// keeps the ID of its first occurrence and is not given a counter of its
+/// own, so that no counter is counted twice.
+pub fn counter_ids(metrics: &[MetricValuePair]) -> impl Iterator<Item = (u32, &MetricValuePair)> {
+    metrics
+        .iter()
+        .enumerate()
+        .filter(|(i, metric)| {
+            !metrics[..*i]
+                .iter()
+                .any(|m| m.metric_name == metric.metric_name)
+        })
+        .map(|(i, metric)| (i as u32, metric))
+}
+
+/// Names of the counters that can be derived from `metrics`, in the order
+/// of their counter IDs: the achieved occupancy, `dram_bw_pct_of_peak` and
+/// the FLOPs if the metrics they need are among `metrics`, then each of the
+/// `expressions`. The same metrics always give the same counters, whether
+/// or not a kernel has a value for each.
+pub fn derived_counters(
+    metrics: &[MetricValuePair],
+    expressions: &[Expression],
+) -> Vec<&'static str> {
+    let has = |name: &str| metrics.iter().any(|m| m.metric_name == name);
+    let mut counters = Vec::new();
+    if has(ACTIVE_WARPS_METRIC) {
+        counters.push(ACHIEVED_OCCUPANCY);
+    }
+    if has("dram__bytes.sum") || (has("dram__bytes_read.sum") && has("dram__bytes_write.sum")) {
+        counters.push(DRAM_BW_PCT_OF_PEAK);
+    }
+    let instructions = FLOPS_METRICS.iter().filter(|m| **m != TENSOR_INST_METRIC);
+    if instructions.into_iter().all(|m| has(m)) {
+        counters.extend(FLOPS_COUNTERS);
+        if has(TENSOR_INST_METRIC) {
+            counters.push(TENSOR_FLOPS_COUNTER);
+        }
+    }
+    counters.extend(expressions.iter().map(|expression| expression.name));
+    counters
+}
+
+/// Counter IDs of the `derived` values, which follow those of the metrics in
+/// the order of `derived_counters`. Values not among `derived_counters` are
+/// left out.
+fn derived_counter_ids<'a>(
+    metrics: &[MetricValuePair],
+    derived: &'a [(&'static str, f64)],
+    derived_counters: &'a [&'static str],
+) -> impl Iterator<Item = (u32, f64)> + 'a {
+    let base = metrics.len() as u32;
+    derived.iter().filter_map(move |&(name, value)| {
+        let i = derived_counters
+            .iter()
+            .position(|counter| *counter == name)?;
+        Some((base + i as u32, value))
+    })
+}
+
+/// Whether the counter `id` is among the `requested` ones, all of them if
+/// none are.
+fn is_requested(requested: &[u32], id: u32) -> bool {
+    requested.is_empty() || requested.contains(&id)
+}
+
+/// Alias of the counter `name` in `aliases`, if it has one.
+pub fn counter_alias<'a>(aliases: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    aliases
+        .iter()
+        .find(|(counter, _)| counter == name)
+        .map(|(_, alias)| alias.as_str())
+}
+
+/// Names the counter of `spec` after its alias in `aliases`, keeping `name`
+/// as its description, or after `name` if it has no alias.
+fn set_counter_name(spec: &mut GpuCounterSpec, name: &str, aliases: &[(String, String)]) {
+    match counter_alias(aliases, name) {
+        Some(alias) => {
+            spec.set_name(alias);
+            spec.set_description(name);
+        }
+        None => {
+            spec.set_name(name);
+        }
+    }
+}
+
+/// Emits the descriptor that names the counters, with counter IDs in the
+/// order of `metrics` and then of the `derived_counters`, leaving out those
+/// not `requested`. Counters with one of `aliases` are shown by it.
+pub fn emit_counter_descriptor(
+    ctx: &mut TraceContext,
+    timestamp: u64,
+    metrics: &[MetricValuePair],
+    derived_counters: &[&'static str],
+    requested: &[u32],
+    aliases: &[(String, String)],
+) {
+    let base = metrics.len() as u32;
+    ctx.add_packet(|packet: &mut TracePacket| {
+        packet
+            .set_timestamp(timestamp)
+            .set_timestamp_clock_id(BuiltinClock::BuiltinClockBoottime.into())
+            .set_gpu_counter_event(|event: &mut GpuCounterEvent| {
+                event.set_counter_descriptor(|desc: &mut GpuCounterDescriptor| {
+                    for (id, metric) in counter_ids(metrics) {
+                        if !is_requested(requested, id) {
+                            continue;
+                        }
+                        desc.set_specs(|desc: &mut GpuCounterSpec| {
+                            desc.set_counter_id(id);
+                            set_counter_name(desc, &metric.metric_name, aliases);
+                            desc.set_groups(GpuCounterDescriptorGpuCounterGroup::Compute);
+                        });
+                    }
+                    for (i, &name) in derived_counters.iter().enumerate() {
+                        let id = base + i as u32;
+                        if !is_requested(requested, id) {
+                            continue;
+                        }
+                        desc.set_specs(|desc: &mut GpuCounterSpec| {
+                            desc.set_counter_id(id);
+                            set_counter_name(desc, name, aliases);
+                            desc.set_groups(GpuCounterDescriptorGpuCounterGroup::Compute);
+                        });
+                    }
+                });
+            });
+    });
+}
+
+/// Emits the counters of a kernel that are `requested`: zero at `timestamp`
+/// and the metric and `derived` values once the kernel has run for
+/// `duration`, the latter under the IDs of their `derived_counters`.
+pub fn emit_counters(
+    ctx: &mut TraceContext,
+    timestamp: u64,
+    duration: u64,
+    metrics: &[MetricValuePair],
+    derived: &[(&'static str, f64)],
+    derived_counters: &[&'static str],
+    requested: &[u32],
+) {
+    let derived_values = derived_counter_ids(metrics, derived, derived_counters);
+    let values: Vec<(u32, f64)> = counter_ids(metrics)
+        .map(|(id, metric)| (id, metric.value))
+        .chain(derived_values)
+        .filter(|&(id, _)| is_requested(requested, id))
+        .collect();
+    if values.is_empty() {
+        return;
+    }
+    ctx.add_packet(|packet: &mut TracePacket| {
+        packet
+            .set_timestamp(timestamp)
+            .set_timestamp_clock_id(BuiltinClock::BuiltinClockBoottime.into())
+            .set_gpu_counter_event(|event: &mut GpuCounterEvent| {
+                for &(id, _) in &values {
+                    event.set_counters(|counter: &mut GpuCounter| {
+                        counter.set_counter_id(id).set_int_value(0);
+                    });
+                }
+            });
+    });
+    ctx.add_packet(|packet: &mut TracePacket| {
+        packet
+            .set_timestamp(timestamp + duration)
+            .set_timestamp_clock_id(BuiltinClock::BuiltinClockBoottime.into())
+            .set_gpu_counter_event(|event: &mut GpuCounterEvent| {
+                for &(id, value) in &values {
+                    event.set_counters(|counter: &mut GpuCounter| {
+                        counter.set_counter_id(id).set_double_value(value);
+                    });
+                }
+            });
+    });
+}
+
+/// Emits the descriptor that names the overhead counters, with counter IDs
+/// from `STATS_COUNTER_ID_BASE` on.
+pub fn emit_stats_descriptor(ctx: &mut TraceContext, timestamp: u64) {
+    ctx.add_packet(|packet: &mut TracePacket| {
+        packet
+            .set_timestamp(timestamp)
+            .set_timestamp_clock_id(BuiltinClock::BuiltinClockBoottime.into())
+            .set_gpu_counter_event(|event: &mut GpuCounterEvent| {
+                event.set_counter_descriptor(|desc: &mut GpuCounterDescriptor| {
+                    for (i, stat) in Stat::ALL.iter().enumerate() {
+                        desc.set_specs(|desc: &mut GpuCounterSpec| {
+                            desc.set_counter_id(STATS_COUNTER_ID_BASE + i as u32);
+                            desc.set_name(stat.name());
+                            desc.set_numerator_units(GpuCounterDescriptorMeasureUnit::Nanosecond);
+                            desc.set_groups(GpuCounterDescriptorGpuCounterGroup::System);
+                        });
+                    }
+                });
+            });
+    });
+}
+
+/// Emits a sample of the overhead counters.
+pub fn emit_stats(ctx: &mut TraceContext, sample: &StatsSample) {
+    ctx.add_packet(|packet: &mut TracePacket| {
+        packet
+            .set_timestamp(sample.timestamp)
+            .set_timestamp_clock_id(BuiltinClock::BuiltinClockBoottime.into())
+            .set_gpu_counter_event(|event: &mut GpuCounterEvent| {
+                for (i, &value) in sample.values.iter().enumerate() {
+                    event.set_counters(|counter: &mut GpuCounter| {
+                        counter
+                            .set_counter_id(STATS_COUNTER_ID_BASE + i as u32)
+                            .set_int_value(value as i64);
+                    });
+                }
+            });
+    });
+}
+
+/// Emits the descriptor of the memory pool counters.
+pub fn emit_memory_pool_descriptor(ctx: &mut TraceContext, timestamp: u64) {
+    ctx.add_packet(|packet: &mut TracePacket| {
+        packet
+            .set_timestamp(timestamp)
+            .set_timestamp_clock_id(BuiltinClock::BuiltinClockBoottime.into())
+            .set_gpu_counter_event(|event: &mut GpuCounterEvent| {
+                event.set_counter_descriptor(|desc: &mut GpuCounterDescriptor| {
+                    for (id, name) in MEMORY_POOL_COUNTERS {
+                        desc.set_specs(|desc: &mut GpuCounterSpec| {
+                            desc.set_counter_id(id);
+                            desc.set_name(name);
+                            desc.set_numerator_units(GpuCounterDescriptorMeasureUnit::Byte);
+                            desc.set_groups(GpuCounterDescriptorGpuCounterGroup::Memory);
+                        });
+                    }
+                });
+            });
+    });
+}
+
+/// Emits the memory pool counters of a device.
+pub fn emit_memory_pool_sample(ctx: &mut TraceContext, sample: &PoolSample) {
+    ctx.add_packet(|packet: &mut TracePacket| {
+        packet
+            .set_timestamp(sample.timestamp)
+            .set_timestamp_clock_id(BuiltinClock::BuiltinClockBoottime.into())
+            .set_gpu_counter_event(|event: &mut GpuCounterEvent| {
+                event.set_gpu_id(sample.device_id as i32);
+                for ((id, _), value) in MEMORY_POOL_COUNTERS
+                    .iter()
+                    .zip([sample.reserved, sample.used])
+                {
+                    event.set_counters(|counter: &mut GpuCounter| {
+                        counter.set_counter_id(*id).set_int_value(value as i64);
+                    });
+                }
+            });
+    });
+}
+
+/// Emits a warning in the GPU log of the trace.
+pub fn emit_warning(ctx: &mut TraceContext, timestamp: u64, message: &str) {
+    emit_log(ctx, timestamp, GpuLogSeverity::LogSeverityWarning, message);
+}
+
+/// Emits an informational GPU log message, such as a kernel's histograms.
+pub fn emit_info(ctx: &mut TraceContext, timestamp: u64, message: &str) {
+    emit_log(ctx, timestamp, GpuLogSeverity::LogSeverityInfo, message);
+}
+
+fn emit_log(ctx: &mut TraceContext, timestamp: u64, severity: GpuLogSeverity, message: &str) {
+    ctx.add_packet(|packet: &mut TracePacket| {
+        packet
+            .set_timestamp(timestamp)
+            .set_timestamp_clock_id(BuiltinClock::BuiltinClockBoottime.into())
+            .set_gpu_log(|log: &mut GpuLog| {
+                log.set_severity(severity)
+                    .set_tag("perfetto-cupti-gpu-compute")
+                    .set_log_message(message);
+            });
+    });
+}
+
+/// Emits a warning that `dropped_kernels` kernels of a context were evicted
+/// before they could be written to the trace.
+pub fn emit_data_loss(ctx: &mut TraceContext, timestamp: u64, context: &str, dropped_kernels: u64) {
+    emit_warning(
+        ctx,
+        timestamp,
+        &format!(
+            "Dropped {} kernels of {} after reaching INJECTION_MAX_KERNELS",
+            dropped_kernels, context
+        ),
+    );
+}
+
+/// Emits a warning that the range profiler of a context dropped `ranges`
+/// ranges, leaving the kernels from `timestamp` on without metrics, and the
+/// dropped ranges counter at `total`. The counter is named by a descriptor
+/// if `descriptor` is set.
+pub fn emit_range_loss(
+    ctx: &mut TraceContext,
+    timestamp: u64,
+    context: &str,
+    ranges: usize,
+    total: u64,
+    descriptor: bool,
+) {
+    emit_warning(
+        ctx,
+        timestamp,
+        &format!(
+            "Dropped {} ranges of {} that did not fit in the counter data image; \
+             kernels from here on lack metrics",
+            ranges, context
+        ),
+    );
+    ctx.add_packet(|packet: &mut TracePacket| {
+        packet
+            .set_timestamp(timestamp)
+            .set_timestamp_clock_id(BuiltinClock::BuiltinClockBoottime.into())
+            .set_gpu_counter_event(|event: &mut GpuCounterEvent| {
+                if descriptor {
+                    event.set_counter_descriptor(|desc: &mut GpuCounterDescriptor| {
+                        desc.set_specs(|desc: &mut GpuCounterSpec| {
+                            desc.set_counter_id(DROPPED_RANGES_COUNTER_ID);
+                            desc.set_name(DROPPED_RANGES_COUNTER);
+                            desc.set_groups(GpuCounterDescriptorGpuCounterGroup::System);
+                        });
+                    });
+                }
+                event.set_counters(|counter: &mut GpuCounter| {
+                    counter
+                        .set_counter_id(DROPPED_RANGES_COUNTER_ID)
+                        .set_int_value(total as i64);
+                });
+            });
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extra<'a>(extra_data: &'a [(&str, String)], name: &str) -> &'a str {
+        extra_data
+            .iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value.as_str())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_join_command_line() {
+        let args = |args: &[&str]| args.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            join_command_line(args(&["python", "train.py", "--lr=0.1"])),
+            "python train.py --lr=0.1"
+        );
+        assert_eq!(
+            join_command_line(args(&["run", "two words", "", "it's"])),
+            r"run 'two words' '' 'it'\''s'"
+        );
+        assert_eq!(join_command_line(Vec::new()), "");
+        let process = ProcessInfo::current();
+        assert!(!process.name.is_empty());
+        assert!(!process.cmdline.is_empty());
+    }
+
+    #[test]
+    fn test_place_on_queue() {
+        let mut queue_end = 0;
+        assert_eq!(place_on_queue(&mut queue_end, 100, 50), 100);
+        assert_eq!(queue_end, 150);
+        // Launched while the previous kernel still ran.
+        assert_eq!(place_on_queue(&mut queue_end, 120, 30), 150);
+        assert_eq!(queue_end, 180);
+        // Launched after the queue went idle.
+        assert_eq!(place_on_queue(&mut queue_end, 200, 10), 200);
+        assert_eq!(queue_end, 210);
+        // Launched before an earlier launch, from another thread.
+        assert_eq!(place_on_queue(&mut queue_end, 190, 0), 210);
+        assert_eq!(queue_end, 210);
+    }
+
+    #[test]
+    fn test_derived_counter_ids() {
+        let metrics: Vec<MetricValuePair> = [ACTIVE_WARPS_METRIC, "dram__bytes.sum"]
+            .iter()
+            .chain(crate::metrics::FLOPS_METRICS)
+            .map(|name| MetricValuePair {
+                metric_name: name.to_string(),
+                value: 1.0,
+            })
+            .collect();
+        let counters = derived_counters(&metrics, &[]);
+        assert_eq!(
+            counters,
+            [
+                ACHIEVED_OCCUPANCY,
+                DRAM_BW_PCT_OF_PEAK,
+                "fp16_flops",
+                "fp32_flops",
+                "fp64_flops",
+                "tensor_flops"
+            ]
+        );
+        let base = metrics.len() as u32;
+        let all = [
+            (ACHIEVED_OCCUPANCY, 50.0),
+            (DRAM_BW_PCT_OF_PEAK, 10.0),
+            ("fp32_flops", 8.0),
+        ];
+        let ids: Vec<(u32, f64)> = derived_counter_ids(&metrics, &all, &counters).collect();
+        assert_eq!(ids, [(base, 50.0), (base + 1, 10.0), (base + 3, 8.0)]);
+        // A kernel without a DRAM bandwidth, e.g. of zero duration, keeps
+        // the IDs of the FLOPs.
+        let ids: Vec<(u32, f64)> =
+            derived_counter_ids(&metrics, &[all[0], all[2]], &counters).collect();
+        assert_eq!(ids, [(base, 50.0), (base + 3, 8.0)]);
+        // Nothing is derived from metrics that are not there.
+        assert!(derived_counters(&metrics[2..3], &[]).is_empty());
+    }
+
+    #[test]
+    fn test_expression_counter_ids() {
+        let expressions = crate::expressions::parse_expressions("a = x / y; b = x * 2");
+        let kernel = |y: f64| {
+            [("x", 4.0), ("y", y)]
+                .iter()
+                .map(|&(name, value)| MetricValuePair {
+                    metric_name: name.to_string(),
+                    value,
+                })
+                .collect::<Vec<_>>()
+        };
+        let (first, second) = (kernel(2.0), kernel(0.0));
+        let counters = derived_counters(&first, &expressions);
+        assert_eq!(counters, derived_counters(&second, &expressions));
+        let ids = |metrics: &[MetricValuePair]| {
+            let values = crate::expressions::evaluate(&expressions, metrics);
+            derived_counter_ids(metrics, &values, &counters).collect::<Vec<_>>()
+        };
+        assert_eq!(ids(&first), [(2, 2.0), (3, 8.0)]);
+        // `a` divides by zero on the second kernel, which leaves out its
+        // value but keeps the ID of `b`.
+        assert_eq!(ids(&second), [(3, 8.0)]);
+    }
+
+    #[test]
+    fn test_counter_ids() {
+        let metric = |name: &str, value: f64| MetricValuePair {
+            metric_name: name.to_string(),
+            value,
+        };
+        let metrics = [
+            metric("gpu__time_duration.sum", 1.0),
+            metric("sm__cycles_elapsed.avg", 2.0),
+            metric("gpu__time_duration.sum", 1.0),
+            metric("dram__bytes.sum", 3.0),
+        ];
+        let ids: Vec<(u32, &str)> = counter_ids(&metrics)
+            .map(|(id, metric)| (id, metric.metric_name.as_str()))
+            .collect();
+        assert_eq!(
+            ids,
+            [
+                (0, "gpu__time_duration.sum"),
+                (1, "sm__cycles_elapsed.avg"),
+                (3, "dram__bytes.sum"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_demangle() {
+        assert_eq!(
+            demangle("_Z9vectorAddPKfS0_Pfi"),
+            "vectorAdd(float const*, float const*, float*, int)"
+        );
+        assert_eq!(demangle("reduce"), "reduce");
+    }
+
+    #[test]
+    fn test_build_collective_data() {
+        assert_eq!(
+            build_collective_data(
+                "ncclKernel_AllReduce_RING_LL_Sum_float(ncclDevComm*, unsigned long, ncclWork*)"
+            ),
+            [
+                ("collective_type", "AllReduce".to_string()),
+                ("reduction_op", "Sum".to_string()),
+                ("datatype", "float".to_string()),
+            ]
+        );
+        assert_eq!(
+            build_collective_data("ncclKernel_ReduceScatter_RING_SIMPLE_MinMax_uint8_t"),
+            [
+                ("collective_type", "ReduceScatter".to_string()),
+                ("reduction_op", "MinMax".to_string()),
+                ("datatype", "uint8_t".to_string()),
+            ]
+        );
+        assert_eq!(
+            build_collective_data(
+                "void ncclDevKernel_AllReduce_Sum_bf16_RING_LL(ncclDevKernelArgsStorage<4096ul>)"
+            ),
+            [
+                ("collective_type", "AllReduce".to_string()),
+                ("reduction_op", "Sum".to_string()),
+                ("datatype", "bf16".to_string()),
+            ]
+        );
+        // Collectives that do not reduce have placeholder operations.
+        assert_eq!(
+            build_collective_data("ncclKernel_AllGather_RING_LL_Sum_int8_t"),
+            [("collective_type", "AllGather".to_string())]
+        );
+        assert_eq!(
+            build_collective_data("ncclDevKernel_SendRecv(ncclDevKernelArgsStorage<4096ul>)"),
+            [("collective_type", "SendRecv".to_string())]
+        );
+        assert!(build_collective_data("vectorAdd(float const*, float*, int)").is_empty());
+        assert!(build_collective_data("ncclKernel_").is_empty());
+    }
+
+    #[test]
+    fn test_build_extra_data() {
+        let process = ProcessInfo {
+            pid: 42,
+            name: "app".to_string(),
+            cmdline: "app --batch 32".to_string(),
+        };
+        let activity = KernelActivity {
+            kernel_name: "kernel".to_string(),
+            grid_size: (40, 2, 1),
+            block_size: (256, 1, 1),
+            registers_per_thread: 32,
+            dynamic_shared_memory: 4096,
+            static_shared_memory: 4096,
+            duration: 0,
+            stream_id: 0,
+            external_ids: Vec::new(),
+            correlation_id: 0,
+        };
+        let device = DeviceProperties {
+            num_sms: 10,
+            warp_size: 32,
+            max_threads_per_sm: 2048,
+            max_blocks_per_sm: 32,
+            registers_per_sm: 65536,
+            shared_mem_per_sm: 65536,
+            compute_capability: (9, 0),
+            clock_rate: 1_980_000,
+            memory_clock_rate: 2_619_000,
+            ..Default::default()
+        };
+        let function = FunctionProperties {
+            cache_mode: CUfunc_cache_enum_CU_FUNC_CACHE_PREFER_SHARED as i32,
+            registers_per_thread: 32,
+            max_active_blocks_per_sm: 4,
+            suggested_block_size: 512,
+        };
+        let extra_data = build_extra_data(&process, &activity, &device, "NVIDIA H100", &function);
+        assert_eq!(extra(&extra_data, "process_id"), "42");
+        assert_eq!(extra(&extra_data, "process_cmdline"), "app --batch 32");
+        assert_eq!(extra(&extra_data, "arch"), "CC_90");
+        assert_eq!(extra(&extra_data, "device_name"), "NVIDIA H100");
+        assert_eq!(extra(&extra_data, "sm_clock_target_mhz"), "1980");
+        assert_eq!(extra(&extra_data, "memory_clock_target_mhz"), "2619");
+        assert_eq!(
+            extra(&extra_data, "launch__func_cache_config"),
+            "CachePreferShared"
+        );
+        assert_eq!(extra(&extra_data, "launch__grid_size"), "80");
+        assert_eq!(extra(&extra_data, "launch__thread_count"), "20480");
+        // 80 blocks over 10 SMs running 4 blocks each.
+        assert_eq!(extra(&extra_data, "launch__waves_per_multiprocessor"), "2");
+        assert_eq!(
+            extra(&extra_data, "launch__shared_mem_per_block_driver"),
+            "8192"
+        );
+        assert_eq!(
+            extra(&extra_data, "launch__occupancy_limit_shared_mem"),
+            "8"
+        );
+        assert_eq!(extra(&extra_data, "launch__occupancy_limit_warps"), "8");
+        assert_eq!(extra(&extra_data, "launch__occupancy_limit_registers"), "8");
+        assert_eq!(extra(&extra_data, "launch__occupancy_limit_blocks"), "32");
+        assert_eq!(extra(&extra_data, "suggested_block_size"), "512");
+        assert_eq!(
+            extra(&extra_data, "sm__maximum_warps_avg_per_active_cycle"),
+            "32"
+        );
+        assert_eq!(
+            extra(&extra_data, "sm__maximum_warps_per_active_cycle_pct"),
+            "50"
+        );
+        assert_eq!(extra(&extra_data, "theoretical_occupancy"), "50.0");
+        // Registers, shared memory and warps all allow 8 blocks.
+        assert_eq!(extra(&extra_data, "occupancy_limiter"), "regs,smem,warps");
+        let activity = KernelActivity {
+            dynamic_shared_memory: 12288,
+            ..activity
+        };
+        let extra_data = build_extra_data(&process, &activity, &device, "NVIDIA H100", &function);
+        assert_eq!(extra(&extra_data, "occupancy_limiter"), "smem");
+    }
+
+    #[test]
+    fn test_build_tuning_data() {
+        let device = DeviceProperties {
+            num_sms: 10,
+            warp_size: 32,
+            max_threads_per_sm: 2048,
+            ..Default::default()
+        };
+        let function = FunctionProperties {
+            max_active_blocks_per_sm: 4,
+            suggested_block_size: 256,
+            ..Default::default()
+        };
+        let activity = |grid_size: i32, block_size: i32| KernelActivity {
+            kernel_name: "kernel".to_string(),
+            grid_size: (grid_size, 1, 1),
+            block_size: (block_size, 1, 1),
+            registers_per_thread: 0,
+            dynamic_shared_memory: 0,
+            static_shared_memory: 0,
+            duration: 0,
+            stream_id: 0,
+            external_ids: Vec::new(),
+            correlation_id: 0,
+        };
+        let hint = |grid_size, block_size, function: &FunctionProperties, achieved| {
+            let data = build_tuning_data(
+                &activity(grid_size, block_size),
+                &device,
+                function,
+                achieved,
+            );
+            data.iter()
+                .find(|(key, _)| *key == "tuning_hint")
+                .map(|(_, hint)| hint.clone())
+        };
+        // 10 SMs running 4 blocks each take 40 blocks a wave.
+        let data = build_tuning_data(&activity(100, 256), &device, &function, None);
+        assert_eq!(extra(&data, "suggested_grid_size_for_full_waves"), "120");
+        assert_eq!(
+            extra(&data, "tuning_hint"),
+            "the last wave fills 50% of the GPU; a grid of 120 blocks runs whole waves"
+        );
+        assert_eq!(
+            hint(20, 256, &function, None).unwrap(),
+            "20 blocks fill 50% of the GPU; launch at least 40 to fill it"
+        );
+        assert_eq!(
+            hint(80, 250, &function, None).unwrap(),
+            "block size 250 is not a multiple of the warp size 32"
+        );
+        let suggesting = FunctionProperties {
+            suggested_block_size: 512,
+            ..function
+        };
+        assert_eq!(
+            hint(80, 256, &suggesting, None).unwrap(),
+            "block size 512 maximizes occupancy, which is 50% at 256"
+        );
+        assert!(hint(80, 256, &function, Some(20.0))
+            .unwrap()
+            .starts_with("achieved occupancy is under half of the theoretical 50%"));
+        // Whole waves at the best block size leave nothing to suggest.
+        assert_eq!(hint(80, 256, &function, Some(40.0)), None);
+        assert!(build_tuning_data(
+            &activity(80, 256),
+            &DeviceProperties::default(),
+            &function,
+            None
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn test_extra_data_cache() {
+        let mut cache = ExtraDataCache::default();
+        let mut builds = 0;
+        let mut build = |activity: &KernelActivity| {
+            builds += 1;
+            vec![("kernel_name", activity.kernel_name.clone())]
+        };
+        let activity = |name: &str, grid_x| KernelActivity {
+            kernel_name: name.to_string(),
+            grid_size: (grid_x, 1, 1),
+            block_size: (32, 1, 1),
+            registers_per_thread: 0,
+            dynamic_shared_memory: 0,
+            static_shared_memory: 0,
+            duration: 0,
+            stream_id: 0,
+            external_ids: Vec::new(),
+            correlation_id: 0,
+        };
+        let function = 0x10 as CUfunction;
+        let first = activity("a", 1);
+        for _ in 0..3 {
+            cache.get_or_build(function, &first, || build(&first));
+        }
+        // Another grid size or a kernel reusing the handle needs new data.
+        let resized = activity("a", 2);
+        cache.get_or_build(function, &resized, || build(&resized));
+        let renamed = activity("b", 1);
+        let extra_data = cache.get_or_build(function, &renamed, || build(&renamed));
+        assert_eq!(extra(extra_data, "kernel_name"), "b");
+        assert_eq!(builds, 3);
+    }
+
+    #[test]
+    fn test_function_properties_cache() {
+        let mut cache = FunctionPropertiesCache::default();
+        let mut queries = 0;
+        let activity = |name: &str, grid_x, block_x| KernelActivity {
+            kernel_name: name.to_string(),
+            grid_size: (grid_x, 1, 1),
+            block_size: (block_x, 1, 1),
+            registers_per_thread: 0,
+            dynamic_shared_memory: 0,
+            static_shared_memory: 0,
+            duration: 0,
+            stream_id: 0,
+            external_ids: Vec::new(),
+            correlation_id: 0,
+        };
+        let function = 0x20 as CUfunction;
+        let mut query = |activity: &KernelActivity| {
+            cache.get_or_query(function, activity, || {
+                queries += 1;
+                FunctionProperties {
+                    max_active_blocks_per_sm: activity.block_size.0,
+                    ..Default::default()
+                }
+            })
+        };
+        // The grid size does not matter.
+        query(&activity("a", 1, 64));
+        let properties = query(&activity("a", 8, 64));
+        assert_eq!(properties.max_active_blocks_per_sm, 64);
+        // The block size and the kernel reusing the handle do.
+        let properties = query(&activity("a", 1, 128));
+        assert_eq!(properties.max_active_blocks_per_sm, 128);
+        query(&activity("b", 1, 128));
+        assert_eq!(queries, 3);
+    }
+
+    #[test]
+    fn test_build_extra_data_without_device() {
+        let process = ProcessInfo {
+            pid: 1,
+            name: String::new(),
+            cmdline: String::new(),
+        };
+        let activity = KernelActivity {
+            kernel_name: "kernel".to_string(),
+            grid_size: (1, 1, 1),
+            block_size: (32, 1, 1),
+            registers_per_thread: 0,
+            dynamic_shared_memory: 0,
+            static_shared_memory: 0,
+            duration: 0,
+            stream_id: 0,
+            external_ids: Vec::new(),
+            correlation_id: 0,
+        };
+        let extra_data = build_extra_data(
+            &process,
+            &activity,
+            &DeviceProperties::default(),
+            "",
+            &FunctionProperties::default(),
+        );
+        assert_eq!(
+            extra(&extra_data, "launch__func_cache_config"),
+            "CachePreferNone"
+        );
+        assert_eq!(extra(&extra_data, "launch__waves_per_multiprocessor"), "0");
+        assert_eq!(
+            extra(&extra_data, "launch__occupancy_limit_shared_mem"),
+            "16"
+        );
+        assert_eq!(extra(&extra_data, "launch__occupancy_limit_warps"), "0");
+        assert_eq!(
+            extra(&extra_data, "sm__maximum_warps_per_active_cycle_pct"),
+            "0"
+        );
+        assert!(!extra_data
+            .iter()
+            .any(|(key, _)| *key == "sm_clock_target_mhz"));
+    }
+
+    #[test]
+    fn test_flop_counts() {
+        let device = DeviceProperties {
+            num_sms: 1,
+            compute_capability: (8, 0),
+            clock_rate: 1_000_000,
+            ..Default::default()
+        };
+        let metrics: Vec<MetricValuePair> = crate::metrics::FLOPS_METRICS
+            .iter()
+            .map(|name| MetricValuePair {
+                metric_name: name.to_string(),
+                value: match *name {
+                    "smsp__sass_thread_inst_executed_op_fadd_pred_on.sum" => 1000.0,
+                    "smsp__sass_thread_inst_executed_op_fmul_pred_on.sum" => 1000.0,
+                    "smsp__sass_thread_inst_executed_op_ffma_pred_on.sum" => 4000.0,
+                    "smsp__sass_thread_inst_executed_op_hfma_pred_on.sum" => 50.0,
+                    TENSOR_INST_METRIC => 2.0,
+                    _ => 0.0,
+                },
+            })
+            .collect();
+        let flops = FlopCounts::estimate(&metrics, &device).unwrap();
+        assert_eq!(
+            flops,
+            FlopCounts {
+                fp16: 100.0,
+                fp32: 10000.0,
+                fp64: 0.0,
+                tensor: Some(8192.0),
+            }
+        );
+        assert_eq!(flops.total(), 10100.0);
+        assert_eq!(flops.counters().len(), 4);
+        let data = build_flops_data(&flops, 1000, &device);
+        assert_eq!(extra(&data, "fp32_flops"), "10000");
+        assert_eq!(extra(&data, "tensor_flops"), "8192");
+        // 10 GFLOP/s of the 128 of one SM at 1 GHz.
+        assert_eq!(extra(&data, "fp32_pct_of_peak"), "7.8");
+        assert_eq!(extra(&data, "fp64_pct_of_peak"), "0.0");
+        // Without the peaks there is nothing to compare with, and without the
+        // FLOPs per instruction no tensor FLOPs.
+        let unknown = DeviceProperties::default();
+        let flops = FlopCounts::estimate(&metrics, &unknown).unwrap();
+        assert_eq!(flops.tensor, None);
+        assert_eq!(flops.counters().len(), 3);
+        assert_eq!(build_flops_data(&flops, 1000, &unknown).len(), 3);
+        assert!(FlopCounts::estimate(&metrics[1..], &device).is_none());
+    }
+
+    #[test]
+    fn test_build_dram_data() {
+        let metrics = crate::metrics::DRAM_METRICS
+            .iter()
+            .zip([96000.0, 32000.0])
+            .map(|(name, value)| MetricValuePair {
+                metric_name: name.to_string(),
+                value,
+            })
+            .collect::<Vec<_>>();
+        let data = build_dram_data(&metrics, 1000);
+        assert_eq!(extra(&data, "dram_bytes_read"), "96000");
+        assert_eq!(extra(&data, "dram_bytes_write"), "32000");
+        assert_eq!(extra(&data, "dram_bandwidth_gbps"), "128.000");
+        assert_eq!(build_dram_data(&metrics, 0).len(), 2);
+        assert!(build_dram_data(&metrics[..1], 1000).is_empty());
+    }
+
+    #[test]
+    fn test_dram_bw_pct_of_peak() {
+        let device = DeviceProperties {
+            memory_clock_rate: 1_000_000,
+            memory_bus_width: 512,
+            ..Default::default()
+        };
+        assert_eq!(device.peak_dram_gbps(), 128.0);
+        let metric = |name: &str, value| MetricValuePair {
+            metric_name: name.to_string(),
+            value,
+        };
+        // 64 of the 128 GB/s a 512-bit bus moves at 1 GHz on both edges.
+        let total = [metric("dram__bytes.sum", 64000.0)];
+        assert_eq!(dram_bw_pct_of_peak(&total, 1000, &device), Some(50.0));
+        let split = [
+            metric("dram__bytes_read.sum", 96000.0),
+            metric("dram__bytes_write.sum", 32000.0),
+        ];
+        assert_eq!(dram_bw_pct_of_peak(&split, 1000, &device), Some(100.0));
+        assert_eq!(dram_bw_pct_of_peak(&split[..1], 1000, &device), None);
+        assert_eq!(dram_bw_pct_of_peak(&total, 0, &device), None);
+        let unknown = DeviceProperties::default();
+        assert_eq!(dram_bw_pct_of_peak(&total, 1000, &unknown), None);
+    }
+
+    #[test]
+    fn test_build_smem_data() {
+        let metrics = |values: [f64; 4]| {
+            crate::metrics::SMEM_METRICS
+                .iter()
+                .zip(values)
+                .map(|(name, value)| MetricValuePair {
+                    metric_name: name.to_string(),
+                    value,
+                })
+                .collect::<Vec<_>>()
+        };
+        let data = build_smem_data(&metrics([300.0, 100.0, 1200.0, 400.0]));
+        assert_eq!(extra(&data, "smem_bank_conflicts"), "400");
+        assert_eq!(extra(&data, "smem_bank_conflict_ratio"), "0.250");
+        // A kernel without shared memory accesses has no ratio.
+        let data = build_smem_data(&metrics([0.0; 4]));
+        assert_eq!(data.len(), 1);
+        assert!(build_smem_data(&metrics([0.0; 4])[1..]).is_empty());
+    }
+
+    #[test]
+    fn test_build_cache_data() {
+        let metrics = |values: [f64; 4]| {
+            crate::metrics::CACHE_METRICS
+                .iter()
+                .zip(values)
+                .map(|(name, value)| MetricValuePair {
+                    metric_name: name.to_string(),
+                    value,
+                })
+                .collect::<Vec<_>>()
+        };
+        let data = build_cache_data(&metrics([300.0, 100.0, 50.0, 150.0]));
+        assert_eq!(extra(&data, "l1_hit_rate"), "75.0");
+        assert_eq!(extra(&data, "l2_hit_rate"), "25.0");
+        // A kernel that looked nothing up in L1 only has the L2 hit rate.
+        let data = build_cache_data(&metrics([0.0, 0.0, 50.0, 150.0]));
+        assert_eq!(data, [("l2_hit_rate", "25.0".to_string())]);
+        assert!(build_cache_data(&metrics([300.0, 100.0, 50.0, 150.0])[1..3]).is_empty());
+    }
+
+    #[test]
+    fn test_build_stall_data() {
+        let stall_metrics: Vec<&str> = STALL_REASONS.iter().map(|&(metric, _)| metric).collect();
+        assert_eq!(stall_metrics, crate::metrics::STALL_METRICS);
+        let metrics: Vec<MetricValuePair> = crate::metrics::STALL_METRICS
+            .iter()
+            .map(|name| MetricValuePair {
+                metric_name: name.to_string(),
+                value: if name.contains("_long_scoreboard_") {
+                    12.5
+                } else if name.contains("_stalled_selected_") {
+                    20.0
+                } else if name.contains("_barrier_") {
+                    3.0
+                } else {
+                    0.0
+                },
+            })
+            .collect();
+        let data = build_stall_data(&metrics);
+        assert_eq!(data.len(), STALL_REASONS.len() + 1);
+        assert_eq!(extra(&data, "stall_long_scoreboard"), "12.50");
+        assert_eq!(extra(&data, "stall_barrier"), "3.00");
+        assert_eq!(extra(&data, "stall_top"), "long_scoreboard");
+        // Each reason is reported on its own, and there is no top reason
+        // without stalls.
+        let data = build_stall_data(&metrics[..2]);
+        assert_eq!(data.len(), 3);
+        assert_eq!(extra(&data, "stall_top"), "barrier");
+        let data = build_stall_data(&metrics[1..2]);
+        assert_eq!(data, vec![("stall_branch_resolving", "0.00".to_string())]);
+        assert!(build_stall_data(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_build_roofline_data() {
+        let device = DeviceProperties {
+            num_sms: 108,
+            compute_capability: (8, 0),
+            clock_rate: 1_410_000,
+            memory_clock_rate: 1_215_000,
+            memory_bus_width: 5120,
+            ..Default::default()
+        };
+        let metrics = |dram_bytes: f64| -> Vec<MetricValuePair> {
+            crate::metrics::ROOFLINE_METRICS
+                .iter()
+                .map(|name| MetricValuePair {
+                    metric_name: name.to_string(),
+                    value: match *name {
+                        "smsp__sass_thread_inst_executed_op_fadd_pred_on.sum" => 1000.0,
+                        "smsp__sass_thread_inst_executed_op_fmul_pred_on.sum" => 1000.0,
+                        "smsp__sass_thread_inst_executed_op_ffma_pred_on.sum" => 4000.0,
+                        "dram__bytes.sum" => dram_bytes,
+                        _ => 0.0,
+                    },
+                })
+                .collect()
+        };
+        let data = build_roofline_data(&metrics(1000.0), 1000, &device);
+        assert_eq!(extra(&data, "flop_count"), "10000");
+        assert_eq!(extra(&data, "dram_bytes"), "1000");
+        assert_eq!(extra(&data, "achieved_gflops"), "10.000");
+        assert_eq!(extra(&data, "arithmetic_intensity"), "10.000");
+        assert_eq!(extra(&data, "device_peak_gflops"), "19491.8");
+        assert_eq!(extra(&data, "device_peak_dram_gbps"), "1555.2");
+        // The ridge point is at 12.5 FLOPs per byte.
+        assert_eq!(extra(&data, "roofline_bound"), "memory");
+        let data = build_roofline_data(&metrics(100.0), 1000, &device);
+        assert_eq!(extra(&data, "roofline_bound"), "compute");
+
+        // Without the metrics or the peaks, nothing is derived.
+        assert!(build_roofline_data(&metrics(100.0)[1..], 1000, &device).is_empty());
+        let data = build_roofline_data(&metrics(100.0), 1000, &DeviceProperties::default());
+        assert_eq!(data.len(), 4);
+        assert!(!data.iter().any(|(key, _)| *key == "roofline_bound"));
+    }
+
+    #[test]
+    fn test_build_ipc_data() {
+        let metric = |name: &str, value| MetricValuePair {
+            metric_name: name.to_string(),
+            value,
+        };
+        let metrics = [
+            metric("sm__inst_executed.avg.per_cycle_elapsed", 1.125),
+            metric("sm__inst_executed.avg.per_cycle_active", 2.5),
+        ];
+        let data = build_ipc_data(&metrics);
+        assert_eq!(extra(&data, "ipc_active"), "2.50");
+        assert_eq!(extra(&data, "ipc_elapsed"), "1.12");
+        // Each is derived on its own, and only from finite values.
+        let data = build_ipc_data(&metrics[..1]);
+        assert_eq!(data.len(), 1);
+        assert_eq!(extra(&data, "ipc_elapsed"), "1.12");
+        let nan = [metric("sm__inst_executed.avg.per_cycle_active", f64::NAN)];
+        assert!(build_ipc_data(&nan).is_empty());
+    }
+
+    #[test]
+    fn test_achieved_occupancy() {
+        let metrics = |active_warps: f64| {
+            vec![MetricValuePair {
+                metric_name: ACTIVE_WARPS_METRIC.to_string(),
+                value: active_warps,
+            }]
+        };
+        let device = DeviceProperties {
+            warp_size: 32,
+            max_threads_per_sm: 2048,
+            ..DeviceProperties::default()
+        };
+        assert_eq!(achieved_occupancy(&metrics(48.0), &device), Some(75.0));
+        assert_eq!(achieved_occupancy(&metrics(0.0), &device), Some(0.0));
+        // Without the metric or the capacity, nothing is derived.
+        assert_eq!(achieved_occupancy(&[], &device), None);
+        let unknown = DeviceProperties::default();
+        assert_eq!(achieved_occupancy(&metrics(48.0), &unknown), None);
+    }
+
+    #[test]
+    fn test_build_sol_data() {
+        let metrics = |sm: f64, mem: f64| {
+            vec![
+                MetricValuePair {
+                    metric_name: "sm__throughput.avg.pct_of_peak_sustained_elapsed".to_string(),
+                    value: sm,
+                },
+                MetricValuePair {
+                    metric_name: "gpu__compute_memory_throughput.avg.pct_of_peak_sustained_elapsed"
+                        .to_string(),
+                    value: mem,
+                },
+            ]
+        };
+        let data = build_sol_data(&metrics(85.25, 30.0));
+        assert_eq!(extra(&data, "sol_sm_pct"), "85.2");
+        assert_eq!(extra(&data, "sol_mem_pct"), "30.0");
+        assert_eq!(extra(&data, "bottleneck"), "compute");
+        let bottleneck = |sm, mem| build_sol_data(&metrics(sm, mem))[2].1.clone();
+        assert_eq!(bottleneck(20.0, 90.0), "memory");
+        assert_eq!(bottleneck(75.0, 82.0), "balanced");
+        assert_eq!(bottleneck(40.0, 59.0), "latency");
+        assert!(build_sol_data(&metrics(50.0, 50.0)[..1]).is_empty());
+        assert!(build_sol_data(&metrics(f64::NAN, 50.0)).is_empty());
+    }
+
+    #[test]
+    fn test_build_external_id_data() {
+        let data = build_external_id_data(&[
+            (
+                CUpti_ExternalCorrelationKind_CUPTI_EXTERNAL_CORRELATION_KIND_CUSTOM0,
+                7,
+            ),
+            (
+                CUpti_ExternalCorrelationKind_CUPTI_EXTERNAL_CORRELATION_KIND_CUSTOM1,
+                9,
+            ),
+            (
+                CUpti_ExternalCorrelationKind_CUPTI_EXTERNAL_CORRELATION_KIND_UNKNOWN,
+                1,
+            ),
+        ]);
+        assert_eq!(
+            data,
+            [
+                ("external_id", "7".to_string()),
+                ("external_id_custom1", "9".to_string()),
+                ("external_id_unknown", "1".to_string()),
+            ]
+        );
+    }
+}
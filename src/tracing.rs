@@ -13,18 +13,26 @@
 // limitations under the License.
 
 use libc::{clock_gettime, timespec};
-use perfetto_sdk::data_source::{
-    DataSource, DataSourceArgsBuilder, DataSourceBufferExhaustedPolicy,
+use perfetto_sdk::{
+    data_source::{
+        self, Clear, DataSource, DataSourceArgsBuilder, DataSourceBufferExhaustedPolicy,
+        OnSetupArgs,
+    },
+    pb_decoder::{PbDecoder, PbDecoderField},
+    tracing_session::{TracingSession, TracingSessionError},
+    track_event::TrackEvent,
 };
 use std::{
-    env,
+    env, fs, io,
+    path::{Path, PathBuf},
     sync::{
-        atomic::{AtomicU64, AtomicU8, Ordering},
-        OnceLock,
+        atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering},
+        Arc, Mutex, Once, OnceLock,
     },
+    time::Duration,
 };
 
-#[cfg(target_os = "linux")]
+#[cfg(any(target_os = "linux", target_os = "android"))]
 use libc::CLOCK_BOOTTIME as TRACE_TIME_CLOCK;
 #[cfg(target_os = "macos")]
 use libc::CLOCK_MONOTONIC as TRACE_TIME_CLOCK;
@@ -37,43 +45,397 @@ pub fn get_next_event_id() -> u64 {
     NEXT_EVENT_ID.fetch_add(1, Ordering::SeqCst)
 }
 
-/// Tracks whether the first counters have been received for a given data source instance.
-pub static GOT_FIRST_COUNTERS: AtomicU8 = AtomicU8::new(0);
+/// What a tracing session was sent on the writer of one thread.
+///
+/// Perfetto creates this incremental state afresh for every session that
+/// starts a data source, also when it reuses the instance of an ended one,
+/// so every session gets the descriptors and specifications it needs no
+/// matter how its start interleaves with emission on other threads. The
+/// render stage data source uses `sent_queues`, the counter data source the
+/// rest.
+#[derive(Default)]
+pub struct SessionState {
+    /// Generation of the stream queues the queue and stage specifications
+    /// were last sent for, `None` until they have been sent.
+    pub sent_queues: Option<u64>,
+    /// Generation of the metrics the counter descriptor was last sent for,
+    /// `None` until it has been sent.
+    pub sent_counter_descriptor: Option<u64>,
+    /// Whether the overhead counter descriptor has been sent.
+    pub sent_stats_descriptor: bool,
+    /// Whether the dropped ranges counter descriptor has been sent.
+    pub sent_data_loss_descriptor: bool,
+    /// Whether the memory pool counter descriptor has been sent.
+    pub sent_memory_pool_descriptor: bool,
+    /// Kernel counter IDs the session asked for, all if empty, looked up
+    /// with `requested_counter_ids` at its first kernel.
+    pub counter_ids: Option<Vec<u32>>,
+}
+
+impl Clear for SessionState {}
+
+/// Generation of the collected metrics, bumped when the control socket
+/// changes them so that every session is sent the counter descriptor again.
+static METRICS_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Records that the collected metrics changed.
+pub fn metrics_changed() {
+    METRICS_GENERATION.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Whether the counter descriptor has to be sent, because `sent` is not the
+/// generation of the metrics, which it is afterwards.
+pub fn counter_descriptor_needed(sent: &mut Option<u64>) -> bool {
+    let generation = METRICS_GENERATION.load(Ordering::SeqCst);
+    sent.replace(generation) != Some(generation)
+}
+
+/// Context of a data source instance, with its `SessionState`.
+pub type TraceContext<'a> = data_source::TraceContext<'a, SessionState>;
+
+/// Instances of a data source that tracing sessions started.
+struct Instances {
+    /// Bitmask of the instances that are currently started.
+    active: AtomicU8,
+    /// Number of times a tracing session started the data source.
+    started: AtomicU32,
+}
 
-static GPU_COUNTERS_DATA_SOURCE: OnceLock<DataSource> = OnceLock::new();
+impl Instances {
+    const fn new() -> Self {
+        Self {
+            active: AtomicU8::new(0),
+            started: AtomicU32::new(0),
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.active.load(Ordering::SeqCst) != 0
+    }
+}
+
+/// Most instances of a data source, as many as `Instances::active` has bits.
+const MAX_INSTANCES: usize = 8;
+
+/// Kernel counter IDs each instance of the counter data source asked for, by
+/// instance index.
+static REQUESTED_COUNTER_IDS: Mutex<[Vec<u32>; MAX_INSTANCES]> =
+    Mutex::new([const { Vec::new() }; MAX_INSTANCES]);
+
+/// Returns the kernel counter IDs that the session of the counter data
+/// source instance `inst_id` listed in the `counter_ids` of its
+/// `GpuCounterConfig`, or none if it did not list any and gets them all.
+///
+/// Every session gets counters of the same metrics, those of
+/// `INJECTION_METRICS`, which thus have to be the union of what concurrent
+/// sessions want, and each is then only sent those it asked for.
+pub fn requested_counter_ids(inst_id: u32) -> Vec<u32> {
+    REQUESTED_COUNTER_IDS
+        .lock()
+        .ok()
+        .and_then(|ids| ids.get(inst_id as usize).cloned())
+        .unwrap_or_default()
+}
+
+/// Reads the `GpuCounterConfig.counter_ids` of a serialized
+/// DataSourceConfig, packed or not.
+fn parse_counter_ids(config: &[u8]) -> Vec<u32> {
+    const GPU_COUNTER_CONFIG: u32 = 108;
+    const COUNTER_IDS: u32 = 2;
+    let mut ids = Vec::new();
+    for field in PbDecoder::new(config) {
+        let Ok((GPU_COUNTER_CONFIG, PbDecoderField::Delimited(gpu_config))) = field else {
+            continue;
+        };
+        for field in PbDecoder::new(gpu_config) {
+            match field {
+                Ok((COUNTER_IDS, PbDecoderField::Varint(id))) => ids.push(id as u32),
+                Ok((COUNTER_IDS, PbDecoderField::Delimited(packed))) => {
+                    let mut rest = packed;
+                    while let Some((id, len)) = read_varint(rest) {
+                        ids.push(id as u32);
+                        rest = &rest[len..];
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    ids
+}
+
+/// Reads a varint from the start of `buf`, returning it and its length.
+fn read_varint(buf: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0;
+    for (i, &byte) in buf.iter().enumerate().take(10) {
+        value |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+static COUNTER_INSTANCES: Instances = Instances::new();
+static RENDER_STAGE_INSTANCES: Instances = Instances::new();
+
+/// Returns how many tracing sessions started the counter data source so far,
+/// the in-process one for `INJECTION_TRACE_FILE` included.
+pub fn started_sessions() -> u32 {
+    COUNTER_INSTANCES.started.load(Ordering::SeqCst)
+}
+
+/// Returns true if at least one tracing session has either data source
+/// started, so that there is someone to write kernels to.
+pub fn is_tracing() -> bool {
+    COUNTER_INSTANCES.is_active() || RENDER_STAGE_INSTANCES.is_active()
+}
+
+/// Returns true if at least one tracing session has the counter data source
+/// started.
+///
+/// Range profiling replays every kernel, so it is only worth its overhead while
+/// someone is recording the results.
+pub fn is_tracing_counters() -> bool {
+    COUNTER_INSTANCES.is_active()
+}
+
+static GPU_COUNTERS_DATA_SOURCE: OnceLock<DataSource<'static, SessionState>> = OnceLock::new();
 static DATA_SOURCE_NAME: OnceLock<String> = OnceLock::new();
 const DEFAULT_DATA_SOURCE_NAME: &str = "gpu.counters";
 
+static GPU_RENDER_STAGES_DATA_SOURCE: OnceLock<DataSource<'static, SessionState>> = OnceLock::new();
+static RENDER_STAGES_DATA_SOURCE_NAME: OnceLock<String> = OnceLock::new();
+const DEFAULT_RENDER_STAGES_DATA_SOURCE_NAME: &str = "gpu.renderstages";
+
 /// Returns the data source name, reading from `INJECTION_DATA_SOURCE_NAME` env var or using default.
-fn get_data_source_name() -> &'static str {
+pub fn get_data_source_name() -> &'static str {
     DATA_SOURCE_NAME.get_or_init(|| {
         env::var("INJECTION_DATA_SOURCE_NAME")
             .unwrap_or_else(|_| DEFAULT_DATA_SOURCE_NAME.to_string())
     })
 }
 
-/// Initializes and retrieves the static Perfetto data source.
+/// Returns the render stage data source name, reading from
+/// `INJECTION_RENDER_STAGES_DATA_SOURCE_NAME` or using the default.
+pub fn get_render_stages_data_source_name() -> &'static str {
+    RENDER_STAGES_DATA_SOURCE_NAME.get_or_init(|| {
+        env::var("INJECTION_RENDER_STAGES_DATA_SOURCE_NAME")
+            .unwrap_or_else(|_| DEFAULT_RENDER_STAGES_DATA_SOURCE_NAME.to_string())
+    })
+}
+
+/// Registers the data source `name`, keeping track of its `instances` and
+/// passing the config of each to `on_setup`.
+fn register<F>(
+    name: &str,
+    instances: &'static Instances,
+    on_setup: F,
+) -> DataSource<'static, SessionState>
+where
+    F: FnMut(u32, &[u8], &mut OnSetupArgs) + Send + Sync + 'static,
+{
+    let data_source_args = DataSourceArgsBuilder::new()
+        .buffer_exhausted_policy(DataSourceBufferExhaustedPolicy::StallAndAbort)
+        .on_setup(on_setup)
+        .on_start(move |inst_id, _| {
+            instances.active.fetch_or(1 << inst_id, Ordering::SeqCst);
+            instances.started.fetch_add(1, Ordering::SeqCst);
+        })
+        .on_stop(move |inst_id, _| {
+            instances
+                .active
+                .fetch_and(!(1 << inst_id), Ordering::SeqCst);
+        });
+    let mut data_source = DataSource::new_with_incremental_state_type();
+    data_source
+        .register(name, data_source_args.build())
+        .expect("failed to register data source");
+    data_source
+}
+
+/// Initializes and retrieves the static Perfetto data source of counters.
 ///
 /// This function is thread-safe and ensures the data source is registered only once.
 /// The data source name can be overridden via the `INJECTION_DATA_SOURCE_NAME` environment variable.
-pub fn get_data_source() -> &'static DataSource<'static> {
+/// It carries the metric, overhead and memory pool counters and the log messages.
+pub fn get_data_source() -> &'static DataSource<'static, SessionState> {
     GPU_COUNTERS_DATA_SOURCE.get_or_init(|| {
-        let data_source_args = DataSourceArgsBuilder::new()
-            .buffer_exhausted_policy(DataSourceBufferExhaustedPolicy::StallAndAbort)
-            .on_start(move |inst_id, _| {
-                GOT_FIRST_COUNTERS.fetch_and(!(1 << inst_id), Ordering::SeqCst);
-            });
-        let mut data_source = DataSource::new();
-        data_source
-            .register(get_data_source_name(), data_source_args.build())
-            .expect("failed to register data source");
-        data_source
+        register(
+            get_data_source_name(),
+            &COUNTER_INSTANCES,
+            |inst_id, config, _| {
+                if let Ok(mut ids) = REQUESTED_COUNTER_IDS.lock() {
+                    if let Some(ids) = ids.get_mut(inst_id as usize) {
+                        *ids = parse_counter_ids(config);
+                    }
+                }
+            },
+        )
     })
 }
 
+/// Initializes and retrieves the static Perfetto data source of render
+/// stages, which carries the slices of kernels and copies on their queues.
+///
+/// Sessions that enable only this one trace kernels without the overhead of
+/// profiling them.
+pub fn get_render_stages_data_source() -> &'static DataSource<'static, SessionState> {
+    GPU_RENDER_STAGES_DATA_SOURCE.get_or_init(|| {
+        register(
+            get_render_stages_data_source_name(),
+            &RENDER_STAGE_INSTANCES,
+            |_, _, _| {},
+        )
+    })
+}
+
+static TRACK_EVENT_INIT: Once = Once::new();
+
+/// Initializes the track event data source, once for everything that emits
+/// track events.
+pub fn init_track_events() {
+    TRACK_EVENT_INIT.call_once(TrackEvent::init);
+}
+
+/// Size of the trace buffer of the in-process tracing session, in kilobytes.
+const TRACE_FILE_BUFFER_KB: u64 = 256 * 1024;
+
+/// Longest time the data sources get to commit its data before the in-process
+/// tracing session stops.
+const TRACE_FILE_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An in-process tracing session that is written to a file when it ends.
+struct FileSession {
+    session: TracingSession,
+    path: PathBuf,
+}
+
+// SAFETY: The tracing session is only used with `FILE_SESSION` locked, and
+// Perfetto's session API may be called from any thread.
+unsafe impl Send for FileSession {}
+
+static FILE_SESSION: Mutex<Option<FileSession>> = Mutex::new(None);
+
+pub(crate) fn append_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+pub(crate) fn append_delimited(buf: &mut Vec<u8>, field_id: u32, data: &[u8]) {
+    append_varint(buf, ((field_id as u64) << 3) | 2);
+    append_varint(buf, data.len() as u64);
+    buf.extend_from_slice(data);
+}
+
+/// Serializes a TraceConfig with a single buffer of `buffer_kb` kilobytes that
+/// enables the counter and render stage data sources.
+pub fn trace_config(buffer_kb: u64) -> Vec<u8> {
+    // BufferConfig { size_kb }
+    let mut buffers = Vec::new();
+    append_varint(&mut buffers, 1 << 3);
+    append_varint(&mut buffers, buffer_kb);
+    let mut config = Vec::new();
+    append_delimited(&mut config, 1, &buffers);
+    append_delimited(&mut config, 2, &data_source_config(get_data_source_name()));
+    append_delimited(
+        &mut config,
+        2,
+        &data_source_config(get_render_stages_data_source_name()),
+    );
+    config
+}
+
+/// `BufferConfig.FillPolicy` that drops what is written to a full buffer.
+const FILL_POLICY_DISCARD: u64 = 2;
+
+/// Serializes the TraceConfig of `trace_config` with track events enabled
+/// too, which carry the slices of library calls and host spans.
+///
+/// Host-side slices can come at a far higher rate than kernels, so they go
+/// to a second buffer of a quarter of `buffer_kb` that drops them once full,
+/// and can never overwrite kernels and counters in the first.
+pub fn file_trace_config(buffer_kb: u64) -> Vec<u8> {
+    let mut config = trace_config(buffer_kb);
+    // BufferConfig { size_kb, fill_policy: DISCARD }
+    let mut buffer = Vec::new();
+    append_varint(&mut buffer, 1 << 3);
+    append_varint(&mut buffer, buffer_kb / 4);
+    append_varint(&mut buffer, 4 << 3);
+    append_varint(&mut buffer, FILL_POLICY_DISCARD);
+    append_delimited(&mut config, 1, &buffer);
+    // DataSource { config: DataSourceConfig { name, target_buffer: 1,
+    // track_event_config { enabled_categories: "*" } } }
+    let mut track_event_config = Vec::new();
+    append_delimited(&mut track_event_config, 2, b"*");
+    let mut ds_config = Vec::new();
+    append_delimited(&mut ds_config, 1, b"track_event");
+    append_varint(&mut ds_config, 2 << 3);
+    append_varint(&mut ds_config, 1);
+    append_delimited(&mut ds_config, 113, &track_event_config);
+    let mut data_source = Vec::new();
+    append_delimited(&mut data_source, 1, &ds_config);
+    append_delimited(&mut config, 2, &data_source);
+    config
+}
+
+/// Serializes the TraceConfig.DataSource that enables the data source `name`.
+fn data_source_config(name: &str) -> Vec<u8> {
+    // DataSource { config: DataSourceConfig { name } }
+    let mut ds_config = Vec::new();
+    append_delimited(&mut ds_config, 1, name.as_bytes());
+    let mut data_source = Vec::new();
+    append_delimited(&mut data_source, 1, &ds_config);
+    data_source
+}
+
+/// Starts recording the data sources and track events in-process, for `finish_file_session` to
+/// write to `path`.
+///
+/// The producer must have been initialized with the in-process backend.
+pub fn start_file_session(path: &Path) -> Result<(), TracingSessionError> {
+    let mut session = TracingSession::in_process()?;
+    session.setup(&file_trace_config(TRACE_FILE_BUFFER_KB));
+    session.start_blocking();
+    if let Ok(mut file_session) = FILE_SESSION.lock() {
+        *file_session = Some(FileSession {
+            session,
+            path: path.to_path_buf(),
+        });
+    }
+    Ok(())
+}
+
+/// Stops the session started by `start_file_session` and writes its trace.
+///
+/// Returns the path written to, or `None` if no session was started.
+pub fn finish_file_session() -> io::Result<Option<PathBuf>> {
+    let Some(FileSession { mut session, path }) =
+        FILE_SESSION.lock().ok().and_then(|mut s| s.take())
+    else {
+        return Ok(None);
+    };
+    session.flush_blocking(TRACE_FILE_FLUSH_TIMEOUT);
+    session.stop_blocking();
+    let trace = Arc::new(Mutex::new(Vec::new()));
+    let trace_for_cb = Arc::clone(&trace);
+    session.read_trace_blocking(move |data, _end| {
+        if let Ok(mut trace) = trace_for_cb.lock() {
+            trace.extend_from_slice(data);
+        }
+    });
+    let trace = trace.lock().map(|trace| trace.clone()).unwrap_or_default();
+    fs::write(&path, trace)?;
+    Ok(Some(path))
+}
+
 /// Returns the current timestamp in nanoseconds from the trace clock.
 ///
-/// Uses `CLOCK_BOOTTIME` on Linux and `CLOCK_MONOTONIC` on macOS.
+/// Uses `CLOCK_BOOTTIME` on Linux and Android, like system Perfetto, and
+/// `CLOCK_MONOTONIC` on macOS.
 pub fn trace_time_ns() -> u64 {
     let mut ts = timespec {
         tv_sec: 0,
@@ -86,6 +448,22 @@ pub fn trace_time_ns() -> u64 {
     (ts.tv_sec as u64) * 1_000_000_000u64 + (ts.tv_nsec as u64)
 }
 
+/// Trace clock minus the clock of CUPTI's activity records, taken the first
+/// time a timestamp is converted.
+static CUPTI_CLOCK_OFFSET: OnceLock<i64> = OnceLock::new();
+
+/// Timestamp on the trace clock of the CUPTI activity record `timestamp`.
+///
+/// Host side records and the GPU timestamps of discrete GPUs are on CUPTI's
+/// clock, while those of Tegra GPUs are not and come out shifted.
+pub fn cupti_trace_time(timestamp: u64) -> u64 {
+    let offset = *CUPTI_CLOCK_OFFSET.get_or_init(|| {
+        let cupti = cupti_profiler::get_timestamp().unwrap_or(0);
+        trace_time_ns() as i64 - cupti as i64
+    });
+    timestamp.saturating_add_signed(offset)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -97,4 +475,65 @@ mod tests {
         assert_eq!(id2, id1 + 1);
         assert!(id1 > 0);
     }
+
+    #[test]
+    fn test_trace_config() {
+        let config = trace_config(1024);
+        // TraceConfig.buffers { size_kb: 1024 }
+        assert_eq!(&config[..5], &[0x0a, 0x03, 0x08, 0x80, 0x08]);
+        // TraceConfig.data_sources { config { name } }
+        let name = get_data_source_name().as_bytes();
+        assert_eq!(config[5], 0x12);
+        assert_eq!(config[6] as usize, name.len() + 4);
+        assert_eq!(
+            &config[7..11],
+            &[0x0a, name.len() as u8 + 2, 0x0a, name.len() as u8]
+        );
+        assert_eq!(&config[11..11 + name.len()], name);
+        let config = &config[11 + name.len()..];
+        let name = get_render_stages_data_source_name().as_bytes();
+        let len = name.len() as u8;
+        assert_eq!(&config[..6], &[0x12, len + 4, 0x0a, len + 2, 0x0a, len]);
+        assert_eq!(&config[6..], name);
+    }
+
+    #[test]
+    fn test_parse_counter_ids() {
+        // DataSourceConfig { name: "gpu.counters", gpu_counter_config {
+        // counter_ids: 0, counter_ids: 3 } }
+        let mut gpu_config = Vec::new();
+        append_varint(&mut gpu_config, 2 << 3);
+        append_varint(&mut gpu_config, 0);
+        append_varint(&mut gpu_config, 2 << 3);
+        append_varint(&mut gpu_config, 3);
+        let mut config = Vec::new();
+        append_delimited(&mut config, 1, b"gpu.counters");
+        append_delimited(&mut config, 108, &gpu_config);
+        assert_eq!(parse_counter_ids(&config), [0, 3]);
+        // Packed, as proto3 encoders write them.
+        let mut packed = Vec::new();
+        append_varint(&mut packed, 1);
+        append_varint(&mut packed, 300);
+        let mut gpu_config = Vec::new();
+        append_delimited(&mut gpu_config, 2, &packed);
+        let mut config = Vec::new();
+        append_delimited(&mut config, 108, &gpu_config);
+        assert_eq!(parse_counter_ids(&config), [1, 300]);
+        assert!(parse_counter_ids(b"").is_empty());
+    }
+
+    #[test]
+    fn test_file_trace_config() {
+        let config = file_trace_config(1024);
+        let track_events = &config[trace_config(1024).len()..];
+        // TraceConfig.buffers { size_kb: 256, fill_policy: DISCARD }
+        assert_eq!(
+            &track_events[..7],
+            &[0x0a, 0x05, 0x08, 0x80, 0x02, 0x20, 0x02]
+        );
+        // TraceConfig.data_sources { config { name, target_buffer: 1, ... } }
+        assert_eq!(&track_events[7..11], &[0x12, 0x17, 0x0a, 0x15]);
+        assert_eq!(&track_events[11..24], b"\x0a\x0btrack_event");
+        assert_eq!(&track_events[24..26], &[0x10, 0x01]);
+    }
 }
@@ -0,0 +1,647 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Background metric evaluation.
+//!
+//! Decoding counter data has to happen on the thread that owns the range
+//! profiler, but evaluating metrics from a decoded image only needs the host
+//! object. Launch callbacks hand the decoded image to a worker thread so
+//! `cuLaunchKernel` does not wait for the evaluation.
+//!
+//! Each context has a second image, so the range profiler can fill one while
+//! the other is evaluated. Evaluated images are reset and returned to the
+//! context's [`CounterDataPool`].
+//!
+//! While the application runs, images are evaluated on the worker thread
+//! alone. When everything is flushed at exit, nothing else competes for the
+//! CPU, so [`set_threads`] lets the ranges of each image be evaluated in
+//! parallel.
+//!
+//! With [`set_dump_dir`], every image is also saved before it is evaluated,
+//! so that the `counter-data-eval` tool can evaluate it again later.
+
+use crate::histograms;
+use crate::overhead::{self, Stat};
+use crate::prometheus;
+use crate::top_kernels;
+use cupti_profiler::{MetricEvaluator, RangeInfo};
+use once_cell::sync::Lazy;
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::Instant,
+};
+
+/// Counter data images of a context that are ready to be filled.
+pub struct CounterDataPool {
+    /// A freshly initialized image, which evaluated images are reset to.
+    blank: Vec<u8>,
+    images: Mutex<Vec<Vec<u8>>>,
+}
+
+impl CounterDataPool {
+    /// Creates a pool holding `count` copies of an initialized, empty image.
+    pub fn new(blank: Vec<u8>, count: usize) -> Self {
+        let images = vec![blank.clone(); count];
+        Self {
+            blank,
+            images: Mutex::new(images),
+        }
+    }
+
+    /// Takes an empty image, if one is not being evaluated.
+    pub fn take(&self) -> Option<Vec<u8>> {
+        self.images.lock().ok()?.pop()
+    }
+
+    /// Resets an image without calling into CUPTI and makes it available
+    /// again.
+    fn recycle(&self, mut image: Vec<u8>) {
+        if image.len() != self.blank.len() {
+            return;
+        }
+        image.copy_from_slice(&self.blank);
+        if let Ok(mut images) = self.images.lock() {
+            images.push(image);
+        }
+    }
+}
+
+/// A decoded counter data image waiting to be evaluated.
+struct CounterDataSnapshot {
+    ctx_id: u32,
+    counter_data_image: Vec<u8>,
+    /// Ranges of the image that were evaluated from an earlier snapshot.
+    first_range: usize,
+    metric_evaluator: Arc<MetricEvaluator>,
+    metric_names: Arc<[String]>,
+    pool: Option<Arc<CounterDataPool>>,
+}
+
+enum Message {
+    Evaluate(CounterDataSnapshot),
+    Flush(mpsc::Sender<()>),
+}
+
+/// Evaluated ranges per context ID, in the order their images were submitted.
+static RESULTS: Lazy<Mutex<HashMap<u32, Vec<RangeInfo>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Threads each image is evaluated on.
+static THREADS: AtomicUsize = AtomicUsize::new(1);
+
+/// Directory images are saved to before they are evaluated, if any.
+static DUMP_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Number of images saved so far, which numbers the files.
+static DUMPS: AtomicUsize = AtomicUsize::new(0);
+
+static WORKER: Lazy<Mutex<mpsc::Sender<Message>>> = Lazy::new(|| {
+    let (sender, receiver) = mpsc::channel();
+    thread::Builder::new()
+        .name("cupti-evaluator".to_string())
+        .spawn(move || run(receiver))
+        .expect("failed to spawn evaluator thread");
+    Mutex::new(sender)
+});
+
+fn run(receiver: mpsc::Receiver<Message>) {
+    for message in receiver {
+        match message {
+            Message::Evaluate(snapshot) => {
+                let dump_dir = DUMP_DIR.lock().ok().and_then(|dir| dir.clone());
+                if let Some(dir) = dump_dir {
+                    if let Err(e) = save_image(&dir, &snapshot) {
+                        eprintln!("Failed to save counter data to {}: {}", dir.display(), e);
+                    }
+                }
+                let started = Instant::now();
+                let infos = snapshot.metric_evaluator.evaluate_ranges_parallel(
+                    &snapshot.counter_data_image,
+                    &snapshot.metric_names,
+                    snapshot.first_range,
+                    THREADS.load(Ordering::Relaxed),
+                );
+                overhead::record_stat(Stat::Evaluate, started.elapsed());
+                if let Ok(infos) = infos {
+                    prometheus::record_ranges(&infos);
+                    top_kernels::record_ranges(&infos);
+                    histograms::record_ranges(&infos);
+                    if let Ok(mut results) = RESULTS.lock() {
+                        results.entry(snapshot.ctx_id).or_default().extend(infos);
+                    }
+                }
+                if let Some(pool) = snapshot.pool {
+                    pool.recycle(snapshot.counter_data_image);
+                }
+            }
+            Message::Flush(done) => {
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+/// Name of the `index`th saved image, which starts with the chip name that
+/// the image is evaluated for.
+pub fn dump_file_name(chip_name: &str, ctx_id: u32, index: usize) -> String {
+    format!("{}-ctx{}-{:06}.counterdata", chip_name, ctx_id, index)
+}
+
+fn save_image(dir: &Path, snapshot: &CounterDataSnapshot) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let name = dump_file_name(
+        snapshot.metric_evaluator.host.chip_name(),
+        snapshot.ctx_id,
+        DUMPS.fetch_add(1, Ordering::Relaxed),
+    );
+    fs::write(dir.join(name), &snapshot.counter_data_image)
+}
+
+fn send(message: Message) -> bool {
+    match WORKER.lock() {
+        Ok(sender) => sender.send(message).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Sets how many threads the ranges of an image are evaluated on, from the
+/// next image on.
+pub fn set_threads(threads: usize) {
+    THREADS.store(threads.max(1), Ordering::Relaxed);
+}
+
+/// Saves every image submitted from now on to `dir`, or stops saving them.
+///
+/// Images are saved whole, so one whose ranges could not be reset after an
+/// earlier snapshot repeats that snapshot's ranges.
+pub fn set_dump_dir(dir: Option<PathBuf>) {
+    if let Ok(mut dump_dir) = DUMP_DIR.lock() {
+        *dump_dir = dir;
+    }
+}
+
+/// Queues the decoded counter data of a context for evaluation.
+///
+/// Only ranges from `first_range` on are evaluated, the ones before it having
+/// been queued with an earlier snapshot of the same image. Once evaluated,
+/// the image goes back to `pool`, if given. Without a metric evaluator
+/// nothing is evaluated and the image is returned right away.
+pub fn submit(
+    ctx_id: u32,
+    metric_evaluator: Option<&Arc<MetricEvaluator>>,
+    counter_data_image: Vec<u8>,
+    first_range: usize,
+    metric_names: &Arc<[String]>,
+    pool: Option<Arc<CounterDataPool>>,
+) {
+    let Some(me) = metric_evaluator else {
+        if let Some(pool) = pool {
+            pool.recycle(counter_data_image);
+        }
+        return;
+    };
+    send(Message::Evaluate(CounterDataSnapshot {
+        ctx_id,
+        counter_data_image,
+        first_range,
+        metric_evaluator: Arc::clone(me),
+        metric_names: Arc::clone(metric_names),
+        pool,
+    }));
+}
+
+/// Blocks until every snapshot submitted so far has been evaluated.
+pub fn flush() {
+    flush_until(None);
+}
+
+/// Like `flush`, but gives up once `deadline` has passed, in which case false
+/// is returned and the remaining snapshots are still evaluated later.
+pub fn flush_until(deadline: Option<Instant>) -> bool {
+    let (done, wait) = mpsc::channel();
+    if !send(Message::Flush(done)) {
+        return true;
+    }
+    match deadline {
+        Some(deadline) => wait
+            .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            .map_or_else(|e| e == mpsc::RecvTimeoutError::Disconnected, |_| true),
+        None => {
+            let _ = wait.recv();
+            true
+        }
+    }
+}
+
+/// Takes the ranges evaluated so far for a context.
+pub fn take_results(ctx_id: u32) -> Vec<RangeInfo> {
+    RESULTS
+        .lock()
+        .ok()
+        .and_then(|mut results| results.remove(&ctx_id))
+        .unwrap_or_default()
+}
+
+#[cfg(all(test, feature = "stubs"))]
+mod tests {
+    use super::*;
+    use cupti_profiler::bindings::*;
+    use cupti_profiler::simulation;
+    use cupti_profiler::RangeProfiler;
+
+    /// Serializes tests, since they share the simulated CUPTI state.
+    static SIMULATION: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn test_evaluate_in_order() {
+        let _guard = SIMULATION.lock().unwrap_or_else(|e| e.into_inner());
+        simulation::reset();
+        let ctx = simulation::context(5);
+        let metrics: Arc<[String]> = Arc::new(["gpu__time_duration.sum".to_string()]);
+        let me = Arc::new(unsafe { MetricEvaluator::new(ctx) }.unwrap());
+        let mut image = Vec::new();
+        let mut rp = RangeProfiler::new(ctx);
+        rp.enable().unwrap();
+        rp.set_config(
+            &metrics,
+            &mut image,
+            4,
+            CUpti_ProfilerReplayMode_CUPTI_KernelReplay,
+        )
+        .unwrap();
+        rp.start().unwrap();
+        for (name, duration) in [("first", 1.0), ("second", 2.0)] {
+            simulation::push_range(5, name, &[duration]);
+            rp.decode_counter_data().unwrap();
+            submit(5, Some(&me), image.clone(), 0, &metrics, None);
+            rp.initialize_counter_data_image(&mut image).unwrap();
+        }
+        rp.disable().unwrap();
+        flush();
+        let ranges = take_results(5);
+        let names: Vec<&str> = ranges.iter().map(|r| r.range_name.as_str()).collect();
+        assert_eq!(names, vec!["first", "second"]);
+        assert_eq!(ranges[1].metric_and_values[0].value, 2.0);
+        assert!(take_results(5).is_empty());
+    }
+
+    #[test]
+    fn test_double_buffering() {
+        let _guard = SIMULATION.lock().unwrap_or_else(|e| e.into_inner());
+        simulation::reset();
+        let ctx = simulation::context(6);
+        let metrics: Arc<[String]> = Arc::new(["gpu__time_duration.sum".to_string()]);
+        let me = Arc::new(unsafe { MetricEvaluator::new(ctx) }.unwrap());
+        let replay_mode = CUpti_ProfilerReplayMode_CUPTI_KernelReplay;
+        let mut image = Vec::new();
+        let mut rp = RangeProfiler::new(ctx);
+        rp.enable().unwrap();
+        rp.set_config(&metrics, &mut image, 4, replay_mode).unwrap();
+        let pool = Arc::new(CounterDataPool::new(image.clone(), 1));
+        rp.start().unwrap();
+        simulation::push_range(6, "first", &[1.0]);
+        rp.decode_counter_data().unwrap();
+
+        // Keep profiling into the spare image while the first is evaluated.
+        rp.stop().unwrap();
+        let spare = pool.take().unwrap();
+        assert!(pool.take().is_none());
+        let filled = std::mem::replace(&mut image, spare);
+        rp.set_counter_data_image(&mut image, 4, replay_mode)
+            .unwrap();
+        rp.start().unwrap();
+        submit(6, Some(&me), filled, 0, &metrics, Some(Arc::clone(&pool)));
+        simulation::push_range(6, "second", &[2.0]);
+        rp.decode_counter_data().unwrap();
+        rp.disable().unwrap();
+        flush();
+
+        // The evaluated image comes back empty.
+        let recycled = pool.take().unwrap();
+        assert_eq!(recycled, pool.blank);
+        let ranges = take_results(6);
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(ranges[0].range_name, "first");
+        submit(6, Some(&me), image, 0, &metrics, None);
+        flush();
+        let ranges = take_results(6);
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(ranges[0].range_name, "second");
+        assert_eq!(ranges[0].metric_and_values[0].value, 2.0);
+    }
+
+    #[test]
+    fn test_parallel_evaluation() {
+        let _guard = SIMULATION.lock().unwrap_or_else(|e| e.into_inner());
+        simulation::reset();
+        let ctx = simulation::context(7);
+        let metrics: Arc<[String]> = Arc::new(["gpu__time_duration.sum".to_string()]);
+        let me = Arc::new(unsafe { MetricEvaluator::new(ctx) }.unwrap());
+        let mut image = Vec::new();
+        let mut rp = RangeProfiler::new(ctx);
+        rp.enable().unwrap();
+        rp.set_config(
+            &metrics,
+            &mut image,
+            64,
+            CUpti_ProfilerReplayMode_CUPTI_KernelReplay,
+        )
+        .unwrap();
+        rp.start().unwrap();
+        for i in 0..50 {
+            simulation::push_range(7, &format!("kernel{}", i), &[i as f64]);
+        }
+        rp.decode_counter_data().unwrap();
+        rp.disable().unwrap();
+        set_threads(4);
+        submit(7, Some(&me), image, 0, &metrics, None);
+        flush();
+        set_threads(1);
+        let ranges = take_results(7);
+        assert_eq!(ranges.len(), 50);
+        for (i, range) in ranges.iter().enumerate() {
+            assert_eq!(range.range_name, format!("kernel{}", i));
+            assert_eq!(range.metric_and_values[0].value, i as f64);
+        }
+    }
+
+    #[test]
+    fn test_evaluate_new_ranges() {
+        let _guard = SIMULATION.lock().unwrap_or_else(|e| e.into_inner());
+        simulation::reset();
+        let ctx = simulation::context(9);
+        let metrics: Arc<[String]> = Arc::new(["gpu__time_duration.sum".to_string()]);
+        let me = Arc::new(unsafe { MetricEvaluator::new(ctx) }.unwrap());
+        let mut image = Vec::new();
+        let mut rp = RangeProfiler::new(ctx);
+        rp.enable().unwrap();
+        rp.set_config(
+            &metrics,
+            &mut image,
+            8,
+            CUpti_ProfilerReplayMode_CUPTI_KernelReplay,
+        )
+        .unwrap();
+        rp.start().unwrap();
+        simulation::push_range(9, "first", &[1.0]);
+        simulation::push_range(9, "second", &[2.0]);
+        rp.decode_counter_data().unwrap();
+        submit(9, Some(&me), image.clone(), 0, &metrics, None);
+
+        // Without resetting the image, later decodes add to the same ranges.
+        simulation::push_range(9, "third", &[3.0]);
+        rp.decode_counter_data().unwrap();
+        assert_eq!(me.get_num_of_ranges(&image).unwrap(), 3);
+        submit(9, Some(&me), image, 2, &metrics, None);
+        rp.disable().unwrap();
+        flush();
+        let ranges = take_results(9);
+        let names: Vec<&str> = ranges.iter().map(|r| r.range_name.as_str()).collect();
+        assert_eq!(names, vec!["first", "second", "third"]);
+        assert_eq!(ranges[2].metric_and_values[0].value, 3.0);
+    }
+
+    #[test]
+    fn test_dump_images() {
+        let _guard = SIMULATION.lock().unwrap_or_else(|e| e.into_inner());
+        simulation::reset();
+        let ctx = simulation::context(10);
+        let metrics: Arc<[String]> = Arc::new(["gpu__time_duration.sum".to_string()]);
+        let me = Arc::new(unsafe { MetricEvaluator::new(ctx) }.unwrap());
+        let mut image = Vec::new();
+        let mut rp = RangeProfiler::new(ctx);
+        rp.enable().unwrap();
+        rp.set_config(
+            &metrics,
+            &mut image,
+            4,
+            CUpti_ProfilerReplayMode_CUPTI_KernelReplay,
+        )
+        .unwrap();
+        rp.start().unwrap();
+        simulation::push_range(10, "saved", &[5.0]);
+        rp.decode_counter_data().unwrap();
+        rp.disable().unwrap();
+        let dir = std::env::temp_dir().join(format!("counterdata-{}", std::process::id()));
+        set_dump_dir(Some(dir.clone()));
+        submit(10, Some(&me), image, 0, &metrics, None);
+        flush();
+        set_dump_dir(None);
+        assert_eq!(take_results(10).len(), 1);
+
+        // The saved image evaluates the same without a GPU.
+        let files: Vec<PathBuf> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(files.len(), 1);
+        let name = files[0].file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("SIM100-ctx10-"));
+        let saved = fs::read(&files[0]).unwrap();
+        let offline = MetricEvaluator::for_chip("SIM100", Vec::new()).unwrap();
+        let ranges = offline.evaluate_all_ranges(&saved, &metrics).unwrap();
+        assert_eq!(ranges[0].range_name, "saved");
+        assert_eq!(ranges[0].metric_and_values[0].value, 5.0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_passes_per_decode() {
+        use crate::state::CtxProfilerData;
+        use std::time::Duration;
+
+        let _guard = SIMULATION.lock().unwrap_or_else(|e| e.into_inner());
+        simulation::reset();
+        let ctx = simulation::context(8);
+        let metrics: Arc<[String]> = Arc::new(["gpu__time_duration.sum".to_string()]);
+        let mut data = CtxProfilerData::new(ctx, 8, 0, 0, 6);
+        data.max_ranges_per_pass = 2;
+        data.metric_evaluator = Some(Arc::new(unsafe { MetricEvaluator::new(ctx) }.unwrap()));
+        data.restart(&metrics).unwrap();
+        assert_eq!(data.ranges_per_pass(), 2);
+
+        // The pass fills up first: a range beyond it is lost.
+        for i in 0..3 {
+            simulation::push_range(8, &format!("lost{}", i), &[i as f64]);
+        }
+        data.decode_ranges();
+        flush();
+        assert_eq!(take_results(8).len(), 2);
+
+        // Ending passes keeps every range in the image until it is full.
+        let mut decodes = 0;
+        for i in 0..6 {
+            if data.should_decode(Duration::MAX) {
+                data.decode_ranges();
+                decodes += 1;
+            } else if data.should_end_pass() {
+                data.end_pass();
+            }
+            simulation::push_range(8, &format!("kernel{}", i), &[i as f64]);
+            data.pending_ranges += 1;
+            data.pass_ranges += 1;
+        }
+        assert_eq!(decodes, 0);
+        assert!(data.should_decode(Duration::MAX));
+        data.flush_ranges();
+        flush();
+        let ranges = take_results(8);
+        assert_eq!(ranges.len(), 6);
+        assert_eq!(ranges[5].range_name, "kernel5");
+    }
+
+    #[test]
+    fn test_restart_out_of_memory() {
+        use crate::state::{CtxProfilerData, MIN_RETRY_RANGES};
+
+        let _guard = SIMULATION.lock().unwrap_or_else(|e| e.into_inner());
+        simulation::reset();
+        let ctx = simulation::context(12);
+        let metrics: Arc<[String]> = Arc::new([
+            "sm__cycles_elapsed.avg".to_string(),
+            "gpu__time_duration.sum".to_string(),
+            "dram__bytes.sum".to_string(),
+        ]);
+        let mut data = CtxProfilerData::new(ctx, 12, 0, 0, MIN_RETRY_RANGES);
+        data.metric_evaluator = Some(Arc::new(unsafe { MetricEvaluator::new(ctx) }.unwrap()));
+        data.restart(&metrics).unwrap();
+        let fitting = data.counter_data_image.len();
+        data.flush_ranges();
+
+        // Fewer ranges make the image fit.
+        simulation::set_max_counter_data_size(fitting);
+        data.max_num_ranges = 64;
+        data.restart(&metrics).unwrap();
+        assert_eq!(data.max_num_ranges, MIN_RETRY_RANGES);
+        assert_eq!(data.metric_names, metrics);
+        data.flush_ranges();
+
+        // Then fewer metrics, keeping the duration.
+        simulation::set_max_counter_data_size(fitting - 1);
+        data.restart(&metrics).unwrap();
+        assert_eq!(
+            data.metric_names[..],
+            ["gpu__time_duration.sum".to_string()]
+        );
+        assert!(!data.metrics_changed(&metrics));
+        simulation::push_range(12, "kernel", &[5.0]);
+        data.flush_ranges();
+        flush();
+        let ranges = take_results(12);
+        assert_eq!(ranges[0].metric_and_values.len(), 1);
+        assert_eq!(ranges[0].metric_and_values[0].value, 5.0);
+
+        // Nothing is left to give up with a single metric.
+        simulation::set_max_counter_data_size(1);
+        assert_eq!(
+            data.restart(&metrics).unwrap_err(),
+            CUptiResult_CUPTI_ERROR_OUT_OF_MEMORY
+        );
+    }
+
+    #[test]
+    fn test_change_metrics() {
+        use crate::state::CtxProfilerData;
+
+        let _guard = SIMULATION.lock().unwrap_or_else(|e| e.into_inner());
+        simulation::reset();
+        let ctx = simulation::context(11);
+        let metrics: Arc<[String]> = Arc::new(["gpu__time_duration.sum".to_string()]);
+        let mut data = CtxProfilerData::new(ctx, 11, 0, 0, 4);
+        data.metric_evaluator = Some(Arc::new(unsafe { MetricEvaluator::new(ctx) }.unwrap()));
+        data.restart(&metrics).unwrap();
+        assert!(!data.metrics_changed(&metrics));
+        assert!(!data.metrics_changed(&Arc::from(metrics.to_vec())));
+        simulation::push_range(11, "before", &[1.0]);
+
+        // Ranges collected so far are evaluated for the metrics they were
+        // collected with.
+        let changed: Arc<[String]> = Arc::new([
+            "sm__cycles_elapsed.avg".to_string(),
+            "gpu__time_duration.sum".to_string(),
+        ]);
+        assert!(data.metrics_changed(&changed));
+        data.flush_ranges();
+        assert!(!data.metrics_changed(&changed));
+        data.restart(&changed).unwrap();
+        simulation::push_range(11, "after", &[2.0, 3.0]);
+        data.flush_ranges();
+        flush();
+        let ranges = take_results(11);
+        assert_eq!(ranges.len(), 2);
+        assert_eq!(ranges[0].metric_and_values.len(), 1);
+        assert_eq!(ranges[1].range_name, "after");
+        assert_eq!(
+            ranges[1].metric_and_values[0].metric_name,
+            "sm__cycles_elapsed.avg"
+        );
+        assert_eq!(ranges[1].metric_and_values[1].value, 3.0);
+    }
+
+    #[test]
+    fn test_switch_contexts() {
+        use crate::state::CtxProfilerData;
+
+        let _guard = SIMULATION.lock().unwrap_or_else(|e| e.into_inner());
+        simulation::reset();
+        let metrics: Arc<[String]> = Arc::new(["gpu__time_duration.sum".to_string()]);
+        let mut contexts: Vec<CtxProfilerData> = [13, 14]
+            .into_iter()
+            .map(|id| {
+                let ctx = simulation::context(id);
+                let mut data = CtxProfilerData::new(ctx, id, 0, 0, 8);
+                data.metric_evaluator =
+                    Some(Arc::new(unsafe { MetricEvaluator::new(ctx) }.unwrap()));
+                data.restart(&metrics).unwrap();
+                data
+            })
+            .collect();
+        contexts[1].pause();
+
+        // Alternating between contexts pauses and resumes their sessions,
+        // without enabling new ones.
+        for i in 0..4 {
+            let (active, paused) = (i % 2, 1 - i % 2);
+            contexts[paused].pause();
+            contexts[active].resume();
+            assert!(contexts[active].is_profiling());
+            assert!(!contexts[paused].is_profiling());
+            let id = contexts[active].ctx_id;
+            simulation::push_range(id, &format!("kernel{}", i), &[i as f64]);
+            simulation::push_range(contexts[paused].ctx_id, "paused", &[0.0]);
+        }
+        assert_eq!(simulation::range_profiler_enables(), 2);
+
+        // Each context keeps the ranges of its own kernels.
+        for data in &mut contexts {
+            data.flush_ranges();
+        }
+        flush();
+        let names = |id| -> Vec<String> {
+            take_results(id)
+                .into_iter()
+                .map(|range| range.range_name)
+                .collect()
+        };
+        assert_eq!(names(13), ["kernel0", "kernel2"]);
+        assert_eq!(names(14), ["kernel1", "kernel3"]);
+    }
+}
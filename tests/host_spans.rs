@@ -0,0 +1,64 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Records the spans of a `tracing` subscriber with `PerfettoLayer` to an
+//! in-process Perfetto session.
+
+#![cfg(feature = "tracing-spans")]
+
+use perfetto_cupti_gpu_compute::spans::PerfettoLayer;
+use perfetto_cupti_gpu_compute::tracing::file_trace_config;
+use perfetto_sdk::{
+    producer::{Backends, Producer, ProducerInitArgsBuilder},
+    tracing_session::TracingSession,
+};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing_subscriber::layer::SubscriberExt;
+
+fn contains(trace: &[u8], s: &str) -> bool {
+    trace.windows(s.len()).any(|w| w == s.as_bytes())
+}
+
+#[test]
+fn test_host_spans() {
+    Producer::init(
+        ProducerInitArgsBuilder::new()
+            .backends(Backends::IN_PROCESS)
+            .build(),
+    );
+    let subscriber = tracing_subscriber::registry().with(PerfettoLayer::new());
+
+    let mut session = TracingSession::in_process().unwrap();
+    session.setup(&file_trace_config(1024));
+    session.start_blocking();
+    tracing::subscriber::with_default(subscriber, || {
+        let span = tracing::info_span!("train_step", step = 7u64);
+        let _entered = span.enter();
+        tracing::info!(batch = 32u64, "launched kernels");
+    });
+    session.flush_blocking(Duration::from_secs(5));
+    session.stop_blocking();
+    let trace = Arc::new(Mutex::new(Vec::new()));
+    let trace_for_cb = Arc::clone(&trace);
+    session.read_trace_blocking(move |data, _end| {
+        trace_for_cb.lock().unwrap().extend_from_slice(data);
+    });
+    let trace = trace.lock().unwrap().clone();
+
+    assert!(contains(&trace, "train_step"));
+    assert!(contains(&trace, "step"));
+    assert!(contains(&trace, "launched kernels"));
+    assert!(contains(&trace, "batch"));
+}
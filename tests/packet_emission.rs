@@ -0,0 +1,926 @@
+// Copyright (C) 2026 David Reveman.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Drives simulated kernel launches through the CUPTI callbacks and checks the
+//! TracePackets emitted to an in-process Perfetto session.
+
+#![cfg(feature = "stubs")]
+
+use cupti_profiler::bindings::*;
+use cupti_profiler::simulation::{self, SimulatedKernel};
+use perfetto_cupti_gpu_compute::baseline::{self, Baseline};
+use perfetto_cupti_gpu_compute::callbacks::{
+    buffer_completed, buffer_requested, profiler_callback_handler,
+};
+use perfetto_cupti_gpu_compute::detach;
+use perfetto_cupti_gpu_compute::json_export::{JsonExport, JsonFormat};
+use perfetto_cupti_gpu_compute::overhead::{OverheadTracker, OVERHEAD_WINDOW};
+use perfetto_cupti_gpu_compute::state::{CONTEXT_DATA, GLOBAL_STATE};
+use perfetto_cupti_gpu_compute::summary;
+use perfetto_cupti_gpu_compute::trace_emitter::{
+    COPY_STAGE_ID, DROPPED_RANGES_COUNTER, DROPPED_RANGES_COUNTER_ID, STATS_COUNTER_ID_BASE,
+};
+use perfetto_cupti_gpu_compute::tracing::{
+    get_data_source, get_render_stages_data_source, trace_config,
+};
+use perfetto_cupti_gpu_compute::worker;
+use perfetto_sdk::{
+    pb_decoder::{PbDecoder, PbDecoderField},
+    producer::{Backends, Producer, ProducerInitArgsBuilder},
+    tracing_session::TracingSession,
+};
+use std::path::PathBuf;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const METRICS: &[&str] = &["gpu__time_duration.sum", "sm__cycles_elapsed.avg"];
+
+const TRACE_PACKET: u32 = 1;
+const PACKET_TIMESTAMP: u32 = 8;
+const PACKET_GPU_COUNTER_EVENT: u32 = 52;
+const PACKET_GPU_RENDER_STAGE_EVENT: u32 = 53;
+const PACKET_GPU_LOG: u32 = 63;
+const RENDER_STAGE_EVENT_ID: u32 = 1;
+const RENDER_STAGE_DURATION: u32 = 2;
+const RENDER_STAGE_HW_QUEUE_ID: u32 = 3;
+const RENDER_STAGE_STAGE_ID: u32 = 4;
+const RENDER_STAGE_EXTRA_DATA: u32 = 6;
+const RENDER_STAGE_SPECIFICATIONS: u32 = 7;
+const SPECIFICATIONS_HW_QUEUE: u32 = 2;
+const DESCRIPTION_NAME: u32 = 1;
+const DESCRIPTION_DESCRIPTION: u32 = 2;
+const EXTRA_DATA_NAME: u32 = 1;
+const EXTRA_DATA_VALUE: u32 = 2;
+const COUNTER_EVENT_DESCRIPTOR: u32 = 1;
+const COUNTER_EVENT_COUNTERS: u32 = 2;
+const DESCRIPTOR_SPECS: u32 = 1;
+const SPEC_NAME: u32 = 2;
+const COUNTER_ID: u32 = 1;
+const COUNTER_INT_VALUE: u32 = 2;
+const COUNTER_DOUBLE_VALUE: u32 = 3;
+const LOG_MESSAGE: u32 = 3;
+
+#[derive(Debug, Default)]
+struct RenderStageEvent {
+    event_id: u64,
+    duration: u64,
+    hw_queue_id: u64,
+    stage_id: u64,
+    extra_data: Vec<(String, String)>,
+    has_specifications: bool,
+    queue_names: Vec<String>,
+    queue_descriptions: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+struct CounterEvent {
+    descriptor: Option<Vec<String>>,
+    int_values: Vec<(u64, i64)>,
+    double_values: Vec<(u64, f64)>,
+}
+
+#[derive(Debug)]
+enum Packet {
+    RenderStage(u64, RenderStageEvent),
+    Counters(u64, CounterEvent),
+    Log(String),
+}
+
+fn string(data: &[u8]) -> String {
+    String::from_utf8_lossy(data).into_owned()
+}
+
+fn parse_render_stage_event(data: &[u8]) -> RenderStageEvent {
+    let mut event = RenderStageEvent::default();
+    for field in PbDecoder::new(data) {
+        match field.unwrap() {
+            (RENDER_STAGE_EVENT_ID, PbDecoderField::Varint(v)) => event.event_id = v,
+            (RENDER_STAGE_DURATION, PbDecoderField::Varint(v)) => event.duration = v,
+            (RENDER_STAGE_HW_QUEUE_ID, PbDecoderField::Varint(v)) => event.hw_queue_id = v,
+            (RENDER_STAGE_STAGE_ID, PbDecoderField::Varint(v)) => event.stage_id = v,
+            (RENDER_STAGE_EXTRA_DATA, PbDecoderField::Delimited(extra)) => {
+                let (mut name, mut value) = (String::new(), String::new());
+                for field in PbDecoder::new(extra) {
+                    match field.unwrap() {
+                        (EXTRA_DATA_NAME, PbDecoderField::Delimited(v)) => name = string(v),
+                        (EXTRA_DATA_VALUE, PbDecoderField::Delimited(v)) => value = string(v),
+                        _ => {}
+                    }
+                }
+                event.extra_data.push((name, value));
+            }
+            (RENDER_STAGE_SPECIFICATIONS, PbDecoderField::Delimited(specs)) => {
+                event.has_specifications = true;
+                for field in PbDecoder::new(specs) {
+                    let (SPECIFICATIONS_HW_QUEUE, PbDecoderField::Delimited(desc)) = field.unwrap()
+                    else {
+                        continue;
+                    };
+                    for field in PbDecoder::new(desc) {
+                        match field.unwrap() {
+                            (DESCRIPTION_NAME, PbDecoderField::Delimited(v)) => {
+                                event.queue_names.push(string(v))
+                            }
+                            (DESCRIPTION_DESCRIPTION, PbDecoderField::Delimited(v)) => {
+                                event.queue_descriptions.push(string(v))
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    event
+}
+
+fn parse_counter_event(data: &[u8]) -> CounterEvent {
+    let mut event = CounterEvent::default();
+    for field in PbDecoder::new(data) {
+        match field.unwrap() {
+            (COUNTER_EVENT_DESCRIPTOR, PbDecoderField::Delimited(desc)) => {
+                let mut names = Vec::new();
+                for field in PbDecoder::new(desc) {
+                    if let (DESCRIPTOR_SPECS, PbDecoderField::Delimited(spec)) = field.unwrap() {
+                        for field in PbDecoder::new(spec) {
+                            if let (SPEC_NAME, PbDecoderField::Delimited(v)) = field.unwrap() {
+                                names.push(string(v));
+                            }
+                        }
+                    }
+                }
+                event.descriptor = Some(names);
+            }
+            (COUNTER_EVENT_COUNTERS, PbDecoderField::Delimited(counter)) => {
+                let mut id = 0;
+                for field in PbDecoder::new(counter) {
+                    match field.unwrap() {
+                        (COUNTER_ID, PbDecoderField::Varint(v)) => id = v,
+                        (COUNTER_INT_VALUE, PbDecoderField::Varint(v)) => {
+                            event.int_values.push((id, v as i64))
+                        }
+                        (COUNTER_DOUBLE_VALUE, PbDecoderField::Fixed64(v)) => {
+                            event.double_values.push((id, f64::from_bits(v)))
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    event
+}
+
+/// Extracts the GPU packets from a serialized trace, in the order written.
+fn parse_trace(trace: &[u8]) -> Vec<Packet> {
+    let mut packets = Vec::new();
+    for field in PbDecoder::new(trace) {
+        let (TRACE_PACKET, PbDecoderField::Delimited(packet)) = field.unwrap() else {
+            continue;
+        };
+        let mut timestamp = 0;
+        let mut parsed = None;
+        for field in PbDecoder::new(packet) {
+            match field.unwrap() {
+                (PACKET_TIMESTAMP, PbDecoderField::Varint(v)) => timestamp = v,
+                (PACKET_GPU_RENDER_STAGE_EVENT, PbDecoderField::Delimited(v)) => {
+                    parsed = Some(Packet::RenderStage(0, parse_render_stage_event(v)))
+                }
+                (PACKET_GPU_COUNTER_EVENT, PbDecoderField::Delimited(v)) => {
+                    parsed = Some(Packet::Counters(0, parse_counter_event(v)))
+                }
+                (PACKET_GPU_LOG, PbDecoderField::Delimited(v)) => {
+                    for field in PbDecoder::new(v) {
+                        if let (LOG_MESSAGE, PbDecoderField::Delimited(v)) = field.unwrap() {
+                            parsed = Some(Packet::Log(string(v)));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        match parsed {
+            Some(Packet::RenderStage(_, event)) => {
+                packets.push(Packet::RenderStage(timestamp, event))
+            }
+            Some(Packet::Counters(_, event)) => packets.push(Packet::Counters(timestamp, event)),
+            Some(log) => packets.push(log),
+            None => {}
+        }
+    }
+    packets
+}
+
+fn start_tracing_session() -> TracingSession {
+    let config = trace_config(1024);
+    let mut session = TracingSession::in_process().unwrap();
+    session.setup(&config);
+    session.start_blocking();
+    session
+}
+
+fn stop_tracing_session(mut session: TracingSession) -> Vec<u8> {
+    session.flush_blocking(Duration::from_secs(5));
+    session.stop_blocking();
+    let trace = Arc::new(Mutex::new(Vec::new()));
+    let trace_for_cb = Arc::clone(&trace);
+    session.read_trace_blocking(move |data, _end| {
+        trace_for_cb.lock().unwrap().extend_from_slice(data);
+    });
+    let trace = trace.lock().unwrap().clone();
+    trace
+}
+
+/// Registers the injection callbacks with the simulated CUPTI, like
+/// `InitializeInjection` does with the real one.
+fn start_injection() {
+    let subscriber =
+        unsafe { cupti_profiler::subscribe(Some(profiler_callback_handler), ptr::null_mut()) }
+            .unwrap();
+    for (domain, cbid) in [
+        (
+            CUpti_CallbackDomain_CUPTI_CB_DOMAIN_DRIVER_API,
+            CUpti_driver_api_trace_cbid_enum_CUPTI_DRIVER_TRACE_CBID_cuLaunchKernel,
+        ),
+        (
+            CUpti_CallbackDomain_CUPTI_CB_DOMAIN_DRIVER_API,
+            CUpti_driver_api_trace_cbid_enum_CUPTI_DRIVER_TRACE_CBID_cuLaunchKernelEx,
+        ),
+        (
+            CUpti_CallbackDomain_CUPTI_CB_DOMAIN_DRIVER_API,
+            CUpti_driver_api_trace_cbid_enum_CUPTI_DRIVER_TRACE_CBID_cuStreamCreate,
+        ),
+        (
+            CUpti_CallbackDomain_CUPTI_CB_DOMAIN_NVTX,
+            CUpti_nvtx_api_trace_cbid_CUPTI_CBID_NVTX_nvtxNameCuStreamA,
+        ),
+        (
+            CUpti_CallbackDomain_CUPTI_CB_DOMAIN_RESOURCE,
+            CUpti_CallbackIdResource_CUPTI_CBID_RESOURCE_CONTEXT_CREATED,
+        ),
+        (
+            CUpti_CallbackDomain_CUPTI_CB_DOMAIN_RESOURCE,
+            CUpti_CallbackIdResource_CUPTI_CBID_RESOURCE_CONTEXT_DESTROY_STARTING,
+        ),
+    ] {
+        unsafe { cupti_profiler::enable_callback(1, subscriber, domain, cbid) }.unwrap();
+    }
+    cupti_profiler::activity_enable(CUpti_ActivityKind_CUPTI_ACTIVITY_KIND_KERNEL).unwrap();
+    cupti_profiler::activity_enable(CUpti_ActivityKind_CUPTI_ACTIVITY_KIND_EXTERNAL_CORRELATION)
+        .unwrap();
+    unsafe {
+        cupti_profiler::activity_register_callbacks(Some(buffer_requested), Some(buffer_completed))
+    }
+    .unwrap();
+    let mut state = GLOBAL_STATE.lock().unwrap();
+    state.config.metrics = METRICS.iter().map(|s| s.to_string()).collect();
+    state.injection_initialized = true;
+    state.detached = false;
+    state.subscriber = Some(subscriber);
+}
+
+fn json_path() -> PathBuf {
+    std::env::temp_dir().join(format!("packet_emission-{}.jsonl", std::process::id()))
+}
+
+fn kernel(name: &str, duration: f64, cycles: f64) -> SimulatedKernel {
+    SimulatedKernel {
+        name: name.to_string(),
+        grid_size: (4, 2, 1),
+        block_size: (128, 1, 1),
+        dynamic_shared_memory: 256,
+        static_shared_memory: 1024,
+        registers_per_thread: 40,
+        metric_values: vec![duration, cycles],
+        ..Default::default()
+    }
+}
+
+fn extra<'a>(event: &'a RenderStageEvent, name: &str) -> Option<&'a str> {
+    event
+        .extra_data
+        .iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.as_str())
+}
+
+#[test]
+fn test_packet_emission() {
+    Producer::init(
+        ProducerInitArgsBuilder::new()
+            .backends(Backends::IN_PROCESS)
+            .build(),
+    );
+    let _ = get_data_source();
+    let _ = get_render_stages_data_source();
+    simulation::reset();
+    GLOBAL_STATE.lock().unwrap().json_export =
+        Some(JsonExport::create(&json_path(), JsonFormat::Lines, &[]).unwrap());
+
+    // Before a tracing session starts, kernels are only traced through their
+    // activity records, without the range profiler.
+    start_injection();
+    assert!(simulation::create_context(1));
+    assert!(simulation::launch_kernel(
+        1,
+        &SimulatedKernel {
+            start: 100,
+            end: 600,
+            external_ids: vec![(
+                CUpti_ExternalCorrelationKind_CUPTI_EXTERNAL_CORRELATION_KIND_CUSTOM0,
+                42
+            )],
+            ..kernel("memset", 9000.0, 10.0)
+        }
+    ));
+    assert!(CONTEXT_DATA
+        .get(1)
+        .unwrap()
+        .lock()
+        .unwrap()
+        .range_profiler
+        .is_none());
+    let session = start_tracing_session();
+
+    // First emission: two profiled kernels on the same context.
+    assert!(simulation::launch_kernel(
+        1,
+        &kernel("_Z9vectorAddPKfS0_Pfi", 1000.0, 50.0)
+    ));
+    assert!(simulation::launch_kernel(
+        1,
+        &kernel("reduce", 2000.0, 80.0)
+    ));
+    detach();
+
+    // Second emission within the same tracing session, with more kernels than
+    // the counter data image initially holds between decodes. The two kernels
+    // decoded by the third launch are spilled to disk once their activities
+    // arrive.
+    start_injection();
+    {
+        let mut state = GLOBAL_STATE.lock().unwrap();
+        state.config.max_ranges = 2;
+        state.config.spill_threshold = 1;
+    }
+    assert!(simulation::create_context(1));
+    for (duration, cycles) in [(3000.0, 90.0), (4000.0, 60.0), (5000.0, 70.0)] {
+        assert!(simulation::launch_kernel(
+            1,
+            &kernel("scale", duration, cycles)
+        ));
+    }
+    worker::flush();
+    cupti_profiler::activity_flush_all(0).unwrap();
+    {
+        let data = CONTEXT_DATA.get(1).unwrap();
+        let data = data.lock().unwrap();
+        assert_eq!(data.spill.as_ref().map(|spill| spill.len()), Some(2));
+        assert_eq!(data.kernel_launches.len(), 1);
+        // Filling the image right away grows it for the next decode.
+        assert_eq!(data.max_num_ranges, 4);
+    }
+    detach();
+
+    // Third emission keeping only the newest kernel.
+    start_injection();
+    {
+        let mut state = GLOBAL_STATE.lock().unwrap();
+        state.config.max_kernels = 1;
+        state.config.spill_threshold = 0;
+    }
+    assert!(simulation::create_context(1));
+    for (duration, cycles) in [(6000.0, 10.0), (7000.0, 20.0)] {
+        assert!(simulation::launch_kernel(
+            1,
+            &kernel("tail", duration, cycles)
+        ));
+    }
+    detach();
+
+    // Fourth emission over an overhead budget that the time already spent
+    // exceeds, so only every other kernel is profiled.
+    start_injection();
+    {
+        let mut state = GLOBAL_STATE.lock().unwrap();
+        state.config.max_kernels = 0;
+        state.overhead = OverheadTracker::new(1e-9, Instant::now() - OVERHEAD_WINDOW, 0);
+    }
+    assert!(simulation::create_context(1));
+    for kernel in [
+        kernel("sampled", 8000.0, 30.0),
+        SimulatedKernel {
+            start: 1000,
+            end: 1900,
+            ..kernel("unsampled", 9000.0, 40.0)
+        },
+        kernel("sampled", 10000.0, 50.0),
+    ] {
+        assert!(simulation::launch_kernel(1, &kernel));
+    }
+    detach();
+
+    // Fifth emission while the application runs: completed kernels are
+    // written as their activities arrive, leaving nothing for the end.
+    start_injection();
+    {
+        let mut state = GLOBAL_STATE.lock().unwrap();
+        state.overhead = OverheadTracker::new(0.0, Instant::now(), 0);
+        state.config.emit_interval = Duration::from_nanos(1);
+    }
+    assert!(simulation::create_context(1));
+    // Only the second kernel is more than 10% slower than the baseline.
+    baseline::set_baseline(Some(
+        Baseline::parse(r#"{"kernels":{"streamed":{"duration":10000}}}"#).unwrap(),
+    ));
+    for (duration, cycles) in [(11000.0, 60.0), (12000.0, 70.0)] {
+        assert!(simulation::launch_kernel(
+            1,
+            &kernel("streamed", duration, cycles)
+        ));
+    }
+    {
+        let data = CONTEXT_DATA.get(1).unwrap();
+        data.lock().unwrap().flush_ranges();
+    }
+    worker::flush();
+    cupti_profiler::activity_flush_all(0).unwrap();
+    {
+        let data = CONTEXT_DATA.get(1).unwrap();
+        let data = data.lock().unwrap();
+        assert_eq!(data.emitted_kernels, 2);
+        assert!(data.kernel_launches.is_empty());
+    }
+    detach();
+    baseline::set_baseline(None);
+
+    // Sixth emission while another profiler owns the counters: the context is
+    // traced from activity records, without profiling any kernel.
+    start_injection();
+    simulation::set_range_profiler_result(CUptiResult_CUPTI_ERROR_HARDWARE_BUSY);
+    assert!(simulation::create_context(1));
+    assert!(simulation::launch_kernel(
+        1,
+        &SimulatedKernel {
+            start: 2000,
+            end: 2300,
+            ..kernel("blocked", 13000.0, 80.0)
+        }
+    ));
+    {
+        let data = CONTEXT_DATA.get(1).unwrap();
+        let data = data.lock().unwrap();
+        assert!(data.range_profiler.is_none());
+        assert!(data
+            .counters_unavailable
+            .as_deref()
+            .is_some_and(|reason| reason.contains("another profiler")));
+        assert_eq!(data.profiled_kernels, 0);
+    }
+    simulation::set_range_profiler_result(CUptiResult_CUPTI_SUCCESS);
+    detach();
+
+    // Seventh emission on a device the range profiler does not support: the
+    // profiler is never set up, so no launch tries it.
+    start_injection();
+    simulation::set_device_attribute(
+        CUdevice_attribute_enum_CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MAJOR,
+        6,
+    );
+    assert!(simulation::create_context(1));
+    assert!(simulation::launch_kernel(
+        1,
+        &SimulatedKernel {
+            start: 3000,
+            end: 3400,
+            ..kernel("legacy", 14000.0, 90.0)
+        }
+    ));
+    {
+        let data = CONTEXT_DATA.get(1).unwrap();
+        let data = data.lock().unwrap();
+        assert!(data.range_profiler.is_none());
+        assert!(data.metric_evaluator.is_none());
+        assert_eq!(
+            data.counters_unavailable.as_deref(),
+            Some("compute capability 6.0 is below the 7.0 the range profiler needs")
+        );
+        assert_eq!(data.profiled_kernels, 0);
+    }
+    simulation::set_device_attribute(
+        CUdevice_attribute_enum_CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MAJOR,
+        9,
+    );
+    detach();
+
+    // Eighth emission when the application destroys the context, as
+    // cudaDeviceReset does: its kernels are written before it is gone,
+    // without flushing anything by hand. The kernel is launched with
+    // cuLaunchKernelEx, as cudaLaunchKernelEx does.
+    start_injection();
+    assert!(simulation::create_context(1));
+    assert!(simulation::launch_kernel_ex(
+        1,
+        &SimulatedKernel {
+            start: 4000,
+            end: 4600,
+            ..kernel("reset", 600.0, 30.0)
+        }
+    ));
+    let data = CONTEXT_DATA.get(1).unwrap();
+    // Kernels the limit dropped are reported before the context goes.
+    data.lock().unwrap().dropped_kernels = 2;
+    assert!(simulation::destroy_context(1));
+    {
+        // Once written, the context is forgotten.
+        assert!(!CONTEXT_DATA.contains(1));
+        let data = data.lock().unwrap();
+        assert_eq!(data.emitted_kernels, 1);
+        assert!(data.kernel_launches.is_empty());
+        assert!(data.range_profiler.is_none());
+    }
+    assert!(GLOBAL_STATE.lock().unwrap().active_ctx.is_none());
+    detach();
+
+    // Ninth emission with a range that does not fit in the counter data
+    // image: its kernel goes without metrics instead of getting another's.
+    start_injection();
+    assert!(simulation::create_context(1));
+    for (name, start, end) in [("kept", 4700, 5400), ("lost", 5500, 6300)] {
+        assert!(simulation::launch_kernel(
+            1,
+            &SimulatedKernel {
+                start,
+                end,
+                ..kernel(name, (end - start) as f64, 40.0)
+            }
+        ));
+    }
+    simulation::drop_ranges(1);
+    detach();
+
+    let trace = stop_tracing_session(session);
+    let packets = parse_trace(&trace);
+    let render_stages: Vec<(u64, &RenderStageEvent)> = packets
+        .iter()
+        .filter_map(|p| match p {
+            Packet::RenderStage(ts, event) => Some((*ts, event)),
+            _ => None,
+        })
+        .collect();
+    // Dropped ranges are counted on a track of their own.
+    let is_dropped_ranges = |event: &CounterEvent| match &event.descriptor {
+        Some(names) => names[0] == DROPPED_RANGES_COUNTER,
+        None => event
+            .int_values
+            .first()
+            .is_some_and(|&(id, _)| id == DROPPED_RANGES_COUNTER_ID as u64),
+    };
+    let dropped_ranges: Vec<&CounterEvent> = packets
+        .iter()
+        .filter_map(|p| match p {
+            Packet::Counters(_, event) if is_dropped_ranges(event) => Some(event),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(dropped_ranges.len(), 1);
+    assert_eq!(
+        dropped_ranges[0].descriptor.as_deref(),
+        Some(&[DROPPED_RANGES_COUNTER.to_string()][..])
+    );
+    assert_eq!(dropped_ranges[0].int_values[0].1, 1);
+    // Overhead counters have IDs of their own, after the metrics.
+    let is_stats = |event: &CounterEvent| match &event.descriptor {
+        Some(names) => names[0].starts_with("injection."),
+        None => event
+            .int_values
+            .first()
+            .is_some_and(|&(id, _)| id >= STATS_COUNTER_ID_BASE as u64),
+    };
+    let (stats, counters): (Vec<_>, Vec<_>) = packets
+        .iter()
+        .filter_map(|p| match p {
+            Packet::Counters(ts, event) => Some((*ts, event)),
+            _ => None,
+        })
+        .filter(|(_, event)| !is_dropped_ranges(event))
+        .partition(|(_, event)| is_stats(event));
+
+    // One render stage event per kernel, with consecutive event ids.
+    assert_eq!(render_stages.len(), 17);
+    let durations: Vec<u64> = render_stages.iter().map(|(_, e)| e.duration).collect();
+    assert_eq!(
+        durations,
+        vec![
+            500, 1000, 2000, 3000, 4000, 5000, 7000, 8000, 900, 10000, 11000, 12000, 300, 400, 600,
+            700, 800
+        ]
+    );
+    for pair in render_stages.windows(2) {
+        assert_eq!(pair[1].1.event_id, pair[0].1.event_id + 1);
+    }
+    // Kernels launched back to back on a context queue up behind each other.
+    for phase in [&render_stages[1..3], &render_stages[3..6]] {
+        for pair in phase.windows(2) {
+            assert!(pair[1].0 >= pair[0].0 + pair[0].1.duration);
+        }
+    }
+    let summaries = summary::summarize(&trace).unwrap();
+    assert_eq!(summaries.iter().map(|s| s.count).sum::<u64>(), 17);
+    assert_eq!(
+        summaries.iter().map(|s| s.total_duration).sum::<u64>(),
+        durations.iter().sum::<u64>()
+    );
+    // A trace compared with its own baseline has no regressions.
+    let baseline = Baseline::from_summaries(&summaries);
+    for s in &summaries {
+        assert_eq!(
+            baseline.duration_change_pct(&s.name, s.mean_duration()),
+            Some(0.0)
+        );
+    }
+
+    // The JSON export has the same kernels, one per line.
+    let json = std::fs::read_to_string(json_path()).unwrap();
+    std::fs::remove_file(json_path()).unwrap();
+    let lines: Vec<&str> = json.lines().collect();
+    assert_eq!(lines.len(), 17);
+    for (line, duration) in lines.iter().zip(&durations) {
+        assert!(line.contains(&format!("\"duration\":{},", duration)));
+    }
+    assert!(lines[0].contains("\"kernel_name\":\"memset\""));
+    // Kineto's operator ID, pushed for the launch, joins it to PyTorch traces.
+    assert!(lines[0].contains("\"external_id\":\"42\""));
+    assert!(!lines[1].contains("external_id"));
+    assert!(lines[1].contains("\"metrics\":{\"gpu__time_duration.sum\":1000,"));
+    assert!(!lines[10].contains("duration_vs_baseline_pct"));
+    assert!(lines[11]
+        .contains("\"duration_vs_baseline_pct\":\"+20.0\",\"baseline_duration\":\"10000\""));
+    assert!(lines[15].contains("\"gpu__time_duration.sum\":700,"));
+    assert!(!lines[16].contains("gpu__time_duration.sum"));
+
+    // The unprofiled kernel still carries its launch metrics.
+    assert_eq!(extra(render_stages[0].1, "kernel_name"), Some("memset"));
+    assert_eq!(extra(render_stages[0].1, "launch__grid_size"), Some("8"));
+
+    // Static launch metrics travel as extra data.
+    let first = render_stages[1].1;
+    assert_eq!(extra(first, "kernel_name"), Some("_Z9vectorAddPKfS0_Pfi"));
+    assert!(extra(first, "kernel_demangled_name")
+        .unwrap()
+        .starts_with("vectorAdd("));
+    assert_eq!(extra(first, "kernel_type"), Some("Compute"));
+    assert_eq!(extra(first, "launch__grid_size"), Some("8"));
+    assert_eq!(extra(first, "launch__block_size"), Some("128"));
+    assert_eq!(extra(first, "launch__thread_count"), Some("1024"));
+    assert_eq!(extra(first, "launch__registers_per_thread"), Some("40"));
+    assert_eq!(
+        extra(first, "launch__shared_mem_per_block_driver"),
+        Some("1280")
+    );
+    assert_eq!(extra(first, "suggested_block_size"), Some("256"));
+    assert_eq!(extra(first, "device_name"), Some("Simulated GPU"));
+    for key in [
+        "process_id",
+        "process_name",
+        "process_cmdline",
+        "arch",
+        "launch__func_cache_config",
+        "launch__waves_per_multiprocessor",
+        "launch__occupancy_limit_warps",
+        "sm__maximum_warps_per_active_cycle_pct",
+    ] {
+        assert!(extra(first, key).is_some(), "missing extra data {}", key);
+    }
+    assert_eq!(extra(render_stages[2].1, "kernel_name"), Some("reduce"));
+
+    // Queue and stage specifications are only sent after incremental state is
+    // cleared, i.e. with the first event of the session.
+    let with_specs: Vec<bool> = render_stages
+        .iter()
+        .map(|(_, e)| e.has_specifications)
+        .collect();
+    assert_eq!(with_specs.iter().filter(|&&specs| specs).count(), 1);
+    assert!(with_specs[0]);
+    // All kernels ran on the default stream, the only queue.
+    assert_eq!(render_stages[0].1.queue_names, ["Stream 0"]);
+    assert_eq!(
+        render_stages[0].1.queue_descriptions,
+        ["GPU 0: Simulated GPU"]
+    );
+    assert!(render_stages.iter().all(|(_, e)| e.hw_queue_id == 0));
+
+    // The counter descriptor is emitted once per data source instance.
+    let descriptors: Vec<&Vec<String>> = counters
+        .iter()
+        .filter_map(|(_, e)| e.descriptor.as_ref())
+        .collect();
+    assert_eq!(descriptors.len(), 1);
+    assert_eq!(descriptors[0], &METRICS.to_vec());
+
+    // Each profiled kernel resets its counters at launch and reports the values
+    // at the end.
+    let samples: Vec<&(u64, &CounterEvent)> = counters
+        .iter()
+        .filter(|(_, e)| e.descriptor.is_none())
+        .collect();
+    let profiled_stages: Vec<&(u64, &RenderStageEvent)> = render_stages
+        .iter()
+        .filter(|(_, e)| {
+            !matches!(
+                extra(e, "kernel_name"),
+                Some("memset" | "unsampled" | "blocked" | "legacy" | "lost")
+            )
+        })
+        .collect();
+    assert_eq!(samples.len(), 2 * profiled_stages.len());
+    for (pair, &&(kernel_ts, event)) in samples.chunks(2).zip(&profiled_stages) {
+        let (launch_ts, start) = pair[0];
+        let (end_ts, end) = pair[1];
+        assert_eq!(*launch_ts, kernel_ts);
+        assert_eq!(*end_ts, launch_ts + event.duration);
+        assert_eq!(start.int_values, vec![(0, 0), (1, 0)]);
+        assert_eq!(end.double_values.len(), METRICS.len());
+    }
+    assert_eq!(samples[1].1.double_values, vec![(0, 1000.0), (1, 50.0)]);
+    assert_eq!(samples[5].1.double_values, vec![(0, 3000.0), (1, 90.0)]);
+    assert_eq!(samples[9].1.double_values, vec![(0, 5000.0), (1, 70.0)]);
+    assert_eq!(samples[11].1.double_values, vec![(0, 7000.0), (1, 20.0)]);
+    assert_eq!(samples[15].1.double_values, vec![(0, 10000.0), (1, 50.0)]);
+    assert_eq!(samples[19].1.double_values, vec![(0, 12000.0), (1, 70.0)]);
+
+    // The overhead counters are described once and sampled at least at exit,
+    // adding up over time.
+    let stats_descriptors: Vec<&Vec<String>> = stats
+        .iter()
+        .filter_map(|(_, e)| e.descriptor.as_ref())
+        .collect();
+    assert_eq!(stats_descriptors.len(), 1);
+    assert_eq!(
+        stats_descriptors[0],
+        &[
+            "injection.launch_callback_time",
+            "injection.decode_time",
+            "injection.evaluate_time",
+            "injection.lock_wait_time",
+        ]
+    );
+    let stats_samples: Vec<&Vec<(u64, i64)>> = stats
+        .iter()
+        .filter(|(_, e)| e.descriptor.is_none())
+        .map(|(_, e)| &e.int_values)
+        .collect();
+    assert!(!stats_samples.is_empty());
+    let last = stats_samples.last().unwrap();
+    let ids: Vec<u64> = last.iter().map(|&(id, _)| id).collect();
+    let base = STATS_COUNTER_ID_BASE as u64;
+    assert_eq!(ids, vec![base, base + 1, base + 2, base + 3]);
+    assert!(last[0].1 > 0, "no launch callback time");
+    assert!(last[2].1 > 0, "no evaluation time");
+    for pair in stats_samples.windows(2) {
+        for (before, after) in pair[0].iter().zip(pair[1]) {
+            assert!(after.1 >= before.1);
+        }
+    }
+
+    // Kernels evicted by the limit are reported as data loss.
+    let logs: Vec<&str> = packets
+        .iter()
+        .filter_map(|p| match p {
+            Packet::Log(message) => Some(message.as_str()),
+            _ => None,
+        })
+        .collect();
+    // Throttling decisions and dropped ranges are logged as well. They are
+    // written by the counter data source and dropped kernels by the render
+    // stage data source, whose packets are in no particular order.
+    assert_eq!(logs.len(), 4);
+    for prefix in [
+        "Dropped 1 kernels",
+        "Dropped 2 kernels of ctx 1 on GPU 0",
+        "Profiling 1 of 2 kernels",
+        "Dropped 1 ranges of ctx 1 on GPU 0",
+    ] {
+        assert!(logs.iter().any(|message| message.starts_with(prefix)));
+    }
+
+    // A session started after the first one ended is sent the specifications
+    // and descriptors again.
+    GLOBAL_STATE.lock().unwrap().json_export = None;
+    let session = start_tracing_session();
+    start_injection();
+    assert!(simulation::create_context(1));
+    assert!(simulation::launch_kernel(1, &kernel("again", 1500.0, 20.0)));
+    // Memory pool records become the pool counters of their device.
+    cupti_profiler::activity_enable(CUpti_ActivityKind_CUPTI_ACTIVITY_KIND_MEMORY_POOL).unwrap();
+    assert!(simulation::push_memory_pool_activity(
+        0,
+        CUpti_ActivityMemoryPoolOperationType_CUPTI_ACTIVITY_MEMORY_POOL_OPERATION_TYPE_CREATED,
+        0x1000,
+        2 << 20,
+        0
+    ));
+    assert!(simulation::push_memory_pool_activity(
+        0,
+        CUpti_ActivityMemoryPoolOperationType_CUPTI_ACTIVITY_MEMORY_POOL_OPERATION_TYPE_TRIMMED,
+        0x1000,
+        1 << 20,
+        512
+    ));
+    // A stream named with NVTX gets a queue named after it.
+    assert!(simulation::create_stream(1, 13));
+    assert!(simulation::name_stream(13, "decode stream"));
+    // A copy to another GPU goes on the queue of its stream as a copy.
+    cupti_profiler::activity_enable(CUpti_ActivityKind_CUPTI_ACTIVITY_KIND_MEMCPY2).unwrap();
+    assert!(simulation::push_peer_copy_activity(
+        0,
+        13,
+        0,
+        1,
+        4 << 20,
+        100
+    ));
+    assert!(simulation::launch_kernel(
+        1,
+        &SimulatedKernel {
+            stream_id: 13,
+            ..kernel("decode", 2500.0, 30.0)
+        }
+    ));
+    detach();
+    let packets = parse_trace(&stop_tracing_session(session));
+    let render_stages: Vec<&RenderStageEvent> = packets
+        .iter()
+        .filter_map(|p| match p {
+            Packet::RenderStage(_, event) => Some(event),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(render_stages.len(), 3);
+    assert!(render_stages[0].has_specifications);
+    assert_eq!(render_stages[0].queue_names, ["Stream 0", "decode stream"]);
+    assert_eq!(
+        render_stages[0].queue_descriptions,
+        ["GPU 0: Simulated GPU", "GPU 0: Simulated GPU"]
+    );
+    let copy = render_stages[0];
+    assert_eq!(copy.stage_id, COPY_STAGE_ID as u64);
+    assert_eq!(copy.hw_queue_id, 1);
+    assert_eq!(copy.duration, 100);
+    assert_eq!(extra(copy, "copy_kind"), Some("PtoP"));
+    assert_eq!(extra(copy, "bytes"), Some("4194304"));
+    assert_eq!(extra(copy, "src_device"), Some("0"));
+    assert_eq!(extra(copy, "dst_device"), Some("1"));
+    // Without NVML, the link cannot be told.
+    assert_eq!(extra(copy, "link"), Some("unknown"));
+    assert!(render_stages[1..].iter().all(|e| e.stage_id == 0));
+    assert_eq!(render_stages[1].hw_queue_id, 0);
+    assert_eq!(render_stages[2].hw_queue_id, 1);
+    assert!(!render_stages[1].has_specifications);
+    assert!(!render_stages[2].has_specifications);
+    let descriptors: Vec<&Vec<String>> = packets
+        .iter()
+        .filter_map(|p| match p {
+            Packet::Counters(_, event) => event.descriptor.as_ref(),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(descriptors.len(), 3);
+    assert!(descriptors.contains(&&METRICS.iter().map(|s| s.to_string()).collect()));
+    assert!(descriptors
+        .iter()
+        .any(|names| names[0] == "injection.launch_callback_time"));
+    assert!(descriptors.contains(&&vec![
+        "memory_pool.reserved".to_string(),
+        "memory_pool.used".to_string()
+    ]));
+    let pool_values: Vec<&[(u64, i64)]> = packets
+        .iter()
+        .filter_map(|p| match p {
+            Packet::Counters(_, event)
+                if event.int_values.first().is_some_and(|&(id, _)| id == 2001) =>
+            {
+                Some(event.int_values.as_slice())
+            }
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        pool_values,
+        [[(2001, 2 << 20), (2002, 0)], [(2001, 1 << 20), (2002, 512)]]
+    );
+}